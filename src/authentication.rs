@@ -12,12 +12,15 @@ pub trait Authentication: Send + Sync + 'static {
 }
 
 pub mod token {
+    use std::fs;
+    use std::path::PathBuf;
     use std::rc::Rc;
 
     use async_trait::async_trait;
 
     use crate::authentication::Authentication;
     use crate::error::AuthenticationError;
+    use crate::executor::Executor;
 
     pub struct TokenAuthentication {
         token: Vec<u8>,
@@ -45,6 +48,80 @@ pub mod token {
             Ok(self.token.clone())
         }
     }
+
+    /// token authentication reading the token from a file
+    ///
+    /// the file is read again every time a connection is authenticated, so
+    /// that tokens rotated by an external process are picked up
+    pub struct TokenFileAuthentication<Exe: Executor> {
+        path: PathBuf,
+        executor: Exe,
+    }
+
+    impl<Exe: Executor> TokenFileAuthentication<Exe> {
+        /// the file is read on a blocking task of `executor`
+        pub fn from_path<P: Into<PathBuf>>(path: P, executor: Exe) -> Box<dyn Authentication> {
+            Box::new(TokenFileAuthentication {
+                path: path.into(),
+                executor,
+            })
+        }
+    }
+
+    #[async_trait]
+    impl<Exe: Executor> Authentication for TokenFileAuthentication<Exe> {
+        fn auth_method_name(&self) -> String {
+            String::from("token")
+        }
+
+        async fn initialize(&mut self) -> Result<(), AuthenticationError> {
+            // fail early if the file cannot be read
+            self.auth_data().await.map(|_| ())
+        }
+
+        async fn auth_data(&mut self) -> Result<Vec<u8>, AuthenticationError> {
+            let path = self.path.clone();
+            let token = self
+                .executor
+                .spawn_blocking(move || fs::read_to_string(path))
+                .await
+                .ok_or_else(|| {
+                    AuthenticationError::Custom("token file reader stopped".to_string())
+                })?
+                .map_err(|e| {
+                    AuthenticationError::Custom(format!(
+                        "could not read token file {}: {}",
+                        self.path.display(),
+                        e
+                    ))
+                })?;
+            Ok(token.trim().as_bytes().to_vec())
+        }
+    }
+
+    #[cfg(all(test, feature = "tokio-runtime"))]
+    mod tests {
+        use std::fs;
+
+        use crate::authentication::token::TokenFileAuthentication;
+
+        #[tokio::test]
+        async fn token_file() {
+            let path =
+                std::env::temp_dir().join(format!("pulsar-token-file-{}", std::process::id()));
+            let mut auth = TokenFileAuthentication::from_path(path.clone(), crate::TokioExecutor);
+            assert!(auth.initialize().await.is_err());
+
+            fs::write(&path, "first-token\n").unwrap();
+            auth.initialize().await.unwrap();
+            assert_eq!(auth.auth_data().await.unwrap(), b"first-token");
+
+            // the token is read again when it changes
+            fs::write(&path, "second-token").unwrap();
+            assert_eq!(auth.auth_data().await.unwrap(), b"second-token");
+            fs::remove_file(&path).unwrap();
+        }
+    }
 }
 
 #[cfg(feature = "auth-oauth2")]
@@ -56,7 +133,7 @@ pub mod oauth2 {
     use async_trait::async_trait;
     use data_url::{DataUrl};
     use nom::lib::std::ops::Add;
-    use oauth2::{AuthUrl, ClientId, ClientSecret, Scope, TokenResponse, TokenUrl};
+    use oauth2::{AuthUrl, ClientId, ClientSecret, RequestTokenError, Scope, TokenResponse, TokenUrl};
     use oauth2::AuthType::RequestBody;
     use oauth2::basic::{BasicClient, BasicTokenResponse};
     use oauth2::reqwest::async_http_client;
@@ -255,6 +332,7 @@ pub mod oauth2 {
             } else {
                 self.params.issuer_url.as_str()
             };
+            let description = format!("client {} at {}", private_params.client_id, issuer_url);
 
             let client = BasicClient::new(
                 ClientId::new(private_params.client_id.clone()),
@@ -274,8 +352,14 @@ pub mod oauth2 {
                 request = request.add_scope(Scope::new(scope.clone()));
             }
 
-            let token = request
-                .request_async(async_http_client).await?;
+            let token = match request.request_async(async_http_client).await {
+                Ok(token) => token,
+                // the server answered with an error like invalid_client or invalid_grant
+                Err(RequestTokenError::ServerResponse(resp)) => {
+                    return Err(Box::from(format!("authorization server rejected the credentials of {}: {}", description, resp)));
+                }
+                Err(e) => return Err(Box::new(e)),
+            };
             debug!("Got a new oauth2 token for [{}]", self.params);
             Ok(token)
         }
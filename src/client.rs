@@ -3,20 +3,48 @@ use std::sync::Arc;
 
 use futures::channel::{mpsc, oneshot};
 
-use crate::connection::Authentication;
+use crate::connection::{Authentication, Connection, ReceiverStats, RequestKey};
 use crate::connection_manager::{
-    BrokerAddress, ConnectionManager, ConnectionRetryOptions, OperationRetryOptions, TlsOptions,
+    BrokerAddress, ConnectionManager, ConnectionOptions, ConnectionRetryOptions, ConnectionState,
+    OperationRetryOptions, TlsOptions,
 };
-use crate::consumer::{ConsumerBuilder, ConsumerOptions, InitialPosition};
-use crate::error::Error;
+use crate::consumer::{Consumer, ConsumerBuilder, ConsumerOptions, InitialPosition};
+use crate::error::{Error, TransactionError};
 use crate::executor::Executor;
+use crate::message::proto::command_subscribe::SubType;
 use crate::message::proto::{self, CommandSendReceipt};
-use crate::message::Payload;
-use crate::producer::{self, ProducerBuilder, SendFuture};
+use crate::message::{Message, Payload};
+use crate::producer::{self, Producer, ProducerBuilder, SendFuture};
 use crate::service_discovery::ServiceDiscovery;
+use crate::transaction::TxnId;
 use futures::StreamExt;
 use futures::lock::Mutex;
 
+/// topics of a namespace returned by [Pulsar::get_topics_of_namespace_with_hash]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NamespaceTopics {
+    /// names of the topics, empty if they did not change
+    pub topics: Vec<String>,
+    /// hash the broker computed from the names of the topics, if it supports it
+    pub hash: Option<String>,
+    /// false if the broker answered that the topics did not change since
+    /// the hash sent with the request
+    pub changed: bool,
+}
+
+impl NamespaceTopics {
+    /// tells if these topics differ from the ones returned with `previous_hash`
+    ///
+    /// brokers that do not compute hashes send none, their topics are always
+    /// considered changed
+    pub fn changed_since(&self, previous_hash: &Option<String>) -> bool {
+        match (previous_hash, &self.hash) {
+            (Some(previous_hash), Some(hash)) => previous_hash != hash,
+            _ => self.changed,
+        }
+    }
+}
+
 /// Helper trait for consumer deserialization
 pub trait DeserializeMessage {
     /// type produced from the message
@@ -163,6 +191,7 @@ impl<Exe: Executor> Pulsar<Exe> {
         connection_retry_parameters: Option<ConnectionRetryOptions>,
         operation_retry_parameters: Option<OperationRetryOptions>,
         tls_options: Option<TlsOptions>,
+        connection_options: Option<ConnectionOptions>,
         executor: Exe,
     ) -> Result<Self, Error> {
         let url: String = url.into();
@@ -174,6 +203,7 @@ impl<Exe: Executor> Pulsar<Exe> {
             connection_retry_parameters,
             operation_retry_options.clone(),
             tls_options,
+            connection_options,
             executor.clone(),
         )
         .await?;
@@ -216,6 +246,38 @@ impl<Exe: Executor> Pulsar<Exe> {
         Ok(client)
     }
 
+    /// client that does not connect to a broker until a connection is requested
+    #[cfg(all(test, feature = "tokio-runtime"))]
+    pub(crate) fn disconnected(executor: Exe) -> Self {
+        let executor = Arc::new(executor);
+        let url = url::Url::parse("pulsar://127.0.0.1:6650").unwrap();
+        let manager = Arc::new(ConnectionManager::disconnected(url, executor.clone()));
+        Pulsar {
+            service_discovery: Arc::new(ServiceDiscovery::with_manager(manager.clone())),
+            manager,
+            producer: None,
+            operation_retry_options: Default::default(),
+            executor,
+        }
+    }
+
+    /// client whose connection to the broker is `connection`
+    #[cfg(all(test, feature = "tokio-runtime"))]
+    pub(crate) fn with_connection(executor: Exe, connection: Arc<Connection<Exe>>) -> Self {
+        let executor = Arc::new(executor);
+        let manager = Arc::new(ConnectionManager::with_connection(
+            executor.clone(),
+            connection,
+        ));
+        Pulsar {
+            service_discovery: Arc::new(ServiceDiscovery::with_manager(manager.clone())),
+            manager,
+            producer: None,
+            operation_retry_options: Default::default(),
+            executor,
+        }
+    }
+
     /// creates a new client builder
     ///
     /// ```rust,no_run
@@ -237,6 +299,7 @@ impl<Exe: Executor> Pulsar<Exe> {
             connection_retry_options: None,
             operation_retry_options: None,
             tls_options: None,
+            connection_options: None,
             executor,
         }
     }
@@ -280,6 +343,57 @@ impl<Exe: Executor> Pulsar<Exe> {
         ProducerBuilder::new(self)
     }
 
+    /// creates a producer on a topic with the default options
+    ///
+    /// the topic lookup, redirections and connection to the broker are handled
+    /// internally. Use [Pulsar::producer] for more configuration options
+    ///
+    /// ```rust,no_run
+    /// # async fn run(pulsar: pulsar::Pulsar<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+    /// let mut producer = pulsar
+    ///     .create_producer("non-persistent://public/default/test")
+    ///     .await?;
+    /// producer.send("hello").await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn create_producer<S: Into<String>>(&self, topic: S) -> Result<Producer<Exe>, Error> {
+        self.producer().with_topic(topic).build().await
+    }
+
+    /// subscribes to a topic with the default options (shared subscription)
+    ///
+    /// the topic lookup, redirections and connection to the broker are handled
+    /// internally. Use [Pulsar::consumer] for more configuration options
+    ///
+    /// ```rust,no_run
+    /// use pulsar::Consumer;
+    ///
+    /// # async fn run(pulsar: pulsar::Pulsar<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+    /// let mut consumer: Consumer<String, _> = pulsar
+    ///     .subscribe("non-persistent://public/default/test", "test_subscription")
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn subscribe<T, S1, S2>(
+        &self,
+        topic: S1,
+        subscription: S2,
+    ) -> Result<Consumer<T, Exe>, Error>
+    where
+        T: DeserializeMessage,
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        self.consumer()
+            .with_topic(topic)
+            .with_subscription(subscription)
+            .with_subscription_type(SubType::Shared)
+            .build()
+            .await
+    }
+
     /// creates a reader builder
     /// ```rust, no_run
     /// use pulsar::reader::Reader;
@@ -357,6 +471,103 @@ impl<Exe: Executor> Pulsar<Exe> {
             .map_err(|e| e.into())
     }
 
+    /// gets the schema of a topic, in its latest version if `version` is not specified
+    ///
+    /// ```rust,no_run
+    /// # async fn run(pulsar: pulsar::Pulsar<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+    /// let response = pulsar.get_schema("persistent://public/default/test", None).await?;
+    /// if let Some(schema) = response.schema {
+    ///     println!("schema {}: {:?}", schema.name, schema.r#type);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn get_schema<S: Into<String>>(
+        &self,
+        topic: S,
+        version: Option<Vec<u8>>,
+    ) -> Result<proto::CommandGetSchemaResponse, Error> {
+        let topic = topic.into();
+        let broker_address = self.lookup_topic(topic.clone()).await?;
+        let conn = self.manager.get_connection(&broker_address).await?;
+        let response = conn.sender().get_schema(topic, version).await?;
+        Ok(response)
+    }
+
+    /// opens a transaction, to attach messages to it with
+    /// [producer::Message::txn_id](crate::producer::Message::txn_id)
+    ///
+    /// the broker must have transactions enabled. The transaction is aborted
+    /// if not ended with [Pulsar::end_txn] within `timeout`, which is rounded
+    /// up to whole seconds
+    pub async fn new_txn(&self, timeout: std::time::Duration) -> Result<TxnId, Error> {
+        let conn = self.transaction_coordinator().await?;
+        let txn_id = conn
+            .sender()
+            .new_txn(0, timeout)
+            .await
+            .map_err(TransactionError::from)?;
+        Ok(txn_id)
+    }
+
+    /// enrolls topic partitions in the transaction, this must be done before
+    /// producing transactional messages on them
+    pub async fn add_partition_to_txn(
+        &self,
+        txn_id: TxnId,
+        partitions: Vec<String>,
+    ) -> Result<(), Error> {
+        let conn = self.transaction_coordinator().await?;
+        conn.sender()
+            .add_partition_to_txn(txn_id, partitions)
+            .await
+            .map_err(TransactionError::from)?;
+        Ok(())
+    }
+
+    /// enrolls a subscription in the transaction, so that its acknowledgements
+    /// are committed or aborted along with the produced messages
+    pub async fn add_subscription_to_txn<S1: Into<String>, S2: Into<String>>(
+        &self,
+        txn_id: TxnId,
+        topic: S1,
+        subscription: S2,
+    ) -> Result<(), Error> {
+        let conn = self.transaction_coordinator().await?;
+        let subscription = proto::Subscription {
+            topic: topic.into(),
+            subscription: subscription.into(),
+        };
+        conn.sender()
+            .add_subscription_to_txn(txn_id, vec![subscription])
+            .await
+            .map_err(TransactionError::from)?;
+        Ok(())
+    }
+
+    /// commits the transaction if `commit` is true, aborts it otherwise
+    ///
+    /// a [TransactionError::Conflict] indicates that the transaction could
+    /// not be committed
+    pub async fn end_txn(&self, txn_id: TxnId, commit: bool) -> Result<(), Error> {
+        let conn = self.transaction_coordinator().await?;
+        conn.sender()
+            .end_txn(txn_id, commit)
+            .await
+            .map_err(TransactionError::from)?;
+        Ok(())
+    }
+
+    async fn transaction_coordinator(&self) -> Result<Arc<Connection<Exe>>, Error> {
+        // transaction coordinator `n` is served by the broker owning the
+        // partition `n` of this topic, only the first one is used
+        let broker_address = self
+            .lookup_topic("persistent://pulsar/system/transaction_coordinator_assign-partition-0")
+            .await?;
+        let conn = self.manager.get_connection(&broker_address).await?;
+        Ok(conn)
+    }
+
     /// gets the list of topics from a namespace
     ///
     /// ```rust,no_run
@@ -372,12 +583,117 @@ impl<Exe: Executor> Pulsar<Exe> {
         namespace: String,
         mode: proto::command_get_topics_of_namespace::Mode,
     ) -> Result<Vec<String>, Error> {
+        let namespace_topics = self
+            .get_topics_of_namespace_with_hash(namespace, mode, None)
+            .await?;
+        Ok(namespace_topics.topics)
+    }
+
+    /// gets the list of topics from a namespace, and the hash the broker
+    /// computed from their names
+    ///
+    /// When `previous_hash` is the hash of the current topics, the broker does
+    /// not send them again and the list is empty: use
+    /// [NamespaceTopics::changed_since] to tell an unchanged list from an
+    /// empty namespace.
+    ///
+    /// ```rust,no_run
+    /// use pulsar::message::proto::command_get_topics_of_namespace::Mode;
+    ///
+    /// # async fn run(pulsar: pulsar::Pulsar<pulsar::TokioExecutor>, previous_hash: Option<String>) -> Result<(), pulsar::Error> {
+    /// let namespace_topics = pulsar
+    ///     .get_topics_of_namespace_with_hash("public/default".to_string(), Mode::All, previous_hash.clone())
+    ///     .await?;
+    /// if namespace_topics.changed_since(&previous_hash) {
+    ///     println!("topics: {:?}", namespace_topics.topics);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn get_topics_of_namespace_with_hash(
+        &self,
+        namespace: String,
+        mode: proto::command_get_topics_of_namespace::Mode,
+        previous_hash: Option<String>,
+    ) -> Result<NamespaceTopics, Error> {
         let conn = self.manager.get_base_connection().await?;
-        let topics = conn
+        let response = conn
+            .sender()
+            .get_topics_of_namespace(namespace, mode, previous_hash)
+            .await?;
+        Ok(NamespaceTopics {
+            topics: response.topics,
+            hash: response.topics_hash,
+            changed: response.changed.unwrap_or(true),
+        })
+    }
+
+    /// sends a command that has no typed wrapper in this crate to a broker,
+    /// and returns its whole response
+    ///
+    /// `build` receives a new request id and returns the command, the
+    /// response is the one carrying that request id
+    pub async fn send_raw_command<F>(
+        &self,
+        broker: &BrokerAddress,
+        build: F,
+    ) -> Result<Message, Error>
+    where
+        F: FnOnce(u64) -> Message,
+    {
+        let conn = self.manager.get_connection(broker).await?;
+        let request_id = conn.sender().new_request_id();
+        let response = conn
             .sender()
-            .get_topics_of_namespace(namespace, mode)
+            .send_raw(build(request_id), RequestKey::RequestId(request_id))
             .await?;
-        Ok(topics.topics)
+        Ok(response)
+    }
+
+    /// sends a command to a broker without waiting for a response
+    pub async fn send_raw_oneway_command(
+        &self,
+        broker: &BrokerAddress,
+        msg: Message,
+    ) -> Result<(), Error> {
+        let conn = self.manager.get_connection(broker).await?;
+        conn.sender().send_raw_oneway(msg).await?;
+        Ok(())
+    }
+
+    /// gets the sizes of the internal request and consumer maps of each broker connection
+    ///
+    /// This is meant for monitoring: values that keep growing indicate orphaned
+    /// requests or a consumer that stopped reading its messages.
+    ///
+    /// ```rust,no_run
+    /// # async fn run(pulsar: pulsar::Pulsar<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+    /// for (broker, stats) in pulsar.receiver_stats().await {
+    ///     println!("{}: {} pending requests", broker.url, stats.pending_requests);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn receiver_stats(&self) -> Vec<(BrokerAddress, ReceiverStats)> {
+        self.manager.receiver_stats().await
+    }
+
+    /// pings the connection to each broker, and tells if it answered within
+    /// `timeout`
+    ///
+    /// unlike [Pulsar::connection_states], this detects connections that were
+    /// lost without being closed. The ones that fail are established again the
+    /// next time they are used
+    pub async fn healthcheck(&self, timeout: std::time::Duration) -> Vec<(BrokerAddress, bool)> {
+        self.manager.healthcheck(timeout).await
+    }
+
+    /// gets the state of the connection to each broker
+    ///
+    /// lost connections are established again with exponential backoff, as
+    /// configured by [ConnectionRetryOptions], the next time they are used
+    pub async fn connection_states(&self) -> Vec<(BrokerAddress, ConnectionState)> {
+        self.manager.connection_states().await
     }
 
     /// Sends a message on a topic.
@@ -428,6 +744,7 @@ pub struct PulsarBuilder<Exe: Executor> {
     connection_retry_options: Option<ConnectionRetryOptions>,
     operation_retry_options: Option<OperationRetryOptions>,
     tls_options: Option<TlsOptions>,
+    connection_options: Option<ConnectionOptions>,
     executor: Exe,
 }
 
@@ -460,6 +777,12 @@ impl<Exe: Executor> PulsarBuilder<Exe> {
         self
     }
 
+    /// protocol parameters sent to the brokers when connecting
+    pub fn with_connection_options(mut self, connection_options: ConnectionOptions) -> Self {
+        self.connection_options = Some(connection_options);
+        self
+    }
+
     /// add a custom certificate chain to authenticate the server in TLS connections
     pub fn with_certificate_chain(mut self, certificate_chain: Vec<u8>) -> Self {
         match &mut self.tls_options {
@@ -474,6 +797,10 @@ impl<Exe: Executor> PulsarBuilder<Exe> {
         self
     }
 
+    /// accept invalid or self-signed server certificates in TLS connections
+    ///
+    /// **this is insecure**: the identity of the server is not verified anymore,
+    /// so it should only be used for local testing. Defaults to *false*
     pub fn with_allow_insecure_connection(mut self, allow: bool) -> Self {
         match &mut self.tls_options {
             Some(tls) => tls.allow_insecure_connection = allow,
@@ -487,6 +814,10 @@ impl<Exe: Executor> PulsarBuilder<Exe> {
         self
     }
 
+    /// check that the server certificate matches the broker's hostname, when
+    /// insecure connections are allowed
+    ///
+    /// disabling it is insecure. Defaults to *true*
     pub fn with_tls_hostname_verification_enabled(mut self, enabled: bool) -> Self {
         match &mut self.tls_options {
             Some(tls) => tls.tls_hostname_verification_enabled = enabled,
@@ -500,7 +831,75 @@ impl<Exe: Executor> PulsarBuilder<Exe> {
         self
     }
 
+    /// authenticate to the broker with a client certificate (mutual TLS)
+    ///
+    /// `certificate` is a PEM encoded certificate chain, the leaf certificate first,
+    /// and `key` is the PEM encoded PKCS #8 private key of the leaf certificate
+    pub fn with_client_certificate(mut self, certificate: Vec<u8>, key: Vec<u8>) -> Self {
+        match &mut self.tls_options {
+            Some(tls) => {
+                tls.client_certificate = Some(certificate);
+                tls.client_key = Some(key);
+            }
+            None => {
+                self.tls_options = Some(TlsOptions {
+                    client_certificate: Some(certificate),
+                    client_key: Some(key),
+                    ..Default::default()
+                })
+            }
+        }
+        self
+    }
+
+    /// authenticate to the broker with a client certificate and key read from PEM files
+    pub fn with_client_certificate_files<P: AsRef<std::path::Path>>(
+        self,
+        certificate_path: P,
+        key_path: P,
+    ) -> Result<Self, std::io::Error> {
+        let certificate = std::fs::read(certificate_path)?;
+        let key = std::fs::read(key_path)?;
+
+        Ok(self.with_client_certificate(certificate, key))
+    }
+
+    /// authenticate to the broker with a client identity stored in a PKCS #12 archive
+    ///
+    /// `archive` is the DER encoded archive, holding the certificate chain and
+    /// its private key, and `password` the password protecting it
+    pub fn with_client_pkcs12(mut self, archive: Vec<u8>, password: String) -> Self {
+        match &mut self.tls_options {
+            Some(tls) => {
+                tls.client_pkcs12 = Some(archive);
+                tls.client_pkcs12_password = Some(password);
+            }
+            None => {
+                self.tls_options = Some(TlsOptions {
+                    client_pkcs12: Some(archive),
+                    client_pkcs12_password: Some(password),
+                    ..Default::default()
+                })
+            }
+        }
+        self
+    }
+
+    /// authenticate to the broker with a client identity read from a PKCS #12 file
+    pub fn with_client_pkcs12_file<P: AsRef<std::path::Path>>(
+        self,
+        path: P,
+        password: String,
+    ) -> Result<Self, std::io::Error> {
+        let archive = std::fs::read(path)?;
+
+        Ok(self.with_client_pkcs12(archive, password))
+    }
+
     /// add a custom certificate chain from a file to authenticate the server in TLS connections
+    ///
+    /// the file can be a bundle of multiple PEM encoded certificates. If it does not
+    /// contain any certificate, building the client will fail
     pub fn with_certificate_chain_file<P: AsRef<std::path::Path>>(
         self,
         path: P,
@@ -522,6 +921,7 @@ impl<Exe: Executor> PulsarBuilder<Exe> {
             connection_retry_options,
             operation_retry_options,
             tls_options,
+            connection_options,
             executor,
         } = self;
 
@@ -531,6 +931,7 @@ impl<Exe: Executor> PulsarBuilder<Exe> {
             connection_retry_options,
             operation_retry_options,
             tls_options,
+            connection_options,
             executor,
         )
         .await
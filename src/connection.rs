@@ -1,7 +1,7 @@
-use native_tls::Certificate;
+use native_tls::{Certificate, Identity};
 use proto::MessageIdData;
 use rand::{thread_rng, Rng};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::Debug;
 use std::net::SocketAddr;
 use std::pin::Pin;
@@ -9,7 +9,7 @@ use std::sync::{
     atomic::{AtomicUsize, Ordering},
     Arc,
 };
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use futures::{
     self,
@@ -21,29 +21,127 @@ use futures::{
 };
 use url::Url;
 
+use crate::connection_manager::ConnectionOptions;
 use crate::consumer::ConsumerOptions;
 use crate::error::{AuthenticationError, ConnectionError, SharedError};
-use crate::executor::{Executor, ExecutorKind};
+use crate::executor::{Elapsed, Executor, ExecutorKind};
 use crate::message::{
     proto::{self, command_subscribe::SubType},
-    BaseCommand, Codec, Message,
+    BaseCommand, Message, DEFAULT_MAX_MESSAGE_SIZE, FRAME_PADDING,
 };
 use crate::producer::{self, ProducerOptions};
+use crate::transaction::TxnId;
 use async_trait::async_trait;
 use futures::lock::Mutex;
 
+/// sends the response of a request
+///
+/// with the `tracing` feature, it is sent in the request's span, so that
+/// both can be followed together
+pub(crate) struct ResponseResolver {
+    sender: oneshot::Sender<Message>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl ResponseResolver {
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn for_request(sender: oneshot::Sender<Message>, key: &RequestKey, msg: &Message) -> Self {
+        ResponseResolver {
+            sender,
+            #[cfg(feature = "tracing")]
+            span: {
+                let span = tracing::debug_span!(
+                    "pulsar_request",
+                    key = ?key,
+                    command = ?proto::base_command::Type::from_i32(msg.command.r#type)
+                );
+                span.in_scope(|| tracing::debug!("sending request"));
+                span
+            },
+        }
+    }
+
+    fn send(self, msg: Message) {
+        #[cfg(feature = "tracing")]
+        let _entered = self.span.enter();
+        #[cfg(feature = "tracing")]
+        tracing::debug!("received response");
+        // We don't care if the receiver has dropped their future
+        let _ = self.sender.send(msg);
+    }
+
+    fn is_canceled(&self) -> bool {
+        self.sender.is_canceled()
+    }
+}
+
+impl From<oneshot::Sender<Message>> for ResponseResolver {
+    fn from(sender: oneshot::Sender<Message>) -> Self {
+        ResponseResolver {
+            sender,
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::none(),
+        }
+    }
+}
+
 pub(crate) enum Register {
     Request {
         key: RequestKey,
-        resolver: oneshot::Sender<Message>,
+        resolver: ResponseResolver,
+    },
+    /// the request timed out, its response will not be awaited anymore
+    CancelRequest {
+        key: RequestKey,
     },
     Consumer {
         consumer_id: u64,
         resolver: mpsc::UnboundedSender<Message>,
     },
+    /// the consumer was closed or its subscription failed, its messages
+    /// will not be read
+    RemoveConsumer {
+        consumer_id: u64,
+    },
+    Producer {
+        producer_id: u64,
+        resolver: mpsc::UnboundedSender<Message>,
+    },
+    /// the producer was closed, the broker's notifications for it are dropped
+    RemoveProducer {
+        producer_id: u64,
+    },
     Ping {
         resolver: oneshot::Sender<()>,
     },
+    Stats {
+        resolver: oneshot::Sender<ReceiverStats>,
+    },
+}
+
+/// position a subscription's cursor is moved to by a seek
+#[derive(Debug, Clone)]
+pub enum SeekTarget {
+    /// the given message
+    MessageId(MessageIdData),
+    /// the first message published at or after the given time, in milliseconds
+    /// since the epoch
+    Timestamp(u64),
+}
+
+/// sizes of the internal maps of a connection's receiver
+///
+/// values growing without bound usually indicate orphaned requests
+/// or a consumer that stopped reading its messages
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReceiverStats {
+    /// requests waiting for a response from the server
+    pub pending_requests: usize,
+    /// responses received before the matching request was registered
+    pub received_messages: usize,
+    /// consumers registered on this connection
+    pub consumers: usize,
 }
 
 /// identifier for a message
@@ -53,6 +151,31 @@ pub enum RequestKey {
     ProducerSend { producer_id: u64, sequence_id: u64 },
     Consumer { consumer_id: u64 },
     CloseConsumer { consumer_id: u64, request_id: u64 },
+    CloseProducer { producer_id: u64, request_id: u64 },
+}
+
+/// receives measurements from the connections, to export them as metrics
+///
+/// every method does nothing by default
+pub trait ConnectionMetrics: Send + Sync {
+    /// a request expecting a response was queued
+    fn on_request_sent(&self, _key: &RequestKey) {}
+
+    /// the response to a request arrived `latency` after it was queued
+    fn on_response(&self, _key: &RequestKey, _latency: Duration) {}
+
+    /// a frame of `bytes` bytes was written to the socket
+    fn on_bytes_sent(&self, _bytes: usize) {}
+
+    /// a frame of `bytes` bytes was read from the socket
+    fn on_bytes_received(&self, _bytes: usize) {}
+}
+
+/// resolves the brokers' host names, replacing the system resolver
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    /// returns the addresses for this host, one of them is picked at random
+    async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, std::io::Error>;
 }
 
 /// Authentication parameters
@@ -81,20 +204,35 @@ impl crate::authentication::Authentication for Authentication {
 
 pub(crate) struct Receiver<S: Stream<Item = Result<Message, ConnectionError>>> {
     inbound: Pin<Box<S>>,
-    outbound: mpsc::UnboundedSender<Message>,
+    outbound: OutboundSender,
     error: SharedError,
-    pending_requests: BTreeMap<RequestKey, oneshot::Sender<Message>>,
+    pending_requests: BTreeMap<RequestKey, ResponseResolver>,
     consumers: BTreeMap<u64, mpsc::UnboundedSender<Message>>,
-    received_messages: BTreeMap<RequestKey, Message>,
+    producers: BTreeMap<u64, mpsc::UnboundedSender<Message>>,
+    /// highest sequence id acknowledged by the broker for each producer
+    last_receipts: BTreeMap<u64, u64>,
+    /// responses that arrived before their request was registered, with
+    /// their arrival time
+    received_messages: BTreeMap<RequestKey, (Instant, Message)>,
+    /// keys of `received_messages` in arrival order, entries already claimed
+    /// are skipped when evicting
+    received_order: VecDeque<(Instant, RequestKey)>,
     registrations: Pin<Box<mpsc::UnboundedReceiver<Register>>>,
     shutdown: Pin<Box<oneshot::Receiver<()>>>,
-    ping: Option<oneshot::Sender<()>>,
+    /// pings waiting for a pong, the broker answers them in order
+    pings: VecDeque<oneshot::Sender<()>>,
+    /// size of `pending_requests` above which requests whose caller
+    /// went away are removed
+    prune_threshold: usize,
+    /// the broker's authentication challenges are answered by the task
+    /// reading this channel
+    auth_challenges: Option<mpsc::UnboundedSender<proto::CommandAuthChallenge>>,
 }
 
 impl<S: Stream<Item = Result<Message, ConnectionError>>> Receiver<S> {
     pub fn new(
         inbound: S,
-        outbound: mpsc::UnboundedSender<Message>,
+        outbound: OutboundSender,
         error: SharedError,
         registrations: mpsc::UnboundedReceiver<Register>,
         shutdown: oneshot::Receiver<()>,
@@ -105,14 +243,295 @@ impl<S: Stream<Item = Result<Message, ConnectionError>>> Receiver<S> {
             error,
             pending_requests: BTreeMap::new(),
             received_messages: BTreeMap::new(),
+            received_order: VecDeque::new(),
             consumers: BTreeMap::new(),
+            producers: BTreeMap::new(),
+            last_receipts: BTreeMap::new(),
             registrations: Box::pin(registrations),
             shutdown: Box::pin(shutdown),
-            ping: None,
+            pings: VecDeque::new(),
+            prune_threshold: MIN_PRUNE_THRESHOLD,
+            auth_challenges: None,
+        }
+    }
+
+    /// forwards the broker's authentication challenges to `auth_challenges`
+    pub fn with_auth_challenges(
+        mut self,
+        auth_challenges: mpsc::UnboundedSender<proto::CommandAuthChallenge>,
+    ) -> Self {
+        self.auth_challenges = Some(auth_challenges);
+        self
+    }
+
+    /// removes the pending requests whose future was dropped before the
+    /// response arrived. The threshold grows with the number of requests
+    /// still awaited, so that pruning stays cheap
+    fn prune_pending_requests(&mut self) {
+        if self.pending_requests.len() < self.prune_threshold {
+            return;
+        }
+
+        self.pending_requests
+            .retain(|_, resolver| !resolver.is_canceled());
+        self.prune_threshold = std::cmp::max(MIN_PRUNE_THRESHOLD, self.pending_requests.len() * 2);
+    }
+
+    /// removes the responses that were never claimed by a request, either
+    /// because they are too old or because there are too many of them
+    fn evict_received_messages(&mut self, now: Instant) {
+        let before = self.received_messages.len();
+        while let Some((received_at, key)) = self.received_order.front() {
+            let claimed = !matches!(
+                self.received_messages.get(key),
+                Some((at, _)) if at == received_at
+            );
+            let expired = now.saturating_duration_since(*received_at) >= RECEIVED_MESSAGES_TTL;
+            if !claimed && !expired && self.received_messages.len() <= MAX_RECEIVED_MESSAGES {
+                break;
+            }
+            if !claimed {
+                self.received_messages.remove(key);
+            }
+            self.received_order.pop_front();
+        }
+        if self.received_messages.len() < before {
+            debug!(
+                "evicted {} responses that no request claimed",
+                before - self.received_messages.len()
+            );
+        }
+    }
+
+    fn handle_registration(&mut self, registration: Register) {
+        match registration {
+            Register::Request { key, resolver } => match self.received_messages.remove(&key) {
+                Some((_, msg)) => resolver.send(msg),
+                None => {
+                    self.pending_requests.insert(key, resolver);
+                    self.prune_pending_requests();
+                }
+            },
+            Register::CancelRequest { key } => {
+                if self.pending_requests.remove(&key).is_none() {
+                    self.received_messages.remove(&key);
+                }
+            }
+            Register::Consumer {
+                consumer_id,
+                resolver,
+            } => {
+                self.consumers.insert(consumer_id, resolver);
+            }
+            Register::RemoveConsumer { consumer_id } => {
+                self.consumers.remove(&consumer_id);
+            }
+            Register::Producer {
+                producer_id,
+                resolver,
+            } => {
+                self.producers.insert(producer_id, resolver);
+            }
+            Register::RemoveProducer { producer_id } => {
+                self.producers.remove(&producer_id);
+                self.last_receipts.remove(&producer_id);
+            }
+            Register::Ping { resolver } => {
+                self.pings.push_back(resolver);
+            }
+            Register::Stats { resolver } => {
+                let _ = resolver.send(ReceiverStats {
+                    pending_requests: self.pending_requests.len(),
+                    received_messages: self.received_messages.len(),
+                    consumers: self.consumers.len(),
+                });
+            }
+        }
+    }
+
+    fn handle_inbound(&mut self, msg: Message) {
+        match msg {
+            Message {
+                command: BaseCommand { ping: Some(_), .. },
+                ..
+            } => {
+                if self.outbound.send_control(messages::pong()).is_err() {
+                    warn!("could not answer the broker's ping");
+                }
+            }
+            Message {
+                command: BaseCommand { pong: Some(_), .. },
+                ..
+            } => {
+                if let Some(sender) = self.pings.pop_front() {
+                    let _ = sender.send(());
+                }
+            }
+            Message {
+                command:
+                    BaseCommand {
+                        auth_challenge: Some(challenge),
+                        ..
+                    },
+                ..
+            } => match &self.auth_challenges {
+                Some(auth_challenges) if auth_challenges.unbounded_send(challenge).is_ok() => {}
+                _ => warn!("could not answer the broker's authentication challenge"),
+            },
+            msg => {
+                match msg.request_key() {
+                    Some(RequestKey::ProducerSend {
+                        producer_id,
+                        sequence_id,
+                    }) if msg.command.send_receipt.is_some() => {
+                        self.handle_receipt(producer_id, sequence_id, msg);
+                    }
+                    Some(key @ RequestKey::RequestId(_))
+                    | Some(key @ RequestKey::ProducerSend { .. }) => {
+                        trace!("received this message: {:?}", msg);
+                        if let Some(resolver) = self.pending_requests.remove(&key) {
+                            resolver.send(msg);
+                        } else {
+                            let now = Instant::now();
+                            self.received_order.push_back((now, key.clone()));
+                            self.received_messages.insert(key, (now, msg));
+                            self.evict_received_messages(now);
+                        }
+                    }
+                    Some(RequestKey::Consumer { consumer_id }) => {
+                        let _ = self
+                            .consumers
+                            .get_mut(&consumer_id)
+                            .map(move |consumer| consumer.unbounded_send(msg));
+                    }
+                    Some(RequestKey::CloseConsumer {
+                        consumer_id,
+                        request_id,
+                    }) => {
+                        // FIXME: could the registration still be in queue while we get the
+                        // CloseConsumer message?
+                        if let Some(resolver) = self
+                            .pending_requests
+                            .remove(&RequestKey::RequestId(request_id))
+                        {
+                            resolver.send(msg);
+                        } else {
+                            let res = self
+                                .consumers
+                                .get_mut(&consumer_id)
+                                .map(move |consumer| consumer.unbounded_send(msg));
+
+                            if !res.as_ref().map(|r| r.is_ok()).unwrap_or(false) {
+                                error!("ConnectionReceiver: error transmitting message to consumer: {:?}", res);
+                            }
+                        }
+                    }
+                    Some(RequestKey::CloseProducer {
+                        producer_id,
+                        request_id,
+                    }) => {
+                        if let Some(resolver) = self
+                            .pending_requests
+                            .remove(&RequestKey::RequestId(request_id))
+                        {
+                            resolver.send(msg);
+                        } else {
+                            // the broker closed the producer (topic unloaded, etc),
+                            // it will be recreated by the producer
+                            self.last_receipts.remove(&producer_id);
+                            let sent = self
+                                .producers
+                                .remove(&producer_id)
+                                .map(move |producer| producer.unbounded_send(msg).is_ok())
+                                .unwrap_or(false);
+
+                            if !sent {
+                                error!(
+                                    "ConnectionReceiver: error transmitting message to producer {}",
+                                    producer_id
+                                );
+                            }
+                        }
+                    }
+                    None => {
+                        warn!(
+                            "Received unexpected message; dropping. Message {:?}",
+                            msg.command
+                        )
+                    }
+                }
+            }
+        }
+    }
+
+    /// gives a send receipt to its request
+    ///
+    /// the broker acknowledges the messages of a producer once and in order,
+    /// so the receipt should be for the oldest send waiting for one. If it is
+    /// not, a message was skipped or acknowledged twice, and the receipt is
+    /// also given to that oldest send, which reports the mismatch. The chunks
+    /// of a message share its sequence id
+    fn handle_receipt(&mut self, producer_id: u64, sequence_id: u64, msg: Message) {
+        let key = RequestKey::ProducerSend {
+            producer_id,
+            sequence_id,
+        };
+        let oldest = self
+            .pending_requests
+            .range(
+                RequestKey::ProducerSend {
+                    producer_id,
+                    sequence_id: 0,
+                }..=RequestKey::ProducerSend {
+                    producer_id,
+                    sequence_id: u64::MAX,
+                },
+            )
+            .next()
+            .map(|(oldest, _)| oldest.clone());
+        if let Some(oldest) = oldest.filter(|oldest| *oldest != key) {
+            warn!(
+                "received a receipt for sequence id {} of producer {} while waiting for {:?}",
+                sequence_id, producer_id, oldest
+            );
+            if let Some(resolver) = self.pending_requests.remove(&oldest) {
+                resolver.send(msg.clone());
+            }
+        }
+
+        let last = self.last_receipts.get(&producer_id).copied();
+        if let Some(resolver) = self.pending_requests.remove(&key) {
+            self.last_receipts
+                .insert(producer_id, last.unwrap_or(0).max(sequence_id));
+            resolver.send(msg);
+        } else if matches!(last, Some(last) if sequence_id < last) {
+            // the message was already acknowledged, a request with the same
+            // sequence id must not get this receipt
+            warn!(
+                "dropping out of order receipt for sequence id {} of producer {}",
+                sequence_id, producer_id
+            );
+        } else {
+            self.last_receipts.insert(producer_id, sequence_id);
+            let now = Instant::now();
+            self.received_order.push_back((now, key.clone()));
+            self.received_messages.insert(key, (now, msg));
+            self.evict_received_messages(now);
         }
     }
 }
 
+const MIN_PRUNE_THRESHOLD: usize = 64;
+
+/// maximum number of responses kept while waiting for their request to be
+/// registered, and how long they are kept
+const MAX_RECEIVED_MESSAGES: usize = 1024;
+const RECEIVED_MESSAGES_TTL: Duration = Duration::from_secs(60);
+
+/// maximum number of registrations, and of inbound messages, handled in a
+/// single poll of the receiver
+const POLL_BUDGET: usize = 32;
+
 impl<S: Stream<Item = Result<Message, ConnectionError>>> Future for Receiver<S> {
     type Output = Result<(), ()>;
 
@@ -124,112 +543,48 @@ impl<S: Stream<Item = Result<Message, ConnectionError>>> Future for Receiver<S>
             Poll::Pending => {}
         }
 
-        //Are we worried about starvation here?
-        loop {
+        // both sources are drained a bounded number of items at a time, so
+        // that a flood on one of them cannot starve the other or the executor
+        let mut registrations_drained = false;
+        for _ in 0..POLL_BUDGET {
             match self.registrations.as_mut().poll_next(cx) {
-                Poll::Ready(Some(Register::Request { key, resolver })) => {
-                    match self.received_messages.remove(&key) {
-                        Some(msg) => {
-                            let _ = resolver.send(msg);
-                        }
-                        None => {
-                            self.pending_requests.insert(key, resolver);
-                        }
-                    }
-                }
-                Poll::Ready(Some(Register::Consumer {
-                    consumer_id,
-                    resolver,
-                })) => {
-                    self.consumers.insert(consumer_id, resolver);
-                }
-                Poll::Ready(Some(Register::Ping { resolver })) => {
-                    self.ping = Some(resolver);
-                }
+                Poll::Ready(Some(registration)) => self.handle_registration(registration),
                 Poll::Ready(None) => {
                     self.error.set(ConnectionError::Disconnected);
                     return Poll::Ready(Err(()));
                 }
-                Poll::Pending => break,
+                Poll::Pending => {
+                    registrations_drained = true;
+                    break;
+                }
             }
         }
 
-        loop {
+        let mut inbound_drained = false;
+        for _ in 0..POLL_BUDGET {
             match self.inbound.as_mut().poll_next(cx) {
-                Poll::Ready(Some(Ok(msg))) => match msg {
-                    Message {
-                        command: BaseCommand { ping: Some(_), .. },
-                        ..
-                    } => {
-                        let _ = self.outbound.unbounded_send(messages::pong());
-                    }
-                    Message {
-                        command: BaseCommand { pong: Some(_), .. },
-                        ..
-                    } => {
-                        if let Some(sender) = self.ping.take() {
-                            let _ = sender.send(());
-                        }
-                    }
-                    msg => match msg.request_key() {
-                        Some(key @ RequestKey::RequestId(_))
-                        | Some(key @ RequestKey::ProducerSend { .. }) => {
-                            trace!("received this message: {:?}", msg);
-                            if let Some(resolver) = self.pending_requests.remove(&key) {
-                                // We don't care if the receiver has dropped their future
-                                let _ = resolver.send(msg);
-                            } else {
-                                self.received_messages.insert(key, msg);
-                            }
-                        }
-                        Some(RequestKey::Consumer { consumer_id }) => {
-                            let _ = self
-                                .consumers
-                                .get_mut(&consumer_id)
-                                .map(move |consumer| consumer.unbounded_send(msg));
-                        }
-                        Some(RequestKey::CloseConsumer {
-                            consumer_id,
-                            request_id,
-                        }) => {
-                            // FIXME: could the registration still be in queue while we get the
-                            // CloseConsumer message?
-                            if let Some(resolver) = self
-                                .pending_requests
-                                .remove(&RequestKey::RequestId(request_id))
-                            {
-                                // We don't care if the receiver has dropped their future
-                                let _ = resolver.send(msg);
-                            } else {
-                                let res = self
-                                    .consumers
-                                    .get_mut(&consumer_id)
-                                    .map(move |consumer| consumer.unbounded_send(msg));
-
-                                if !res.as_ref().map(|r| r.is_ok()).unwrap_or(false) {
-                                    error!("ConnectionReceiver: error transmitting message to consumer: {:?}", res);
-                                }
-                            }
-                        }
-                        None => {
-                            warn!(
-                                "Received unexpected message; dropping. Message {:?}",
-                                msg.command
-                            )
-                        }
-                    },
-                },
+                Poll::Ready(Some(Ok(msg))) => self.handle_inbound(msg),
                 Poll::Ready(None) => {
                     self.error.set(ConnectionError::Disconnected);
                     return Poll::Ready(Err(()));
                 }
-                Poll::Pending => return Poll::Pending,
                 Poll::Ready(Some(Err(e))) => {
                     self.error.set(e);
                     return Poll::Ready(Err(()));
                 }
+                Poll::Pending => {
+                    inbound_drained = true;
+                    break;
+                }
             }
         }
+
+        if !(registrations_drained && inbound_drained) {
+            // one of the sources used its whole budget, it may have more
+            // items ready: ask to be polled again after the other tasks
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
     }
 }
 
@@ -246,44 +601,229 @@ impl SerialId {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// creates an allocator whose first value will be `value`
+    pub fn starting_at(value: u64) -> Self {
+        SerialId(Arc::new(AtomicUsize::new(value as usize)))
+    }
+
+    /// returns the value the next call to `get` will return, without using it
+    pub fn current(&self) -> u64 {
+        self.0.load(Ordering::Relaxed) as u64
+    }
+
     pub fn get(&self) -> u64 {
         self.0.fetch_add(1, Ordering::Relaxed) as u64
     }
+
+    /// makes sure the next value returned by `get` is greater than `value`
+    pub fn advance_past(&self, value: u64) {
+        self.0.fetch_max(value as usize + 1, Ordering::Relaxed);
+    }
+}
+
+/// sending half of the queue of commands written to the socket
+///
+/// the bounded variant makes senders wait when the queue is full, see
+/// `ConnectionOptions::outbound_channel_size`. Its control frames, like the
+/// answers to the broker's pings, go through a separate unbounded queue that
+/// is written first, so that a full queue cannot break the keep-alive
+#[derive(Clone)]
+pub(crate) enum OutboundSender {
+    Unbounded(mpsc::UnboundedSender<Message>),
+    Bounded {
+        // the futures bounded channel grants an extra slot to each sender, so
+        // all senders share the same one
+        messages: Arc<Mutex<mpsc::Sender<Message>>>,
+        control: mpsc::UnboundedSender<Message>,
+    },
+}
+
+impl OutboundSender {
+    /// creates the queue, unbounded if `size` is None
+    pub(crate) fn channel(
+        size: Option<usize>,
+    ) -> (
+        OutboundSender,
+        Pin<Box<dyn Stream<Item = Message> + Send + 'static>>,
+    ) {
+        match size {
+            None => {
+                let (tx, rx) = mpsc::unbounded();
+                (OutboundSender::Unbounded(tx), Box::pin(rx))
+            }
+            Some(size) => {
+                let (tx, rx) = mpsc::channel(size);
+                let (control_tx, control_rx) = mpsc::unbounded();
+                let rx = futures::stream::select_with_strategy(control_rx, rx, |_: &mut ()| {
+                    futures::stream::PollNext::Left
+                });
+                (
+                    OutboundSender::Bounded {
+                        messages: Arc::new(Mutex::new(tx)),
+                        control: control_tx,
+                    },
+                    Box::pin(rx),
+                )
+            }
+        }
+    }
+
+    /// queues the message, waiting for room if the queue is full
+    pub(crate) async fn send(&self, msg: Message) -> Result<(), ConnectionError> {
+        match self {
+            OutboundSender::Unbounded(tx) => tx
+                .unbounded_send(msg)
+                .map_err(|_| ConnectionError::Disconnected),
+            OutboundSender::Bounded { messages, .. } => messages
+                .lock()
+                .await
+                .send(msg)
+                .await
+                .map_err(|_| ConnectionError::Disconnected),
+        }
+    }
+
+    /// queues a control frame without waiting, ahead of the other messages
+    /// of a bounded queue. It only fails if the queue is closed
+    pub(crate) fn send_control(&self, msg: Message) -> Result<(), ConnectionError> {
+        let tx = match self {
+            OutboundSender::Unbounded(tx) => tx,
+            OutboundSender::Bounded { control, .. } => control,
+        };
+        tx.unbounded_send(msg)
+            .map_err(|_| ConnectionError::Disconnected)
+    }
+
+    /// rejects new messages, the ones already queued are still written
+    pub(crate) async fn close(&self) {
+        match self {
+            OutboundSender::Unbounded(tx) => tx.close_channel(),
+            OutboundSender::Bounded { messages, control } => {
+                control.close_channel();
+                messages.lock().await.close_channel();
+            }
+        }
+    }
+}
+
+/// removes a consumer registered before its subscription is confirmed,
+/// if the subscription fails or its future is dropped
+struct ConsumerRegistration<'a> {
+    registrations: &'a mpsc::UnboundedSender<Register>,
+    consumer_id: Option<u64>,
+}
+
+impl<'a> Drop for ConsumerRegistration<'a> {
+    fn drop(&mut self) {
+        if let Some(consumer_id) = self.consumer_id.take() {
+            let _ = self
+                .registrations
+                .unbounded_send(Register::RemoveConsumer { consumer_id });
+        }
+    }
 }
 
 /// An owned type that can send messages like a connection
 //#[derive(Clone)]
 pub struct ConnectionSender<Exe: Executor> {
-    tx: mpsc::UnboundedSender<Message>,
+    tx: OutboundSender,
     registrations: mpsc::UnboundedSender<Register>,
-    receiver_shutdown: Option<oneshot::Sender<()>>,
+    receiver_shutdown: Mutex<Option<oneshot::Sender<()>>>,
+    /// resolves once the outbound queue is closed and written to the socket
+    outbound_flushed: Mutex<Option<oneshot::Receiver<()>>>,
     request_id: SerialId,
+    consumer_id: SerialId,
+    producer_id: SerialId,
+    protocol_version: i32,
     error: SharedError,
     executor: Arc<Exe>,
     operation_timeout: Duration,
+    metrics: Option<Arc<dyn ConnectionMetrics>>,
+    /// last time a request was sent
+    last_used: std::sync::Mutex<Instant>,
+    max_frame_size: usize,
+    /// largest message accepted by the broker
+    max_message_size: usize,
+    /// features supported by the broker
+    feature_flags: proto::FeatureFlags,
 }
 
 impl<Exe: Executor> ConnectionSender<Exe> {
     pub(crate) fn new(
-        tx: mpsc::UnboundedSender<Message>,
+        tx: OutboundSender,
         registrations: mpsc::UnboundedSender<Register>,
         receiver_shutdown: oneshot::Sender<()>,
+        outbound_flushed: oneshot::Receiver<()>,
         request_id: SerialId,
+        connected: proto::CommandConnected,
         error: SharedError,
         executor: Arc<Exe>,
         operation_timeout: Duration,
+        metrics: Option<Arc<dyn ConnectionMetrics>>,
+        max_frame_size: Option<usize>,
     ) -> ConnectionSender<Exe> {
+        // brokers that do not send the protocol version are older than v1
+        let protocol_version = connected.protocol_version.unwrap_or(0);
+        let max_message_size = connected
+            .max_message_size
+            .filter(|size| *size > 0)
+            .map(|size| size as usize)
+            .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE);
         ConnectionSender {
             tx,
             registrations,
-            receiver_shutdown: Some(receiver_shutdown),
+            receiver_shutdown: Mutex::new(Some(receiver_shutdown)),
+            outbound_flushed: Mutex::new(Some(outbound_flushed)),
             request_id,
+            consumer_id: SerialId::new(),
+            producer_id: SerialId::new(),
+            protocol_version,
             error,
             executor,
             operation_timeout,
+            metrics,
+            last_used: std::sync::Mutex::new(Instant::now()),
+            max_frame_size: max_frame_size
+                .unwrap_or(usize::MAX)
+                .min(max_message_size + FRAME_PADDING),
+            max_message_size,
+            feature_flags: connected.feature_flags.unwrap_or_default(),
         }
     }
 
+    /// allocates an id for a new consumer on this connection
+    ///
+    /// manually chosen ids can still be used, but they must be unique
+    /// on the connection
+    pub fn next_consumer_id(&self) -> u64 {
+        self.consumer_id.get()
+    }
+
+    /// allocates an id for a new producer on this connection
+    ///
+    /// manually chosen ids can still be used, but they must be unique
+    /// on the connection
+    pub fn next_producer_id(&self) -> u64 {
+        self.producer_id.get()
+    }
+
+    /// protocol version agreed with the broker during the handshake
+    pub fn negotiated_protocol_version(&self) -> i32 {
+        self.protocol_version
+    }
+
+    /// largest message accepted by the broker, older brokers do not send it
+    /// and the default of 5MB is used instead
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    /// features supported by the broker, all unset for older brokers
+    pub fn feature_flags(&self) -> &proto::FeatureFlags {
+        &self.feature_flags
+    }
+
     pub(crate) async fn send(
         &self,
         producer_id: u64,
@@ -291,52 +831,108 @@ impl<Exe: Executor> ConnectionSender<Exe> {
         sequence_id: u64,
         message: producer::ProducerMessage,
     ) -> Result<proto::CommandSendReceipt, ConnectionError> {
+        self.start_send(producer_id, producer_name, sequence_id, message)
+            .await?
+            .await
+    }
+
+    /// writes the message to the connection and returns the future of its
+    /// receipt, so that the caller does not have to wait for it to release
+    /// what orders its sends
+    pub(crate) async fn start_send(
+        &self,
+        producer_id: u64,
+        producer_name: String,
+        sequence_id: u64,
+        message: producer::ProducerMessage,
+    ) -> Result<
+        impl Future<Output = Result<proto::CommandSendReceipt, ConnectionError>> + '_,
+        ConnectionError,
+    > {
         let key = RequestKey::ProducerSend {
             producer_id,
             sequence_id,
         };
         let msg = messages::send(producer_id, producer_name, sequence_id, message);
-        self.send_message(msg, key, |resp| resp.command.send_receipt)
+        // the codec would refuse it too, but that error closes the connection
+        let size = msg.frame_size();
+        if size > self.max_frame_size {
+            return Err(ConnectionError::FrameTooLarge {
+                size,
+                max: self.max_frame_size,
+            });
+        }
+        self.start_request(msg, key, |resp| resp.command.send_receipt)
             .await
     }
 
+    /// reserves a request id, to build the commands sent with [Self::send_raw]
+    pub fn new_request_id(&self) -> u64 {
+        self.request_id.get()
+    }
+
+    /// sends a command that has no dedicated method and returns the whole
+    /// response, for example to use a broker feature this crate does not
+    /// support yet
+    ///
+    /// the response is matched with `key`, it must be a command for which
+    /// [Message::request_key] returns the same key. Error responses are
+    /// returned as [ConnectionError::PulsarError]
+    pub async fn send_raw(
+        &self,
+        msg: Message,
+        key: RequestKey,
+    ) -> Result<Message, ConnectionError> {
+        self.send_message(msg, key, Some).await
+    }
+
+    /// sends a command without waiting for a response
+    pub async fn send_raw_oneway(&self, msg: Message) -> Result<(), ConnectionError> {
+        *self.last_used.lock().unwrap() = Instant::now();
+        self.tx.send(msg).await
+    }
+
     pub async fn send_ping(&self) -> Result<(), ConnectionError> {
+        self.send_ping_with_timeout(self.operation_timeout).await
+    }
+
+    /// sends a ping and waits at most `timeout` for the pong. If it does not
+    /// arrive in time, the connection is marked as failed
+    pub async fn send_ping_with_timeout(&self, timeout: Duration) -> Result<(), ConnectionError> {
         let (resolver, response) = oneshot::channel();
         trace!("sending ping");
 
         match (
             self.registrations
                 .unbounded_send(Register::Ping { resolver }),
-            self.tx.unbounded_send(messages::ping()),
+            self.tx.send(messages::ping()).await,
         ) {
-            (Ok(_), Ok(_)) => {
-                let delay_f = self.executor.delay(self.operation_timeout);
-                pin_mut!(response);
-                pin_mut!(delay_f);
-
-                match select(response, delay_f).await {
-                    Either::Left((res, _)) => res
-                        .map_err(|oneshot::Canceled| {
-                            self.error.set(ConnectionError::Disconnected);
-                            ConnectionError::Disconnected
-                        })
-                        .map(move |_| trace!("received pong")),
-                    Either::Right(_) => {
-                        self.error.set(ConnectionError::Io(std::io::Error::new(
-                            std::io::ErrorKind::TimedOut,
-                            "timeout when sending ping to the Pulsar server",
-                        )));
-                        Err(ConnectionError::Io(std::io::Error::new(
-                            std::io::ErrorKind::TimedOut,
-                            "timeout when sending ping to the Pulsar server",
-                        )))
-                    }
+            (Ok(_), Ok(_)) => match self.executor.timeout(timeout, response).await {
+                Ok(res) => res
+                    .map_err(|oneshot::Canceled| {
+                        self.error.set(ConnectionError::Disconnected);
+                        ConnectionError::Disconnected
+                    })
+                    .map(move |_| trace!("received pong")),
+                Err(Elapsed) => {
+                    warn!("timeout when sending ping to the Pulsar server");
+                    self.error.set(ConnectionError::Timeout);
+                    Err(ConnectionError::Timeout)
                 }
-            }
+            },
             _ => Err(ConnectionError::Disconnected),
         }
     }
 
+    /// queries the current sizes of the receiver's internal maps
+    pub async fn receiver_stats(&self) -> Result<ReceiverStats, ConnectionError> {
+        let (resolver, response) = oneshot::channel();
+        self.registrations
+            .unbounded_send(Register::Stats { resolver })
+            .map_err(|_| ConnectionError::Disconnected)?;
+        response.await.map_err(|_| ConnectionError::Disconnected)
+    }
+
     pub async fn lookup_topic<S: Into<String>>(
         &self,
         topic: S,
@@ -386,6 +982,32 @@ impl<Exe: Executor> ConnectionSender<Exe> {
         .await
     }
 
+    /// gets the schema registered for a topic, in its latest version if
+    /// `version` is not specified
+    pub async fn get_schema(
+        &self,
+        topic: String,
+        version: Option<Vec<u8>>,
+    ) -> Result<proto::CommandGetSchemaResponse, ConnectionError> {
+        let request_id = self.request_id.get();
+        let msg = messages::get_schema(topic, version, request_id);
+        let response = self
+            .send_message(msg, RequestKey::RequestId(request_id), |resp| {
+                resp.command.get_schema_response
+            })
+            .await?;
+
+        // errors are returned in the response instead of a CommandError,
+        // TopicNotFound indicates that the topic has no schema
+        match response.error_code {
+            Some(error_code) => Err(ConnectionError::PulsarError(
+                crate::error::server_error(error_code),
+                response.error_message,
+            )),
+            None => Ok(response),
+        }
+    }
+
     pub async fn create_producer(
         &self,
         topic: String,
@@ -401,6 +1023,25 @@ impl<Exe: Executor> ConnectionSender<Exe> {
         .await
     }
 
+    /// registers a channel on which the broker's notifications for this producer
+    /// (like `CommandCloseProducer`) will be sent
+    pub fn register_producer(
+        &self,
+        producer_id: u64,
+        resolver: mpsc::UnboundedSender<Message>,
+    ) -> Result<(), ConnectionError> {
+        match self.registrations.unbounded_send(Register::Producer {
+            producer_id,
+            resolver,
+        }) {
+            Ok(_) => Ok(()),
+            Err(_) => {
+                self.error.set(ConnectionError::Disconnected);
+                Err(ConnectionError::Disconnected)
+            }
+        }
+    }
+
     pub async fn wait_for_exclusive_access(
         &self,
         request_id: u64,
@@ -411,29 +1052,134 @@ impl<Exe: Executor> ConnectionSender<Exe> {
         .await
     }
 
+    /// if `topics_hash` is the hash of the current topics, the broker does
+    /// not send them again and the response's `changed` field is false
     pub async fn get_topics_of_namespace(
         &self,
         namespace: String,
         mode: proto::command_get_topics_of_namespace::Mode,
+        topics_hash: Option<String>,
     ) -> Result<proto::CommandGetTopicsOfNamespaceResponse, ConnectionError> {
         let request_id = self.request_id.get();
-        let msg = messages::get_topics_of_namespace(request_id, namespace, mode);
+        let msg = messages::get_topics_of_namespace(request_id, namespace, mode, topics_hash);
         self.send_message(msg, RequestKey::RequestId(request_id), |resp| {
             resp.command.get_topics_of_namespace_response
         })
         .await
     }
 
-    pub async fn close_producer(
-        &self,
-        producer_id: u64,
-    ) -> Result<proto::CommandSuccess, ConnectionError> {
+    /// opens a transaction on the transaction coordinator `tc_id`, it is
+    /// aborted by the broker if not committed within `timeout`, rounded up
+    /// to whole seconds
+    pub async fn new_txn(&self, tc_id: u64, timeout: Duration) -> Result<TxnId, ConnectionError> {
         let request_id = self.request_id.get();
-        let msg = messages::close_producer(producer_id, request_id);
-        self.send_message(msg, RequestKey::RequestId(request_id), |resp| {
-            resp.command.success
-        })
-        .await
+        let msg = messages::new_txn(request_id, tc_id, timeout);
+        let response = self
+            .send_message(msg, RequestKey::RequestId(request_id), |resp| {
+                resp.command.new_txn_response
+            })
+            .await?;
+
+        // like schemas, errors are returned in the response
+        match response.error {
+            Some(error) => Err(ConnectionError::PulsarError(
+                crate::error::server_error(error),
+                response.message,
+            )),
+            None => Ok(TxnId {
+                most_bits: response.txnid_most_bits.unwrap_or(0),
+                least_bits: response.txnid_least_bits.unwrap_or(0),
+            }),
+        }
+    }
+
+    /// registers the partitions on which messages of the transaction will be
+    /// produced, before sending them
+    pub async fn add_partition_to_txn(
+        &self,
+        txn_id: TxnId,
+        partitions: Vec<String>,
+    ) -> Result<proto::CommandAddPartitionToTxnResponse, ConnectionError> {
+        let request_id = self.request_id.get();
+        let msg = messages::add_partition_to_txn(request_id, txn_id, partitions);
+        let response = self
+            .send_message(msg, RequestKey::RequestId(request_id), |resp| {
+                resp.command.add_partition_to_txn_response
+            })
+            .await?;
+
+        match response.error {
+            Some(error) => Err(ConnectionError::PulsarError(
+                crate::error::server_error(error),
+                response.message,
+            )),
+            None => Ok(response),
+        }
+    }
+
+    /// registers the subscriptions on which messages will be acknowledged
+    /// within the transaction
+    pub async fn add_subscription_to_txn(
+        &self,
+        txn_id: TxnId,
+        subscriptions: Vec<proto::Subscription>,
+    ) -> Result<proto::CommandAddSubscriptionToTxnResponse, ConnectionError> {
+        let request_id = self.request_id.get();
+        let msg = messages::add_subscription_to_txn(request_id, txn_id, subscriptions);
+        let response = self
+            .send_message(msg, RequestKey::RequestId(request_id), |resp| {
+                resp.command.add_subscription_to_txn_response
+            })
+            .await?;
+
+        match response.error {
+            Some(error) => Err(ConnectionError::PulsarError(
+                crate::error::server_error(error),
+                response.message,
+            )),
+            None => Ok(response),
+        }
+    }
+
+    /// commits the transaction if `commit` is true, aborts it otherwise
+    pub async fn end_txn(
+        &self,
+        txn_id: TxnId,
+        commit: bool,
+    ) -> Result<proto::CommandEndTxnResponse, ConnectionError> {
+        let request_id = self.request_id.get();
+        let msg = messages::end_txn(request_id, txn_id, commit);
+        let response = self
+            .send_message(msg, RequestKey::RequestId(request_id), |resp| {
+                resp.command.end_txn_response
+            })
+            .await?;
+
+        match response.error {
+            Some(error) => Err(ConnectionError::PulsarError(
+                crate::error::server_error(error),
+                response.message,
+            )),
+            None => Ok(response),
+        }
+    }
+
+    pub async fn close_producer(
+        &self,
+        producer_id: u64,
+    ) -> Result<proto::CommandSuccess, ConnectionError> {
+        let request_id = self.request_id.get();
+        let msg = messages::close_producer(producer_id, request_id);
+        let res = self
+            .send_message(msg, RequestKey::RequestId(request_id), |resp| {
+                resp.command.success
+            })
+            .await;
+        // the producer is not used anymore, even if the broker did not answer
+        let _ = self
+            .registrations
+            .unbounded_send(Register::RemoveProducer { producer_id });
+        res
     }
 
     pub async fn subscribe(
@@ -446,6 +1192,15 @@ impl<Exe: Executor> ConnectionSender<Exe> {
         consumer_name: Option<String>,
         options: ConsumerOptions,
     ) -> Result<proto::CommandSuccess, ConnectionError> {
+        // Key_Shared subscriptions appeared with Pulsar 2.4 (protocol version 14)
+        let version = self.negotiated_protocol_version();
+        if sub_type == SubType::KeyShared && version < 14 {
+            return Err(ConnectionError::UnsupportedByBroker(format!(
+                "Key_Shared subscriptions need protocol version 14, the connection uses version {}",
+                version
+            )));
+        }
+
         let request_id = self.request_id.get();
         let msg = messages::subscribe(
             topic,
@@ -466,40 +1221,99 @@ impl<Exe: Executor> ConnectionSender<Exe> {
                 return Err(ConnectionError::Disconnected);
             }
         }
-        self.send_message(msg, RequestKey::RequestId(request_id), |resp| {
-            resp.command.success
-        })
-        .await
+        let mut registration = ConsumerRegistration {
+            registrations: &self.registrations,
+            consumer_id: Some(consumer_id),
+        };
+        let success = self
+            .send_message(msg, RequestKey::RequestId(request_id), |resp| {
+                resp.command.success
+            })
+            .await?;
+        registration.consumer_id = None;
+        Ok(success)
+    }
+
+    /// subscribes to a topic, then grants `initial_permits` to the broker so that it
+    /// starts sending messages. No flow is sent if `initial_permits` is 0
+    ///
+    /// `subscribe` and `send_flow` can still be used separately to control the
+    /// flow of messages from the start
+    pub async fn subscribe_with_flow(
+        &self,
+        resolver: mpsc::UnboundedSender<Message>,
+        topic: String,
+        subscription: String,
+        sub_type: SubType,
+        consumer_id: u64,
+        consumer_name: Option<String>,
+        options: ConsumerOptions,
+        initial_permits: u32,
+    ) -> Result<proto::CommandSuccess, ConnectionError> {
+        let success = self
+            .subscribe(
+                resolver,
+                topic,
+                subscription,
+                sub_type,
+                consumer_id,
+                consumer_name,
+                options,
+            )
+            .await?;
+        if initial_permits > 0 {
+            self.send_flow(consumer_id, initial_permits).await?;
+        }
+        Ok(success)
     }
 
-    pub fn send_flow(&self, consumer_id: u64, message_permits: u32) -> Result<(), ConnectionError> {
+    pub async fn send_flow(
+        &self,
+        consumer_id: u64,
+        message_permits: u32,
+    ) -> Result<(), ConnectionError> {
         self.tx
-            .unbounded_send(messages::flow(consumer_id, message_permits))
-            .map_err(|_| ConnectionError::Disconnected)
+            .send(messages::flow(consumer_id, message_permits))
+            .await
     }
 
-    pub fn send_ack(
+    pub async fn send_ack(
         &self,
         consumer_id: u64,
         message_ids: Vec<proto::MessageIdData>,
         cumulative: bool,
     ) -> Result<(), ConnectionError> {
         self.tx
-            .unbounded_send(messages::ack(consumer_id, message_ids, cumulative))
-            .map_err(|_| ConnectionError::Disconnected)
+            .send(messages::ack(consumer_id, message_ids, cumulative))
+            .await
     }
 
-    pub fn send_redeliver_unacknowleged_messages(
+    pub async fn send_redeliver_unacknowleged_messages(
         &self,
         consumer_id: u64,
         message_ids: Vec<proto::MessageIdData>,
+        max_per_command: usize,
     ) -> Result<(), ConnectionError> {
-        self.tx
-            .unbounded_send(messages::redeliver_unacknowleged_messages(
-                consumer_id,
-                message_ids,
-            ))
-            .map_err(|_| ConnectionError::Disconnected)
+        // without ids, the broker redelivers all the unacked messages
+        if message_ids.is_empty() {
+            return self
+                .tx
+                .send(messages::redeliver_unacknowleged_messages(
+                    consumer_id,
+                    message_ids,
+                ))
+                .await;
+        }
+        // a single command with too many ids could exceed the maximum frame size
+        for ids in message_ids.chunks(max_per_command.max(1)) {
+            self.tx
+                .send(messages::redeliver_unacknowleged_messages(
+                    consumer_id,
+                    ids.to_vec(),
+                ))
+                .await?;
+        }
+        Ok(())
     }
 
     pub async fn close_consumer(
@@ -508,26 +1322,59 @@ impl<Exe: Executor> ConnectionSender<Exe> {
     ) -> Result<proto::CommandSuccess, ConnectionError> {
         let request_id = self.request_id.get();
         let msg = messages::close_consumer(consumer_id, request_id);
-        self.send_message(msg, RequestKey::RequestId(request_id), |resp| {
-            resp.command.success
-        })
-        .await
+        let success = self
+            .send_message(msg, RequestKey::RequestId(request_id), |resp| {
+                resp.command.success
+            })
+            .await?;
+        // dropping the consumer's sender ends its stream of messages
+        let _ = self
+            .registrations
+            .unbounded_send(Register::RemoveConsumer { consumer_id });
+        Ok(success)
     }
 
     pub async fn seek(
         &self,
         consumer_id: u64,
-        message_id: Option<MessageIdData>,
-        timestamp: Option<u64>,
+        target: SeekTarget,
     ) -> Result<proto::CommandSuccess, ConnectionError> {
         let request_id = self.request_id.get();
-        let msg = messages::seek(consumer_id, request_id, message_id, timestamp);
+        let msg = messages::seek(consumer_id, request_id, target);
         self.send_message(msg, RequestKey::RequestId(request_id), |resp| {
             resp.command.success
         })
         .await
     }
 
+    /// moves the subscription's cursor to `message_id`
+    ///
+    /// after a successful seek, the broker closes the consumer and delivers the
+    /// messages again from the new position once it has subscribed again, so the
+    /// flow permits must be granted again by the caller
+    pub async fn seek_message_id(
+        &self,
+        consumer_id: u64,
+        message_id: MessageIdData,
+    ) -> Result<proto::CommandSuccess, ConnectionError> {
+        self.seek(consumer_id, SeekTarget::MessageId(message_id))
+            .await
+    }
+
+    /// moves the subscription's cursor to the first message published at or
+    /// after `timestamp_ms`
+    ///
+    /// as with [ConnectionSender::seek_message_id], the flow permits must be
+    /// granted again after a successful seek
+    pub async fn seek_timestamp(
+        &self,
+        consumer_id: u64,
+        timestamp_ms: u64,
+    ) -> Result<proto::CommandSuccess, ConnectionError> {
+        self.seek(consumer_id, SeekTarget::Timestamp(timestamp_ms))
+            .await
+    }
+
     pub async fn unsubscribe(
         &self,
         consumer_id: u64,
@@ -548,12 +1395,33 @@ impl<Exe: Executor> ConnectionSender<Exe> {
     ) -> Result<R, ConnectionError>
     where
         F: FnOnce(Message) -> Option<R>,
+    {
+        self.start_request(msg, key, extract).await?.await
+    }
+
+    /// writes the request to the connection and returns the future of its
+    /// response
+    async fn start_request<'a, R: Debug + 'a, F>(
+        &'a self,
+        msg: Message,
+        key: RequestKey,
+        extract: F,
+    ) -> Result<impl Future<Output = Result<R, ConnectionError>> + 'a, ConnectionError>
+    where
+        F: FnOnce(Message) -> Option<R> + 'a,
     {
         let (resolver, response) = oneshot::channel();
         trace!("sending message(key = {:?}): {:?}", key, msg);
+        let resolver = ResponseResolver::for_request(resolver, &key, &msg);
 
         let k = key.clone();
-        let response = async {
+        let timeout_key = key.clone();
+        if let Some(metrics) = &self.metrics {
+            metrics.on_request_sent(&key);
+        }
+        let sent_at = Instant::now();
+        *self.last_used.lock().unwrap() = sent_at;
+        let response = async move {
             response
                 .await
                 .map_err(|oneshot::Canceled| {
@@ -569,9 +1437,9 @@ impl<Exe: Executor> ConnectionSender<Exe> {
         match (
             self.registrations
                 .unbounded_send(Register::Request { key, resolver }),
-            self.tx.unbounded_send(msg),
+            self.tx.send(msg).await,
         ) {
-            (Ok(_), Ok(_)) => {
+            (Ok(_), Ok(_)) => Ok(async move {
                 let delay_f = self.executor.delay(self.operation_timeout);
                 pin_mut!(response);
                 pin_mut!(delay_f);
@@ -579,14 +1447,21 @@ impl<Exe: Executor> ConnectionSender<Exe> {
                 match select(response, delay_f).await {
                     Either::Left((res, _)) => {
                         // println!("recv msg: {:?}", res);
+                        if let (Some(metrics), Ok(_)) = (&self.metrics, &res) {
+                            metrics.on_response(&timeout_key, sent_at.elapsed());
+                        }
                         res
                     }
-                    Either::Right(_) => Err(ConnectionError::Io(std::io::Error::new(
-                        std::io::ErrorKind::TimedOut,
-                        "timeout sending message to the Pulsar server",
-                    ))),
+                    Either::Right(_) => {
+                        // remove the pending request, otherwise it would stay in the
+                        // receiver until the connection is closed
+                        let _ = self
+                            .registrations
+                            .unbounded_send(Register::CancelRequest { key: timeout_key });
+                        Err(ConnectionError::Timeout)
+                    }
                 }
-            }
+            }),
             _ => Err(ConnectionError::Disconnected),
         }
     }
@@ -616,10 +1491,10 @@ impl<Exe: Executor> ConnectionSender<Exe> {
                 })?
         };
 
-        match self
-            .registrations
-            .unbounded_send(Register::Request { key, resolver })
-        {
+        match self.registrations.unbounded_send(Register::Request {
+            key,
+            resolver: resolver.into(),
+        }) {
             Ok(_) => {
                 //there should be no timeout for this message
                 pin_mut!(response);
@@ -634,6 +1509,7 @@ impl<Exe: Executor> ConnectionSender<Exe> {
 pub struct Connection<Exe: Executor> {
     id: i64,
     url: Url,
+    address: SocketAddr,
     sender: ConnectionSender<Exe>,
 }
 
@@ -643,93 +1519,119 @@ impl<Exe: Executor> Connection<Exe> {
         auth_data: Option<Arc<Mutex<Box<dyn crate::authentication::Authentication>>>>,
         proxy_to_broker_url: Option<String>,
         certificate_chain: &[Certificate],
+        identity: Option<Identity>,
         allow_insecure_connection: bool,
         tls_hostname_verification_enabled: bool,
         connection_timeout: Duration,
         operation_timeout: Duration,
+        connection_options: ConnectionOptions,
         executor: Arc<Exe>,
     ) -> Result<Connection<Exe>, ConnectionError> {
-        if url.scheme() != "pulsar" && url.scheme() != "pulsar+ssl" {
-            error!("invalid scheme: {}", url.scheme());
-            return Err(ConnectionError::NotFound);
-        }
         let hostname = url.host().map(|s| s.to_string());
 
         let tls = match url.scheme() {
             "pulsar" => false,
             "pulsar+ssl" => true,
             s => {
-                error!("invalid scheme: {}", s);
-                return Err(ConnectionError::NotFound);
+                error!("invalid scheme for {}: {}", url, s);
+                return Err(ConnectionError::InvalidUrl {
+                    scheme: s.to_string(),
+                });
             }
         };
 
-        let u = url.clone();
-        let address: SocketAddr = match executor
-            .spawn_blocking(move || {
-                u.socket_addrs(|| match u.scheme() {
-                    "pulsar" => Some(6650),
-                    "pulsar+ssl" => Some(6651),
-                    _ => None,
-                })
-                .map_err(|e| {
-                    error!("could not look up address: {:?}", e);
-                    e
-                })
-                .ok()
-                .and_then(|v| {
-                    let mut rng = thread_rng();
-                    let index: usize = rng.gen_range(0..v.len());
-                    v.get(index).copied()
-                })
-            })
-            .await
-        {
-            Some(Some(address)) => address,
-            _ =>
-            //return Err(Error::Custom(format!("could not query address: {}", url))),
-            {
-                return Err(ConnectionError::NotFound)
-            }
+        // the timeout covers the address lookup, the TCP connection, the TLS
+        // handshake and the CONNECT command
+        let connect = async {
+            let address =
+                Self::resolve(&url, connection_options.resolver.clone(), executor.clone()).await?;
+
+            let hostname = hostname.unwrap_or_else(|| address.ip().to_string());
+
+            debug!("Connecting to {}: {}", url, address);
+            let sender = Connection::prepare_stream(
+                address,
+                hostname,
+                tls,
+                auth_data,
+                proxy_to_broker_url,
+                certificate_chain,
+                identity,
+                allow_insecure_connection,
+                tls_hostname_verification_enabled,
+                connection_options,
+                executor.clone(),
+                operation_timeout,
+            )
+            .await?;
+            Ok::<_, ConnectionError>((address, sender))
         };
 
-        let hostname = hostname.unwrap_or_else(|| address.ip().to_string());
+        let (address, sender) = match executor.timeout(connection_timeout, connect).await {
+            Ok(res) => res?,
+            Err(Elapsed) => {
+                warn!("timeout connecting to {}", url);
+                return Err(ConnectionError::Timeout);
+            }
+        };
 
-        debug!("Connecting to {}: {}", url, address);
-        let sender_prepare = Connection::prepare_stream(
+        let id = rand::random();
+        Ok(Connection {
+            id,
+            url,
             address,
-            hostname,
-            tls,
-            auth_data,
-            proxy_to_broker_url,
-            certificate_chain,
-            allow_insecure_connection,
-            tls_hostname_verification_enabled,
-            executor.clone(),
-            operation_timeout,
-        );
-        let delay_f = executor.delay(connection_timeout);
-
-        pin_mut!(sender_prepare);
-        pin_mut!(delay_f);
+            sender,
+        })
+    }
 
-        let sender;
-        match select(sender_prepare, delay_f).await {
-            Either::Left((res, _)) => sender = res?,
-            Either::Right(_) => {
-                return Err(ConnectionError::Io(std::io::Error::new(
-                    std::io::ErrorKind::TimedOut,
-                    "timeout connecting to the Pulsar server",
-                )));
+    /// looks up the broker's addresses and picks one at random
+    async fn resolve(
+        url: &Url,
+        resolver: Option<Arc<dyn Resolver>>,
+        executor: Arc<Exe>,
+    ) -> Result<SocketAddr, ConnectionError> {
+        let host = url.host_str().unwrap_or_default().to_string();
+        let default_port = match url.scheme() {
+            "pulsar" => 6650,
+            _ => 6651,
+        };
+        let addresses = match resolver {
+            Some(resolver) => {
+                resolver
+                    .resolve(&host, url.port().unwrap_or(default_port))
+                    .await
+            }
+            None => {
+                let u = url.clone();
+                executor
+                    .spawn_blocking(move || u.socket_addrs(|| Some(default_port)))
+                    .await
+                    .unwrap_or_else(|| Err(std::io::Error::other("the address lookup task failed")))
             }
         };
 
-        let id = rand::random();
-        Ok(Connection { id, url, sender })
+        match addresses {
+            Ok(addresses) if !addresses.is_empty() => {
+                let index: usize = thread_rng().gen_range(0..addresses.len());
+                Ok(addresses[index])
+            }
+            Ok(_) => {
+                error!("could not look up address of {}: no address found", host);
+                Err(ConnectionError::DnsResolution { host, source: None })
+            }
+            Err(e) => {
+                error!("could not look up address of {}: {:?}", host, e);
+                Err(ConnectionError::DnsResolution {
+                    host,
+                    source: Some(e),
+                })
+            }
+        }
     }
 
     async fn prepare_auth_data(
         auth: Option<Arc<Mutex<Box<dyn crate::authentication::Authentication>>>>,
+        client_certificate: bool,
     ) -> Result<Option<Authentication>, ConnectionError> {
         match auth {
             Some(m_auth) => {
@@ -739,10 +1641,32 @@ impl<Exe: Executor> Connection<Exe> {
                     data: auth_guard.auth_data().await?,
                 }))
             }
+            // the broker authenticates the client with the certificate
+            // presented during the TLS handshake
+            None if client_certificate => Ok(Some(Authentication {
+                name: "tls".to_string(),
+                data: Vec::new(),
+            })),
             None => Ok(None),
         }
     }
 
+    /// runs `f` as a task of the executor and waits for its result
+    #[cfg(feature = "tokio-runtime")]
+    async fn run_on_executor<F, T>(executor: &Exe, f: F) -> Result<T, ConnectionError>
+    where
+        F: Future<Output = Result<T, ConnectionError>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        executor
+            .spawn(Box::pin(async move {
+                let _ = tx.send(f.await);
+            }))
+            .map_err(|_| ConnectionError::Shutdown)?;
+        rx.await.map_err(|_| ConnectionError::Canceled)?
+    }
+
     async fn prepare_stream(
         address: SocketAddr,
         hostname: String,
@@ -750,17 +1674,19 @@ impl<Exe: Executor> Connection<Exe> {
         auth: Option<Arc<Mutex<Box<dyn crate::authentication::Authentication>>>>,
         proxy_to_broker_url: Option<String>,
         certificate_chain: &[Certificate],
+        identity: Option<Identity>,
         allow_insecure_connection: bool,
         tls_hostname_verification_enabled: bool,
+        connection_options: ConnectionOptions,
         executor: Arc<Exe>,
         operation_timeout: Duration,
     ) -> Result<ConnectionSender<Exe>, ConnectionError> {
         match executor.kind() {
             #[cfg(feature = "tokio-runtime")]
             ExecutorKind::Tokio => {
+                // tokio's sockets are created on the executor, because with
+                // TokioHandleExecutor the caller may not run in a runtime
                 if tls {
-                    let stream = tokio::net::TcpStream::connect(&address).await?;
-
                     let mut builder = native_tls::TlsConnector::builder();
                     for certificate in certificate_chain {
                         builder.add_root_certificate(certificate.clone());
@@ -769,30 +1695,47 @@ impl<Exe: Executor> Connection<Exe> {
                         allow_insecure_connection && !tls_hostname_verification_enabled,
                     );
                     builder.danger_accept_invalid_certs(allow_insecure_connection);
+                    if let Some(identity) = identity.clone() {
+                        builder.identity(identity);
+                    }
                     let cx = builder.build()?;
                     let cx = tokio_native_tls::TlsConnector::from(cx);
-                    let stream = cx
-                        .connect(&hostname, stream)
-                        .await
-                        .map(|stream| tokio_util::codec::Framed::new(stream, Codec))?;
+                    let stream = Self::run_on_executor(&executor, async move {
+                        let stream = tokio::net::TcpStream::connect(&address).await?;
+                        cx.connect(&hostname, stream)
+                            .await
+                            .map_err(ConnectionError::TlsHandshake)
+                    })
+                    .await
+                    .map(|stream| {
+                        tokio_util::codec::Framed::new(stream, connection_options.codec())
+                    })?;
 
                     Connection::connect(
                         stream,
-                        Self::prepare_auth_data(auth).await?,
+                        auth,
+                        tls && identity.is_some(),
                         proxy_to_broker_url,
+                        connection_options,
                         executor,
                         operation_timeout,
                     )
                     .await
                 } else {
-                    let stream = tokio::net::TcpStream::connect(&address)
-                        .await
-                        .map(|stream| tokio_util::codec::Framed::new(stream, Codec))?;
+                    let stream = Self::run_on_executor(&executor, async move {
+                        Ok(tokio::net::TcpStream::connect(&address).await?)
+                    })
+                    .await
+                    .map(|stream| {
+                        tokio_util::codec::Framed::new(stream, connection_options.codec())
+                    })?;
 
                     Connection::connect(
                         stream,
-                        Self::prepare_auth_data(auth).await?,
+                        auth,
+                        tls && identity.is_some(),
                         proxy_to_broker_url,
+                        connection_options,
                         executor,
                         operation_timeout,
                     )
@@ -815,28 +1758,41 @@ impl<Exe: Executor> Connection<Exe> {
                         allow_insecure_connection && !tls_hostname_verification_enabled,
                     );
                     connector = connector.danger_accept_invalid_certs(allow_insecure_connection);
+                    if let Some(identity) = identity.clone() {
+                        connector = connector.identity(identity);
+                    }
                     let stream = connector
                         .connect(&hostname, stream)
                         .await
-                        .map(|stream| asynchronous_codec::Framed::new(stream, Codec))?;
+                        .map_err(ConnectionError::TlsHandshake)
+                        .map(|stream| {
+                            asynchronous_codec::Framed::new(stream, connection_options.codec())
+                        })?;
 
                     Connection::connect(
                         stream,
-                        Self::prepare_auth_data(auth).await?,
+                        auth,
+                        tls && identity.is_some(),
                         proxy_to_broker_url,
+                        connection_options,
                         executor,
                         operation_timeout,
                     )
                     .await
                 } else {
-                    let stream = async_std::net::TcpStream::connect(&address)
-                        .await
-                        .map(|stream| asynchronous_codec::Framed::new(stream, Codec))?;
+                    let stream =
+                        async_std::net::TcpStream::connect(&address)
+                            .await
+                            .map(|stream| {
+                                asynchronous_codec::Framed::new(stream, connection_options.codec())
+                            })?;
 
                     Connection::connect(
                         stream,
-                        Self::prepare_auth_data(auth).await?,
+                        auth,
+                        tls && identity.is_some(),
                         proxy_to_broker_url,
+                        connection_options,
                         executor,
                         operation_timeout,
                     )
@@ -847,13 +1803,71 @@ impl<Exe: Executor> Connection<Exe> {
             ExecutorKind::AsyncStd => {
                 unimplemented!("the async-std-runtime cargo feature is not active");
             }
+            #[cfg(feature = "smol-runtime")]
+            ExecutorKind::Smol => {
+                if tls {
+                    let stream = smol::net::TcpStream::connect(&address).await?;
+                    let mut connector = async_native_tls::TlsConnector::new();
+                    for certificate in certificate_chain {
+                        connector = connector.add_root_certificate(certificate.clone());
+                    }
+                    connector = connector.danger_accept_invalid_hostnames(
+                        allow_insecure_connection && !tls_hostname_verification_enabled,
+                    );
+                    connector = connector.danger_accept_invalid_certs(allow_insecure_connection);
+                    if let Some(identity) = identity.clone() {
+                        connector = connector.identity(identity);
+                    }
+                    let stream = connector
+                        .connect(&hostname, stream)
+                        .await
+                        .map_err(ConnectionError::TlsHandshake)
+                        .map(|stream| {
+                            asynchronous_codec::Framed::new(stream, connection_options.codec())
+                        })?;
+
+                    Connection::connect(
+                        stream,
+                        auth,
+                        tls && identity.is_some(),
+                        proxy_to_broker_url,
+                        connection_options,
+                        executor,
+                        operation_timeout,
+                    )
+                    .await
+                } else {
+                    let stream = smol::net::TcpStream::connect(&address)
+                        .await
+                        .map(|stream| {
+                            asynchronous_codec::Framed::new(stream, connection_options.codec())
+                        })?;
+
+                    Connection::connect(
+                        stream,
+                        auth,
+                        tls && identity.is_some(),
+                        proxy_to_broker_url,
+                        connection_options,
+                        executor,
+                        operation_timeout,
+                    )
+                    .await
+                }
+            }
+            #[cfg(not(feature = "smol-runtime"))]
+            ExecutorKind::Smol => {
+                unimplemented!("the smol-runtime cargo feature is not active");
+            }
         }
     }
 
     pub async fn connect<S>(
         mut stream: S,
-        auth_data: Option<Authentication>,
+        auth: Option<Arc<Mutex<Box<dyn crate::authentication::Authentication>>>>,
+        client_certificate: bool,
         proxy_to_broker_url: Option<String>,
+        connection_options: ConnectionOptions,
         executor: Arc<Exe>,
         operation_timeout: Duration,
     ) -> Result<ConnectionSender<Exe>, ConnectionError>
@@ -862,26 +1876,29 @@ impl<Exe: Executor> Connection<Exe> {
         S: Sink<Message, Error = ConnectionError>,
         S: Send + std::marker::Unpin + 'static,
     {
+        let auth_data = Self::prepare_auth_data(auth.clone(), client_certificate).await?;
         let _ = stream
             .send({
-                let msg = messages::connect(auth_data, proxy_to_broker_url);
+                let msg = messages::connect(
+                    auth_data,
+                    proxy_to_broker_url,
+                    connection_options.protocol_version,
+                    connection_options.client_version.clone(),
+                );
                 trace!("connection message: {:?}", msg);
                 msg
             })
             .await?;
 
         let msg = stream.next().await;
-        match msg {
+        let connected = match msg {
             Some(Ok(Message {
                 command:
                     proto::BaseCommand {
                         error: Some(error), ..
                     },
                 ..
-            })) => Err(ConnectionError::PulsarError(
-                crate::error::server_error(error.error),
-                Some(error.message),
-            )),
+            })) => Err(handshake_error(error)),
             Some(Ok(msg)) => {
                 let cmd = msg.command.clone();
                 trace!("received connection response: {:?}", msg);
@@ -897,37 +1914,83 @@ impl<Exe: Executor> Connection<Exe> {
         }?;
 
         let (mut sink, stream) = stream.split();
-        let (tx, mut rx) = mpsc::unbounded();
+        let metrics = connection_options.metrics.clone();
+        let received_metrics = metrics.clone();
+        let stream = stream.inspect(move |msg| {
+            if let (Some(metrics), Ok(msg)) = (&received_metrics, msg) {
+                metrics.on_bytes_received(msg.frame_size());
+            }
+        });
+        let (tx, mut rx) = OutboundSender::channel(connection_options.outbound_channel_size);
         let (registrations_tx, registrations_rx) = mpsc::unbounded();
         let error = SharedError::new();
         let (receiver_shutdown_tx, receiver_shutdown_rx) = oneshot::channel();
+        let (outbound_flushed_tx, outbound_flushed_rx) = oneshot::channel();
 
-        if executor
-            .spawn(Box::pin(
-                Receiver::new(
-                    stream,
-                    tx.clone(),
-                    error.clone(),
-                    registrations_rx,
-                    receiver_shutdown_rx,
-                )
-                .map(|_| ()),
-            ))
-            .is_err()
-        {
+        let mut receiver = Receiver::new(
+            stream,
+            tx.clone(),
+            error.clone(),
+            registrations_rx,
+            receiver_shutdown_rx,
+        );
+
+        // the broker sends authentication challenges when the credentials
+        // it accepted are about to expire, they are answered with fresh ones
+        // without closing the connection
+        if let Some(auth) = auth {
+            let (auth_challenges_tx, mut auth_challenges) = mpsc::unbounded();
+            receiver = receiver.with_auth_challenges(auth_challenges_tx);
+            let outbound = tx.clone();
+            let protocol_version = connection_options.protocol_version;
+            let client_version = connection_options.client_version.clone();
+            let res = executor.spawn(Box::pin(async move {
+                while let Some(challenge) = auth_challenges.next().await {
+                    debug!(
+                        "received an authentication challenge for method {:?}",
+                        challenge.challenge.and_then(|c| c.auth_method_name)
+                    );
+                    match Self::prepare_auth_data(Some(auth.clone()), false).await {
+                        Ok(Some(auth_data)) => {
+                            let msg = messages::auth_response(
+                                auth_data,
+                                protocol_version,
+                                client_version.clone(),
+                            );
+                            if outbound.send(msg).await.is_err() {
+                                break;
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(e) => error!("could not refresh the authentication data: {}", e),
+                    }
+                }
+            }));
+            if res.is_err() {
+                error!("the executor could not spawn the authentication refresh future");
+                return Err(ConnectionError::Shutdown);
+            }
+        }
+
+        if executor.spawn(Box::pin(receiver.map(|_| ()))).is_err() {
             error!("the executor could not spawn the Receiver future");
             return Err(ConnectionError::Shutdown);
         }
 
         let err = error.clone();
+        let sent_metrics = metrics.clone();
         let res = executor.spawn(Box::pin(async move {
             while let Some(msg) = rx.next().await {
+                if let Some(metrics) = &sent_metrics {
+                    metrics.on_bytes_sent(msg.frame_size());
+                }
                 // println!("real sent msg: {:?}", msg);
                 if let Err(e) = sink.send(msg).await {
                     err.set(e);
                     break;
                 }
             }
+            let _ = outbound_flushed_tx.send(());
         }));
         if res.is_err() {
             error!("the executor could not spawn the Receiver future");
@@ -938,10 +2001,14 @@ impl<Exe: Executor> Connection<Exe> {
             tx,
             registrations_tx,
             receiver_shutdown_tx,
+            outbound_flushed_rx,
             SerialId::new(),
+            connected,
             error,
             executor.clone(),
             operation_timeout,
+            metrics,
+            connection_options.max_frame_size,
         );
 
         Ok(sender)
@@ -951,33 +2018,149 @@ impl<Exe: Executor> Connection<Exe> {
         self.id
     }
 
+    /// replaces the consumer and producer id allocators
+    ///
+    /// producers and consumers keep their id when they reconnect, so
+    /// connections created by the same client share their allocators to
+    /// avoid collisions after a reconnection
+    pub(crate) fn with_id_allocators(
+        mut self,
+        consumer_id: SerialId,
+        producer_id: SerialId,
+    ) -> Self {
+        self.sender.consumer_id = consumer_id;
+        self.sender.producer_id = producer_id;
+        self
+    }
+
     pub fn error(&self) -> Option<ConnectionError> {
         self.sender.error.remove()
     }
 
+    /// protocol version agreed with the broker during the handshake
+    pub fn negotiated_protocol_version(&self) -> i32 {
+        self.sender.negotiated_protocol_version()
+    }
+
+    /// largest message accepted by the broker
+    pub fn max_message_size(&self) -> usize {
+        self.sender.max_message_size()
+    }
+
+    /// features supported by the broker
+    pub fn feature_flags(&self) -> &proto::FeatureFlags {
+        self.sender.feature_flags()
+    }
+
     pub fn is_valid(&self) -> bool {
         !self.sender.error.is_set()
     }
 
+    /// checks the connection with a ping, unlike [Connection::is_valid] which
+    /// only reports the errors already detected, like a half-open TCP connection
+    ///
+    /// the connection is marked as failed if the broker does not answer
+    /// within `timeout`
+    pub async fn ping_healthcheck(&self, timeout: Duration) -> bool {
+        match self.sender.send_ping_with_timeout(timeout).await {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("health check of connection {} failed: {}", self.id, e);
+                false
+            }
+        }
+    }
+
+    /// resolves with the connection's error once it fails, so that it can be
+    /// awaited instead of polling [Connection::is_valid]
+    pub fn closed(&self) -> impl Future<Output = ConnectionError> {
+        let error = self.sender.error.subscribe();
+        async move { error.await.unwrap_or(ConnectionError::Shutdown) }
+    }
+
     pub fn url(&self) -> &Url {
         &self.url
     }
 
+    /// time elapsed since the last request sent on this connection
+    pub fn idle_time(&self) -> Duration {
+        self.sender.last_used.lock().unwrap().elapsed()
+    }
+
+    /// address of the broker (or proxy) this connection is established to
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// closes the connection once the commands already queued are written
+    ///
+    /// new commands are rejected right away, and the queued ones have at
+    /// most the operation timeout to reach the socket. Dropping the
+    /// connection instead is best effort: queued commands can be lost
+    pub async fn close(&self) {
+        let sender = &self.sender;
+        let flush = async {
+            sender.tx.close().await;
+            if let Some(flushed) = sender.outbound_flushed.lock().await.take() {
+                let _ = flushed.await;
+            }
+        };
+        let delay_f = sender.executor.delay(sender.operation_timeout);
+        pin_mut!(flush);
+        pin_mut!(delay_f);
+        if let Either::Right(_) = select(flush, delay_f).await {
+            warn!(
+                "timeout writing the queued commands of connection {} to {}",
+                self.id, self.url
+            );
+        }
+
+        if let Some(shutdown) = sender.receiver_shutdown.lock().await.take() {
+            let _ = shutdown.send(());
+        }
+        if !sender.error.is_set() {
+            sender.error.set(ConnectionError::Shutdown);
+        }
+    }
+
     /// Chain to send a message, e.g. conn.sender().send_ping()
     pub fn sender(&self) -> &ConnectionSender<Exe> {
         &self.sender
     }
 }
 
+/// stops the receiver without waiting for the queued commands, use
+/// [Connection::close] to write them first
 impl<Exe: Executor> Drop for Connection<Exe> {
     fn drop(&mut self) {
         trace!("dropping connection {} for {}", self.id, self.url);
-        if let Some(shutdown) = self.sender.receiver_shutdown.take() {
+        if let Some(shutdown) = self.sender.receiver_shutdown.get_mut().take() {
             let _ = shutdown.send(());
         }
     }
 }
 
+/// classifies an error returned by the server in response to `CommandConnect`,
+/// so that callers can tell apart rejected credentials from a broker that
+/// is not ready yet (and can be retried)
+fn handshake_error(error: proto::CommandError) -> ConnectionError {
+    let code = crate::error::server_error(error.error);
+    error!(
+        "connection handshake failed (request id {}): {:?} {}",
+        error.request_id, code, error.message
+    );
+    match code {
+        Some(proto::ServerError::AuthenticationError)
+        | Some(proto::ServerError::AuthorizationError) => {
+            ConnectionError::Authentication(AuthenticationError::Custom(error.message))
+        }
+        Some(proto::ServerError::ServiceNotReady) => {
+            ConnectionError::ServiceNotReady(error.message)
+        }
+        _ => ConnectionError::PulsarError(code, Some(error.message)),
+    }
+}
+
 fn extract_message<T: Debug, F>(message: Message, extract: F) -> Result<T, ConnectionError>
 where
     F: FnOnce(Message) -> Option<T>,
@@ -1000,17 +2183,23 @@ where
 
 pub(crate) mod messages {
     use chrono::Utc;
-    use proto::MessageIdData;
+    use std::time::Duration;
 
-    use crate::connection::Authentication;
+    use crate::connection::{Authentication, SeekTarget};
     use crate::consumer::ConsumerOptions;
     use crate::message::{
         proto::{self, base_command::Type as CommandType, command_subscribe::SubType},
         Message, Payload,
     };
     use crate::producer::{self, ProducerOptions};
+    use crate::transaction::TxnId;
 
-    pub fn connect(auth: Option<Authentication>, proxy_to_broker_url: Option<String>) -> Message {
+    pub fn connect(
+        auth: Option<Authentication>,
+        proxy_to_broker_url: Option<String>,
+        protocol_version: i32,
+        client_version: String,
+    ) -> Message {
         let (auth_method_name, auth_data) = match auth {
             Some(auth) => (Some(auth.name), Some(auth.data)),
             None => (None, None),
@@ -1023,8 +2212,13 @@ pub(crate) mod messages {
                     auth_method_name,
                     auth_data,
                     proxy_to_broker_url,
-                    client_version: String::from("2.0.1-incubating"),
-                    protocol_version: Some(12),
+                    client_version,
+                    protocol_version: Some(protocol_version),
+                    feature_flags: Some(proto::FeatureFlags {
+                        supports_auth_refresh: Some(true),
+                        supports_broker_entry_metadata: Some(true),
+                        ..Default::default()
+                    }),
                     ..Default::default()
                 }),
                 ..Default::default()
@@ -1033,10 +2227,33 @@ pub(crate) mod messages {
         }
     }
 
-    pub fn ping() -> Message {
+    /// answers the broker's authentication challenge with fresh credentials
+    pub fn auth_response(
+        auth: Authentication,
+        protocol_version: i32,
+        client_version: String,
+    ) -> Message {
         Message {
             command: proto::BaseCommand {
-                r#type: CommandType::Ping as i32,
+                r#type: CommandType::AuthResponse as i32,
+                auth_response: Some(proto::CommandAuthResponse {
+                    client_version: Some(client_version),
+                    response: Some(proto::AuthData {
+                        auth_method_name: Some(auth.name),
+                        auth_data: Some(auth.data),
+                    }),
+                    protocol_version: Some(protocol_version),
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        }
+    }
+
+    pub fn ping() -> Message {
+        Message {
+            command: proto::BaseCommand {
+                r#type: CommandType::Ping as i32,
                 ping: Some(proto::CommandPing {}),
                 ..Default::default()
             },
@@ -1093,6 +2310,7 @@ pub(crate) mod messages {
         request_id: u64,
         namespace: String,
         mode: proto::command_get_topics_of_namespace::Mode,
+        topics_hash: Option<String>,
     ) -> Message {
         Message {
             command: proto::BaseCommand {
@@ -1101,6 +2319,7 @@ pub(crate) mod messages {
                     request_id,
                     namespace,
                     mode: Some(mode as i32),
+                    topics_hash,
                     ..Default::default()
                 }),
                 ..Default::default()
@@ -1128,18 +2347,22 @@ pub(crate) mod messages {
                     producer_id,
                     sequence_id,
                     num_messages: message.num_messages_in_batch,
+                    marker: message.marker_type.map(|_| true),
+                    txnid_least_bits: message.txn_id.map(|txn| txn.least_bits),
+                    txnid_most_bits: message.txn_id.map(|txn| txn.most_bits),
                     ..Default::default()
                 }),
                 ..Default::default()
             },
-            payload: Some(Payload {
-                metadata: proto::MessageMetadata {
+            payload: Some(Payload::new(
+                proto::MessageMetadata {
                     producer_name,
                     sequence_id,
                     properties,
                     publish_time: Utc::now().timestamp_millis() as u64,
                     replicated_from: None,
                     partition_key: message.partition_key,
+                    ordering_key: message.ordering_key,
                     replicate_to: message.replicate_to,
                     compression: message.compression,
                     uncompressed_size: message.uncompressed_size,
@@ -1150,10 +2373,17 @@ pub(crate) mod messages {
                     encryption_param: message.encryption_param,
                     schema_version: message.schema_version,
                     deliver_at_time: message.deliver_at_time,
+                    marker_type: message.marker_type,
+                    uuid: message.uuid,
+                    chunk_id: message.chunk_id,
+                    num_chunks_from_msg: message.num_chunks_from_msg,
+                    total_chunk_msg_size: message.total_chunk_msg_size,
+                    txnid_least_bits: message.txn_id.map(|txn| txn.least_bits),
+                    txnid_most_bits: message.txn_id.map(|txn| txn.most_bits),
                     ..Default::default()
                 },
-                data: message.payload,
-            }),
+                message.payload,
+            )),
         }
     }
 
@@ -1217,6 +2447,101 @@ pub(crate) mod messages {
         }
     }
 
+    pub fn get_schema(topic: String, schema_version: Option<Vec<u8>>, request_id: u64) -> Message {
+        Message {
+            command: proto::BaseCommand {
+                r#type: CommandType::GetSchema as i32,
+                get_schema: Some(proto::CommandGetSchema {
+                    request_id,
+                    topic,
+                    schema_version,
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        }
+    }
+
+    pub fn new_txn(request_id: u64, tc_id: u64, timeout: Duration) -> Message {
+        Message {
+            command: proto::BaseCommand {
+                r#type: CommandType::NewTxn as i32,
+                new_txn: Some(proto::CommandNewTxn {
+                    request_id,
+                    // the broker counts in seconds and a TTL of 0 would
+                    // abort the transaction right away
+                    txn_ttl_seconds: Some(
+                        (timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0)).max(1),
+                    ),
+                    tc_id: Some(tc_id),
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        }
+    }
+
+    pub fn add_partition_to_txn(
+        request_id: u64,
+        txn_id: TxnId,
+        partitions: Vec<String>,
+    ) -> Message {
+        Message {
+            command: proto::BaseCommand {
+                r#type: CommandType::AddPartitionToTxn as i32,
+                add_partition_to_txn: Some(proto::CommandAddPartitionToTxn {
+                    request_id,
+                    txnid_least_bits: Some(txn_id.least_bits),
+                    txnid_most_bits: Some(txn_id.most_bits),
+                    partitions,
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        }
+    }
+
+    pub fn add_subscription_to_txn(
+        request_id: u64,
+        txn_id: TxnId,
+        subscription: Vec<proto::Subscription>,
+    ) -> Message {
+        Message {
+            command: proto::BaseCommand {
+                r#type: CommandType::AddSubscriptionToTxn as i32,
+                add_subscription_to_txn: Some(proto::CommandAddSubscriptionToTxn {
+                    request_id,
+                    txnid_least_bits: Some(txn_id.least_bits),
+                    txnid_most_bits: Some(txn_id.most_bits),
+                    subscription,
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        }
+    }
+
+    pub fn end_txn(request_id: u64, txn_id: TxnId, commit: bool) -> Message {
+        let action = if commit {
+            proto::TxnAction::Commit
+        } else {
+            proto::TxnAction::Abort
+        };
+        Message {
+            command: proto::BaseCommand {
+                r#type: CommandType::EndTxn as i32,
+                end_txn: Some(proto::CommandEndTxn {
+                    request_id,
+                    txnid_least_bits: Some(txn_id.least_bits),
+                    txnid_most_bits: Some(txn_id.most_bits),
+                    txn_action: Some(action as i32),
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        }
+    }
+
     pub fn close_producer(producer_id: u64, request_id: u64) -> Message {
         Message {
             command: proto::BaseCommand {
@@ -1240,6 +2565,11 @@ pub(crate) mod messages {
         consumer_name: Option<String>,
         options: ConsumerOptions,
     ) -> Message {
+        let key_shared_meta = match sub_type {
+            SubType::KeyShared => options.key_shared_meta,
+            _ => None,
+        };
+
         Message {
             command: proto::BaseCommand {
                 r#type: CommandType::Subscribe as i32,
@@ -1264,6 +2594,7 @@ pub(crate) mod messages {
                     initial_position: Some(options.initial_position.into()),
                     schema: options.schema,
                     start_message_id: options.start_message_id,
+                    key_shared_meta,
                     ..Default::default()
                 }),
                 ..Default::default()
@@ -1286,11 +2617,21 @@ pub(crate) mod messages {
         }
     }
 
+    /// acks of single entries of a batch, which carry the batch's size, get an
+    /// ack set so that the broker only acknowledges those entries
     pub fn ack(
         consumer_id: u64,
-        message_id: Vec<proto::MessageIdData>,
+        mut message_id: Vec<proto::MessageIdData>,
         cumulative: bool,
     ) -> Message {
+        for id in message_id.iter_mut() {
+            if let (Some(batch_index), Some(batch_size)) = (id.batch_index, id.batch_size) {
+                if id.ack_set.is_empty() {
+                    id.ack_set = batch_ack_set(batch_size, batch_index);
+                }
+            }
+        }
+
         Message {
             command: proto::BaseCommand {
                 r#type: CommandType::Ack as i32,
@@ -1312,6 +2653,21 @@ pub(crate) mod messages {
         }
     }
 
+    /// bitset of the entries of a batch that are still not acknowledged, as
+    /// expected by the broker: every entry but `batch_index` is set
+    pub fn batch_ack_set(batch_size: i32, batch_index: i32) -> Vec<i64> {
+        let batch_size = batch_size.max(0) as usize;
+        let mut ack_set = vec![0u64; batch_size.div_ceil(64)];
+        for i in 0..batch_size {
+            ack_set[i / 64] |= 1 << (i % 64);
+        }
+        if batch_index >= 0 && (batch_index as usize) < batch_size {
+            let i = batch_index as usize;
+            ack_set[i / 64] &= !(1 << (i % 64));
+        }
+        ack_set.into_iter().map(|word| word as i64).collect()
+    }
+
     pub fn redeliver_unacknowleged_messages(
         consumer_id: u64,
         message_ids: Vec<proto::MessageIdData>,
@@ -1346,12 +2702,12 @@ pub(crate) mod messages {
         }
     }
 
-    pub fn seek(
-        consumer_id: u64,
-        request_id: u64,
-        message_id: Option<MessageIdData>,
-        message_publish_time: Option<u64>,
-    ) -> Message {
+    pub fn seek(consumer_id: u64, request_id: u64, target: SeekTarget) -> Message {
+        let (message_id, message_publish_time) = match target {
+            SeekTarget::MessageId(message_id) => (Some(message_id), None),
+            SeekTarget::Timestamp(timestamp) => (None, Some(timestamp)),
+        };
+
         Message {
             command: proto::BaseCommand {
                 r#type: CommandType::Seek as i32,
@@ -1381,3 +2737,1525 @@ pub(crate) mod messages {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::message::Codec;
+    use futures::task::noop_waker;
+
+    /// sender writing commands to `outbound` and registering its requests on
+    /// `registrations`, without a connection task behind it
+    #[cfg(feature = "tokio-runtime")]
+    pub(crate) fn test_sender(
+        outbound: OutboundSender,
+        registrations: mpsc::UnboundedSender<Register>,
+        request_ids: SerialId,
+        connected: proto::CommandConnected,
+    ) -> ConnectionSender<crate::TokioExecutor> {
+        let (shutdown_tx, _shutdown) = oneshot::channel();
+        let (_flushed_tx, flushed) = oneshot::channel();
+        ConnectionSender::new(
+            outbound,
+            registrations,
+            shutdown_tx,
+            flushed,
+            request_ids,
+            connected,
+            SharedError::new(),
+            Arc::new(crate::TokioExecutor),
+            Duration::from_secs(30),
+            None,
+            None,
+        )
+    }
+
+    /// connection on which every request succeeds, returned with the commands
+    /// written to it
+    #[cfg(feature = "tokio-runtime")]
+    pub(crate) fn test_connection() -> (
+        Arc<Connection<crate::TokioExecutor>>,
+        impl Stream<Item = Message> + Unpin,
+    ) {
+        let (connection, outbound_rx, mut registrations) = manual_connection();
+        std::thread::spawn(move || {
+            futures::executor::block_on(async move {
+                // the consumers' channels are kept open while the connection lives
+                let mut consumers = Vec::new();
+                while let Some(registration) = registrations.next().await {
+                    match registration {
+                        Register::Consumer { resolver, .. } => consumers.push(resolver),
+                        registration => answer(registration),
+                    }
+                }
+            })
+        });
+        (connection, outbound_rx)
+    }
+
+    /// connection whose requests are left for the caller to answer, returned
+    /// with the commands written to it and the requests registered on it
+    #[cfg(feature = "tokio-runtime")]
+    pub(crate) fn manual_connection() -> (
+        Arc<Connection<crate::TokioExecutor>>,
+        impl Stream<Item = Message> + Unpin,
+        mpsc::UnboundedReceiver<Register>,
+    ) {
+        let (outbound, outbound_rx) = OutboundSender::channel(None);
+        let (registrations_tx, registrations) = mpsc::unbounded();
+        let sender = test_sender(
+            outbound,
+            registrations_tx,
+            SerialId::new(),
+            Default::default(),
+        );
+        let connection = Connection {
+            id: 0,
+            url: Url::parse("pulsar://127.0.0.1:6650").unwrap(),
+            address: "127.0.0.1:6650".parse().unwrap(),
+            sender,
+        };
+        (Arc::new(connection), outbound_rx, registrations)
+    }
+
+    /// answers a request with a success, or with a receipt for a send
+    pub(crate) fn answer(registration: Register) {
+        match registration {
+            Register::Request {
+                key: RequestKey::RequestId(request_id),
+                resolver,
+            } => resolver.send(Message {
+                command: proto::BaseCommand {
+                    r#type: proto::base_command::Type::Success as i32,
+                    success: Some(proto::CommandSuccess {
+                        request_id,
+                        schema: None,
+                    }),
+                    ..Default::default()
+                },
+                payload: None,
+            }),
+            Register::Request {
+                key:
+                    RequestKey::ProducerSend {
+                        producer_id,
+                        sequence_id,
+                    },
+                resolver,
+            } => resolver.send(Message {
+                command: proto::BaseCommand {
+                    r#type: proto::base_command::Type::SendReceipt as i32,
+                    send_receipt: Some(proto::CommandSendReceipt {
+                        producer_id,
+                        sequence_id,
+                        message_id: Some(Default::default()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                payload: None,
+            }),
+            _ => {}
+        }
+    }
+
+    /// receiver reading `inbound` outside of a connection, returned with the
+    /// outbound channel it writes to (bounded to `bound` messages), the sender
+    /// of its registrations and its shutdown signal
+    fn test_receiver<S: Stream<Item = Result<Message, ConnectionError>>>(
+        inbound: S,
+        bound: Option<usize>,
+    ) -> (
+        Receiver<S>,
+        OutboundSender,
+        impl Stream<Item = Message> + Unpin,
+        mpsc::UnboundedSender<Register>,
+        oneshot::Sender<()>,
+    ) {
+        let (outbound, outbound_rx) = OutboundSender::channel(bound);
+        let (registrations_tx, registrations) = mpsc::unbounded();
+        let (shutdown_tx, shutdown) = oneshot::channel();
+        let receiver = Receiver::new(
+            inbound,
+            outbound.clone(),
+            SharedError::new(),
+            registrations,
+            shutdown,
+        );
+        (
+            receiver,
+            outbound,
+            outbound_rx,
+            registrations_tx,
+            shutdown_tx,
+        )
+    }
+
+    /// answers a request with `command`
+    pub(crate) fn respond(registration: Register, command: proto::BaseCommand) {
+        if let Register::Request { resolver, .. } = registration {
+            resolver.send(Message {
+                command,
+                payload: None,
+            });
+        }
+    }
+
+    #[test]
+    fn prune_canceled_requests() {
+        let inbound = futures::stream::pending::<Result<Message, ConnectionError>>();
+        let (mut receiver, _outbound, _outbound_rx, registrations_tx, _shutdown_tx) =
+            test_receiver(inbound, None);
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        for request_id in 0..10_000 {
+            // the caller drops the response future right away
+            let (resolver, _) = oneshot::channel();
+            registrations_tx
+                .unbounded_send(Register::Request {
+                    key: RequestKey::RequestId(request_id),
+                    resolver: resolver.into(),
+                })
+                .unwrap();
+            assert!(Pin::new(&mut receiver).poll(&mut cx).is_pending());
+        }
+
+        assert!(receiver.pending_requests.len() <= MIN_PRUNE_THRESHOLD);
+    }
+
+    #[test]
+    fn batch_entry_ack_command() {
+        let id = MessageIdData {
+            ledger_id: 1,
+            entry_id: 2,
+            batch_index: Some(1),
+            batch_size: Some(3),
+            ..Default::default()
+        };
+        let msg = messages::ack(7, vec![id], false);
+        let ack = msg.command.ack.unwrap();
+        assert_eq!(ack.consumer_id, 7);
+        assert_eq!(ack.message_id[0].batch_index, Some(1));
+        assert_eq!(ack.message_id[0].ack_set, vec![0b101]);
+
+        let mut encoded = Vec::new();
+        prost::Message::encode(&ack, &mut encoded).unwrap();
+        let decoded: proto::CommandAck = prost::Message::decode(&encoded[..]).unwrap();
+        assert_eq!(decoded.message_id[0].ack_set, vec![0b101]);
+
+        // batches larger than 64 entries use several words
+        assert_eq!(messages::batch_ack_set(70, 65), vec![-1, 0b111101]);
+
+        // acks of whole messages are unchanged
+        let id = MessageIdData {
+            ledger_id: 1,
+            entry_id: 2,
+            batch_index: Some(1),
+            ..Default::default()
+        };
+        let msg = messages::ack(7, vec![id], false);
+        assert!(msg.command.ack.unwrap().message_id[0].ack_set.is_empty());
+    }
+
+    #[test]
+    fn send_deliver_at_time() {
+        let deliver_at_time = 1_600_000_060_000;
+        let message = crate::producer::ProducerMessage {
+            payload: b"data".to_vec(),
+            deliver_at_time: Some(deliver_at_time),
+            ..Default::default()
+        };
+        let msg = messages::send(1, "producer".to_string(), 3, message);
+        let payload = msg.payload.unwrap();
+
+        let mut encoded = Vec::new();
+        prost::Message::encode(&payload.metadata, &mut encoded).unwrap();
+        let metadata: proto::MessageMetadata = prost::Message::decode(&encoded[..]).unwrap();
+        assert_eq!(metadata.deliver_at_time, Some(deliver_at_time));
+        assert_eq!(metadata.sequence_id, 3);
+    }
+
+    #[test]
+    fn shared_error_notifies_subscribers() {
+        let error = crate::error::SharedError::new();
+        let first = error.subscribe();
+        let second = error.subscribe();
+
+        error.set(ConnectionError::Disconnected);
+        let late = error.subscribe();
+
+        for rx in [first, second, late] {
+            match futures::executor::block_on(rx) {
+                Ok(ConnectionError::Disconnected) => {}
+                other => panic!("unexpected notification: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn seek_timestamp_command() {
+        let msg = messages::seek(1, 2, SeekTarget::Timestamp(1_600_000_000_000));
+        let seek = msg.command.seek.unwrap();
+        assert_eq!(seek.consumer_id, 1);
+        assert_eq!(seek.request_id, 2);
+        assert_eq!(seek.message_publish_time, Some(1_600_000_000_000));
+        assert!(seek.message_id.is_none());
+    }
+
+    #[test]
+    fn route_response_while_consumer_receives() {
+        let consumer_id = 1;
+        let request_id = 2;
+        let message = Message {
+            command: proto::BaseCommand {
+                r#type: proto::base_command::Type::Message as i32,
+                message: Some(proto::CommandMessage {
+                    consumer_id,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        };
+        let response = Message {
+            command: proto::BaseCommand {
+                r#type: proto::base_command::Type::GetLastMessageIdResponse as i32,
+                get_last_message_id_response: Some(proto::CommandGetLastMessageIdResponse {
+                    request_id,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        };
+        let inbound = futures::stream::iter(vec![Ok(message.clone()), Ok(response), Ok(message)])
+            .chain(futures::stream::pending());
+
+        let (mut receiver, _outbound, _outbound_rx, registrations_tx, _shutdown_tx) =
+            test_receiver(inbound, None);
+
+        let (consumer_tx, mut consumer_rx) = mpsc::unbounded();
+        registrations_tx
+            .unbounded_send(Register::Consumer {
+                consumer_id,
+                resolver: consumer_tx,
+            })
+            .unwrap();
+        let (resolver, mut response_rx) = oneshot::channel();
+        registrations_tx
+            .unbounded_send(Register::Request {
+                key: RequestKey::RequestId(request_id),
+                resolver: resolver.into(),
+            })
+            .unwrap();
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut receiver).poll(&mut cx).is_pending());
+
+        let response = response_rx.try_recv().unwrap().unwrap();
+        assert!(response.command.get_last_message_id_response.is_some());
+        let mut received = 0;
+        while let Some(Some(msg)) = consumer_rx.next().now_or_never() {
+            assert!(msg.command.message.is_some());
+            received += 1;
+        }
+        assert_eq!(received, 2);
+    }
+
+    #[test]
+    fn auth_challenge() {
+        let challenge = Message {
+            command: proto::BaseCommand {
+                r#type: proto::base_command::Type::AuthChallenge as i32,
+                auth_challenge: Some(proto::CommandAuthChallenge {
+                    challenge: Some(proto::AuthData {
+                        auth_method_name: Some("token".to_string()),
+                        auth_data: Some(b"refresh".to_vec()),
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        };
+        let inbound = futures::stream::iter(vec![Ok(challenge)]).chain(futures::stream::pending());
+
+        let (receiver, _outbound, _outbound_rx, _registrations_tx, _shutdown_tx) =
+            test_receiver(inbound, None);
+        let (auth_challenges_tx, mut auth_challenges) = mpsc::unbounded();
+        let mut receiver = receiver.with_auth_challenges(auth_challenges_tx);
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut receiver).poll(&mut cx).is_pending());
+
+        let challenge = auth_challenges.next().now_or_never().unwrap().unwrap();
+        assert_eq!(
+            challenge.challenge.unwrap().auth_method_name.as_deref(),
+            Some("token")
+        );
+
+        let response = messages::auth_response(
+            Authentication {
+                name: "token".to_string(),
+                data: b"fresh".to_vec(),
+            },
+            19,
+            "2.0.0".to_string(),
+        );
+        assert_eq!(
+            response.command.r#type,
+            proto::base_command::Type::AuthResponse as i32
+        );
+        let auth_data = response.command.auth_response.unwrap().response.unwrap();
+        assert_eq!(auth_data.auth_data.as_deref(), Some(&b"fresh"[..]));
+    }
+
+    #[test]
+    fn bounded_outbound_waits_for_room() {
+        let (tx, mut rx) = OutboundSender::channel(Some(4));
+        let mut sent = 0;
+        while tx.send(messages::ping()).now_or_never().is_some() {
+            sent += 1;
+            assert!(sent <= 4, "the outbound queue grew past its capacity");
+        }
+        // the last message is queued, but its sender waits for room
+        assert!(tx.send(messages::ping()).now_or_never().is_none());
+
+        let mut received = 0;
+        while let Some(Some(_)) = rx.next().now_or_never() {
+            received += 1;
+        }
+        assert_eq!(received, sent + 1);
+        assert!(tx.send(messages::ping()).now_or_never().is_some());
+    }
+
+    #[test]
+    fn closed_outbound_keeps_queued_messages() {
+        let (tx, rx) = OutboundSender::channel(None);
+        tx.send_control(messages::ping()).unwrap();
+        tx.send_control(messages::ping()).unwrap();
+        tx.close().now_or_never().unwrap();
+        assert!(tx.send_control(messages::ping()).is_err());
+
+        let queued: Vec<_> = rx.collect().now_or_never().unwrap();
+        assert_eq!(queued.len(), 2);
+    }
+
+    #[test]
+    fn concurrent_pings() {
+        let (_inbound_tx, inbound) = mpsc::unbounded();
+        let (mut receiver, _outbound, _outbound_rx, _registrations_tx, _shutdown_tx) =
+            test_receiver(inbound, None);
+
+        let (first, mut first_pong) = oneshot::channel();
+        let (second, mut second_pong) = oneshot::channel();
+        receiver.handle_registration(Register::Ping { resolver: first });
+        receiver.handle_registration(Register::Ping { resolver: second });
+
+        // each pong answers the oldest ping
+        receiver.handle_inbound(messages::pong());
+        assert!(matches!(first_pong.try_recv(), Ok(Some(()))));
+        assert!(matches!(second_pong.try_recv(), Ok(None)));
+        receiver.handle_inbound(messages::pong());
+        assert!(matches!(second_pong.try_recv(), Ok(Some(()))));
+    }
+
+    #[test]
+    fn pong_with_full_outbound() {
+        let (_inbound_tx, inbound) = mpsc::unbounded();
+        let (mut receiver, tx, mut rx, _registrations_tx, _shutdown_tx) =
+            test_receiver(inbound, Some(1));
+        while tx.send(messages::ping()).now_or_never().is_some() {}
+        receiver.handle_inbound(messages::ping());
+
+        // the pong is written before the messages waiting in the queue
+        let first = rx.next().now_or_never().unwrap().unwrap();
+        assert!(first.command.pong.is_some());
+        while let Some(Some(msg)) = rx.next().now_or_never() {
+            assert!(msg.command.ping.is_some());
+        }
+
+        tx.close().now_or_never().unwrap();
+        assert!(tx.send_control(messages::pong()).is_err());
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn broker_message_size() {
+        let sender = |connected| {
+            let (outbound, _outbound_rx) = OutboundSender::channel(None);
+            let (registrations_tx, _registrations) = mpsc::unbounded();
+            test_sender(outbound, registrations_tx, SerialId::new(), connected)
+        };
+
+        // older brokers do not send their limit
+        let old = sender(proto::CommandConnected::default());
+        assert_eq!(old.max_message_size(), DEFAULT_MAX_MESSAGE_SIZE);
+        assert_eq!(old.max_frame_size, crate::message::DEFAULT_MAX_FRAME_SIZE);
+        assert_eq!(old.feature_flags().supports_auth_refresh, None);
+
+        let new = sender(proto::CommandConnected {
+            max_message_size: Some(1024),
+            feature_flags: Some(proto::FeatureFlags {
+                supports_auth_refresh: Some(true),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert_eq!(new.max_message_size(), 1024);
+        assert_eq!(new.max_frame_size, 1024 + FRAME_PADDING);
+        assert_eq!(new.feature_flags().supports_auth_refresh, Some(true));
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn received_frame_size() {
+        use tokio_util::codec::{Decoder, Encoder};
+
+        // brokers can be configured with a `maxMessageSize` above the default
+        let message = messages::send(
+            1,
+            "producer".to_string(),
+            0,
+            producer::ProducerMessage {
+                payload: vec![0; 2 * DEFAULT_MAX_MESSAGE_SIZE],
+                ..Default::default()
+            },
+        );
+        let size = message.frame_size();
+        assert!(size > crate::message::DEFAULT_MAX_FRAME_SIZE);
+        let mut frame = bytes::BytesMut::new();
+        Codec::new(size).encode(message, &mut frame).unwrap();
+
+        let mut codec = ConnectionOptions::default().codec();
+        let message = codec.decode(&mut frame.clone()).unwrap().unwrap();
+        assert_eq!(
+            message.payload.unwrap().data.len(),
+            2 * DEFAULT_MAX_MESSAGE_SIZE
+        );
+
+        // unless a limit was configured
+        let mut codec = ConnectionOptions {
+            max_frame_size: Some(crate::message::DEFAULT_MAX_FRAME_SIZE),
+            ..Default::default()
+        }
+        .codec();
+        assert!(matches!(
+            codec.decode(&mut frame),
+            Err(ConnectionError::FrameTooLarge { .. })
+        ));
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn failed_subscribe_removes_consumer() {
+        let (inbound_tx, inbound) = mpsc::unbounded();
+        let (receiver, outbound, _outbound_rx, registrations_tx, _shutdown_tx) =
+            test_receiver(inbound, None);
+
+        let sender = test_sender(
+            outbound,
+            registrations_tx,
+            SerialId::starting_at(3),
+            Default::default(),
+        );
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            tokio::spawn(receiver);
+            inbound_tx
+                .unbounded_send(Ok(Message {
+                    command: BaseCommand {
+                        r#type: proto::base_command::Type::Error as i32,
+                        error: Some(proto::CommandError {
+                            request_id: 3,
+                            error: proto::ServerError::ConsumerBusy as i32,
+                            message: "Exclusive consumer is already connected".to_string(),
+                        }),
+                        ..Default::default()
+                    },
+                    payload: None,
+                }))
+                .unwrap();
+
+            let (consumer_tx, _consumer_rx) = mpsc::unbounded();
+            let res = sender
+                .subscribe(
+                    consumer_tx,
+                    "test".to_string(),
+                    "test".to_string(),
+                    SubType::Exclusive,
+                    1,
+                    None,
+                    Default::default(),
+                )
+                .await;
+            assert!(matches!(res, Err(ConnectionError::PulsarError(_, _))));
+            assert_eq!(sender.receiver_stats().await.unwrap().consumers, 0);
+
+            // a subscription whose future is dropped is removed as well
+            let (consumer_tx, _consumer_rx) = mpsc::unbounded();
+            let subscribe = sender.subscribe(
+                consumer_tx,
+                "test".to_string(),
+                "test".to_string(),
+                SubType::Exclusive,
+                2,
+                None,
+                Default::default(),
+            );
+            assert!(subscribe.now_or_never().is_none());
+            assert_eq!(sender.receiver_stats().await.unwrap().consumers, 0);
+        });
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn close_consumer_removes_consumer() {
+        let (inbound_tx, inbound) = mpsc::unbounded();
+        let (receiver, outbound, _outbound_rx, registrations_tx, _shutdown_tx) =
+            test_receiver(inbound, None);
+
+        let sender = test_sender(
+            outbound,
+            registrations_tx,
+            SerialId::starting_at(3),
+            Default::default(),
+        );
+        let success = |request_id| {
+            Ok(Message {
+                command: BaseCommand {
+                    r#type: proto::base_command::Type::Success as i32,
+                    success: Some(proto::CommandSuccess {
+                        request_id,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                payload: None,
+            })
+        };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            tokio::spawn(receiver);
+            inbound_tx.unbounded_send(success(3)).unwrap();
+            inbound_tx.unbounded_send(success(4)).unwrap();
+
+            let (consumer_tx, mut consumer_rx) = mpsc::unbounded();
+            sender
+                .subscribe(
+                    consumer_tx,
+                    "test".to_string(),
+                    "test".to_string(),
+                    SubType::Exclusive,
+                    1,
+                    None,
+                    Default::default(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(sender.receiver_stats().await.unwrap().consumers, 1);
+
+            sender.close_consumer(1).await.unwrap();
+            assert_eq!(sender.receiver_stats().await.unwrap().consumers, 0);
+            assert!(consumer_rx.next().await.is_none());
+        });
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn close_producer_removes_producer() {
+        let (inbound_tx, inbound) = mpsc::unbounded();
+        let (mut receiver, outbound, _outbound_rx, receiver_registrations_tx, _shutdown_tx) =
+            test_receiver(inbound, None);
+        let (registrations_tx, mut registrations) = mpsc::unbounded();
+        let (producer_tx, mut producer_rx) = mpsc::unbounded();
+        receiver.handle_registration(Register::Producer {
+            producer_id: 1,
+            resolver: producer_tx,
+        });
+
+        let mut sender = test_sender(
+            outbound,
+            registrations_tx,
+            SerialId::new(),
+            Default::default(),
+        );
+        sender.operation_timeout = Duration::from_millis(10);
+
+        // the producer is removed even if the broker does not answer
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let res = runtime.block_on(sender.close_producer(1));
+        assert!(matches!(res, Err(ConnectionError::Timeout)));
+        while let Some(Some(registration)) = registrations.next().now_or_never() {
+            receiver_registrations_tx
+                .unbounded_send(registration)
+                .unwrap();
+        }
+        drop(inbound_tx);
+        let _ = receiver.poll_unpin(&mut Context::from_waker(&noop_waker()));
+
+        assert!(receiver.producers.is_empty());
+        assert!(producer_rx.next().now_or_never().unwrap().is_none());
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn out_of_order_receipt() {
+        let (inbound_tx, inbound) = mpsc::unbounded();
+        let (receiver, outbound, _outbound_rx, registrations_tx, _shutdown_tx) =
+            test_receiver(inbound, None);
+        let mut sender = test_sender(
+            outbound,
+            registrations_tx,
+            SerialId::new(),
+            Default::default(),
+        );
+        sender.operation_timeout = Duration::from_millis(100);
+        let receipt = |sequence_id| {
+            Ok(Message {
+                command: BaseCommand {
+                    r#type: proto::base_command::Type::SendReceipt as i32,
+                    send_receipt: Some(proto::CommandSendReceipt {
+                        producer_id: 1,
+                        sequence_id,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                payload: None,
+            })
+        };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            tokio::spawn(receiver);
+            inbound_tx.unbounded_send(receipt(1)).unwrap();
+            let res = sender
+                .send(1, "test".to_string(), 1, Default::default())
+                .await;
+            assert_eq!(res.unwrap().sequence_id, 1);
+
+            // a receipt older than the last one is dropped instead of being
+            // kept for the next request with its sequence id
+            inbound_tx.unbounded_send(receipt(0)).unwrap();
+            let res = sender
+                .send(1, "test".to_string(), 0, Default::default())
+                .await;
+            assert!(matches!(res, Err(ConnectionError::Timeout)));
+            assert_eq!(sender.receiver_stats().await.unwrap().received_messages, 0);
+
+            // a duplicate receipt is given to the send waiting for one, which
+            // can then report the mismatch
+            let send = sender.send(1, "test".to_string(), 2, Default::default());
+            pin_mut!(send);
+            assert!(futures::poll!(&mut send).is_pending());
+            assert_eq!(sender.receiver_stats().await.unwrap().pending_requests, 1);
+            inbound_tx.unbounded_send(receipt(1)).unwrap();
+            assert_eq!(send.await.unwrap().sequence_id, 1);
+
+            // as is a receipt skipping the oldest send
+            let first = sender.send(1, "test".to_string(), 3, Default::default());
+            let second = sender.send(1, "test".to_string(), 4, Default::default());
+            pin_mut!(first);
+            pin_mut!(second);
+            assert!(futures::poll!(&mut first).is_pending());
+            assert!(futures::poll!(&mut second).is_pending());
+            assert_eq!(sender.receiver_stats().await.unwrap().pending_requests, 2);
+            inbound_tx.unbounded_send(receipt(4)).unwrap();
+            assert_eq!(first.await.unwrap().sequence_id, 4);
+            assert_eq!(second.await.unwrap().sequence_id, 4);
+        });
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn raw_commands() {
+        let (outbound, mut outbound_rx) = OutboundSender::channel(None);
+        let (registrations_tx, mut registrations) = mpsc::unbounded();
+        let sender = test_sender(
+            outbound,
+            registrations_tx,
+            SerialId::new(),
+            Default::default(),
+        );
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            sender.send_raw_oneway(messages::ping()).await.unwrap();
+            let sent = outbound_rx.next().await.unwrap();
+            assert!(sent.command.ping.is_some());
+
+            let request = messages::get_topics_of_namespace(
+                7,
+                "public/default".to_string(),
+                proto::command_get_topics_of_namespace::Mode::All,
+                None,
+            );
+            let response = sender.send_raw(request, RequestKey::RequestId(7));
+            pin_mut!(response);
+            assert!(futures::poll!(&mut response).is_pending());
+
+            match registrations.next().await {
+                Some(Register::Request { key, resolver }) => {
+                    assert_eq!(key, RequestKey::RequestId(7));
+                    resolver.send(Message {
+                        command: BaseCommand {
+                            r#type: proto::base_command::Type::GetTopicsOfNamespaceResponse as i32,
+                            get_topics_of_namespace_response: Some(
+                                proto::CommandGetTopicsOfNamespaceResponse {
+                                    request_id: 7,
+                                    topics: vec!["topic".to_string()],
+                                    ..Default::default()
+                                },
+                            ),
+                            ..Default::default()
+                        },
+                        payload: None,
+                    });
+                }
+                _ => panic!("the request was not registered"),
+            }
+
+            let response = response.await.unwrap();
+            assert_eq!(
+                response
+                    .command
+                    .get_topics_of_namespace_response
+                    .unwrap()
+                    .topics,
+                vec!["topic".to_string()]
+            );
+        });
+    }
+
+    #[test]
+    fn transaction_timeout() {
+        let ttl = |timeout| {
+            messages::new_txn(1, 0, timeout)
+                .command
+                .new_txn
+                .unwrap()
+                .txn_ttl_seconds
+        };
+        assert_eq!(ttl(Duration::from_secs(60)), Some(60));
+        assert_eq!(ttl(Duration::from_millis(1500)), Some(2));
+        assert_eq!(ttl(Duration::from_millis(200)), Some(1));
+        assert_eq!(ttl(Duration::ZERO), Some(1));
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn transactions() {
+        let (outbound, mut outbound_rx) = OutboundSender::channel(None);
+        let (registrations_tx, mut registrations) = mpsc::unbounded();
+        let sender = test_sender(
+            outbound,
+            registrations_tx,
+            SerialId::new(),
+            Default::default(),
+        );
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let txn_id = sender.new_txn(0, Duration::from_secs(60));
+            pin_mut!(txn_id);
+            assert!(futures::poll!(&mut txn_id).is_pending());
+
+            let sent = outbound_rx.next().await.unwrap();
+            let new_txn = sent.command.new_txn.unwrap();
+            assert_eq!(new_txn.txn_ttl_seconds, Some(60));
+            assert_eq!(new_txn.tc_id, Some(0));
+
+            match registrations.next().await {
+                Some(Register::Request { key, resolver }) => {
+                    assert_eq!(key, RequestKey::RequestId(new_txn.request_id));
+                    resolver.send(Message {
+                        command: BaseCommand {
+                            r#type: proto::base_command::Type::NewTxnResponse as i32,
+                            new_txn_response: Some(proto::CommandNewTxnResponse {
+                                request_id: new_txn.request_id,
+                                txnid_least_bits: Some(3),
+                                txnid_most_bits: Some(1),
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        },
+                        payload: None,
+                    });
+                }
+                _ => panic!("the request was not registered"),
+            }
+
+            let txn_id = txn_id.await.unwrap();
+            assert_eq!(
+                txn_id,
+                TxnId {
+                    most_bits: 1,
+                    least_bits: 3
+                }
+            );
+
+            let message = producer::ProducerMessage {
+                payload: b"data".to_vec(),
+                txn_id: Some(txn_id),
+                ..Default::default()
+            };
+            let msg = messages::send(1, "producer".to_string(), 2, message);
+            let send = msg.command.send.unwrap();
+            assert_eq!(send.txnid_most_bits, Some(1));
+            assert_eq!(send.txnid_least_bits, Some(3));
+            assert_eq!(msg.payload.unwrap().metadata.txnid_least_bits, Some(3));
+
+            let end_txn = sender.end_txn(txn_id, false);
+            pin_mut!(end_txn);
+            assert!(futures::poll!(&mut end_txn).is_pending());
+
+            let sent = outbound_rx.next().await.unwrap();
+            let request = sent.command.end_txn.unwrap();
+            assert_eq!(request.txnid_most_bits, Some(1));
+            assert_eq!(request.txnid_least_bits, Some(3));
+            assert_eq!(request.txn_action, Some(proto::TxnAction::Abort as i32));
+
+            match registrations.next().await {
+                Some(Register::Request { key, resolver }) => {
+                    assert_eq!(key, RequestKey::RequestId(request.request_id));
+                    resolver.send(Message {
+                        command: BaseCommand {
+                            r#type: proto::base_command::Type::EndTxnResponse as i32,
+                            end_txn_response: Some(proto::CommandEndTxnResponse {
+                                request_id: request.request_id,
+                                error: Some(proto::ServerError::InvalidTxnStatus as i32),
+                                message: Some("already aborted".to_string()),
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        },
+                        payload: None,
+                    });
+                }
+                _ => panic!("the request was not registered"),
+            }
+
+            let error = end_txn.await.unwrap_err();
+            assert!(matches!(
+                crate::error::TransactionError::from(error),
+                crate::error::TransactionError::InvalidStatus(Some(_))
+            ));
+
+            let msg = messages::add_partition_to_txn(4, txn_id, vec!["topic".to_string()]);
+            assert_eq!(msg.request_key(), Some(RequestKey::RequestId(4)));
+            assert_eq!(
+                msg.command.add_partition_to_txn.unwrap().partitions,
+                vec!["topic".to_string()]
+            );
+        });
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn request_timeout() {
+        let (outbound, _outbound_rx) = OutboundSender::channel(None);
+        let (registrations_tx, mut registrations) = mpsc::unbounded();
+        let mut sender = test_sender(
+            outbound,
+            registrations_tx,
+            SerialId::new(),
+            Default::default(),
+        );
+        sender.operation_timeout = Duration::from_millis(10);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let res = runtime.block_on(sender.lookup_topic("test", false));
+        assert!(matches!(res, Err(ConnectionError::Timeout)));
+        assert!(ConnectionError::Timeout.is_retriable());
+
+        // the request is removed from the receiver once it timed out
+        match registrations.next().now_or_never() {
+            Some(Some(Register::Request { key, .. })) => {
+                assert_eq!(key, RequestKey::RequestId(0))
+            }
+            _ => panic!("the lookup was not registered"),
+        }
+        match registrations.next().now_or_never() {
+            Some(Some(Register::CancelRequest { key })) => {
+                assert_eq!(key, RequestKey::RequestId(0))
+            }
+            _ => panic!("the lookup was not canceled"),
+        }
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn connection_timeout() {
+        // the listener accepts the TCP connection, but nobody answers the
+        // CONNECT command
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "pulsar://127.0.0.1:{}",
+            listener.local_addr().unwrap().port()
+        ))
+        .unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let res = runtime.block_on(Connection::new(
+            url,
+            None,
+            None,
+            &[],
+            None,
+            false,
+            false,
+            Duration::from_millis(100),
+            Duration::from_secs(30),
+            ConnectionOptions::default(),
+            Arc::new(crate::TokioExecutor),
+        ));
+        assert!(matches!(res, Err(ConnectionError::Timeout)));
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn connect_outside_of_the_runtime() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let executor = crate::TokioHandleExecutor(runtime.handle().clone());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "pulsar://127.0.0.1:{}",
+            listener.local_addr().unwrap().port()
+        ))
+        .unwrap();
+
+        // this thread has no tokio runtime, creating the socket here would panic.
+        // The listener never answers the CONNECT command
+        let res = futures::executor::block_on(Connection::new(
+            url,
+            None,
+            None,
+            &[],
+            None,
+            false,
+            false,
+            Duration::from_millis(100),
+            Duration::from_secs(30),
+            ConnectionOptions::default(),
+            Arc::new(executor),
+        ));
+        assert!(matches!(res, Err(ConnectionError::Timeout)));
+        listener.set_nonblocking(true).unwrap();
+        assert!(listener.accept().is_ok());
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn preseeded_request_ids() {
+        let (outbound, _outbound_rx) = OutboundSender::channel(None);
+        let (registrations_tx, mut registrations) = mpsc::unbounded();
+        let request_ids = SerialId::starting_at(42);
+        let sender = test_sender(
+            outbound,
+            registrations_tx,
+            request_ids.clone(),
+            Default::default(),
+        );
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let lookup = sender.lookup_topic("test", false);
+            pin_mut!(lookup);
+            assert!(futures::poll!(lookup).is_pending());
+        });
+
+        match registrations.next().now_or_never() {
+            Some(Some(Register::Request { key, .. })) => {
+                assert_eq!(key, RequestKey::RequestId(42))
+            }
+            _ => panic!("the lookup was not registered"),
+        }
+        assert_eq!(request_ids.current(), 43);
+        assert_eq!(request_ids.get(), 43);
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn split_redelivery_requests() {
+        let (outbound, mut outbound_rx) = OutboundSender::channel(None);
+        let (registrations_tx, _registrations) = mpsc::unbounded();
+        let sender = test_sender(
+            outbound,
+            registrations_tx,
+            SerialId::new(),
+            Default::default(),
+        );
+        let ids: Vec<_> = (0..5)
+            .map(|entry_id| MessageIdData {
+                ledger_id: 1,
+                entry_id,
+                ..Default::default()
+            })
+            .collect();
+        let mut sent_commands = |ids: &[MessageIdData], max_per_command| {
+            futures::executor::block_on(sender.send_redeliver_unacknowleged_messages(
+                7,
+                ids.to_vec(),
+                max_per_command,
+            ))
+            .unwrap();
+            let mut sizes = Vec::new();
+            while let Some(Some(message)) = outbound_rx.next().now_or_never() {
+                let redeliver = message.command.redeliver_unacknowledged_messages.unwrap();
+                assert_eq!(redeliver.consumer_id, 7);
+                sizes.push(redeliver.message_ids.len());
+            }
+            sizes
+        };
+
+        assert_eq!(sent_commands(&ids, 2000), vec![5]);
+        assert_eq!(sent_commands(&ids, 2), vec![2, 2, 1]);
+        // at least one id is sent per command
+        assert_eq!(sent_commands(&ids, 0), vec![1; 5]);
+        // a command without ids asks for all the unacked messages
+        assert_eq!(sent_commands(&[], 2000), vec![0]);
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn key_shared_needs_protocol_version_14() {
+        let (outbound, _outbound_rx) = OutboundSender::channel(None);
+        let (registrations_tx, mut registrations) = mpsc::unbounded();
+        let sender = |protocol_version| {
+            test_sender(
+                outbound.clone(),
+                registrations_tx.clone(),
+                SerialId::new(),
+                proto::CommandConnected {
+                    protocol_version: Some(protocol_version),
+                    ..Default::default()
+                },
+            )
+        };
+        let (resolver, _messages) = mpsc::unbounded();
+        let subscribe = |sender: ConnectionSender<crate::TokioExecutor>| {
+            let resolver = resolver.clone();
+            async move {
+                sender
+                    .subscribe(
+                        resolver,
+                        "test".to_string(),
+                        "subscription".to_string(),
+                        SubType::KeyShared,
+                        1,
+                        None,
+                        ConsumerOptions::default(),
+                    )
+                    .await
+            }
+        };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let result = subscribe(sender(13)).await;
+            assert!(matches!(
+                result,
+                Err(ConnectionError::UnsupportedByBroker(_))
+            ));
+            assert!(registrations.next().now_or_never().is_none());
+
+            let subscription = subscribe(sender(14));
+            pin_mut!(subscription);
+            assert!(futures::poll!(subscription).is_pending());
+            assert!(registrations.next().now_or_never().is_some());
+        });
+    }
+
+    struct StaticResolver(SocketAddr);
+
+    #[async_trait]
+    impl Resolver for StaticResolver {
+        async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, std::io::Error> {
+            assert_eq!(host, "broker.internal");
+            assert_eq!(port, 6651);
+            Ok(vec![self.0])
+        }
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn custom_resolver() {
+        let address: SocketAddr = "10.0.0.1:6651".parse().unwrap();
+        let url = Url::parse("pulsar+ssl://broker.internal").unwrap();
+        let resolved = futures::executor::block_on(Connection::resolve(
+            &url,
+            Some(Arc::new(StaticResolver(address))),
+            Arc::new(crate::executor::TokioExecutor),
+        ));
+        assert_eq!(resolved.unwrap(), address);
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn tls_handshake_failure() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            use std::io::Write;
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+        });
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let result = runtime.block_on(Connection::prepare_stream(
+            address,
+            "localhost".to_string(),
+            true,
+            None,
+            None,
+            &[],
+            None,
+            false,
+            true,
+            Default::default(),
+            Arc::new(crate::TokioExecutor),
+            Duration::from_secs(5),
+        ));
+        server.join().unwrap();
+
+        match result {
+            Err(ConnectionError::TlsHandshake(_)) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("the handshake succeeded"),
+        }
+    }
+
+    /// self-signed certificate for `localhost` and its PEM encoded PKCS #8 key
+    #[cfg(feature = "tokio-runtime")]
+    fn self_signed_certificate() -> (Vec<u8>, Vec<u8>) {
+        use openssl::{
+            asn1::Asn1Time, bn::BigNum, hash::MessageDigest, pkey::PKey, rsa::Rsa,
+            x509::extension::SubjectAlternativeName, x509::X509Name, x509::X509,
+        };
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("localhost")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        (
+            builder.build().to_pem().unwrap(),
+            key.private_key_to_pem_pkcs8().unwrap(),
+        )
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn self_signed_tls_broker() {
+        use std::io::{Read, Write};
+        use tokio_util::codec::Encoder;
+
+        let (certificate, key) = self_signed_certificate();
+        let acceptor =
+            native_tls::TlsAcceptor::new(Identity::from_pkcs8(&certificate, &key).unwrap())
+                .unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // answers the CONNECT command of every client completing the handshake
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match acceptor.accept(stream.unwrap()) {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let mut size = [0u8; 4];
+                stream.read_exact(&mut size).unwrap();
+                let mut frame = vec![0u8; u32::from_be_bytes(size) as usize];
+                stream.read_exact(&mut frame).unwrap();
+
+                let mut connected = bytes::BytesMut::new();
+                Codec::new(crate::message::DEFAULT_MAX_FRAME_SIZE)
+                    .encode(
+                        Message {
+                            command: BaseCommand {
+                                r#type: proto::base_command::Type::Connected as i32,
+                                connected: Some(proto::CommandConnected {
+                                    server_version: "test".to_string(),
+                                    protocol_version: Some(10),
+                                    ..Default::default()
+                                }),
+                                ..Default::default()
+                            },
+                            payload: None,
+                        },
+                        &mut connected,
+                    )
+                    .unwrap();
+                stream.write_all(&connected).unwrap();
+            }
+        });
+
+        let connect = |certificate_chain: Vec<Certificate>, allow_insecure_connection: bool| async move {
+            Connection::prepare_stream(
+                address,
+                "localhost".to_string(),
+                true,
+                None,
+                None,
+                &certificate_chain,
+                None,
+                allow_insecure_connection,
+                true,
+                Default::default(),
+                Arc::new(crate::TokioExecutor),
+                Duration::from_secs(5),
+            )
+            .await
+        };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            // the certificate is not trusted by default
+            let result = connect(vec![], false).await;
+            assert!(matches!(result, Err(ConnectionError::TlsHandshake(_))));
+
+            // accepted when insecure connections are allowed
+            let sender = connect(vec![], true).await.unwrap();
+            assert_eq!(sender.negotiated_protocol_version(), 10);
+
+            // or when it is part of the trusted certificate chain
+            let chain = vec![Certificate::from_pem(&certificate).unwrap()];
+            let sender = connect(chain, false).await.unwrap();
+            assert_eq!(sender.negotiated_protocol_version(), 10);
+        });
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn handshake_authentication_failures() {
+        struct ExpiredToken;
+
+        #[async_trait]
+        impl crate::authentication::Authentication for ExpiredToken {
+            fn auth_method_name(&self) -> String {
+                "token".to_string()
+            }
+
+            async fn initialize(&mut self) -> Result<(), AuthenticationError> {
+                Ok(())
+            }
+
+            async fn auth_data(&mut self) -> Result<Vec<u8>, AuthenticationError> {
+                Err(AuthenticationError::Custom("token expired".to_string()))
+            }
+        }
+
+        async fn connect(
+            auth: Box<dyn crate::authentication::Authentication>,
+            broker_error: proto::ServerError,
+        ) -> Result<ConnectionSender<crate::TokioExecutor>, ConnectionError> {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = tokio_util::codec::Framed::new(
+                    stream,
+                    Codec::new(crate::message::DEFAULT_MAX_FRAME_SIZE),
+                );
+                if let Some(Ok(_connect)) = stream.next().await {
+                    let _ = stream
+                        .send(Message {
+                            command: BaseCommand {
+                                r#type: proto::base_command::Type::Error as i32,
+                                error: Some(proto::CommandError {
+                                    request_id: 0,
+                                    error: broker_error as i32,
+                                    message: "invalid token".to_string(),
+                                }),
+                                ..Default::default()
+                            },
+                            payload: None,
+                        })
+                        .await;
+                }
+            });
+
+            let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+            Connection::connect(
+                tokio_util::codec::Framed::new(
+                    stream,
+                    Codec::new(crate::message::DEFAULT_MAX_FRAME_SIZE),
+                ),
+                Some(Arc::new(Mutex::new(auth))),
+                false,
+                None,
+                Default::default(),
+                Arc::new(crate::TokioExecutor),
+                Duration::from_secs(5),
+            )
+            .await
+        }
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            // the provider cannot produce the credentials
+            let result = connect(
+                Box::new(ExpiredToken),
+                proto::ServerError::AuthenticationError,
+            )
+            .await;
+            assert!(matches!(
+                result,
+                Err(ConnectionError::Authentication(AuthenticationError::Custom(m)))
+                    if m == "token expired"
+            ));
+
+            // the broker rejects the credentials
+            let token = Authentication {
+                name: "token".to_string(),
+                data: b"secret".to_vec(),
+            };
+            for broker_error in [
+                proto::ServerError::AuthenticationError,
+                proto::ServerError::AuthorizationError,
+            ] {
+                let result = connect(Box::new(token.clone()), broker_error).await;
+                assert!(matches!(
+                    result,
+                    Err(ConnectionError::Authentication(AuthenticationError::Custom(m)))
+                        if m == "invalid token"
+                ));
+            }
+        });
+    }
+
+    #[test]
+    fn registrations_and_inbound_share_polls() {
+        const COUNT: u64 = 1_000;
+        // responses to requests that were not registered yet
+        let inbound = futures::stream::iter(
+            (COUNT..2 * COUNT).map(|id| Ok(messages::lookup_topic("test".to_string(), false, id))),
+        )
+        .chain(futures::stream::pending());
+
+        let (mut receiver, _outbound, _outbound_rx, registrations_tx, _shutdown_tx) =
+            test_receiver(inbound, None);
+
+        let mut responses = Vec::new();
+        for request_id in 0..COUNT {
+            let (resolver, response) = oneshot::channel();
+            responses.push(response);
+            registrations_tx
+                .unbounded_send(Register::Request {
+                    key: RequestKey::RequestId(request_id),
+                    resolver: resolver.into(),
+                })
+                .unwrap();
+        }
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut receiver).poll(&mut cx).is_pending());
+        assert_eq!(receiver.pending_requests.len(), POLL_BUDGET);
+        assert_eq!(receiver.received_messages.len(), POLL_BUDGET);
+
+        for _ in 0..COUNT {
+            assert!(Pin::new(&mut receiver).poll(&mut cx).is_pending());
+        }
+        assert_eq!(receiver.pending_requests.len(), COUNT as usize);
+        assert_eq!(receiver.received_messages.len(), COUNT as usize);
+    }
+
+    #[test]
+    fn unclaimed_responses_are_evicted() {
+        let count = MAX_RECEIVED_MESSAGES as u64 + 100;
+        // responses whose request future was abandoned before registering
+        let inbound = futures::stream::iter(
+            (0..count).map(|id| Ok(messages::lookup_topic("test".to_string(), false, id))),
+        )
+        .chain(futures::stream::pending());
+
+        let (mut receiver, _outbound, _outbound_rx, _registrations_tx, _shutdown_tx) =
+            test_receiver(inbound, None);
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        for _ in 0..count {
+            assert!(Pin::new(&mut receiver).poll(&mut cx).is_pending());
+        }
+
+        assert_eq!(receiver.received_messages.len(), MAX_RECEIVED_MESSAGES);
+        assert!(!receiver
+            .received_messages
+            .contains_key(&RequestKey::RequestId(0)));
+        assert!(receiver
+            .received_messages
+            .contains_key(&RequestKey::RequestId(count - 1)));
+
+        // the others are evicted once they expire
+        receiver.evict_received_messages(Instant::now() + RECEIVED_MESSAGES_TTL);
+        assert!(receiver.received_messages.is_empty());
+        assert!(receiver.received_order.is_empty());
+    }
+
+    #[test]
+    fn broker_close_is_forwarded() {
+        // the broker closes a producer and a consumer on its own, the request
+        // ids do not match any pending request
+        let inbound = futures::stream::iter(vec![
+            Ok(messages::close_producer(3, 100)),
+            Ok(messages::close_consumer(4, 101)),
+        ])
+        .chain(futures::stream::pending());
+
+        let (mut receiver, _outbound, _outbound_rx, registrations_tx, _shutdown_tx) =
+            test_receiver(inbound, None);
+
+        let (producer_tx, mut producer_rx) = mpsc::unbounded();
+        registrations_tx
+            .unbounded_send(Register::Producer {
+                producer_id: 3,
+                resolver: producer_tx,
+            })
+            .unwrap();
+        let (consumer_tx, mut consumer_rx) = mpsc::unbounded();
+        registrations_tx
+            .unbounded_send(Register::Consumer {
+                consumer_id: 4,
+                resolver: consumer_tx,
+            })
+            .unwrap();
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut receiver).poll(&mut cx).is_pending());
+
+        let msg = producer_rx.next().now_or_never().unwrap().unwrap();
+        assert!(msg.command.close_producer.is_some());
+        let msg = consumer_rx.next().now_or_never().unwrap().unwrap();
+        assert!(msg.command.close_consumer.is_some());
+    }
+}
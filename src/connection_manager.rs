@@ -1,12 +1,12 @@
-use crate::connection::{Connection};
+use crate::connection::{Connection, ConnectionMetrics, ReceiverStats, Resolver, SerialId};
 use crate::error::ConnectionError;
 use crate::executor::Executor;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
 
-use futures::{channel::oneshot, lock::Mutex};
-use native_tls::Certificate;
+use futures::{channel::oneshot, future::join_all, lock::Mutex};
+use native_tls::{Certificate, Identity};
 use rand::Rng;
 use url::Url;
 
@@ -31,10 +31,14 @@ pub struct ConnectionRetryOptions {
     pub max_backoff: Duration,
     /// maximum number of connection retries
     pub max_retries: u32,
-    /// time limit to establish a connection
+    /// time limit to establish a connection, from the address lookup to the
+    /// broker accepting the CONNECT command (default: 10 seconds)
     pub connection_timeout: Duration,
     /// keep-alive interval for each broker connection
     pub keep_alive: Duration,
+    /// time limit to receive the answer to a keep-alive ping, after which the
+    /// connection is considered lost
+    pub keep_alive_timeout: Duration,
 }
 
 impl std::default::Default for ConnectionRetryOptions {
@@ -45,6 +49,7 @@ impl std::default::Default for ConnectionRetryOptions {
             max_retries: 12u32,
             connection_timeout: Duration::from_secs(10),
             keep_alive: Duration::from_secs(60),
+            keep_alive_timeout: Duration::from_secs(30),
         }
     }
 }
@@ -70,13 +75,79 @@ impl std::default::Default for OperationRetryOptions {
     }
 }
 
+/// configuration of the connections to the brokers
+#[derive(Clone)]
+pub struct ConnectionOptions {
+    /// protocol version announced to the brokers (default: 14). The version
+    /// used on a connection is the lowest of this one and the broker's, Key_Shared
+    /// subscriptions need at least version 14
+    pub protocol_version: i32,
+    /// client name and version reported to the brokers, visible in their stats
+    pub client_version: String,
+    /// maximum number of commands waiting to be written to the socket. When
+    /// set, sending on a full connection waits for room instead of buffering
+    /// without limit. None (the default) keeps the queue unbounded
+    pub outbound_channel_size: Option<usize>,
+    /// receives latency and traffic measurements from the connections
+    pub metrics: Option<Arc<dyn ConnectionMetrics>>,
+    /// resolves the brokers' host names instead of the system resolver
+    pub resolver: Option<Arc<dyn Resolver>>,
+    /// connections without producers or consumers are closed once they have
+    /// not sent a request for this long. None (the default) closes them at
+    /// the next periodic check
+    pub idle_timeout: Option<Duration>,
+    /// maximum size in bytes of the frames sent and received, it should be
+    /// above the brokers' `maxMessageSize`. None (the default) limits the
+    /// frames sent to the `maxMessageSize` announced by the broker plus
+    /// 10KB for the command and metadata, and does not limit the frames
+    /// received
+    pub max_frame_size: Option<usize>,
+}
+
+impl std::fmt::Debug for ConnectionOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionOptions")
+            .field("protocol_version", &self.protocol_version)
+            .field("client_version", &self.client_version)
+            .field("outbound_channel_size", &self.outbound_channel_size)
+            .field("metrics", &self.metrics.is_some())
+            .field("resolver", &self.resolver.is_some())
+            .field("idle_timeout", &self.idle_timeout)
+            .field("max_frame_size", &self.max_frame_size)
+            .finish()
+    }
+}
+
+impl std::default::Default for ConnectionOptions {
+    fn default() -> Self {
+        ConnectionOptions {
+            protocol_version: 14,
+            client_version: format!("Pulsar-rs-v{}", env!("CARGO_PKG_VERSION")),
+            outbound_channel_size: None,
+            metrics: None,
+            resolver: None,
+            idle_timeout: None,
+            max_frame_size: None,
+        }
+    }
+}
+
+impl ConnectionOptions {
+    /// codec for the frames exchanged with the brokers, received frames are
+    /// only limited when `max_frame_size` is set
+    pub(crate) fn codec(&self) -> crate::message::Codec {
+        crate::message::Codec::new(self.max_frame_size.unwrap_or(usize::MAX))
+    }
+}
+
 /// configuration for TLS connections
 #[derive(Debug, Clone)]
 pub struct TlsOptions {
     /// contains a list of PEM encoded certificates
     pub certificate_chain: Option<Vec<u8>>,
 
-    /// allow insecure TLS connection if set to true
+    /// allow insecure TLS connection if set to true: invalid and self-signed
+    /// certificates are accepted. This must only be used for testing
     ///
     /// defaults to *false*
     pub allow_insecure_connection: bool,
@@ -85,6 +156,20 @@ pub struct TlsOptions {
     ///
     /// defaults to *true*
     pub tls_hostname_verification_enabled: bool,
+
+    /// PEM encoded certificate chain presented to the broker for TLS
+    /// authentication, the leaf certificate first
+    pub client_certificate: Option<Vec<u8>>,
+
+    /// PEM encoded PKCS #8 private key of the client certificate
+    pub client_key: Option<Vec<u8>>,
+
+    /// DER encoded PKCS #12 archive holding the client certificate chain and
+    /// its private key, used instead of `client_certificate` and `client_key`
+    pub client_pkcs12: Option<Vec<u8>>,
+
+    /// password of the PKCS #12 archive
+    pub client_pkcs12_password: Option<String>,
 }
 
 impl Default for TlsOptions {
@@ -93,10 +178,25 @@ impl Default for TlsOptions {
             certificate_chain: None,
             allow_insecure_connection: false,
             tls_hostname_verification_enabled: true,
+            client_certificate: None,
+            client_key: None,
+            client_pkcs12: None,
+            client_pkcs12_password: None,
         }
     }
 }
 
+/// state of a broker connection, as seen by the [ConnectionManager]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// the connection is usable
+    Connected,
+    /// the connection is being established, retrying with exponential backoff
+    Reconnecting,
+    /// the connection was lost, it will be established again on the next use
+    Failed,
+}
+
 enum ConnectionStatus<Exe: Executor> {
     Connected(Arc<Connection<Exe>>),
     Connecting(Vec<oneshot::Sender<Result<Arc<Connection<Exe>>, ConnectionError>>>),
@@ -116,7 +216,14 @@ pub struct ConnectionManager<Exe: Executor> {
     connection_retry_options: ConnectionRetryOptions,
     pub(crate) operation_retry_options: OperationRetryOptions,
     tls_options: TlsOptions,
+    connection_options: ConnectionOptions,
     certificate_chain: Vec<native_tls::Certificate>,
+    /// client certificate and key used for TLS authentication
+    identity: Option<Identity>,
+    /// consumer ids, shared by all connections
+    consumer_ids: SerialId,
+    /// producer ids, shared by all connections
+    producer_ids: SerialId,
 }
 
 impl<Exe: Executor> ConnectionManager<Exe> {
@@ -126,10 +233,12 @@ impl<Exe: Executor> ConnectionManager<Exe> {
         connection_retry: Option<ConnectionRetryOptions>,
         operation_retry_options: OperationRetryOptions,
         tls: Option<TlsOptions>,
+        connection_options: Option<ConnectionOptions>,
         executor: Arc<Exe>,
     ) -> Result<Self, ConnectionError> {
         let connection_retry_options = connection_retry.unwrap_or_default();
         let tls_options = tls.unwrap_or_default();
+        let connection_options = connection_options.unwrap_or_default();
         let url = Url::parse(&url)
             .map_err(|e| {
                 error!("error parsing URL: {:?}", e);
@@ -151,16 +260,47 @@ impl<Exe: Executor> ConnectionManager<Exe> {
                     .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
                     .iter()
                     .rev()
+                    // bundles can contain other PEM blocks, like private keys
+                    .filter(|pem| pem.tag == "CERTIFICATE")
                 {
                     v.push(
                         Certificate::from_der(&cert.contents[..])
                             .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?,
                     );
                 }
+                if v.is_empty() {
+                    error!("the TLS certificate chain does not contain any certificate");
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "no PEM encoded certificate found in the certificate chain",
+                    )
+                    .into());
+                }
                 v
             }
         };
 
+        let identity = match (
+            tls_options.client_certificate.as_ref(),
+            tls_options.client_key.as_ref(),
+            tls_options.client_pkcs12.as_ref(),
+        ) {
+            (Some(certificate), Some(key), None) => Some(Identity::from_pkcs8(certificate, key)?),
+            (None, None, Some(archive)) => Some(Identity::from_pkcs12(
+                archive,
+                tls_options.client_pkcs12_password.as_deref().unwrap_or(""),
+            )?),
+            (None, None, None) => None,
+            _ => {
+                error!("conflicting or incomplete TLS client identity options");
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "TLS authentication needs either a client certificate and a key, or a PKCS #12 archive",
+                )
+                .into());
+            }
+        };
+
         if let Some(auth) = auth.clone() {
             auth.lock().await.initialize().await?;
         }
@@ -173,7 +313,11 @@ impl<Exe: Executor> ConnectionManager<Exe> {
             connection_retry_options,
             operation_retry_options,
             tls_options,
+            connection_options,
             certificate_chain,
+            identity,
+            consumer_ids: SerialId::new(),
+            producer_ids: SerialId::new(),
         };
         let broker_address = BrokerAddress {
             url: url.clone(),
@@ -184,6 +328,39 @@ impl<Exe: Executor> ConnectionManager<Exe> {
         Ok(manager)
     }
 
+    /// manager that does not connect to `url` until a connection is requested
+    #[cfg(all(test, feature = "tokio-runtime"))]
+    pub(crate) fn disconnected(url: Url, executor: Arc<Exe>) -> Self {
+        ConnectionManager {
+            url,
+            auth: None,
+            executor,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            connection_retry_options: Default::default(),
+            operation_retry_options: Default::default(),
+            tls_options: Default::default(),
+            connection_options: Default::default(),
+            certificate_chain: vec![],
+            identity: None,
+            consumer_ids: SerialId::new(),
+            producer_ids: SerialId::new(),
+        }
+    }
+
+    /// manager whose connection to the service URL is `connection`
+    #[cfg(all(test, feature = "tokio-runtime"))]
+    pub(crate) fn with_connection(executor: Arc<Exe>, connection: Arc<Connection<Exe>>) -> Self {
+        let manager = Self::disconnected(connection.url().clone(), executor);
+        let connections = HashMap::from([(
+            manager.get_base_address(),
+            ConnectionStatus::Connected(connection),
+        )]);
+        ConnectionManager {
+            connections: Arc::new(Mutex::new(connections)),
+            ..manager
+        }
+    }
+
     pub fn get_base_address(&self) -> BrokerAddress {
         BrokerAddress {
             url: self.url.clone(),
@@ -297,24 +474,20 @@ impl<Exe: Executor> ConnectionManager<Exe> {
                 self.auth.clone(),
                 proxy_url.clone(),
                 &self.certificate_chain,
+                self.identity.clone(),
                 self.tls_options.allow_insecure_connection,
                 self.tls_options.tls_hostname_verification_enabled,
                 self.connection_retry_options.connection_timeout,
                 self.operation_retry_options.operation_timeout,
+                self.connection_options.clone(),
                 self.executor.clone(),
             )
             .await
             {
                 Ok(c) => break c,
-                Err(ConnectionError::Io(e)) => {
-                    if e.kind() != std::io::ErrorKind::ConnectionRefused
-                        && e.kind() != std::io::ErrorKind::TimedOut
-                    {
-                        return Err(ConnectionError::Io(e));
-                    }
-
+                Err(e) if e.is_retriable() => {
                     if current_retries == self.connection_retry_options.max_retries {
-                        return Err(ConnectionError::Io(e));
+                        return Err(e);
                     }
 
                     let jitter = rand::thread_rng().gen_range(0..10);
@@ -332,7 +505,8 @@ impl<Exe: Executor> ConnectionManager<Exe> {
                         current_backoff.as_millis()
                     );
                     error!(
-                        "connection error, retrying connection to {} after {}ms",
+                        "connection error ({}), retrying connection to {} after {}ms",
+                        e,
                         broker.url,
                         current_backoff.as_millis()
                     );
@@ -344,21 +518,31 @@ impl<Exe: Executor> ConnectionManager<Exe> {
         let connection_id = conn.id();
         if let Some(url) = proxy_url.as_ref() {
             info!(
-                "Connected n°{} to {} via proxy {} in {}ms",
+                "Connected n°{} to {} via proxy {} in {}ms (protocol version {})",
                 connection_id,
                 url,
                 broker.url,
-                (std::time::Instant::now() - start).as_millis()
+                (std::time::Instant::now() - start).as_millis(),
+                conn.negotiated_protocol_version()
             );
         } else {
             info!(
-                "Connected n°{} to {} in {}ms",
+                "Connected n°{} to {} ({}) in {}ms (protocol version {})",
                 connection_id,
                 broker.url,
-                (std::time::Instant::now() - start).as_millis()
+                conn.address(),
+                (std::time::Instant::now() - start).as_millis(),
+                conn.negotiated_protocol_version()
             );
         }
-        let c = Arc::new(conn);
+        debug!(
+            "broker {} accepts messages of up to {} bytes, features: {:?}",
+            broker.url,
+            conn.max_message_size(),
+            conn.feature_flags()
+        );
+        let c =
+            Arc::new(conn.with_id_allocators(self.consumer_ids.clone(), self.producer_ids.clone()));
 
         Ok(c)
     }
@@ -401,11 +585,28 @@ impl<Exe: Executor> ConnectionManager<Exe> {
         let mut interval = self
             .executor
             .interval(self.connection_retry_options.keep_alive);
+        let keep_alive_timeout = self.connection_retry_options.keep_alive_timeout;
         let broker_url = broker.url.clone();
         let proxy_to_broker_url = proxy_url.clone();
+        let mut closed = Box::pin(c.closed());
         let res = self.executor.spawn(Box::pin(async move {
             use crate::futures::StreamExt;
-            while let Some(()) = interval.next().await {
+            use futures::future::{select, Either};
+            loop {
+                // the heartbeat stops as soon as the connection fails
+                match select(interval.next(), closed.as_mut()).await {
+                    Either::Left((Some(()), _)) => {}
+                    Either::Left((None, _)) => break,
+                    Either::Right((e, _)) => {
+                        trace!(
+                            "connection {} failed, stopping keepalive task: {}",
+                            connection_id,
+                            e
+                        );
+                        break;
+                    }
+                }
+
                 if let Some(url) = proxy_to_broker_url.as_ref() {
                     trace!(
                         "will ping connection {} to {} via proxy {}",
@@ -422,7 +623,11 @@ impl<Exe: Executor> ConnectionManager<Exe> {
                              connection_id);
                         break;
                     }
-                    if let Err(e) = strong_conn.sender().send_ping().await {
+                    if let Err(e) = strong_conn
+                        .sender()
+                        .send_ping_with_timeout(keep_alive_timeout)
+                        .await
+                    {
                         error!(
                             "could not ping connection {} to the server at {}: {}",
                             connection_id, broker_url, e
@@ -465,9 +670,70 @@ impl<Exe: Executor> ConnectionManager<Exe> {
         Ok(c)
     }
 
+    /// collects the connections that are established, skipping the ones
+    /// still connecting
+    async fn established_connections(&self) -> Vec<(BrokerAddress, Arc<Connection<Exe>>)> {
+        self.connections
+            .lock()
+            .await
+            .iter()
+            .filter_map(|(broker, status)| match status {
+                ConnectionStatus::Connected(conn) => Some((broker.clone(), conn.clone())),
+                ConnectionStatus::Connecting(_) => None,
+            })
+            .collect()
+    }
+
+    /// gets the sizes of the receiver's internal maps for every active connection
+    pub async fn receiver_stats(&self) -> Vec<(BrokerAddress, ReceiverStats)> {
+        let connections = self.established_connections().await;
+
+        let mut res = Vec::new();
+        for (broker, conn) in connections {
+            match conn.sender().receiver_stats().await {
+                Ok(stats) => res.push((broker, stats)),
+                Err(e) => debug!("could not get stats for connection to {:?}: {}", broker, e),
+            }
+        }
+        res
+    }
+
+    /// pings every connection, the ones that do not answer within `timeout`
+    /// are marked as failed and established again the next time they are used
+    pub async fn healthcheck(&self, timeout: Duration) -> Vec<(BrokerAddress, bool)> {
+        let connections = self.established_connections().await;
+
+        join_all(connections.into_iter().map(|(broker, conn)| async move {
+            let healthy = conn.ping_healthcheck(timeout).await;
+            (broker, healthy)
+        }))
+        .await
+    }
+
+    /// gets the state of the connection to each known broker
+    pub async fn connection_states(&self) -> Vec<(BrokerAddress, ConnectionState)> {
+        self.connections
+            .lock()
+            .await
+            .iter()
+            .map(|(broker, status)| {
+                let state = match status {
+                    ConnectionStatus::Connected(conn) if conn.is_valid() => {
+                        ConnectionState::Connected
+                    }
+                    ConnectionStatus::Connected(_) => ConnectionState::Failed,
+                    ConnectionStatus::Connecting(_) => ConnectionState::Reconnecting,
+                };
+                (broker.clone(), state)
+            })
+            .collect()
+    }
+
     /// tests that all connections are valid and still used
     pub(crate) async fn check_connections(&self) {
         trace!("cleaning invalid or unused connections");
+        let idle_timeout = self.connection_options.idle_timeout.unwrap_or_default();
+        let mut unused = Vec::new();
         self.connections
             .lock()
             .await
@@ -480,8 +746,19 @@ impl<Exe: Executor> ConnectionManager<Exe> {
                     // in a mutex, and a case appears where the Arc is cloned
                     // somewhere at the same time, that just means the manager
                     // will create a new connection the next time it is asked
-                    conn.is_valid() && Arc::strong_count(conn) > 1
+                    if !conn.is_valid() {
+                        false
+                    } else if Arc::strong_count(conn) > 1 || conn.idle_time() < idle_timeout {
+                        true
+                    } else {
+                        unused.push(conn.clone());
+                        false
+                    }
                 }
             });
+
+        // commands like acks may still be queued on unused connections, a slow
+        // connection must not delay closing the others
+        join_all(unused.iter().map(|conn| conn.close())).await;
     }
 }
@@ -3,10 +3,11 @@ use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 use futures::channel::mpsc::unbounded;
 use futures::task::{Context, Poll};
 use futures::{
@@ -45,6 +46,11 @@ pub struct ConsumerOptions {
     /// marked-delete position on the particular message id and
     /// will send messages from that point
     pub start_message_id: Option<MessageIdData>,
+    /// if true, the message at `start_message_id` is delivered, if false
+    /// delivery starts at the following message. If not set, messages are
+    /// delivered from the start of the entry containing `start_message_id`, as
+    /// sent by the broker
+    pub start_message_id_inclusive: Option<bool>,
     /// Add optional metadata key=value to this consumer
     pub metadata: BTreeMap<String, String>,
     pub read_compacted: Option<bool>,
@@ -60,9 +66,109 @@ pub struct ConsumerOptions {
     /// }
     /// ```
     pub initial_position: InitialPosition,
+    /// maximum number of messages split in chunks by the producer that can be
+    /// reassembled at the same time (default: 10). Once the limit is reached,
+    /// the oldest incomplete message is evicted
+    pub max_pending_chunked_messages: Option<usize>,
+    /// if true, the chunks of an incomplete message evicted because of
+    /// `max_pending_chunked_messages` are acked, so that the message is lost.
+    /// Otherwise, they are discarded and the broker is asked to redeliver them
+    pub auto_ack_oldest_on_queue_full: bool,
+    /// maximum number of message ids sent in a single redelivery command
+    /// (default: 2000). Larger redelivery requests are split in multiple
+    /// commands to stay under the frame size limit
+    pub max_redeliver_per_command: Option<usize>,
+    /// if true, messages carrying a broker entry index lower than or equal to
+    /// the highest index seen so far are acked and dropped, which filters
+    /// redelivered duplicates. Requires the broker to expose the broker entry
+    /// metadata to clients. The tracked index is reset when seeking, so that
+    /// messages sent again after a seek are still delivered.
+    ///
+    /// Messages redelivered because of a nack, the ack timeout or a
+    /// reconnection are still delivered. Shared and Key_Shared subscriptions
+    /// can deliver messages out of order, which would drop them: this option
+    /// is meant for Exclusive and Failover subscriptions
+    pub dedup_by_broker_index: bool,
+    /// time to wait before asking for the redelivery of a negatively
+    /// acknowledged message. If not set, the redelivery is requested
+    /// immediately
+    pub negative_ack_redelivery_delay: Option<Duration>,
+    /// time after which a received message that was not acked is redelivered.
+    /// Used if the consumer builder does not set an unacked message resend delay
+    pub ack_timeout: Option<Duration>,
+    /// Key_Shared subscription settings: the hash ranges of keys this consumer
+    /// gets with the sticky mode, or the broker's automatic split.
+    /// Hash ranges must be within 0..=65535 and must not overlap
+    pub key_shared_meta: Option<proto::KeySharedMeta>,
+    /// number of messages the consumer asks the broker for, used if the
+    /// consumer builder does not set a batch size (default: 1000)
+    pub receiver_queue_size: Option<u32>,
+    /// once fewer flow permits than this remain, the consumer asks the broker
+    /// for enough messages to get back to the receiver queue size (default:
+    /// half the receiver queue size)
+    pub flow_refill_threshold: Option<u32>,
+    /// if set, individual acks are grouped and sent together in a single
+    /// command. Cumulative acks are still sent immediately
+    pub ack_grouping: Option<AckConfig>,
+}
+
+/// how individual acknowledgements are grouped before being sent
+#[derive(Debug, Clone)]
+pub struct AckConfig {
+    /// maximum time an acknowledgement waits before being sent (default: 100ms)
+    pub ack_group_time: Duration,
+    /// number of acknowledgements that triggers sending the group before the
+    /// timer fires (default: 1000)
+    pub max_group_size: usize,
+}
+
+impl Default for AckConfig {
+    fn default() -> Self {
+        AckConfig {
+            ack_group_time: Duration::from_millis(100),
+            max_group_size: 1000,
+        }
+    }
 }
 
 impl ConsumerOptions {
+    /// checks the combinations of options that the broker would reject for a
+    /// subscription of type `sub_type`
+    ///
+    /// consumers and readers check their options before connecting. As for
+    /// the broker, a subscription is durable if `durable` is not set, and the
+    /// start message id of a durable subscription is ignored. It is only
+    /// rejected if `durable` is explicitly true, see [ConsumerOptionsBuilder]
+    ///
+    /// ```rust
+    /// use pulsar::{ConsumerOptions, SubType};
+    ///
+    /// let options = ConsumerOptions::default().read_compacted(true);
+    /// assert!(options.check(SubType::Failover).is_ok());
+    /// assert!(options.check(SubType::Shared).is_err());
+    /// ```
+    pub fn check(&self, sub_type: SubType) -> Result<(), ConsumerError> {
+        if self.read_compacted == Some(true)
+            && sub_type != SubType::Exclusive
+            && sub_type != SubType::Failover
+        {
+            return Err(ConsumerError::InvalidOptions(format!(
+                "compacted topics can only be read by Exclusive or Failover subscriptions, not {:?}",
+                sub_type
+            )));
+        }
+        if self.start_message_id.is_some() && self.durable == Some(true) {
+            return Err(ConsumerError::InvalidOptions(
+                "a start message id can only be set on non durable subscriptions".to_string(),
+            ));
+        }
+        if let Some(key_shared_meta) = self.key_shared_meta.as_ref() {
+            check_hash_ranges(&key_shared_meta.hash_ranges)
+                .map_err(ConsumerError::InvalidOptions)?;
+        }
+        Ok(())
+    }
+
     /// within options, sets the priority level
     pub fn with_priority_level(mut self, priority_level: i32) -> Self {
         self.priority_level = Some(priority_level);
@@ -79,6 +185,12 @@ impl ConsumerOptions {
         self
     }
 
+    /// within options, sets whether the message at `start_message_id` is delivered
+    pub fn with_start_message_id_inclusive(mut self, inclusive: bool) -> Self {
+        self.start_message_id_inclusive = Some(inclusive);
+        self
+    }
+
     pub fn with_metadata(mut self, metadata: BTreeMap<String, String>) -> Self {
         self.metadata = metadata;
         self
@@ -98,6 +210,158 @@ impl ConsumerOptions {
         self.initial_position = initial_position;
         self
     }
+
+    /// within options, sets the maximum number of chunked messages being reassembled
+    pub fn with_max_pending_chunked_messages(
+        mut self,
+        max_pending_chunked_messages: usize,
+    ) -> Self {
+        self.max_pending_chunked_messages = Some(max_pending_chunked_messages);
+        self
+    }
+
+    /// within options, acks the chunks of the oldest incomplete message when
+    /// `max_pending_chunked_messages` is reached, instead of asking the broker
+    /// to redeliver them. The evicted message is then lost
+    pub fn auto_ack_oldest_on_queue_full(mut self, auto_ack: bool) -> Self {
+        self.auto_ack_oldest_on_queue_full = auto_ack;
+        self
+    }
+
+    /// within options, sets the maximum number of message ids per redelivery command
+    pub fn with_max_redeliver_per_command(mut self, max_redeliver_per_command: usize) -> Self {
+        self.max_redeliver_per_command = Some(max_redeliver_per_command);
+        self
+    }
+
+    /// within options, acks and drops the messages whose broker entry index was
+    /// already seen. The tracked index is reset when seeking
+    pub fn dedup_by_broker_index(mut self, dedup: bool) -> Self {
+        self.dedup_by_broker_index = dedup;
+        self
+    }
+
+    /// within options, sets the delay before negatively acknowledged messages are redelivered
+    pub fn with_negative_ack_redelivery_delay(mut self, delay: Duration) -> Self {
+        self.negative_ack_redelivery_delay = Some(delay);
+        self
+    }
+
+    /// within options, sets the time after which unacked messages are redelivered
+    pub fn with_ack_timeout(mut self, ack_timeout: Duration) -> Self {
+        self.ack_timeout = Some(ack_timeout);
+        self
+    }
+
+    /// within options, sets the Key_Shared subscription settings
+    pub fn with_key_shared_meta(mut self, key_shared_meta: proto::KeySharedMeta) -> Self {
+        self.key_shared_meta = Some(key_shared_meta);
+        self
+    }
+
+    /// within options, groups individual acks
+    pub fn with_ack_grouping(mut self, ack_grouping: AckConfig) -> Self {
+        self.ack_grouping = Some(ack_grouping);
+        self
+    }
+
+    /// within options, sets the number of messages the consumer asks the broker for
+    pub fn with_receiver_queue_size(mut self, receiver_queue_size: u32) -> Self {
+        self.receiver_queue_size = Some(receiver_queue_size);
+        self
+    }
+
+    /// within options, sets the number of remaining flow permits below which
+    /// more messages are requested
+    pub fn with_flow_refill_threshold(mut self, threshold: u32) -> Self {
+        self.flow_refill_threshold = Some(threshold);
+        self
+    }
+}
+
+/// builds [ConsumerOptions] for a subscription type, rejecting the combinations
+/// of options that the broker would reject or ignore before any network call
+///
+/// the subscription is durable and starts at the latest message unless set
+/// otherwise. The options that are not checked can be set on the built
+/// [ConsumerOptions]
+///
+/// ```rust
+/// use pulsar::{ConsumerOptionsBuilder, SubType};
+///
+/// let options = ConsumerOptionsBuilder::new(SubType::Failover)
+///     .read_compacted(true)
+///     .build()
+///     .unwrap()
+///     .with_receiver_queue_size(100);
+/// assert_eq!(options.durable, Some(true));
+///
+/// // a start message id needs a non durable subscription
+/// let start = ConsumerOptionsBuilder::new(SubType::Exclusive)
+///     .starting_on_message(Default::default());
+/// assert!(start.clone().build().is_err());
+/// assert!(start.durable(false).build().is_ok());
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConsumerOptionsBuilder {
+    sub_type: SubType,
+    options: ConsumerOptions,
+}
+
+impl ConsumerOptionsBuilder {
+    /// creates a builder for a subscription of type `sub_type`
+    pub fn new(sub_type: SubType) -> Self {
+        ConsumerOptionsBuilder {
+            sub_type,
+            options: ConsumerOptions {
+                durable: Some(true),
+                initial_position: InitialPosition::Latest,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// sets whether the subscription is backed by a durable cursor
+    pub fn durable(mut self, durable: bool) -> Self {
+        self.options.durable = Some(durable);
+        self
+    }
+
+    /// starts a non durable subscription at `message_id`
+    pub fn starting_on_message(mut self, message_id: MessageIdData) -> Self {
+        self.options.start_message_id = Some(message_id);
+        self
+    }
+
+    /// sets whether the message at the start message id is delivered
+    pub fn with_start_message_id_inclusive(mut self, inclusive: bool) -> Self {
+        self.options.start_message_id_inclusive = Some(inclusive);
+        self
+    }
+
+    /// reads the compacted topic, only for Exclusive and Failover subscriptions
+    pub fn read_compacted(mut self, read_compacted: bool) -> Self {
+        self.options.read_compacted = Some(read_compacted);
+        self
+    }
+
+    /// sets the position a new subscription starts at
+    pub fn with_initial_position(mut self, initial_position: InitialPosition) -> Self {
+        self.options.initial_position = initial_position;
+        self
+    }
+
+    /// sets the Key_Shared subscription settings
+    pub fn with_key_shared_meta(mut self, key_shared_meta: proto::KeySharedMeta) -> Self {
+        self.options.key_shared_meta = Some(key_shared_meta);
+        self
+    }
+
+    /// checks the options, see [ConsumerOptions::check]
+    pub fn build(self) -> Result<ConsumerOptions, ConsumerError> {
+        self.options.check(self.sub_type)?;
+        Ok(self.options)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -108,6 +372,39 @@ pub struct DeadLetterPolicy {
     pub dead_letter_topic: String,
 }
 
+impl DeadLetterPolicy {
+    /// creates a policy sending the failing messages to the default dead letter
+    /// topic, named `<topic>-<subscription>-DLQ`
+    pub fn new(topic: &str, subscription: &str, max_redeliver_count: usize) -> Self {
+        DeadLetterPolicy {
+            max_redeliver_count,
+            dead_letter_topic: format!("{}-{}-DLQ", topic, subscription),
+        }
+    }
+}
+
+/// callback called when a consumer has received all the messages allowed by
+/// its flow permits
+///
+/// it receives the consumer id and the suggested number of permits (the batch
+/// size), and returns the number of permits to grant to the broker. If it
+/// returns 0, no more messages will be received until permits are granted
+/// with [Consumer::grant_permits]
+#[derive(Clone)]
+pub struct FlowPermitsCallback(Arc<dyn Fn(u64, u32) -> u32 + Send + Sync>);
+
+impl FlowPermitsCallback {
+    pub fn new<F: Fn(u64, u32) -> u32 + Send + Sync + 'static>(f: F) -> Self {
+        FlowPermitsCallback(Arc::new(f))
+    }
+}
+
+impl Debug for FlowPermitsCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FlowPermitsCallback")
+    }
+}
+
 /// position of the first message that will be consumed
 #[derive(Clone, Debug)]
 pub enum InitialPosition {
@@ -165,6 +462,10 @@ impl From<InitialPosition> for i32 {
 /// # Ok(())
 /// # }
 /// ```
+///
+/// dropping a consumer closes it on the broker in the background, without
+/// waiting for the broker's answer, and the acks waiting to be grouped
+/// (see [AckConfig]) may be lost. Use [Consumer::close] to send them first
 pub struct Consumer<T: DeserializeMessage, Exe: Executor> {
     inner: InnerConsumer<T, Exe>,
 }
@@ -198,6 +499,21 @@ impl<T: DeserializeMessage, Exe: Executor> Consumer<T, Exe> {
         }
     }
 
+    /// acknowledges a single entry of the batch `msg` belongs to, the other
+    /// entries of the batch stay unacknowledged on the broker
+    ///
+    /// this requires batch index acknowledgement to be enabled on the broker
+    pub async fn ack_batch_entry(
+        &mut self,
+        msg: &Message<T>,
+        batch_index: i32,
+    ) -> Result<(), ConsumerError> {
+        match &mut self.inner {
+            InnerConsumer::Single(c) => c.ack_batch_entry(msg, batch_index).await,
+            InnerConsumer::Multi(c) => c.ack_batch_entry(msg, batch_index).await,
+        }
+    }
+
     /// acknowledges a message and all the preceding messages
     pub async fn cumulative_ack(&mut self, msg: &Message<T>) -> Result<(), ConsumerError> {
         match &mut self.inner {
@@ -206,9 +522,21 @@ impl<T: DeserializeMessage, Exe: Executor> Consumer<T, Exe> {
         }
     }
 
+    /// acknowledges all the messages received so far, with one cumulative ack
+    /// per topic for the highest message id
+    ///
+    /// this is only allowed on Exclusive and Failover subscriptions
+    pub async fn ack_cumulative(&mut self) -> Result<(), ConsumerError> {
+        match &mut self.inner {
+            InnerConsumer::Single(c) => c.ack_cumulative().await,
+            InnerConsumer::Multi(c) => c.ack_cumulative().await,
+        }
+    }
+
     /// negative acknowledgement
     ///
-    /// the message will be sent again on the subscription
+    /// the message will be sent again on the subscription, after
+    /// [ConsumerOptions::negative_ack_redelivery_delay] if it is set
     pub async fn nack(&mut self, msg: &Message<T>) -> Result<(), ConsumerError> {
         match &mut self.inner {
             InnerConsumer::Single(c) => c.nack(msg).await,
@@ -216,6 +544,37 @@ impl<T: DeserializeMessage, Exe: Executor> Consumer<T, Exe> {
         }
     }
 
+    /// negative acknowledgement, the message will be sent again on the
+    /// subscription once `delay` has elapsed
+    ///
+    /// messages whose delays expire together are redelivered with a single command
+    pub async fn nack_with_delay(
+        &mut self,
+        msg: &Message<T>,
+        delay: Duration,
+    ) -> Result<(), ConsumerError> {
+        match &mut self.inner {
+            InnerConsumer::Single(c) => c.nack_with_delay(msg, delay).await,
+            InnerConsumer::Multi(c) => c.nack_with_delay(msg, delay).await,
+        }
+    }
+
+    /// allows the broker to send `permits` more messages to the consumer `consumer_id`
+    ///
+    /// this is only needed when flow control is managed with a [FlowPermitsCallback],
+    /// otherwise permits are granted automatically. It fails if none of the
+    /// topic consumers has this id
+    pub async fn grant_permits(
+        &mut self,
+        consumer_id: u64,
+        permits: u32,
+    ) -> Result<(), ConsumerError> {
+        match &mut self.inner {
+            InnerConsumer::Single(c) => c.grant_permits(consumer_id, permits).await,
+            InnerConsumer::Multi(c) => c.grant_permits(consumer_id, permits).await,
+        }
+    }
+
     /// seek currently destroys the existing consumer and creates a new one
     /// this is how java and cpp pulsar client implement this feature mainly because
     /// there are many minor problems with flushing existing messages and receiving new ones
@@ -228,15 +587,17 @@ impl<T: DeserializeMessage, Exe: Executor> Consumer<T, Exe> {
     ) -> Result<(), Error> {
         let inner_consumer: InnerConsumer<T, Exe> = match &mut self.inner {
             InnerConsumer::Single(c) => {
-                c.seek(message_id, timestamp).await?;
+                c.seek(message_id.clone(), timestamp).await?;
                 let topic = c.topic().to_string();
                 let addr = client.lookup_topic(&topic).await?;
-                let config = c.config().clone();
+                let config = seek_config(c.config(), message_id.as_ref(), c.is_paused());
                 InnerConsumer::Single(TopicConsumer::new(client, topic, addr, config).await?)
             }
             InnerConsumer::Multi(c) => {
-                c.seek(consumer_ids, message_id, timestamp).await?;
+                c.seek(consumer_ids, message_id.clone(), timestamp).await?;
                 let topics = c.topics();
+                let config = seek_config(c.config(), message_id.as_ref(), false);
+                let paused = c.paused;
 
                 //currently, pulsar only supports seek for non partitioned topics
                 let addrs =
@@ -246,7 +607,11 @@ impl<T: DeserializeMessage, Exe: Executor> Consumer<T, Exe> {
                 let topic_addr_pair = c.topics.iter().cloned().zip(addrs.iter().cloned());
 
                 let consumers = try_join_all(topic_addr_pair.map(|(topic, addr)| {
-                    TopicConsumer::new(client.clone(), topic, addr, c.config().clone())
+                    let config = ConsumerConfig {
+                        paused,
+                        ..config.clone()
+                    };
+                    TopicConsumer::new(client.clone(), topic, addr, config)
                 }))
                 .await?;
 
@@ -258,19 +623,22 @@ impl<T: DeserializeMessage, Exe: Executor> Consumer<T, Exe> {
                 let topic_refresh = Duration::from_secs(30);
                 let refresh = Box::pin(client.executor.interval(topic_refresh).map(drop));
                 let namespace = c.namespace.clone();
-                let config = c.config().clone();
                 let topic_regex = c.topic_regex.clone();
+                let partitioned_topics = c.partitioned_topics.clone();
                 InnerConsumer::Multi(MultiTopicConsumer {
                     namespace,
                     topic_regex,
+                    partitioned_topics,
                     pulsar: client,
                     consumers,
                     topics,
                     new_consumers: None,
+                    topics_hash: None,
                     refresh,
                     config,
                     disc_last_message_received: None,
                     disc_messages_received: 0,
+                    paused,
                 })
             }
         };
@@ -293,6 +661,38 @@ impl<T: DeserializeMessage, Exe: Executor> Consumer<T, Exe> {
         }
     }
 
+    /// estimates the number of messages remaining to be consumed on each topic
+    ///
+    /// see [Backlog] for the limitations of this estimation
+    pub async fn get_backlog(&mut self) -> Result<Vec<Backlog>, Error> {
+        match &mut self.inner {
+            InnerConsumer::Single(c) => Ok(vec![c.get_backlog().await?]),
+            InnerConsumer::Multi(c) => c.get_backlog().await,
+        }
+    }
+
+    /// gets the schema the message was produced with, which can differ from
+    /// the consumer's schema after the topic's schema evolved. The schemas
+    /// are fetched once per schema version
+    ///
+    /// returns `None` if the topic has no schema
+    pub async fn writer_schema(
+        &mut self,
+        message: &Message<T>,
+    ) -> Result<Option<proto::Schema>, Error> {
+        let version = message.metadata().schema_version.clone();
+        match &mut self.inner {
+            InnerConsumer::Single(c) => c.writer_schema(version).await,
+            InnerConsumer::Multi(c) => match c.consumers.get_mut(&message.topic) {
+                Some(c) => c.writer_schema(version).await,
+                None => Err(Error::Custom(format!(
+                    "the consumer is not subscribed to {}",
+                    message.topic
+                ))),
+            },
+        }
+    }
+
     /// returns the list of topics this consumer is subscribed on
     pub fn topics(&self) -> Vec<String> {
         match &self.inner {
@@ -407,6 +807,128 @@ impl<T: DeserializeMessage, Exe: Executor> Consumer<T, Exe> {
             InnerConsumer::Multi(c) => c.messages_received(),
         }
     }
+
+    /// returns false while another consumer of a failover subscription
+    /// receives the messages. Consumers of the other subscription types are
+    /// always active
+    ///
+    /// a multi topic consumer is active if it is active on at least one topic
+    pub fn is_active(&self) -> bool {
+        match &self.inner {
+            InnerConsumer::Single(c) => c.is_active(),
+            InnerConsumer::Multi(c) => c.consumers.values().any(|c| c.is_active()),
+        }
+    }
+
+    /// returns true once the broker reported that the topic was terminated
+    /// and all of its messages were delivered. The message stream then ends
+    ///
+    /// a multi topic consumer reaches the end when all of its topics do
+    pub fn reached_end_of_topic(&self) -> bool {
+        match &self.inner {
+            InnerConsumer::Single(c) => c.reached_end_of_topic(),
+            InnerConsumer::Multi(c) => {
+                !c.consumers.is_empty() && c.consumers.values().all(|c| c.reached_end_of_topic())
+            }
+        }
+    }
+
+    /// sends the acks waiting to be grouped, then closes the consumer on the
+    /// broker and waits for its answer, at most for the operation timeout
+    pub async fn close(mut self) -> Result<(), Error> {
+        match &mut self.inner {
+            InnerConsumer::Single(c) => c.close().await,
+            InnerConsumer::Multi(c) => {
+                for consumer in c.consumers.values_mut() {
+                    consumer.close().await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// stops asking the broker for more messages
+    ///
+    /// the connection stays open and the messages already sent by the broker
+    /// can still be received
+    pub fn pause(&mut self) {
+        match &mut self.inner {
+            InnerConsumer::Single(c) => c.pause(),
+            InnerConsumer::Multi(c) => {
+                c.paused = true;
+                c.consumers.values().for_each(|c| c.pause());
+            }
+        }
+    }
+
+    /// asks the broker for messages again after [Consumer::pause], up to the
+    /// batch size
+    pub async fn resume(&mut self) -> Result<(), ConsumerError> {
+        match &mut self.inner {
+            InnerConsumer::Single(c) => c.resume().await,
+            InnerConsumer::Multi(c) => {
+                c.paused = false;
+                for consumer in c.consumers.values_mut() {
+                    consumer.resume().await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// returns true if the consumer was paused with [Consumer::pause]
+    pub fn is_paused(&self) -> bool {
+        match &self.inner {
+            InnerConsumer::Single(c) => c.is_paused(),
+            InnerConsumer::Multi(c) => c.paused,
+        }
+    }
+
+    /// processes each message with `f`, and acknowledges it once `f` returns `Ok`
+    ///
+    /// this stops at the first error, which is returned. The message that
+    /// failed is not acknowledged, so it will be redelivered
+    pub async fn try_for_each_ack<F, Fut, E>(&mut self, mut f: F) -> Result<(), E>
+    where
+        T: 'static,
+        F: FnMut(Message<T>) -> Fut,
+        Fut: Future<Output = Result<(), E>>,
+        E: From<Error>,
+    {
+        while let Some(res) = self.next().await {
+            let msg = res?;
+            let topic = msg.topic.clone();
+            let message_id = msg.message_id.clone();
+            f(msg).await?;
+            ack_with(self.acker(&topic), &topic, message_id).map_err(Error::Consumer)?;
+        }
+        Ok(())
+    }
+
+    /// transforms the consumer in a stream that acknowledges a message once
+    /// the next one is requested
+    ///
+    /// if the processing of a message fails, stop polling the stream: the
+    /// message will not be acknowledged and will be redelivered
+    pub fn auto_ack(self) -> AutoAck<T, Exe> {
+        AutoAck {
+            consumer: self,
+            pending: None,
+        }
+    }
+
+    /// transforms the consumer in a stream of messages that are acknowledged
+    /// when dropped, whether their processing succeeded or not
+    pub fn ack_on_drop(self) -> AckOnDropConsumer<T, Exe> {
+        AckOnDropConsumer { consumer: self }
+    }
+
+    fn acker(&self, topic: &str) -> Option<mpsc::UnboundedSender<EngineMessage<Exe>>> {
+        match &self.inner {
+            InnerConsumer::Single(c) => Some(c.acker()),
+            InnerConsumer::Multi(c) => c.consumers.get(topic).map(|c| c.acker()),
+        }
+    }
 }
 
 //TODO: why does T need to be 'static?
@@ -421,12 +943,117 @@ impl<T: DeserializeMessage + 'static, Exe: Executor> Stream for Consumer<T, Exe>
     }
 }
 
+fn ack_with<Exe: Executor>(
+    acker: Option<mpsc::UnboundedSender<EngineMessage<Exe>>>,
+    topic: &str,
+    message_id: MessageData,
+) -> Result<(), ConsumerError> {
+    match acker {
+        Some(acker) => acker
+            .unbounded_send(EngineMessage::Ack(message_id, false))
+            .map_err(|e| e.into_send_error().into()),
+        None => Err(ConnectionError::Unexpected(format!("no consumer for topic {}", topic)).into()),
+    }
+}
+
+/// a stream of messages that are acknowledged once the next message is requested
+///
+/// created by [Consumer::auto_ack]
+pub struct AutoAck<T: DeserializeMessage, Exe: Executor> {
+    consumer: Consumer<T, Exe>,
+    pending: Option<(String, MessageData)>,
+}
+
+impl<T: DeserializeMessage, Exe: Executor> AutoAck<T, Exe> {
+    /// returns the underlying consumer. The last message returned by the
+    /// stream is not acknowledged
+    pub fn into_inner(self) -> Consumer<T, Exe> {
+        self.consumer
+    }
+}
+
+impl<T: DeserializeMessage + 'static, Exe: Executor> Stream for AutoAck<T, Exe> {
+    type Item = Result<Message<T>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some((topic, message_id)) = self.pending.take() {
+            if let Err(e) = ack_with(self.consumer.acker(&topic), &topic, message_id) {
+                return Poll::Ready(Some(Err(Error::Consumer(e))));
+            }
+        }
+
+        match Pin::new(&mut self.consumer).poll_next(cx) {
+            Poll::Ready(Some(Ok(msg))) => {
+                self.pending = Some((msg.topic.clone(), msg.message_id.clone()));
+                Poll::Ready(Some(Ok(msg)))
+            }
+            res => res,
+        }
+    }
+}
+
+/// a stream of messages that are acknowledged when dropped
+///
+/// created by [Consumer::ack_on_drop]
+pub struct AckOnDropConsumer<T: DeserializeMessage, Exe: Executor> {
+    consumer: Consumer<T, Exe>,
+}
+
+impl<T: DeserializeMessage + 'static, Exe: Executor> Stream for AckOnDropConsumer<T, Exe> {
+    type Item = Result<AckOnDrop<T, Exe>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match Pin::new(&mut self.consumer).poll_next(cx) {
+            Poll::Ready(Some(Ok(msg))) => {
+                let acker = self.consumer.acker(&msg.topic);
+                Poll::Ready(Some(Ok(AckOnDrop {
+                    message: msg,
+                    acker,
+                })))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// a message that is acknowledged when dropped
+pub struct AckOnDrop<T, Exe: Executor> {
+    message: Message<T>,
+    acker: Option<mpsc::UnboundedSender<EngineMessage<Exe>>>,
+}
+
+impl<T, Exe: Executor> std::ops::Deref for AckOnDrop<T, Exe> {
+    type Target = Message<T>;
+
+    fn deref(&self) -> &Message<T> {
+        &self.message
+    }
+}
+
+impl<T, Exe: Executor> Drop for AckOnDrop<T, Exe> {
+    fn drop(&mut self) {
+        let res = ack_with(
+            self.acker.take(),
+            &self.message.topic,
+            self.message.message_id.clone(),
+        );
+        if let Err(e) = res {
+            error!(
+                "could not acknowledge message {:?}: {:?}",
+                self.message.message_id, e
+            );
+        }
+    }
+}
+
 enum InnerConsumer<T: DeserializeMessage, Exe: Executor> {
     Single(TopicConsumer<T, Exe>),
     Multi(MultiTopicConsumer<T, Exe>),
 }
 
-type MessageIdDataReceiver = mpsc::Receiver<Result<(proto::MessageIdData, Payload), Error>>;
+type MessageIdDataReceiver = mpsc::Receiver<Result<(proto::MessageIdData, Payload, u32), Error>>;
 
 // this is entirely public for use in reader.rs
 pub(crate) struct TopicConsumer<T: DeserializeMessage, Exe: Executor> {
@@ -440,6 +1067,16 @@ pub(crate) struct TopicConsumer<T: DeserializeMessage, Exe: Executor> {
     pub(crate) dead_letter_policy: Option<DeadLetterPolicy>,
     last_message_received: Option<DateTime<Utc>>,
     messages_received: u64,
+    /// highest message id received, acked by `ack_cumulative`
+    highest_message_id: Option<MessageData>,
+    /// message id the consumer started from or was moved to by a seek, and
+    /// whether that message is delivered. None if the position is not known
+    start_message_id: Option<(MessageIdData, bool)>,
+    status: Arc<ConsumerStatus>,
+    /// schemas returned by `writer_schema`, by schema version
+    schemas: HashMap<Vec<u8>, Option<proto::Schema>>,
+    /// stops the tasks sending periodic messages to the engine when dropped
+    _stop_tickers: oneshot::Sender<()>,
 }
 
 impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
@@ -458,12 +1095,14 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
             unacked_message_redelivery_delay,
             options,
             dead_letter_policy,
+            flow_permits_exhausted,
+            paused,
         } = config.clone();
-        let consumer_id = consumer_id.unwrap_or_else(rand::random);
         let (resolver, messages) = mpsc::unbounded();
         let batch_size = batch_size.unwrap_or(1000);
 
         let mut connection = client.manager.get_connection(&addr).await?;
+        let consumer_id = consumer_id.unwrap_or_else(|| connection.sender().next_consumer_id());
         let mut current_retries = 0u32;
         let start = std::time::Instant::now();
         let operation_retry_options = client.operation_retry_options.clone();
@@ -537,14 +1176,18 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
             }
         }
 
-        connection
-            .sender()
-            .send_flow(consumer_id, batch_size)
-            .map_err(|e| {
-                error!("TopicConsumer::new error[{}]: {:?}", line!(), e);
-                e
-            })
-            .map_err(|e| Error::Consumer(ConsumerError::Connection(e)))?;
+        // a paused consumer gets its permits when it is resumed
+        if !paused {
+            connection
+                .sender()
+                .send_flow(consumer_id, batch_size)
+                .await
+                .map_err(|e| {
+                    error!("TopicConsumer::new error[{}]: {:?}", line!(), e);
+                    e
+                })
+                .map_err(|e| Error::Consumer(ConsumerError::Connection(e)))?;
+        }
 
         let (engine_tx, engine_rx) = unbounded();
         // drop_signal will be dropped when Consumer is dropped, then
@@ -567,18 +1210,38 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
             }
         }));
 
-        if unacked_message_redelivery_delay.is_some() {
-            let mut redelivery_tx = engine_tx.clone();
-            let mut interval = client.executor.interval(Duration::from_millis(500));
-            let res = client.executor.spawn(Box::pin(async move {
-                while interval.next().await.is_some() {
-                    if redelivery_tx
-                        .send(EngineMessage::UnackedRedelivery)
-                        .await
-                        .is_err()
-                    {
-                        // Consumer shut down - stop ticker
-                        break;
+        // the tickers stop when the consumer is dropped, the engine then sees
+        // its channel closed and flushes the pending acks
+        let (stop_tickers, tickers_stopped) = oneshot::channel::<()>();
+        let tickers_stopped = tickers_stopped.shared();
+        // the redelivery ticker also runs without an unacked message delay,
+        // since it redelivers the messages negatively acknowledged with a delay
+        let mut redelivery_tx = engine_tx.clone();
+        let mut interval = client.executor.interval(Duration::from_millis(500));
+        let mut stopped = tickers_stopped.clone();
+        let res = client.executor.spawn(Box::pin(async move {
+            while let Either::Left((Some(_), _)) = select(interval.next(), &mut stopped).await {
+                if redelivery_tx
+                    .send(EngineMessage::UnackedRedelivery(Instant::now()))
+                    .await
+                    .is_err()
+                {
+                    // Consumer shut down - stop ticker
+                    break;
+                }
+            }
+        }));
+        if res.is_err() {
+            return Err(Error::Executor);
+        }
+        if let Some(ack_grouping) = &options.ack_grouping {
+            let mut flush_tx = engine_tx.clone();
+            let mut interval = client.executor.interval(ack_grouping.ack_group_time);
+            let mut stopped = tickers_stopped;
+            let res = client.executor.spawn(Box::pin(async move {
+                while let Either::Left((Some(_), _)) = select(interval.next(), &mut stopped).await {
+                    if flush_tx.send(EngineMessage::FlushAcks).await.is_err() {
+                        break;
                     }
                 }
             }));
@@ -586,7 +1249,20 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
                 return Err(Error::Executor);
             }
         }
+        // without filtering, the broker delivers the start message
+        let start_message_id = options.start_message_id.clone().map(|message_id| {
+            (
+                message_id,
+                options.start_message_id_inclusive.unwrap_or(true),
+            )
+        });
         let (tx, rx) = mpsc::channel(1000);
+        // the broker tells failover consumers whether they are active
+        let status = Arc::new(ConsumerStatus {
+            active: AtomicBool::new(sub_type != SubType::Failover),
+            reached_end_of_topic: AtomicBool::new(false),
+            paused: AtomicBool::new(paused),
+        });
         let mut c = ConsumerEngine::new(
             client.clone(),
             connection.clone(),
@@ -599,11 +1275,16 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
             messages,
             engine_rx,
             batch_size,
+            flow_permits_exhausted,
             unacked_message_redelivery_delay,
             dead_letter_policy.clone(),
             options.clone(),
+            status.clone(),
             _drop_signal,
         );
+        if paused {
+            c.remaining_messages = 0;
+        }
         let f = async move {
             c.engine()
                 .map(|res| {
@@ -625,6 +1306,11 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
             dead_letter_policy,
             last_message_received: None,
             messages_received: 0,
+            highest_message_id: None,
+            start_message_id,
+            status,
+            schemas: HashMap::new(),
+            _stop_tickers: stop_tickers,
         })
     }
 
@@ -666,46 +1352,170 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
         Ok(())
     }
 
+    async fn ack_batch_entry(
+        &mut self,
+        msg: &Message<T>,
+        batch_index: i32,
+    ) -> Result<(), ConsumerError> {
+        let message_id = MessageData {
+            id: MessageIdData {
+                batch_index: Some(batch_index),
+                batch_size: msg.message_id.batch_size,
+                ..msg.message_id.id.clone()
+            },
+            batch_size: msg.message_id.batch_size,
+            redelivery_count: msg.message_id.redelivery_count,
+        };
+        self.engine_tx
+            .send(EngineMessage::Ack(message_id, false))
+            .await?;
+        Ok(())
+    }
+
     pub(crate) fn acker(&self) -> mpsc::UnboundedSender<EngineMessage<Exe>> {
         self.engine_tx.clone()
     }
 
     async fn cumulative_ack(&mut self, msg: &Message<T>) -> Result<(), ConsumerError> {
+        self.check_cumulative_ack()?;
         self.engine_tx
             .send(EngineMessage::Ack(msg.message_id.clone(), true))
             .await?;
         Ok(())
     }
 
+    async fn ack_cumulative(&mut self) -> Result<(), ConsumerError> {
+        self.check_cumulative_ack()?;
+        if let Some(message_id) = self.highest_message_id.clone() {
+            self.engine_tx
+                .send(EngineMessage::Ack(message_id, true))
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn check_cumulative_ack(&self) -> Result<(), ConsumerError> {
+        match self.config.sub_type {
+            SubType::Shared | SubType::KeyShared => {
+                Err(ConsumerError::CumulativeAckNotAllowed(self.config.sub_type))
+            }
+            SubType::Exclusive | SubType::Failover => Ok(()),
+        }
+    }
+
     async fn nack(&mut self, msg: &Message<T>) -> Result<(), ConsumerError> {
+        if let Some(delay) = self.config.options.negative_ack_redelivery_delay {
+            return self.nack_with_delay(msg, delay).await;
+        }
         self.engine_tx
             .send(EngineMessage::Nack(msg.message_id.clone()))
             .await?;
         Ok(())
     }
 
+    async fn nack_with_delay(
+        &mut self,
+        msg: &Message<T>,
+        delay: Duration,
+    ) -> Result<(), ConsumerError> {
+        self.engine_tx
+            .send(EngineMessage::DelayedNack(
+                msg.message_id.clone(),
+                Instant::now() + delay,
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn grant_permits(&mut self, consumer_id: u64, permits: u32) -> Result<(), ConsumerError> {
+        if consumer_id != self.consumer_id {
+            return Err(ConnectionError::Unexpected(format!(
+                "no consumer with id {}",
+                consumer_id
+            ))
+            .into());
+        }
+        self.engine_tx.send(EngineMessage::Flow(permits)).await?;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<(), Error> {
+        let (resolver, response) = oneshot::channel();
+        self.engine_tx
+            .send(EngineMessage::Close(resolver))
+            .await
+            .map_err(ConsumerError::from)?;
+        response
+            .await
+            .map_err(|_| ConsumerError::Closed)?
+            .map_err(|e| e.into())
+    }
+
+    fn pause(&self) {
+        self.status.paused.store(true, Ordering::Release);
+    }
+
+    async fn resume(&mut self) -> Result<(), ConsumerError> {
+        self.status.paused.store(false, Ordering::Release);
+        // the engine may be waiting for messages that it did not ask for
+        self.engine_tx.send(EngineMessage::Resume).await?;
+        Ok(())
+    }
+
+    fn is_paused(&self) -> bool {
+        self.status.paused.load(Ordering::Acquire)
+    }
+
     pub async fn seek(
         &mut self,
         message_id: Option<MessageIdData>,
         timestamp: Option<u64>,
     ) -> Result<(), Error> {
         let consumer_id = self.consumer_id;
-        self.connection()
-            .await?
-            .sender()
-            .seek(consumer_id, message_id, timestamp)
-            .await?;
+        let conn = self.connection().await?;
+        let start_message_id = message_id.clone().map(|message_id| (message_id, true));
+        match (message_id, timestamp) {
+            (Some(message_id), _) => {
+                conn.sender()
+                    .seek_message_id(consumer_id, message_id)
+                    .await?
+            }
+            (None, Some(timestamp)) => conn.sender().seek_timestamp(consumer_id, timestamp).await?,
+            (None, None) => {
+                return Err(ConnectionError::Unexpected(
+                    "seek needs a message id or a timestamp".to_string(),
+                )
+                .into())
+            }
+        };
+        // messages before the new position can be received again
+        self.highest_message_id = None;
+        self.start_message_id = start_message_id;
+        self.engine_tx
+            .send(EngineMessage::Seek)
+            .await
+            .map_err(ConsumerError::from)?;
         Ok(())
     }
 
     pub async fn unsubscribe(&mut self) -> Result<(), Error> {
         let consumer_id = self.consumer_id;
-        self.connection()
+        match self
+            .connection()
             .await?
             .sender()
             .unsubscribe(consumer_id)
-            .await?;
-        Ok(())
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(ConnectionError::PulsarError(Some(proto::ServerError::ConsumerBusy), msg)) => {
+                Err(ConsumerError::SubscriptionBusy(msg.unwrap_or_default()).into())
+            }
+            Err(ConnectionError::PulsarError(Some(proto::ServerError::MetadataError), msg)) => {
+                Err(ConsumerError::Metadata(msg.unwrap_or_default()).into())
+            }
+            Err(e) => Err(e.into()),
+        }
     }
 
     pub async fn get_last_message_id(&mut self) -> Result<MessageIdData, Error> {
@@ -715,6 +1525,42 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
         Ok(get_last_message_id_response.last_message_id)
     }
 
+    pub async fn get_backlog(&mut self) -> Result<Backlog, Error> {
+        let consumer_id = self.consumer_id;
+        let conn = self.connection().await?;
+        let response = conn.sender().get_last_message_id(consumer_id).await?;
+        Ok(Backlog::new(
+            self.topic.clone(),
+            response.last_message_id,
+            response.consumer_mark_delete_position,
+        ))
+    }
+
+    async fn writer_schema(
+        &mut self,
+        version: Option<Vec<u8>>,
+    ) -> Result<Option<proto::Schema>, Error> {
+        // a schema version never changes, unlike the topic's latest schema
+        if let Some(schema) = version.as_ref().and_then(|v| self.schemas.get(v)) {
+            return Ok(schema.clone());
+        }
+
+        let conn = self.connection().await?;
+        let schema = match conn
+            .sender()
+            .get_schema(self.topic.clone(), version.clone())
+            .await
+        {
+            Ok(response) => response.schema,
+            Err(ConnectionError::PulsarError(Some(proto::ServerError::TopicNotFound), _)) => None,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(version) = version {
+            self.schemas.insert(version, schema.clone());
+        }
+        Ok(schema)
+    }
+
     pub fn last_message_received(&self) -> Option<DateTime<Utc>> {
         self.last_message_received
     }
@@ -723,16 +1569,42 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
         self.messages_received
     }
 
+    pub fn is_active(&self) -> bool {
+        self.status.active.load(Ordering::Relaxed)
+    }
+
+    pub fn reached_end_of_topic(&self) -> bool {
+        self.status.reached_end_of_topic.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn highest_message_id(&self) -> Option<&MessageIdData> {
+        self.highest_message_id
+            .as_ref()
+            .map(|message_id| &message_id.id)
+    }
+
+    pub(crate) fn start_message_id(&self) -> Option<(&MessageIdData, bool)> {
+        self.start_message_id
+            .as_ref()
+            .map(|(message_id, inclusive)| (message_id, *inclusive))
+    }
+
     fn config(&self) -> &ConsumerConfig {
         &self.config
     }
 
-    fn create_message(&self, message_id: proto::MessageIdData, payload: Payload) -> Message<T> {
+    fn create_message(
+        &self,
+        message_id: proto::MessageIdData,
+        payload: Payload,
+        redelivery_count: u32,
+    ) -> Message<T> {
         Message {
             topic: self.topic.clone(),
             message_id: MessageData {
                 id: message_id,
                 batch_size: payload.metadata.num_messages_in_batch,
+                redelivery_count,
             },
             payload,
             _phantom: PhantomData,
@@ -747,10 +1619,21 @@ impl<T: DeserializeMessage, Exe: Executor> Stream for TopicConsumer<T, Exe> {
         match self.messages.as_mut().poll_next(cx) {
             Poll::Pending => Poll::Pending,
             Poll::Ready(None) => Poll::Ready(None),
-            Poll::Ready(Some(Ok((id, payload)))) => {
+            Poll::Ready(Some(Ok((id, payload, redelivery_count)))) => {
                 self.last_message_received = Some(Utc::now());
                 self.messages_received += 1;
-                Poll::Ready(Some(Ok(self.create_message(id, payload))))
+                let message = self.create_message(id, payload, redelivery_count);
+                let is_highest = match &self.highest_message_id {
+                    Some(highest) => {
+                        compare_message_ids(&message.message_id.id, &highest.id)
+                            == std::cmp::Ordering::Greater
+                    }
+                    None => true,
+                };
+                if is_highest {
+                    self.highest_message_id = Some(message.message_id.clone());
+                }
+                Poll::Ready(Some(Ok(message)))
             }
             Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
         }
@@ -765,22 +1648,107 @@ struct ConsumerEngine<Exe: Executor> {
     sub_type: SubType,
     id: u64,
     name: Option<String>,
-    tx: mpsc::Sender<Result<(proto::MessageIdData, Payload), Error>>,
+    tx: mpsc::Sender<Result<(proto::MessageIdData, Payload, u32), Error>>,
     messages_rx: Option<mpsc::UnboundedReceiver<RawMessage>>,
     engine_rx: Option<mpsc::UnboundedReceiver<EngineMessage<Exe>>>,
     batch_size: u32,
     remaining_messages: u32,
+    /// if set, permits are not granted automatically
+    flow_permits_exhausted: Option<FlowPermitsCallback>,
+    /// true if the flow permits callback was called but did not grant permits
+    waiting_for_permits: bool,
     unacked_message_redelivery_delay: Option<Duration>,
     unacked_messages: HashMap<MessageIdData, Instant>,
+    /// negatively acknowledged messages waiting for their redelivery delay
+    nacked_messages: HashMap<MessageIdData, Instant>,
     dead_letter_policy: Option<DeadLetterPolicy>,
     options: ConsumerOptions,
+    /// messages split in chunks that are not complete yet, oldest first
+    chunked_messages: VecDeque<ChunkedMessage>,
+    /// ids of the other chunks of a reassembled message, indexed by the id
+    /// of its last chunk, which is the one given to the consumer
+    chunked_message_ids: HashMap<MessageIdData, Vec<MessageIdData>>,
+    /// highest broker entry index received, used by `dedup_by_broker_index`
+    highest_broker_index: Option<u64>,
+    /// messages this consumer asked the broker to send again, delivered even
+    /// if `dedup_by_broker_index` sees them as duplicates
+    redelivery_requested: HashSet<MessageIdData>,
+    /// messages up to this one are dropped if `start_message_id_inclusive` is
+    /// set, the broker delivers the whole entry containing the start message id.
+    /// Cleared by a seek
+    start_message_id: Option<MessageIdData>,
+    /// individual acks waiting to be sent, if `ack_grouping` is set
+    pending_acks: AckGroup,
+    status: Arc<ConsumerStatus>,
     _drop_signal: oneshot::Sender<()>,
 }
 
+/// individual acks waiting to be sent together
+#[derive(Default)]
+struct AckGroup {
+    ids: Vec<MessageIdData>,
+}
+
+impl AckGroup {
+    /// adds acks to the group, returns the whole group once it reached
+    /// `max_size`
+    fn push(&mut self, ids: Vec<MessageIdData>, max_size: usize) -> Option<Vec<MessageIdData>> {
+        self.ids.extend(ids);
+        if self.ids.len() >= max_size {
+            Some(self.take())
+        } else {
+            None
+        }
+    }
+
+    fn take(&mut self) -> Vec<MessageIdData> {
+        std::mem::take(&mut self.ids)
+    }
+}
+
+/// number of flow permits to request once the remaining permits went below
+/// the threshold, so that the consumer gets back to `batch_size` permits
+fn permits_to_refill(remaining: u32, batch_size: u32, threshold: Option<u32>) -> Option<u32> {
+    // with a threshold of 0, a consumer that used all of its permits would
+    // never ask for more messages
+    let threshold = threshold.unwrap_or(batch_size / 2).min(batch_size).max(1);
+    if remaining < threshold {
+        Some(batch_size.saturating_sub(remaining))
+    } else {
+        None
+    }
+}
+
+/// consumer state reported by the broker, shared by the engine and the consumer
+struct ConsumerStatus {
+    /// false while another consumer of a failover subscription is active
+    active: AtomicBool,
+    /// the topic was terminated and all its messages were delivered
+    reached_end_of_topic: AtomicBool,
+    /// no flow permits are sent while the consumer is paused
+    paused: AtomicBool,
+}
+
+/// chunks received so far for a message split by the producer
+struct ChunkedMessage {
+    uuid: String,
+    message_ids: Vec<MessageIdData>,
+    last_chunk_id: i32,
+    payload: Payload,
+}
+
 pub(crate) enum EngineMessage<Exe: Executor> {
     Ack(MessageData, bool),
     Nack(MessageData),
-    UnackedRedelivery,
+    DelayedNack(MessageData, Instant),
+    /// asks for the redelivery of the messages whose deadline is before this time
+    UnackedRedelivery(Instant),
+    Flow(u32),
+    /// the subscription was moved, messages already seen can be received again
+    Seek,
+    FlushAcks,
+    Resume,
+    Close(oneshot::Sender<Result<(), ConnectionError>>),
     GetConnection(oneshot::Sender<Arc<Connection<Exe>>>),
 }
 
@@ -793,15 +1761,20 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
         sub_type: SubType,
         id: u64,
         name: Option<String>,
-        tx: mpsc::Sender<Result<(proto::MessageIdData, Payload), Error>>,
+        tx: mpsc::Sender<Result<(proto::MessageIdData, Payload, u32), Error>>,
         messages_rx: mpsc::UnboundedReceiver<RawMessage>,
         engine_rx: mpsc::UnboundedReceiver<EngineMessage<Exe>>,
         batch_size: u32,
+        flow_permits_exhausted: Option<FlowPermitsCallback>,
         unacked_message_redelivery_delay: Option<Duration>,
         dead_letter_policy: Option<DeadLetterPolicy>,
         options: ConsumerOptions,
+        status: Arc<ConsumerStatus>,
         _drop_signal: oneshot::Sender<()>,
     ) -> ConsumerEngine<Exe> {
+        let start_message_id = options
+            .start_message_id_inclusive
+            .and(options.start_message_id.clone());
         ConsumerEngine {
             client,
             connection,
@@ -815,10 +1788,20 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
             engine_rx: Some(engine_rx),
             batch_size,
             remaining_messages: batch_size,
+            flow_permits_exhausted,
+            waiting_for_permits: false,
             unacked_message_redelivery_delay,
             unacked_messages: HashMap::new(),
+            nacked_messages: HashMap::new(),
             dead_letter_policy,
             options,
+            chunked_messages: VecDeque::new(),
+            chunked_message_ids: HashMap::new(),
+            highest_broker_index: None,
+            redelivery_requested: HashSet::new(),
+            start_message_id,
+            pending_acks: AckGroup::default(),
+            status,
             _drop_signal,
         }
     }
@@ -838,18 +1821,27 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
                 }
             }
 
-            if self.remaining_messages < self.batch_size / 2 {
-                match self
-                    .connection
-                    .sender()
-                    .send_flow(self.id, self.batch_size - self.remaining_messages)
-                {
+            if self.status.paused.load(Ordering::Acquire) {
+                // the broker stops sending messages once the permits are used
+            } else if let Some(callback) = self.flow_permits_exhausted.clone() {
+                if self.remaining_messages == 0 && !self.waiting_for_permits {
+                    let permits = (callback.0)(self.id, self.batch_size);
+                    if permits > 0 {
+                        self.grant_permits(permits).await?;
+                    } else {
+                        self.waiting_for_permits = true;
+                    }
+                }
+            } else if let Some(permits) = permits_to_refill(
+                self.remaining_messages,
+                self.batch_size,
+                self.options.flow_refill_threshold,
+            ) {
+                match self.connection.sender().send_flow(self.id, permits).await {
                     Ok(()) => {}
                     Err(ConnectionError::Disconnected) => {
                         self.reconnect().await?;
-                        self.connection
-                            .sender()
-                            .send_flow(self.id, self.batch_size - self.remaining_messages)?;
+                        self.connection.sender().send_flow(self.id, permits).await?;
                     }
                     Err(e) => return Err(e.into()),
                 }
@@ -930,52 +1922,96 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
                     match ack_opt {
                         None => {
                             trace!("ack channel was closed");
+                            self.flush_acks().await;
                             return Ok(());
                         }
                         Some(EngineMessage::Ack(message_id, cumulative)) => {
-                            self.ack(message_id, cumulative);
+                            self.ack(message_id, cumulative).await;
                         }
                         Some(EngineMessage::Nack(message_id)) => {
-                            if let Err(e) = self
-                                .connection
-                                .sender()
-                                .send_redeliver_unacknowleged_messages(
-                                    self.id,
-                                    vec![message_id.id.clone()],
-                                )
-                            {
+                            if let Err(e) = self.redeliver(vec![message_id.id.clone()]).await {
                                 error!(
                                     "could not ask for redelivery for message {:?}: {:?}",
                                     message_id, e
                                 );
                             }
                         }
-                        Some(EngineMessage::UnackedRedelivery) => {
-                            let mut h = HashSet::new();
-                            let now = Instant::now();
-                            //info!("unacked messages length: {}", self.unacked_messages.len());
-                            for (id, t) in self.unacked_messages.iter() {
-                                if *t < now {
-                                    h.insert(id.clone());
+                        Some(EngineMessage::DelayedNack(message_id, deadline)) => {
+                            self.nacked_messages.insert(message_id.id, deadline);
+                        }
+                        Some(EngineMessage::UnackedRedelivery(now)) => {
+                            let ids = expired_messages(&self.nacked_messages, now);
+                            if !ids.is_empty() {
+                                self.nacked_messages.retain(|id, _| !ids.contains(id));
+                                if let Err(e) = self.redeliver(ids.into_iter().collect()).await {
+                                    error!("could not ask for redelivery: {:?}", e);
                                 }
                             }
 
+                            // without a redelivery delay, unacked messages are
+                            // only redelivered when the consumer reconnects
+                            let h = if self.unacked_message_redelivery_delay.is_some() {
+                                expired_messages(&self.unacked_messages, now)
+                            } else {
+                                HashSet::new()
+                            };
+
                             let ids: Vec<_> = h.iter().cloned().collect();
                             if !ids.is_empty() {
                                 //info!("will unack ids: {:?}", ids);
                                 if let Err(e) = self
                                     .connection
                                     .sender()
-                                    .send_redeliver_unacknowleged_messages(self.id, ids)
+                                    .send_redeliver_unacknowleged_messages(
+                                        self.id,
+                                        ids,
+                                        self.max_redeliver_per_command(),
+                                    )
+                                    .await
                                 {
                                     error!("could not ask for redelivery: {:?}", e);
                                 } else {
+                                    self.expect_redelivery(h.iter());
                                     for i in h.iter() {
                                         self.unacked_messages.remove(i);
                                     }
                                 }
                             }
                         }
+                        Some(EngineMessage::Flow(permits)) => {
+                            self.grant_permits(permits).await?;
+                        }
+                        Some(EngineMessage::Seek) => {
+                            self.highest_broker_index = None;
+                            self.redelivery_requested.clear();
+                            self.start_message_id = None;
+                        }
+                        Some(EngineMessage::FlushAcks) => {
+                            self.flush_acks().await;
+                        }
+                        Some(EngineMessage::Close(resolver)) => {
+                            self.flush_acks().await;
+                            let res = self
+                                .connection
+                                .sender()
+                                .close_consumer(self.id)
+                                .await
+                                .map(drop);
+                            // the consumer is closed, it must not be closed
+                            // again once the engine is dropped
+                            let (signal, _) = oneshot::channel();
+                            let _ = std::mem::replace(&mut self._drop_signal, signal).send(());
+                            let _ = resolver.send(res);
+                            return Ok(());
+                        }
+                        Some(EngineMessage::Resume) => {
+                            if self.flow_permits_exhausted.is_none()
+                                && self.remaining_messages < self.batch_size
+                            {
+                                let permits = self.batch_size - self.remaining_messages;
+                                self.grant_permits(permits).await?;
+                            }
+                        }
                         Some(EngineMessage::GetConnection(sender)) => {
                             let _ = sender.send(self.connection.clone()).map_err(|_| {
                                 error!("consumer requested the engine's connection but dropped the channel before receiving");
@@ -987,13 +2023,65 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
         }
     }
 
-    fn ack(&mut self, message_id: MessageData, cumulative: bool) {
-        //FIXME: this does not handle cumulative acks
-        self.unacked_messages.remove(&message_id.id);
+    async fn grant_permits(&mut self, permits: u32) -> Result<(), Error> {
+        match self.connection.sender().send_flow(self.id, permits).await {
+            Ok(()) => {}
+            Err(ConnectionError::Disconnected) => {
+                // reconnecting grants the initial permits again
+                self.reconnect().await?;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        }
+        self.remaining_messages += permits;
+        self.waiting_for_permits = false;
+        Ok(())
+    }
+
+    async fn ack(&mut self, message_id: MessageData, cumulative: bool) {
+        if cumulative {
+            self.unacked_messages.retain(|id, _| {
+                compare_message_ids(id, &message_id.id) == std::cmp::Ordering::Greater
+            });
+        }
+        // received messages are tracked without their batch size
+        self.unacked_messages.remove(&MessageIdData {
+            batch_size: None,
+            ..message_id.id.clone()
+        });
+        let mut ids = self
+            .chunked_message_ids
+            .remove(&message_id.id)
+            .unwrap_or_default();
+        ids.push(message_id.id);
+
+        if let (Some(ack_grouping), false) = (&self.options.ack_grouping, cumulative) {
+            let max_size = ack_grouping.max_group_size;
+            if let Some(group) = self.pending_acks.push(ids, max_size) {
+                self.send_ack(group, false).await;
+            }
+            return;
+        }
+
+        // the grouped acks are sent first, a cumulative ack may cover them
+        self.flush_acks().await;
+        self.send_ack(ids, cumulative).await;
+    }
+
+    /// sends the individual acks grouped so far
+    async fn flush_acks(&mut self) {
+        let ids = self.pending_acks.take();
+        if !ids.is_empty() {
+            self.send_ack(ids, false).await;
+        }
+    }
+
+    async fn send_ack(&mut self, ids: Vec<MessageIdData>, cumulative: bool) {
         let res = self
             .connection
             .sender()
-            .send_ack(self.id, vec![message_id.id], cumulative);
+            .send_ack(self.id, ids, cumulative)
+            .await;
         if res.is_err() {
             error!("ack error: {:?}", res);
         }
@@ -1010,6 +2098,10 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
                     },
                 ..
             } => {
+                info!("{} reached the end of the topic", self.debug_format());
+                self.status
+                    .reached_end_of_topic
+                    .store(true, Ordering::Release);
                 return Ok(false);
             }
             RawMessage {
@@ -1020,12 +2112,15 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
                     },
                 ..
             } => {
-                // TODO: Communicate this status to the Consumer and expose it
                 debug!(
                     "Active consumer change for {} - Active: {:?}",
                     self.debug_format(),
                     active_consumer_change.is_active
                 );
+                self.status.active.store(
+                    active_consumer_change.is_active.unwrap_or(false),
+                    Ordering::Relaxed,
+                );
             }
             RawMessage {
                 command:
@@ -1038,15 +2133,21 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
                 self.process_payload(message, payload).await?;
             }
             RawMessage {
-                command: BaseCommand {
-                    message: Some(_), ..
-                },
+                command:
+                    BaseCommand {
+                        message: Some(message),
+                        ..
+                    },
                 payload: None,
             } => {
+                // the codec drops payloads that do not match their checksum
                 error!(
-                    "Consumer {} received message without payload",
+                    "Consumer {} received message without a valid payload, asking for its redelivery",
                     self.debug_format()
                 );
+                if let Err(e) = self.redeliver(vec![message.message_id]).await {
+                    error!("could not ask for redelivery: {:?}", e);
+                }
             }
             RawMessage {
                 command:
@@ -1080,8 +2181,18 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
     async fn process_payload(
         &mut self,
         message: CommandMessage,
-        mut payload: Payload,
+        payload: Payload,
     ) -> Result<(), Error> {
+        let (message, mut payload) = match payload.metadata.num_chunks_from_msg {
+            Some(num_chunks) if num_chunks > 1 => {
+                match self.process_chunk(message, payload).await {
+                    Some(res) => res,
+                    None => return Ok(()),
+                }
+            }
+            _ => (message, payload),
+        };
+
         let compression = payload.metadata.compression;
 
         let payload = match compression {
@@ -1090,11 +2201,10 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
             Some(1) => {
                 #[cfg(not(feature = "lz4"))]
                 {
-                    return Err(Error::Consumer(ConsumerError::Io(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "got a LZ4 compressed message but 'lz4' cargo feature is deactivated",
-                    )))
-                    .into());
+                    return Err(Error::Consumer(ConsumerError::UnsupportedCompression(
+                        "got a LZ4 compressed message but 'lz4' cargo feature is deactivated"
+                            .to_string(),
+                    )));
                 }
 
                 #[cfg(feature = "lz4")]
@@ -1113,11 +2223,10 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
             Some(2) => {
                 #[cfg(not(feature = "flate2"))]
                 {
-                    return Err(Error::Consumer(ConsumerError::Io(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "got a zlib compressed message but 'flate2' cargo feature is deactivated",
-                    )))
-                    .into());
+                    return Err(Error::Consumer(ConsumerError::UnsupportedCompression(
+                        "got a zlib compressed message but 'flate2' cargo feature is deactivated"
+                            .to_string(),
+                    )));
                 }
 
                 #[cfg(feature = "flate2")]
@@ -1138,11 +2247,10 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
             Some(3) => {
                 #[cfg(not(feature = "zstd"))]
                 {
-                    return Err(Error::Consumer(ConsumerError::Io(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "got a zstd compressed message but 'zstd' cargo feature is deactivated",
-                    )))
-                    .into());
+                    return Err(Error::Consumer(ConsumerError::UnsupportedCompression(
+                        "got a zstd compressed message but 'zstd' cargo feature is deactivated"
+                            .to_string(),
+                    )));
                 }
 
                 #[cfg(feature = "zstd")]
@@ -1158,11 +2266,10 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
             Some(4) => {
                 #[cfg(not(feature = "snap"))]
                 {
-                    return Err(Error::Consumer(ConsumerError::Io(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "got a Snappy compressed message but 'snap' cargo feature is deactivated",
-                    )))
-                    .into());
+                    return Err(Error::Consumer(ConsumerError::UnsupportedCompression(
+                        "got a Snappy compressed message but 'snap' cargo feature is deactivated"
+                            .to_string(),
+                    )));
                 }
 
                 #[cfg(feature = "snap")]
@@ -1181,83 +2288,361 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
             }
             Some(i) => {
                 error!("unknown compression type: {}", i);
-                return Err(Error::Consumer(ConsumerError::Io(std::io::Error::new(
-                    std::io::ErrorKind::Other,
+                return Err(Error::Consumer(ConsumerError::UnsupportedCompression(
                     format!("unknown compression type: {}", i),
-                ))));
+                )));
             }
         };
 
+        // the broker counts redeliveries in the command, not in the metadata
+        let redelivery_count = message.redelivery_count.unwrap_or(0);
         match payload.metadata.num_messages_in_batch {
             Some(_) => {
                 let it = BatchedMessageIterator::new(message.message_id, payload)?;
                 for (id, payload) in it {
                     // TODO: Dead letter policy for batched messages
-                    self.send_to_consumer(id, payload).await?;
+                    self.send_to_consumer(id, payload, redelivery_count).await?;
                 }
             }
-            None => match (message.redelivery_count, self.dead_letter_policy.as_ref()) {
-                (Some(redelivery_count), Some(dead_letter_policy)) => {
+            None => match self.dead_letter_policy.as_ref() {
+                Some(dead_letter_policy)
+                    if redelivery_count as usize >= dead_letter_policy.max_redeliver_count =>
+                {
                     // Send message to Dead Letter Topic and ack message in original topic
-                    if redelivery_count as usize >= dead_letter_policy.max_redeliver_count {
-                        self.client
-                            .send(&dead_letter_policy.dead_letter_topic, payload.data)
-                            .await?
-                            .await
-                            .map_err(|e| {
-                                error!("One shot cancelled {:?}", e);
-                                Error::Custom("DLQ send error".to_string())
-                            })?;
-
-                        self.ack(
-                            MessageData {
-                                id: message.message_id,
-                                batch_size: None,
-                            },
-                            false,
-                        );
-                    } else {
-                        self.send_to_consumer(message.message_id, payload).await?
-                    }
+                    // the properties and keys are kept so that the message
+                    // can be inspected or replayed from the dead letter topic
+                    let metadata = payload.metadata;
+                    let dead_letter = crate::producer::Message {
+                        payload: payload.data,
+                        properties: metadata
+                            .properties
+                            .into_iter()
+                            .map(|kv| (kv.key, kv.value))
+                            .collect(),
+                        partition_key: metadata.partition_key,
+                        ordering_key: metadata.ordering_key,
+                        event_time: metadata.event_time,
+                        ..Default::default()
+                    };
+                    self.client
+                        .send(&dead_letter_policy.dead_letter_topic, dead_letter)
+                        .await?
+                        .await
+                        .map_err(|e| {
+                            error!("One shot cancelled {:?}", e);
+                            Error::Custom("DLQ send error".to_string())
+                        })?;
+
+                    self.ack(
+                        MessageData {
+                            id: message.message_id,
+                            batch_size: None,
+                            redelivery_count,
+                        },
+                        false,
+                    )
+                    .await;
+                }
+                _ => {
+                    self.send_to_consumer(message.message_id, payload, redelivery_count)
+                        .await?
                 }
-                _ => self.send_to_consumer(message.message_id, payload).await?,
             },
         }
         Ok(())
     }
 
+    /// buffers a chunk of a message that was split by the producer
+    ///
+    /// returns the reassembled message once its last chunk is received
+    async fn process_chunk(
+        &mut self,
+        message: CommandMessage,
+        payload: Payload,
+    ) -> Option<(CommandMessage, Payload)> {
+        let uuid = payload.metadata.uuid.clone().unwrap_or_default();
+        let chunk_id = payload.metadata.chunk_id.unwrap_or(0);
+        let num_chunks = payload.metadata.num_chunks_from_msg.unwrap_or(1);
+        let position = self.chunked_messages.iter().position(|c| c.uuid == uuid);
+
+        if chunk_id == 0 {
+            if let Some(position) = position {
+                warn!(
+                    "{} received the first chunk of message {} again, dropping the previous chunks",
+                    self.debug_format(),
+                    uuid
+                );
+                self.chunked_messages.remove(position);
+            }
+
+            let max_pending = self.options.max_pending_chunked_messages.unwrap_or(10);
+            while !self.chunked_messages.is_empty() && self.chunked_messages.len() >= max_pending {
+                if let Some(oldest) = self.chunked_messages.pop_front() {
+                    self.evict_chunked_message(oldest).await;
+                }
+            }
+
+            self.chunked_messages.push_back(ChunkedMessage {
+                uuid: uuid.clone(),
+                message_ids: vec![message.message_id.clone()],
+                last_chunk_id: 0,
+                payload,
+            });
+        } else {
+            match position {
+                Some(position) if self.chunked_messages[position].last_chunk_id + 1 == chunk_id => {
+                    let chunked = &mut self.chunked_messages[position];
+                    chunked.message_ids.push(message.message_id.clone());
+                    chunked.last_chunk_id = chunk_id;
+                    chunked.payload.data.extend_from_slice(&payload.data);
+                }
+                Some(position) if chunk_id <= self.chunked_messages[position].last_chunk_id => {
+                    warn!(
+                        "{} received chunk {} of message {} twice, dropping it",
+                        self.debug_format(),
+                        chunk_id,
+                        uuid
+                    );
+                    return None;
+                }
+                _ => {
+                    warn!(
+                        "{} received chunk {} of message {} out of order, dropping it",
+                        self.debug_format(),
+                        chunk_id,
+                        uuid
+                    );
+                    return None;
+                }
+            }
+        }
+
+        if chunk_id + 1 < num_chunks {
+            return None;
+        }
+
+        let position = self.chunked_messages.iter().position(|c| c.uuid == uuid)?;
+        let mut chunked = self.chunked_messages.remove(position)?;
+        if let Some(total_size) = chunked.payload.metadata.total_chunk_msg_size {
+            if chunked.payload.data.len() != total_size as usize {
+                warn!(
+                    "{} reassembled message {} has {} bytes instead of {}, asking for its redelivery",
+                    self.debug_format(),
+                    uuid,
+                    chunked.payload.data.len(),
+                    total_size
+                );
+                if let Err(e) = self
+                    .connection
+                    .sender()
+                    .send_redeliver_unacknowleged_messages(
+                        self.id,
+                        chunked.message_ids,
+                        self.max_redeliver_per_command(),
+                    )
+                    .await
+                {
+                    error!("could not ask for redelivery of message {}: {:?}", uuid, e);
+                }
+                return None;
+            }
+        }
+        // the last chunk's id identifies the whole message for the consumer
+        chunked.message_ids.pop();
+        if !chunked.message_ids.is_empty() {
+            self.chunked_message_ids
+                .insert(message.message_id.clone(), chunked.message_ids);
+        }
+        Some((message, chunked.payload))
+    }
+
+    fn max_redeliver_per_command(&self) -> usize {
+        self.options.max_redeliver_per_command.unwrap_or(2000)
+    }
+
+    /// asks the broker to send the messages again, along with the other
+    /// chunks of reassembled messages
+    async fn redeliver(&mut self, message_ids: Vec<MessageIdData>) -> Result<(), ConnectionError> {
+        let mut ids = Vec::with_capacity(message_ids.len());
+        for id in message_ids {
+            if let Some(chunk_ids) = self.chunked_message_ids.remove(&id) {
+                ids.extend(chunk_ids);
+            }
+            ids.push(id);
+        }
+        self.expect_redelivery(ids.iter());
+        self.connection
+            .sender()
+            .send_redeliver_unacknowleged_messages(self.id, ids, self.max_redeliver_per_command())
+            .await
+    }
+
+    /// records the messages asked again to the broker, so that
+    /// `dedup_by_broker_index` does not drop them when they come back
+    fn expect_redelivery<'a>(&mut self, ids: impl Iterator<Item = &'a MessageIdData>) {
+        if self.options.dedup_by_broker_index {
+            self.redelivery_requested
+                .extend(ids.map(|id| MessageIdData {
+                    batch_size: None,
+                    ..id.clone()
+                }));
+        }
+    }
+
+    async fn evict_chunked_message(&mut self, chunked: ChunkedMessage) {
+        warn!(
+            "{} has too many pending chunked messages, evicting message {}",
+            self.debug_format(),
+            chunked.uuid
+        );
+        let res = if self.options.auto_ack_oldest_on_queue_full {
+            self.connection
+                .sender()
+                .send_ack(self.id, chunked.message_ids, false)
+                .await
+        } else {
+            self.connection
+                .sender()
+                .send_redeliver_unacknowleged_messages(
+                    self.id,
+                    chunked.message_ids,
+                    self.max_redeliver_per_command(),
+                )
+                .await
+        };
+        if let Err(e) = res {
+            error!("could not evict chunked message {}: {:?}", chunked.uuid, e);
+        }
+    }
+
     async fn send_to_consumer(
         &mut self,
         message_id: MessageIdData,
         payload: Payload,
+        redelivery_count: u32,
     ) -> Result<(), Error> {
+        if let (Some(start), Some(inclusive)) = (
+            &self.start_message_id,
+            self.options.start_message_id_inclusive,
+        ) {
+            if before_start(&message_id, start, inclusive) {
+                trace!(
+                    "{} dropping message {:?} before the start message id",
+                    self.debug_format(),
+                    message_id
+                );
+                return Ok(());
+            }
+        }
+
+        if self.options.dedup_by_broker_index
+            && self.is_duplicate(&message_id, &payload, redelivery_count)
+        {
+            debug!(
+                "{} dropping duplicate message {:?}",
+                self.debug_format(),
+                message_id
+            );
+            self.ack(
+                MessageData {
+                    id: message_id,
+                    batch_size: None,
+                    redelivery_count,
+                },
+                false,
+            )
+            .await;
+            return Ok(());
+        }
+
         let now = Instant::now();
         self.tx
-            .send(Ok((message_id.clone(), payload)))
+            .send(Ok((message_id.clone(), payload, redelivery_count)))
             .await
             .map_err(|e| {
                 error!("tx returned {:?}", e);
                 Error::Custom("tx closed".to_string())
             })?;
-        if let Some(duration) = self.unacked_message_redelivery_delay {
-            self.unacked_messages.insert(message_id, now + duration);
+        // messages are tracked even without a redelivery delay, so that they can
+        // be redelivered right away if the consumer has to reconnect. Without a
+        // delay, the timer does not redeliver them, and only the most recent
+        // ones, up to the receiver queue size, are kept
+        let duration = self.unacked_message_redelivery_delay.unwrap_or_default();
+        self.unacked_messages.insert(message_id, now + duration);
+        if self.unacked_message_redelivery_delay.is_none()
+            && self.unacked_messages.len() > self.batch_size as usize
+        {
+            let oldest = self
+                .unacked_messages
+                .iter()
+                .min_by_key(|(_, received)| **received)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                self.unacked_messages.remove(&oldest);
+            }
         }
         Ok(())
     }
 
-    async fn reconnect(&mut self) -> Result<(), Error> {
+    /// returns true if the message's broker entry index was already seen,
+    /// otherwise records it as the highest index. Messages sent again because
+    /// this consumer asked for it, or that the broker marked as redelivered,
+    /// are not duplicates
+    fn is_duplicate(
+        &mut self,
+        message_id: &MessageIdData,
+        payload: &Payload,
+        redelivery_count: u32,
+    ) -> bool {
+        let requested = self.redelivery_requested.remove(&MessageIdData {
+            batch_size: None,
+            ..message_id.clone()
+        });
+        if requested || redelivery_count > 0 {
+            return false;
+        }
+
+        let index = match payload
+            .broker_entry_metadata
+            .as_ref()
+            .and_then(|entry| entry.index)
+        {
+            Some(index) => index,
+            None => return false,
+        };
+
+        match self.highest_broker_index {
+            Some(highest) if index <= highest => true,
+            _ => {
+                self.highest_broker_index = Some(index);
+                false
+            }
+        }
+    }
+
+    async fn reconnect(&mut self) -> Result<(), Error> {
         debug!("reconnecting consumer for topic: {}", self.topic);
         let broker_address = self.client.lookup_topic(&self.topic).await?;
         let conn = self.client.manager.get_connection(&broker_address).await?;
+        self.resubscribe(conn).await
+    }
 
+    /// subscribes again on `conn`, replacing the previous connection
+    async fn resubscribe(&mut self, conn: Arc<Connection<Exe>>) -> Result<(), Error> {
         self.connection = conn;
 
         let topic = self.topic.clone();
         let (resolver, messages) = mpsc::unbounded();
+        // a paused consumer gets its permits when it is resumed
+        let permits = if self.status.paused.load(Ordering::Acquire) {
+            0
+        } else {
+            self.batch_size
+        };
 
         self.connection
             .sender()
-            .subscribe(
+            .subscribe_with_flow(
                 resolver,
                 topic.clone(),
                 self.subscription.clone(),
@@ -1265,16 +2650,46 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
                 self.id,
                 self.name.clone(),
                 self.options.clone(),
+                permits,
             )
             .await
             .map_err(Error::Connection)?;
-
-        self.connection
-            .sender()
-            .send_flow(self.id, self.batch_size)
-            .map_err(|e| Error::Consumer(ConsumerError::Connection(e)))?;
+        self.remaining_messages = permits;
+        self.waiting_for_permits = false;
 
         self.messages_rx = Some(messages);
+        // the broker will send the chunks again on the new connection
+        self.chunked_messages.clear();
+        // along with the messages that were not acked, which are not
+        // duplicates for `dedup_by_broker_index`
+        self.highest_broker_index = None;
+
+        // messages delivered but not acked on the old connection would only be
+        // sent again once the broker notices they are unacked, so we ask for
+        // their redelivery now
+        if !self.unacked_messages.is_empty() {
+            let ids: Vec<_> = self.unacked_messages.keys().cloned().collect();
+            debug!(
+                "asking for redelivery of {} unacked messages after reconnection of {}",
+                ids.len(),
+                self.debug_format()
+            );
+            if let Err(e) = self
+                .connection
+                .sender()
+                .send_redeliver_unacknowleged_messages(
+                    self.id,
+                    ids,
+                    self.max_redeliver_per_command(),
+                )
+                .await
+            {
+                // with a redelivery delay, they are sent again once it expires
+                error!("could not ask for redelivery after reconnection: {:?}", e);
+            } else {
+                self.unacked_messages.clear();
+            }
+        }
 
         // drop_signal will be dropped when Consumer is dropped, then
         // drop_receiver will return, and we can close the consumer
@@ -1321,16 +2736,65 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
     }
 }
 
+/// backlog of a subscription on a topic, estimated from the last message id of the
+/// topic and the mark delete position of the subscription
+///
+/// Entry ids are only contiguous inside a ledger, so the number of entries is an
+/// approximation: if both positions are in the same ledger it is exact (counting
+/// batches as one entry), otherwise only the entries of the last ledger are
+/// counted. For an exact count, use the broker's admin API with the raw positions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Backlog {
+    pub topic: String,
+    /// id of the last message published on the topic
+    pub last_message_id: MessageIdData,
+    /// position up to which all messages were acked. Older brokers do not send it
+    pub mark_delete_position: Option<MessageIdData>,
+    /// approximate number of entries remaining, if the mark delete position is known
+    pub estimated_entries: Option<u64>,
+}
+
+impl Backlog {
+    fn new(
+        topic: String,
+        last_message_id: MessageIdData,
+        mark_delete_position: Option<MessageIdData>,
+    ) -> Self {
+        // number of entries of the ledger up to this one. The broker sends an
+        // entry id of -1, so u64::MAX, for a position before the first entry,
+        // as for the last message id when the last ledger is empty
+        let entries = |id: &MessageIdData| id.entry_id.wrapping_add(1);
+        let estimated_entries = mark_delete_position.as_ref().map(|mark_delete| {
+            if mark_delete.ledger_id == last_message_id.ledger_id {
+                entries(&last_message_id).saturating_sub(entries(mark_delete))
+            } else if mark_delete.ledger_id < last_message_id.ledger_id {
+                entries(&last_message_id)
+            } else {
+                0
+            }
+        });
+
+        Backlog {
+            topic,
+            last_message_id,
+            mark_delete_position,
+            estimated_entries,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct MessageData {
     pub id: proto::MessageIdData,
     batch_size: Option<i32>,
+    redelivery_count: u32,
 }
 
 struct BatchedMessageIterator {
     messages: std::vec::IntoIter<BatchedMessage>,
     message_id: proto::MessageIdData,
     metadata: Metadata,
+    broker_entry_metadata: Option<proto::BrokerEntryMetadata>,
     total_messages: u32,
     current_index: u32,
 }
@@ -1342,12 +2806,14 @@ impl BatchedMessageIterator {
             .num_messages_in_batch
             .expect("expected batched message") as u32;
         let messages = parse_batched_message(total_messages, &payload.data)?;
+        let broker_entry_metadata = payload.broker_entry_metadata.clone();
 
         Ok(Self {
             messages: messages.into_iter(),
             message_id,
             total_messages,
             metadata: payload.metadata,
+            broker_entry_metadata,
             current_index: 0,
         })
     }
@@ -1376,10 +2842,22 @@ impl Iterator for BatchedMessageIterator {
                 ..self.metadata.clone()
             };
 
-            let payload = Payload {
-                metadata,
-                data: batched_message.payload,
-            };
+            // the broker index is the one of the last message in the batch
+            let last_index = self.total_messages - 1;
+            let broker_entry_metadata =
+                self.broker_entry_metadata
+                    .clone()
+                    .map(|entry| proto::BrokerEntryMetadata {
+                        index: entry
+                            .index
+                            .map(|i| i.saturating_sub((last_index - index) as u64)),
+                        ..entry
+                    });
+
+            let mut payload = Payload::new(metadata, batched_message.payload);
+            if let Some(broker_entry_metadata) = broker_entry_metadata {
+                payload = payload.with_broker_entry_metadata(broker_entry_metadata);
+            }
 
             Some((id, payload))
         } else {
@@ -1403,6 +2881,7 @@ pub struct ConsumerBuilder<Exe: Executor> {
     batch_size: Option<u32>,
     unacked_message_resend_delay: Option<Duration>,
     dead_letter_policy: Option<DeadLetterPolicy>,
+    flow_permits_exhausted: Option<FlowPermitsCallback>,
     consumer_options: Option<ConsumerOptions>,
     namespace: Option<String>,
     topic_refresh: Option<Duration>,
@@ -1423,6 +2902,7 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
             //TODO what should this default to? None seems incorrect..
             unacked_message_resend_delay: None,
             dead_letter_policy: None,
+            flow_permits_exhausted: None,
             consumer_options: None,
             namespace: None,
             topic_refresh: None,
@@ -1481,13 +2961,15 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
         self
     }
 
-    /// Interval for refreshing the topics when using a topic regex. Unused otherwise.
+    /// Interval for refreshing the topics when using a topic regex, and the
+    /// partitions of partitioned topics. Unused otherwise.
     pub fn with_topic_refresh(mut self, refresh_interval: Duration) -> Self {
         self.topic_refresh = Some(refresh_interval);
         self
     }
 
-    /// sets the consumer id for this consumer
+    /// sets the consumer id for this consumer. Only allowed for a single,
+    /// non partitioned topic, since each consumer needs its own id
     pub fn with_consumer_id(mut self, consumer_id: u64) -> Self {
         self.consumer_id = Some(consumer_id);
         self
@@ -1522,6 +3004,16 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
         self
     }
 
+    /// manages flow control manually: instead of granting permits automatically, the
+    /// callback is called when the consumer has received all the messages it allowed
+    pub fn with_flow_permits_exhausted_callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(u64, u32) -> u32 + Send + Sync + 'static,
+    {
+        self.flow_permits_exhausted = Some(FlowPermitsCallback::new(callback));
+        self
+    }
+
     /// The time after which a message is dropped without being acknowledged or nacked
     /// that the message is resent. If `None`, messages will only be resent when a
     /// consumer disconnects with pending unacknowledged messages.
@@ -1547,6 +3039,7 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
             unacked_message_resend_delay,
             consumer_options,
             dead_letter_policy,
+            flow_permits_exhausted,
             namespace: _,
             topic_refresh: _,
         } = self;
@@ -1565,6 +3058,10 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
             ));
         }
 
+        if let Some(options) = consumer_options.as_ref() {
+            options.check(subscription_type.unwrap_or(SubType::Shared))?;
+        }
+
         let topics: Vec<(String, BrokerAddress)> = try_join_all(
             topics
                 .into_iter()
@@ -1607,15 +3104,18 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
             SubType::Shared
         });
 
+        let options = consumer_options.unwrap_or_default();
         let config = ConsumerConfig {
             subscription,
             sub_type,
-            batch_size,
+            batch_size: batch_size.or(options.receiver_queue_size),
             consumer_name,
             consumer_id,
-            unacked_message_redelivery_delay: unacked_message_resend_delay,
-            options: consumer_options.unwrap_or_default(),
+            unacked_message_redelivery_delay: unacked_message_resend_delay.or(options.ack_timeout),
+            options,
             dead_letter_policy,
+            flow_permits_exhausted,
+            paused: false,
         };
         Ok((config, topics))
     }
@@ -1625,12 +3125,33 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
         // would this clone() consume too much memory?
         let (config, joined_topics) = self.clone().validate::<T>().await?;
 
+        // partitions can be added to these topics later
+        let partitioned_topics: Vec<String> = self
+            .topics
+            .iter()
+            .flatten()
+            .filter(|topic| {
+                let prefix = format!("{}-partition-", topic);
+                joined_topics.iter().any(|(t, _)| t.starts_with(&prefix))
+            })
+            .cloned()
+            .collect();
+        // each topic and partition has its own consumer, including the
+        // partitions added later, they cannot share an id
+        if config.consumer_id.is_some()
+            && (joined_topics.len() > 1 || !partitioned_topics.is_empty())
+        {
+            return Err(Error::Custom(
+                "Cannot set a consumer id for several topics or a partitioned topic".to_string(),
+            ));
+        }
+
         let consumers = try_join_all(joined_topics.into_iter().map(|(topic, addr)| {
             TopicConsumer::new(self.pulsar.clone(), topic, addr, config.clone())
         }))
         .await?;
 
-        let consumer = if consumers.len() == 1 {
+        let consumer = if consumers.len() == 1 && partitioned_topics.is_empty() {
             let consumer = consumers.into_iter().next().unwrap();
             InnerConsumer::Single(consumer)
         } else {
@@ -1648,19 +3169,24 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
                     .namespace
                     .unwrap_or_else(|| "public/default".to_string()),
                 topic_regex: self.topic_regex,
+                partitioned_topics,
                 pulsar: self.pulsar,
                 consumers,
                 topics,
                 new_consumers: None,
+                topics_hash: None,
                 refresh,
                 config,
                 disc_last_message_received: None,
                 disc_messages_received: 0,
+                paused: false,
             };
             if consumer.topic_regex.is_some() {
                 consumer.update_topics();
-                let initial_consumers = consumer.new_consumers.take().unwrap().await?;
+                let (initial_consumers, _, topics_hash) =
+                    consumer.new_consumers.take().unwrap().await?;
                 consumer.add_consumers(initial_consumers);
+                consumer.topics_hash = topics_hash;
             }
             InnerConsumer::Multi(consumer)
         };
@@ -1669,13 +3195,15 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
 
     /// creates a [Reader] from this builder
     pub async fn into_reader<T: DeserializeMessage>(self) -> Result<Reader<T, Exe>, Error> {
-        // would this clone() consume too much memory?
-        let (mut config, mut joined_topics) = self.clone().validate::<T>().await?;
-
         // the validate() function defaults sub_type to SubType::Shared,
         // but a reader's subscription is exclusive
-        warn!("Subscription Type for a reader is `Exclusive`. Resetting.");
-        config.sub_type = SubType::Exclusive;
+        let mut builder = self.clone();
+        if builder.subscription_type != Some(SubType::Exclusive) {
+            warn!("Subscription Type for a reader is `Exclusive`. Resetting.");
+            builder.subscription_type = Some(SubType::Exclusive);
+        }
+        builder.consumer_options = Some(reader_options(builder.consumer_options));
+        let (config, mut joined_topics) = builder.validate::<T>().await?;
 
         if self.topics.unwrap().len() > 1 {
             return Err(Error::Custom(
@@ -1693,6 +3221,32 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
     }
 }
 
+/// a reader starting from a message id needs a non durable subscription,
+/// which is used unless the options say otherwise. Other readers keep the
+/// broker's default
+fn reader_options(options: Option<ConsumerOptions>) -> ConsumerOptions {
+    let mut options = options.unwrap_or_default();
+    if options.durable.is_none() && options.start_message_id.is_some() {
+        options.durable = Some(false);
+    }
+    options
+}
+
+/// configuration of the consumers recreated by a seek: they start from the
+/// seek position, not from the start message id of the options, and stay
+/// paused if the consumer was
+fn seek_config(
+    config: &ConsumerConfig,
+    message_id: Option<&MessageIdData>,
+    paused: bool,
+) -> ConsumerConfig {
+    let mut config = config.clone();
+    config.options.start_message_id = message_id.cloned();
+    config.options.start_message_id_inclusive = message_id.map(|_| true);
+    config.paused = paused;
+    config
+}
+
 /// the complete configuration of a consumer
 #[derive(Debug, Clone, Default)]
 pub(crate) struct ConsumerConfig {
@@ -1716,23 +3270,51 @@ pub(crate) struct ConsumerConfig {
     pub(crate) options: ConsumerOptions,
     /// dead letter policy
     dead_letter_policy: Option<DeadLetterPolicy>,
+    /// manual flow control
+    flow_permits_exhausted: Option<FlowPermitsCallback>,
+    /// the consumer starts paused, without granting flow permits
+    paused: bool,
 }
 
 /// A consumer that can subscribe on multiple topics, from a regex matching topic names
+///
+/// Messages are read from each topic in turn, the ones from a topic or a
+/// partition are received in order
 struct MultiTopicConsumer<T: DeserializeMessage, Exe: Executor> {
     namespace: String,
     topic_regex: Option<Regex>,
+    /// partitioned topics whose new partitions are subscribed at each refresh
+    partitioned_topics: Vec<String>,
     pulsar: Pulsar<Exe>,
     consumers: BTreeMap<String, Pin<Box<TopicConsumer<T, Exe>>>>,
     topics: VecDeque<String>,
+    /// consumers for the new topics matching the regex and the new
+    /// partitions, the topics that
+    /// were deleted since the last refresh, and the hash of the namespace's
+    /// topics
     #[allow(clippy::type_complexity)]
-    new_consumers:
-        Option<Pin<Box<dyn Future<Output = Result<Vec<TopicConsumer<T, Exe>>, Error>> + Send>>>,
+    new_consumers: Option<
+        Pin<
+            Box<
+                dyn Future<
+                        Output = Result<
+                            (Vec<TopicConsumer<T, Exe>>, Vec<String>, Option<String>),
+                            Error,
+                        >,
+                    > + Send,
+            >,
+        >,
+    >,
+    /// hash of the namespace's topics at the last refresh, the consumers
+    /// are not updated while it does not change
+    topics_hash: Option<String>,
     refresh: Pin<Box<dyn Stream<Item = ()> + Send>>,
     config: ConsumerConfig,
     // Stats on disconnected consumers to keep metrics correct
     disc_messages_received: u64,
     disc_last_message_received: Option<DateTime<Utc>>,
+    /// set by `Consumer::pause`, applied to the consumers created later
+    paused: bool,
 }
 
 impl<T: DeserializeMessage, Exe: Executor> MultiTopicConsumer<T, Exe> {
@@ -1782,6 +3364,10 @@ impl<T: DeserializeMessage, Exe: Executor> MultiTopicConsumer<T, Exe> {
         Ok(responses)
     }
 
+    async fn get_backlog(&mut self) -> Result<Vec<Backlog>, Error> {
+        try_join_all(self.consumers.values_mut().map(|c| c.get_backlog())).await
+    }
+
     async fn unsubscribe(&mut self) -> Result<(), Error> {
         for consumer in self.consumers.values_mut() {
             consumer.unsubscribe().await?;
@@ -1792,6 +3378,9 @@ impl<T: DeserializeMessage, Exe: Executor> MultiTopicConsumer<T, Exe> {
 
     fn add_consumers<I: IntoIterator<Item = TopicConsumer<T, Exe>>>(&mut self, consumers: I) {
         for consumer in consumers {
+            if self.paused {
+                consumer.pause();
+            }
             let topic = consumer.topic().to_owned();
             self.consumers.insert(topic.clone(), Box::pin(consumer));
             self.topics.push_back(topic);
@@ -1799,6 +3388,10 @@ impl<T: DeserializeMessage, Exe: Executor> MultiTopicConsumer<T, Exe> {
     }
 
     fn remove_consumers(&mut self, topics: &[String]) {
+        if !topics.is_empty() {
+            // the next refresh must create consumers for these topics again
+            self.topics_hash = None;
+        }
         self.topics.retain(|t| !topics.contains(t));
         for topic in topics {
             if let Some(consumer) = self.consumers.remove(topic) {
@@ -1813,47 +3406,100 @@ impl<T: DeserializeMessage, Exe: Executor> MultiTopicConsumer<T, Exe> {
     }
 
     fn update_topics(&mut self) {
-        if let Some(regex) = self.topic_regex.clone() {
-            let pulsar = self.pulsar.clone();
-            let namespace = self.namespace.clone();
-            let existing_topics: BTreeSet<String> = self.consumers.keys().cloned().collect();
-            let consumer_config = self.config.clone();
-
-            self.new_consumers = Some(Box::pin(async move {
-                let topics = pulsar
-                    .get_topics_of_namespace(
+        if self.topic_regex.is_none() && self.partitioned_topics.is_empty() {
+            return;
+        }
+
+        let regex = self.topic_regex.clone();
+        let partitioned_topics = self.partitioned_topics.clone();
+        let pulsar = self.pulsar.clone();
+        let namespace = self.namespace.clone();
+        let existing_topics: BTreeSet<String> = self.consumers.keys().cloned().collect();
+        let consumer_config = self.config.clone();
+        let previous_hash = self.topics_hash.clone();
+
+        self.new_consumers = Some(Box::pin(async move {
+            // partitions can be added to a partitioned topic, not removed,
+            // only the new ones are looked up
+            let partitions = try_join_all(
+                partitioned_topics
+                    .iter()
+                    .map(|topic| pulsar.lookup_partitioned_topic_number(topic.clone())),
+            )
+            .await?;
+            let new_partitions = partitioned_topics
+                .iter()
+                .zip(partitions)
+                .flat_map(|(topic, partitions)| {
+                    (0..partitions).map(move |n| format!("{}-partition-{}", topic, n))
+                })
+                .filter(|partition| !existing_topics.contains(partition));
+            let pulsar = &pulsar;
+            let mut topics = try_join_all(new_partitions.map(|partition| async move {
+                let addr = pulsar.lookup_topic(partition.clone()).await?;
+                Ok::<_, Error>((partition, addr))
+            }))
+            .await?;
+
+            let mut deleted_topics = Vec::new();
+            let mut topics_hash = None;
+            if let Some(regex) = regex {
+                let namespace_topics = pulsar
+                    .get_topics_of_namespace_with_hash(
                         namespace.clone(),
                         proto::command_get_topics_of_namespace::Mode::All,
+                        previous_hash.clone(),
                     )
                     .await?;
-                trace!("fetched topics {:?}", topics);
+                topics_hash = namespace_topics.hash.clone();
 
-                let topics: Vec<_> = try_join_all(
-                    topics
-                        .into_iter()
-                        .filter(|t| regex.is_match(t))
-                        .map(|topic| pulsar.lookup_partitioned_topic(topic)),
-                )
-                .await?
-                .into_iter()
-                .flatten()
-                .collect();
+                if namespace_topics.changed_since(&previous_hash) {
+                    trace!("fetched topics {:?}", namespace_topics.topics);
 
-                trace!("matched topics {:?} (regex: {})", topics, &regex);
+                    let matched_topics: Vec<_> = try_join_all(
+                        namespace_topics
+                            .topics
+                            .into_iter()
+                            .filter(|t| regex.is_match(t))
+                            .map(|topic| pulsar.lookup_partitioned_topic(topic)),
+                    )
+                    .await?
+                    .into_iter()
+                    .flatten()
+                    .collect();
 
-                let consumers = try_join_all(
-                    topics
-                        .into_iter()
-                        .filter(|(t, _)| !existing_topics.contains(t))
-                        .map(|(topic, addr)| {
-                            TopicConsumer::new(pulsar.clone(), topic, addr, consumer_config.clone())
-                        }),
-                )
-                .await?;
-                trace!("created {} consumers", consumers.len());
-                Ok(consumers)
-            }));
-        }
+                    trace!("matched topics {:?} (regex: {})", matched_topics, &regex);
+
+                    // consumers on topics that matched the regex but do not exist
+                    // anymore are dropped
+                    deleted_topics = existing_topics
+                        .iter()
+                        .filter(|t| {
+                            regex.is_match(t)
+                                && !matched_topics.iter().any(|(topic, _)| topic == *t)
+                        })
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    topics.extend(matched_topics);
+                } else {
+                    trace!("topics of namespace {} did not change", namespace);
+                }
+            }
+
+            // a partition can be listed by a partitioned topic and match the regex
+            let mut new_topics = BTreeSet::new();
+            let consumers = try_join_all(
+                topics
+                    .into_iter()
+                    .filter(|(t, _)| !existing_topics.contains(t) && new_topics.insert(t.clone()))
+                    .map(|(topic, addr)| {
+                        TopicConsumer::new(pulsar.clone(), topic, addr, consumer_config.clone())
+                    }),
+            )
+            .await?;
+            trace!("created {} consumers", consumers.len());
+            Ok((consumers, deleted_topics, topics_hash))
+        }));
     }
 
     async fn ack(&mut self, msg: &Message<T>) -> Result<(), ConsumerError> {
@@ -1864,6 +3510,18 @@ impl<T: DeserializeMessage, Exe: Executor> MultiTopicConsumer<T, Exe> {
         }
     }
 
+    async fn ack_batch_entry(
+        &mut self,
+        msg: &Message<T>,
+        batch_index: i32,
+    ) -> Result<(), ConsumerError> {
+        if let Some(c) = self.consumers.get_mut(&msg.topic) {
+            c.ack_batch_entry(msg, batch_index).await
+        } else {
+            Err(ConnectionError::Unexpected(format!("no consumer for topic {}", msg.topic)).into())
+        }
+    }
+
     async fn cumulative_ack(&mut self, msg: &Message<T>) -> Result<(), ConsumerError> {
         if let Some(c) = self.consumers.get_mut(&msg.topic) {
             c.cumulative_ack(msg).await
@@ -1872,6 +3530,13 @@ impl<T: DeserializeMessage, Exe: Executor> MultiTopicConsumer<T, Exe> {
         }
     }
 
+    async fn ack_cumulative(&mut self) -> Result<(), ConsumerError> {
+        for c in self.consumers.values_mut() {
+            c.ack_cumulative().await?;
+        }
+        Ok(())
+    }
+
     async fn nack(&mut self, msg: &Message<T>) -> Result<(), ConsumerError> {
         if let Some(c) = self.consumers.get_mut(&msg.topic) {
             c.nack(msg).await?;
@@ -1881,6 +3546,33 @@ impl<T: DeserializeMessage, Exe: Executor> MultiTopicConsumer<T, Exe> {
         }
     }
 
+    async fn nack_with_delay(
+        &mut self,
+        msg: &Message<T>,
+        delay: Duration,
+    ) -> Result<(), ConsumerError> {
+        if let Some(c) = self.consumers.get_mut(&msg.topic) {
+            c.nack_with_delay(msg, delay).await
+        } else {
+            Err(ConnectionError::Unexpected(format!("no consumer for topic {}", msg.topic)).into())
+        }
+    }
+
+    async fn grant_permits(&mut self, consumer_id: u64, permits: u32) -> Result<(), ConsumerError> {
+        match self
+            .consumers
+            .values_mut()
+            .find(|c| c.consumer_id == consumer_id)
+        {
+            Some(c) => c.grant_permits(consumer_id, permits).await,
+            None => Err(ConnectionError::Unexpected(format!(
+                "no consumer with id {}",
+                consumer_id
+            ))
+            .into()),
+        }
+    }
+
     /// Assume that this seek method will call seek for the topics given in the consumer_ids
     async fn seek(
         &mut self,
@@ -1950,7 +3642,67 @@ impl<T> Message<T> {
     pub fn key(&self) -> Option<String> {
         self.payload.metadata.partition_key.clone()
     }
+
+    /// index of the partition the message was received from, if its topic
+    /// is partitioned
+    pub fn partition(&self) -> Option<u32> {
+        let (_, index) = self.topic.rsplit_once("-partition-")?;
+        index.parse().ok()
+    }
+
+    /// number of times the broker delivered this message before, after it
+    /// was negatively acknowledged or its ack timed out
+    pub fn redelivery_count(&self) -> u32 {
+        self.message_id.redelivery_count
+    }
+
+    /// time the event happened, if the producer set it
+    pub fn event_time(&self) -> Option<DateTime<Utc>> {
+        self.payload
+            .metadata
+            .event_time
+            .filter(|time| *time > 0)
+            .and_then(millis_to_date)
+    }
+
+    /// time the producer published the message
+    pub fn publish_time(&self) -> Option<DateTime<Utc>> {
+        millis_to_date(self.payload.metadata.publish_time)
+    }
+
+    /// user defined properties
+    pub fn properties(&self) -> HashMap<String, String> {
+        self.payload
+            .metadata
+            .properties
+            .iter()
+            .map(|kv| (kv.key.clone(), kv.value.clone()))
+            .collect()
+    }
+
+    /// type of the marker, if this is a replication or transaction marker
+    /// message (values of Pulsar's `MarkerType`)
+    pub fn marker_type(&self) -> Option<i32> {
+        self.payload.metadata.marker_type
+    }
+
+    /// returns true for replication and transaction markers, which do not
+    /// contain application data and can be skipped
+    pub fn is_marker(&self) -> bool {
+        self.payload.metadata.marker_type.is_some()
+    }
+
+    /// metadata added by the broker when storing the message, if the broker
+    /// exposes it to clients
+    pub fn broker_entry_metadata(&self) -> Option<&proto::BrokerEntryMetadata> {
+        self.payload.broker_entry_metadata.as_ref()
+    }
+}
+
+fn millis_to_date(millis: u64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_millis_opt(millis as i64).single()
 }
+
 impl<T: DeserializeMessage> Message<T> {
     /// directly deserialize a message
     pub fn deserialize(&self) -> T::Output {
@@ -1974,8 +3726,13 @@ impl<T: 'static + DeserializeMessage, Exe: Executor> Stream for MultiTopicConsum
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         if let Some(mut new_consumers) = self.new_consumers.take() {
             match new_consumers.as_mut().poll(cx) {
-                Poll::Ready(Ok(new_consumers)) => {
+                Poll::Ready(Ok((new_consumers, deleted_topics, topics_hash))) => {
                     self.add_consumers(new_consumers);
+                    if !deleted_topics.is_empty() {
+                        debug!("dropping consumers for deleted topics {:?}", deleted_topics);
+                        self.remove_consumers(&deleted_topics);
+                    }
+                    self.topics_hash = topics_hash;
                 }
                 Poll::Pending => {
                     self.new_consumers = Some(new_consumers);
@@ -2034,6 +3791,79 @@ impl<T: 'static + DeserializeMessage, Exe: Executor> Stream for MultiTopicConsum
     }
 }
 
+/// checks that Key_Shared hash ranges are valid and do not overlap
+fn check_hash_ranges(hash_ranges: &[proto::IntRange]) -> Result<(), String> {
+    let mut ranges: Vec<_> = hash_ranges.iter().collect();
+    ranges.sort_by_key(|range| range.start);
+
+    let mut previous: Option<&proto::IntRange> = None;
+    for range in ranges {
+        if range.start < 0 || range.end > 65535 || range.start > range.end {
+            return Err(format!(
+                "invalid Key_Shared hash range [{}, {}], ranges must be within [0, 65535]",
+                range.start, range.end
+            ));
+        }
+        if let Some(previous) = previous {
+            if range.start <= previous.end {
+                return Err(format!(
+                    "Key_Shared hash ranges [{}, {}] and [{}, {}] overlap",
+                    previous.start, previous.end, range.start, range.end
+                ));
+            }
+        }
+        previous = Some(range);
+    }
+    Ok(())
+}
+
+/// orders message ids by ledger, entry and index in the batch
+///
+/// a message that is not part of a batch comes before the messages of a
+/// batch stored in the same entry
+pub(crate) fn compare_message_ids(a: &MessageIdData, b: &MessageIdData) -> std::cmp::Ordering {
+    (a.ledger_id, a.entry_id, a.batch_index.unwrap_or(-1)).cmp(&(
+        b.ledger_id,
+        b.entry_id,
+        b.batch_index.unwrap_or(-1),
+    ))
+}
+
+/// tells if a message is not delivered to a subscription starting at `start`
+///
+/// messages before `start` are skipped, as is the start message itself
+/// unless `inclusive` is true. A start message id outside of a batch stands
+/// for its whole entry: every message of that entry compares equal to it
+pub(crate) fn before_start(
+    message_id: &MessageIdData,
+    start: &MessageIdData,
+    inclusive: bool,
+) -> bool {
+    match (message_id.ledger_id, message_id.entry_id).cmp(&(start.ledger_id, start.entry_id)) {
+        std::cmp::Ordering::Less => true,
+        std::cmp::Ordering::Greater => false,
+        std::cmp::Ordering::Equal => match start.batch_index {
+            Some(start_index) if start_index >= 0 => {
+                let index = message_id.batch_index.unwrap_or(-1);
+                index < start_index || (index == start_index && !inclusive)
+            }
+            _ => !inclusive,
+        },
+    }
+}
+
+/// ids of the unacked messages whose redelivery deadline has passed
+fn expired_messages(
+    unacked_messages: &HashMap<MessageIdData, Instant>,
+    now: Instant,
+) -> HashSet<MessageIdData> {
+    unacked_messages
+        .iter()
+        .filter(|(_, deadline)| **deadline < now)
+        .map(|(id, _)| id.clone())
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use std::time::{SystemTime, UNIX_EPOCH};
@@ -2078,6 +3908,1405 @@ mod tests {
     pub static MULTI_LOGGER: crate::tests::SimpleLogger = crate::tests::SimpleLogger {
         tag: "multi_consumer",
     };
+
+    /// consumer engine on a test connection, with the channels around it
+    #[cfg(feature = "tokio-runtime")]
+    struct TestEngine {
+        engine: ConsumerEngine<TokioExecutor>,
+        /// commands sent by the engine
+        outbound: futures::stream::BoxStream<'static, RawMessage>,
+        /// messages received from the broker
+        messages_tx: mpsc::UnboundedSender<RawMessage>,
+        engine_tx: mpsc::UnboundedSender<EngineMessage<TokioExecutor>>,
+        /// messages sent to the consumer
+        rx: mpsc::Receiver<Result<(MessageIdData, Payload, u32), Error>>,
+        status: Arc<ConsumerStatus>,
+        _drop_receiver: oneshot::Receiver<()>,
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    impl TestEngine {
+        /// engine of the exclusive consumer 1, which was granted
+        /// `receiver_queue_size` flow permits
+        fn new(
+            options: ConsumerOptions,
+            unacked_message_redelivery_delay: Option<Duration>,
+        ) -> Self {
+            let (connection, outbound) = crate::connection::tests::test_connection();
+            let (tx, rx) = mpsc::channel(1000);
+            let (messages_tx, messages_rx) = mpsc::unbounded();
+            let (engine_tx, engine_rx) = mpsc::unbounded();
+            let (drop_signal, _drop_receiver) = oneshot::channel();
+            let status = Arc::new(ConsumerStatus {
+                active: AtomicBool::new(true),
+                reached_end_of_topic: AtomicBool::new(false),
+                paused: AtomicBool::new(false),
+            });
+            let batch_size = options.receiver_queue_size.unwrap_or(1000);
+            let engine = ConsumerEngine::new(
+                Pulsar::disconnected(TokioExecutor),
+                connection,
+                "test".to_string(),
+                "test".to_string(),
+                SubType::Exclusive,
+                1,
+                None,
+                tx,
+                messages_rx,
+                engine_rx,
+                batch_size,
+                None,
+                unacked_message_redelivery_delay,
+                None,
+                options,
+                status.clone(),
+                drop_signal,
+            );
+            TestEngine {
+                engine,
+                outbound: Box::pin(outbound),
+                messages_tx,
+                engine_tx,
+                rx,
+                status,
+                _drop_receiver,
+            }
+        }
+
+        /// runs the engine until `f` completes
+        async fn run<F: Future>(engine: &mut ConsumerEngine<TokioExecutor>, f: F) -> F::Output {
+            let engine = engine.engine();
+            pin_mut!(engine);
+            pin_mut!(f);
+            match timeout(Duration::from_secs(5), select(engine, f))
+                .await
+                .unwrap()
+            {
+                Either::Left((res, _)) => panic!("the engine stopped: {:?}", res),
+                Either::Right((output, _)) => output,
+            }
+        }
+
+        /// commands sent by the engine since the last call
+        fn sent_commands(&mut self) -> Vec<BaseCommand> {
+            let mut commands = Vec::new();
+            while let Some(Some(message)) = self.outbound.next().now_or_never() {
+                commands.push(message.command);
+            }
+            commands
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn ack_adapters() {
+        // consumer that already received the messages `entry_ids`
+        let received = |entry_ids: &[u64]| {
+            let (engine_tx, engine_rx) = mpsc::unbounded();
+            let (mut messages_tx, messages) = mpsc::channel(entry_ids.len());
+            for entry_id in entry_ids {
+                let id = MessageIdData {
+                    ledger_id: 1,
+                    entry_id: *entry_id,
+                    ..Default::default()
+                };
+                messages_tx
+                    .try_send(Ok((id, Payload::new(Default::default(), Vec::new()), 0)))
+                    .unwrap();
+            }
+            let consumer = Consumer {
+                inner: InnerConsumer::Single(topic_consumer::<Vec<u8>>(engine_tx, messages)),
+            };
+            (consumer, engine_rx)
+        };
+        // entry ids of the messages acked so far
+        let acked = |engine_rx: &mut mpsc::UnboundedReceiver<EngineMessage<TokioExecutor>>| {
+            let mut entry_ids = Vec::new();
+            while let Some(Some(message)) = engine_rx.next().now_or_never() {
+                if let EngineMessage::Ack(message_id, false) = message {
+                    entry_ids.push(message_id.id.entry_id);
+                }
+            }
+            entry_ids
+        };
+
+        // a message is acked once the next one is requested
+        let (consumer, mut engine_rx) = received(&[1, 2]);
+        let mut stream = consumer.auto_ack();
+        let msg = stream.next().await.unwrap().unwrap();
+        assert_eq!(msg.message_id.id.entry_id, 1);
+        assert!(acked(&mut engine_rx).is_empty());
+        let msg = stream.next().await.unwrap().unwrap();
+        assert_eq!(msg.message_id.id.entry_id, 2);
+        assert_eq!(acked(&mut engine_rx), vec![1]);
+
+        // a message is acked when dropped
+        let (consumer, mut engine_rx) = received(&[1]);
+        let mut stream = consumer.ack_on_drop();
+        let msg = stream.next().await.unwrap().unwrap();
+        assert_eq!(msg.message_id.id.entry_id, 1);
+        assert!(acked(&mut engine_rx).is_empty());
+        drop(msg);
+        assert_eq!(acked(&mut engine_rx), vec![1]);
+
+        // processing stops at the first error, that message is not acked
+        let (mut consumer, mut engine_rx) = received(&[1, 2, 3]);
+        let res = consumer
+            .try_for_each_ack(|msg| async move {
+                match msg.message_id.id.entry_id {
+                    2 => Err(Error::Consumer(ConsumerError::Closed)),
+                    _ => Ok(()),
+                }
+            })
+            .await;
+        assert!(matches!(res, Err(Error::Consumer(ConsumerError::Closed))));
+        assert_eq!(acked(&mut engine_rx), vec![1]);
+    }
+
+    #[test]
+    fn backlog_estimation() {
+        let id = |ledger_id, entry_id| MessageIdData {
+            ledger_id,
+            entry_id,
+            ..Default::default()
+        };
+        let estimate = |mark_delete_position| {
+            Backlog::new("test".to_string(), id(3, 10), mark_delete_position).estimated_entries
+        };
+
+        // exact in the same ledger
+        assert_eq!(estimate(Some(id(3, 4))), Some(6));
+        assert_eq!(estimate(Some(id(3, 10))), Some(0));
+        // only the entries of the last ledger are counted
+        assert_eq!(estimate(Some(id(2, 50))), Some(11));
+        assert_eq!(estimate(Some(id(4, 0))), Some(0));
+        // nothing was acked in the last ledger yet
+        assert_eq!(estimate(Some(id(3, u64::MAX))), Some(11));
+        // the last ledger is empty
+        let empty = |mark_delete_position| {
+            Backlog::new("test".to_string(), id(3, u64::MAX), mark_delete_position)
+                .estimated_entries
+        };
+        assert_eq!(empty(Some(id(2, 50))), Some(0));
+        assert_eq!(empty(Some(id(3, u64::MAX))), Some(0));
+        // older brokers do not send the mark delete position
+        assert_eq!(estimate(None), None);
+    }
+
+    #[test]
+    fn start_message_id() {
+        let id = |entry_id, batch_index| MessageIdData {
+            ledger_id: 1,
+            entry_id,
+            batch_index,
+            ..Default::default()
+        };
+
+        let start = id(5, None);
+        assert!(before_start(&id(4, None), &start, true));
+        assert!(before_start(&id(5, None), &start, false));
+        assert!(!before_start(&id(5, None), &start, true));
+        assert!(!before_start(&id(6, None), &start, false));
+        // the start message id stands for the whole batch
+        assert!(before_start(&id(5, Some(2)), &start, false));
+        assert!(!before_start(&id(5, Some(0)), &start, true));
+
+        let start = id(5, Some(2));
+        assert!(before_start(&id(5, Some(1)), &start, true));
+        assert!(before_start(&id(5, Some(2)), &start, false));
+        assert!(!before_start(&id(5, Some(2)), &start, true));
+        assert!(!before_start(&id(5, Some(3)), &start, false));
+
+        // a reader that started at the last message still has to read it
+        let last = id(5, None);
+        assert!(!before_start(&last, &id(5, None), true));
+        assert!(!before_start(&last, &id(4, None), false));
+        assert!(before_start(&last, &id(5, None), false));
+        assert!(before_start(&last, &id(6, None), true));
+    }
+
+    #[test]
+    fn invalid_options() {
+        let compacted = ConsumerOptions::default().read_compacted(true);
+        assert!(compacted.check(SubType::Exclusive).is_ok());
+        assert!(compacted.check(SubType::Failover).is_ok());
+        assert!(matches!(
+            compacted.check(SubType::KeyShared),
+            Err(ConsumerError::InvalidOptions(_))
+        ));
+
+        // the broker ignores the start message id of durable subscriptions
+        let start = ConsumerOptions::default().starting_on_message(MessageIdData::default());
+        assert!(start.check(SubType::Exclusive).is_ok());
+        assert!(start
+            .clone()
+            .durable(true)
+            .check(SubType::Exclusive)
+            .is_err());
+        assert!(start.durable(false).check(SubType::Exclusive).is_ok());
+
+        let key_shared = ConsumerOptions::default().with_key_shared_meta(proto::KeySharedMeta {
+            hash_ranges: vec![proto::IntRange {
+                start: 0,
+                end: 70000,
+            }],
+            ..Default::default()
+        });
+        assert!(key_shared.check(SubType::KeyShared).is_err());
+    }
+
+    #[test]
+    fn options_builder() {
+        let options = ConsumerOptionsBuilder::new(SubType::Shared)
+            .build()
+            .unwrap();
+        assert_eq!(options.durable, Some(true));
+        assert!(matches!(options.initial_position, InitialPosition::Latest));
+
+        assert!(matches!(
+            ConsumerOptionsBuilder::new(SubType::Shared)
+                .read_compacted(true)
+                .build(),
+            Err(ConsumerError::InvalidOptions(_))
+        ));
+        assert!(matches!(
+            ConsumerOptionsBuilder::new(SubType::Exclusive)
+                .starting_on_message(MessageIdData::default())
+                .build(),
+            Err(ConsumerError::InvalidOptions(_))
+        ));
+        let options = ConsumerOptionsBuilder::new(SubType::Exclusive)
+            .durable(false)
+            .starting_on_message(MessageIdData::default())
+            .with_initial_position(InitialPosition::Earliest)
+            .build()
+            .unwrap();
+        assert_eq!(options.durable, Some(false));
+        assert!(matches!(
+            options.initial_position,
+            InitialPosition::Earliest
+        ));
+    }
+
+    #[test]
+    fn reader_options_not_durable() {
+        let options = reader_options(None);
+        assert_eq!(options.durable, None);
+
+        let start = ConsumerOptions::default().starting_on_message(MessageIdData::default());
+        let options = reader_options(Some(start));
+        assert_eq!(options.durable, Some(false));
+        assert!(options.check(SubType::Exclusive).is_ok());
+
+        let durable = ConsumerOptions::default().durable(true);
+        assert_eq!(reader_options(Some(durable)).durable, Some(true));
+    }
+
+    #[test]
+    fn key_shared_hash_ranges() {
+        let range = |start, end| proto::IntRange { start, end };
+        assert!(check_hash_ranges(&[]).is_ok());
+        assert!(check_hash_ranges(&[range(32768, 65535), range(0, 32767)]).is_ok());
+        assert!(check_hash_ranges(&[range(0, 65536)]).is_err());
+        assert!(check_hash_ranges(&[range(-1, 10)]).is_err());
+        assert!(check_hash_ranges(&[range(10, 5)]).is_err());
+        assert!(check_hash_ranges(&[range(0, 100), range(100, 200)]).is_err());
+    }
+
+    #[test]
+    fn ack_group_flushes_when_full() {
+        let id = |entry_id| MessageIdData {
+            ledger_id: 1,
+            entry_id,
+            ..Default::default()
+        };
+        let mut group = AckGroup::default();
+        assert!(group.push(vec![id(1)], 3).is_none());
+        // the chunks of a message are acked together
+        assert!(group
+            .push(vec![id(2), id(3), id(4)], 3)
+            .is_some_and(|ids| ids.len() == 4));
+        assert!(group.take().is_empty());
+
+        assert!(group.push(vec![id(5)], 3).is_none());
+        assert_eq!(group.take(), vec![id(5)]);
+    }
+
+    #[test]
+    fn flow_permits_refill() {
+        // the initial flow grants 1000 permits, more are requested once
+        // fewer than half remain
+        assert_eq!(permits_to_refill(1000, 1000, None), None);
+        assert_eq!(permits_to_refill(500, 1000, None), None);
+        assert_eq!(permits_to_refill(499, 1000, None), Some(501));
+        assert_eq!(permits_to_refill(899, 1000, Some(900)), Some(101));
+        // a consumer with a batch size of 1 asks for a message after each one
+        assert_eq!(permits_to_refill(1, 1, None), None);
+        assert_eq!(permits_to_refill(0, 1, None), Some(1));
+        assert_eq!(permits_to_refill(0, 10, Some(0)), Some(10));
+    }
+
+    #[test]
+    fn message_times_and_properties() {
+        let message = Message::<Vec<u8>> {
+            topic: "test".to_string(),
+            payload: Payload::new(
+                MessageMetadata {
+                    publish_time: 1_600_000_000_123,
+                    event_time: Some(0),
+                    properties: vec![proto::KeyValue {
+                        key: "a".to_string(),
+                        value: "b".to_string(),
+                    }],
+                    ..Default::default()
+                },
+                Vec::new(),
+            ),
+            message_id: MessageData {
+                id: Default::default(),
+                batch_size: None,
+                redelivery_count: 0,
+            },
+            _phantom: PhantomData,
+        };
+
+        assert_eq!(
+            message.publish_time().unwrap().timestamp_millis(),
+            1_600_000_000_123
+        );
+        // an event time of 0 means it was not set
+        assert!(message.event_time().is_none());
+        assert_eq!(message.properties().get("a").map(String::as_str), Some("b"));
+        assert_eq!(message.partition(), None);
+
+        let message = Message::<Vec<u8>> {
+            topic: "persistent://public/default/test-partition-3".to_string(),
+            ..message
+        };
+        assert_eq!(message.partition(), Some(3));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn redelivered_message() {
+        let mut test = TestEngine::new(ConsumerOptions::default(), None);
+        let TestEngine {
+            engine,
+            messages_tx,
+            rx,
+            ..
+        } = &mut test;
+
+        // the broker delivers the message a third time
+        let mut message = broker_message(1);
+        message.command.message.as_mut().unwrap().redelivery_count = Some(2);
+        let (id, payload, redelivery_count) = TestEngine::run(engine, async {
+            messages_tx.unbounded_send(message).unwrap();
+            rx.next().await.unwrap().unwrap()
+        })
+        .await;
+
+        let message = Message::<Vec<u8>> {
+            topic: "test".to_string(),
+            message_id: MessageData {
+                id,
+                batch_size: payload.metadata.num_messages_in_batch,
+                redelivery_count,
+            },
+            payload,
+            _phantom: PhantomData,
+        };
+        assert_eq!(message.redelivery_count(), 2);
+    }
+
+    /// message `entry_id` sent by the broker to consumer 1
+    #[cfg(feature = "tokio-runtime")]
+    fn broker_message(entry_id: u64) -> RawMessage {
+        RawMessage {
+            command: BaseCommand {
+                r#type: proto::base_command::Type::Message as i32,
+                message: Some(CommandMessage {
+                    consumer_id: 1,
+                    message_id: MessageIdData {
+                        ledger_id: 1,
+                        entry_id,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            payload: Some(Payload::new(
+                Default::default(),
+                entry_id.to_string().into_bytes(),
+            )),
+        }
+    }
+
+    /// consumer 1 receiving `messages`, with `engine_tx` in place of its engine
+    #[cfg(feature = "tokio-runtime")]
+    fn topic_consumer<T: DeserializeMessage>(
+        engine_tx: mpsc::UnboundedSender<EngineMessage<TokioExecutor>>,
+        messages: MessageIdDataReceiver,
+    ) -> TopicConsumer<T, TokioExecutor> {
+        TopicConsumer {
+            consumer_id: 1,
+            config: ConsumerConfig::default(),
+            topic: "test".to_string(),
+            messages: Box::pin(messages),
+            engine_tx,
+            data_type: PhantomData,
+            dead_letter_policy: None,
+            last_message_received: None,
+            messages_received: 0,
+            highest_message_id: None,
+            start_message_id: None,
+            status: Arc::new(ConsumerStatus {
+                active: AtomicBool::new(true),
+                reached_end_of_topic: AtomicBool::new(false),
+                paused: AtomicBool::new(false),
+            }),
+            schemas: HashMap::new(),
+            _stop_tickers: oneshot::channel().0,
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn writer_schema_cached() {
+        use crate::connection::{
+            tests::{manual_connection, respond},
+            Register,
+        };
+
+        let (connection, mut outbound, mut registrations) = manual_connection();
+        let (engine_tx, mut engine_rx) = mpsc::unbounded();
+        let (_messages_tx, messages) = mpsc::channel(1);
+        let mut consumer = topic_consumer::<Vec<u8>>(engine_tx, messages);
+        tokio::spawn(async move {
+            while let Some(EngineMessage::GetConnection(resolver)) = engine_rx.next().await {
+                let _ = resolver.send(connection.clone());
+            }
+        });
+        // the broker answers with a schema named after the requested version
+        let (requests_tx, mut requests) = mpsc::unbounded();
+        tokio::spawn(async move {
+            while let Some(message) = outbound.next().await {
+                let request = message.command.get_schema.unwrap();
+                let schema = proto::Schema {
+                    name: format!("{:?}", request.schema_version),
+                    ..Default::default()
+                };
+                let response = BaseCommand {
+                    r#type: proto::base_command::Type::GetSchemaResponse as i32,
+                    get_schema_response: Some(proto::CommandGetSchemaResponse {
+                        request_id: request.request_id,
+                        schema: Some(schema),
+                        schema_version: request.schema_version.clone(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                };
+                let registration = registrations.next().await.unwrap();
+                assert!(matches!(registration, Register::Request { .. }));
+                requests_tx.unbounded_send(request.schema_version).unwrap();
+                respond(registration, response);
+            }
+        });
+
+        for version in [Some(vec![1]), Some(vec![1]), Some(vec![2]), None, None] {
+            let name = format!("{:?}", version);
+            let schema = consumer.writer_schema(version).await.unwrap().unwrap();
+            assert_eq!(schema.name, name);
+        }
+
+        // the latest schema can change, it is not cached
+        let mut sent = Vec::new();
+        while let Ok(version) = requests.try_recv() {
+            sent.push(version);
+        }
+        assert_eq!(sent, vec![Some(vec![1]), Some(vec![2]), None, None]);
+    }
+
+    #[test]
+    #[cfg(feature = "tokio-runtime")]
+    fn grant_permits_checks_consumer_id() {
+        let (engine_tx, mut engine_rx) = mpsc::unbounded();
+        let (_messages_tx, messages) = mpsc::channel(1);
+        let mut consumer = topic_consumer::<String>(engine_tx, messages);
+
+        let res = futures::executor::block_on(consumer.grant_permits(2, 10));
+        assert!(matches!(
+            res,
+            Err(ConsumerError::Connection(ConnectionError::Unexpected(_)))
+        ));
+        assert!(engine_rx.next().now_or_never().is_none());
+
+        futures::executor::block_on(consumer.grant_permits(1, 10)).unwrap();
+        assert!(matches!(
+            engine_rx.next().now_or_never(),
+            Some(Some(EngineMessage::Flow(10)))
+        ));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn flow_after_watermark() {
+        let options = ConsumerOptions::default().with_receiver_queue_size(4);
+        let mut test = TestEngine::new(options, None);
+        let TestEngine {
+            engine,
+            messages_tx,
+            outbound,
+            rx,
+            ..
+        } = &mut test;
+
+        let flow = TestEngine::run(engine, async {
+            for entry_id in 0..3 {
+                messages_tx
+                    .unbounded_send(broker_message(entry_id))
+                    .unwrap();
+                rx.next().await.unwrap().unwrap();
+            }
+            // a single permit remains, fewer than half the receiver queue size
+            outbound.next().await.unwrap().command.flow.unwrap()
+        })
+        .await;
+        assert_eq!(flow.consumer_id, 1);
+        assert_eq!(flow.message_permits, 3);
+        assert!(test.sent_commands().is_empty());
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn chunked_messages_queue_full() {
+        // chunk `chunk_id` of the message `uuid` split in two chunks of one byte
+        let chunk = |entry_id, uuid: &str, chunk_id| {
+            let mut message = broker_message(entry_id);
+            let metadata = &mut message.payload.as_mut().unwrap().metadata;
+            metadata.uuid = Some(uuid.to_string());
+            metadata.chunk_id = Some(chunk_id);
+            metadata.num_chunks_from_msg = Some(2);
+            metadata.total_chunk_msg_size = Some(2);
+            message
+        };
+
+        for auto_ack in [false, true] {
+            let options = ConsumerOptions::default()
+                .with_max_pending_chunked_messages(1)
+                .auto_ack_oldest_on_queue_full(auto_ack);
+            let mut test = TestEngine::new(options, None);
+            let TestEngine {
+                engine,
+                messages_tx,
+                rx,
+                ..
+            } = &mut test;
+
+            let (id, payload, _) = TestEngine::run(engine, async {
+                messages_tx.unbounded_send(chunk(1, "a", 0)).unwrap();
+                // the queue is full, the first message is evicted
+                messages_tx.unbounded_send(chunk(2, "b", 0)).unwrap();
+                messages_tx.unbounded_send(chunk(3, "b", 1)).unwrap();
+                rx.next().await.unwrap().unwrap()
+            })
+            .await;
+            assert_eq!(id.entry_id, 3);
+            assert_eq!(payload.data, b"23");
+
+            let commands = test.sent_commands();
+            assert_eq!(commands.len(), 1);
+            let evicted = if auto_ack {
+                commands[0].ack.clone().unwrap().message_id
+            } else {
+                commands[0]
+                    .redeliver_unacknowledged_messages
+                    .clone()
+                    .unwrap()
+                    .message_ids
+            };
+            assert_eq!(evicted.len(), 1);
+            assert_eq!(evicted[0].entry_id, 1);
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn chunked_message_wrong_size() {
+        let mut test = TestEngine::new(ConsumerOptions::default(), None);
+        let TestEngine {
+            engine,
+            messages_tx,
+            rx,
+            ..
+        } = &mut test;
+
+        let (id, _, _) = TestEngine::run(engine, async {
+            for chunk_id in 0..2 {
+                let mut message = broker_message(chunk_id as u64 + 1);
+                let metadata = &mut message.payload.as_mut().unwrap().metadata;
+                metadata.uuid = Some("a".to_string());
+                metadata.chunk_id = Some(chunk_id);
+                metadata.num_chunks_from_msg = Some(2);
+                metadata.total_chunk_msg_size = Some(3);
+                messages_tx.unbounded_send(message).unwrap();
+            }
+            messages_tx.unbounded_send(broker_message(3)).unwrap();
+            rx.next().await.unwrap().unwrap()
+        })
+        .await;
+        // the reassembled message is not sent to the consumer
+        assert_eq!(id.entry_id, 3);
+
+        // the chunks are redelivered instead of acked
+        let commands = test.sent_commands();
+        assert_eq!(commands.len(), 1);
+        assert!(commands[0].ack.is_none());
+        let redelivered: Vec<u64> = commands[0]
+            .redeliver_unacknowledged_messages
+            .clone()
+            .unwrap()
+            .message_ids
+            .iter()
+            .map(|id| id.entry_id)
+            .collect();
+        assert_eq!(redelivered, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn duplicates_acked_in_group() {
+        let options = ConsumerOptions::default()
+            .dedup_by_broker_index(true)
+            .with_ack_grouping(AckConfig {
+                ack_group_time: Duration::from_secs(3600),
+                max_group_size: 1000,
+            });
+        let mut test = TestEngine::new(options, None);
+        let TestEngine {
+            engine,
+            messages_tx,
+            engine_tx,
+            outbound,
+            rx,
+            ..
+        } = &mut test;
+
+        // message `entry_id` stored at the broker index `index`
+        let message = |entry_id, index| {
+            let mut message = broker_message(entry_id);
+            let payload = message.payload.take().unwrap();
+            message.payload = Some(payload.with_broker_entry_metadata(
+                proto::BrokerEntryMetadata {
+                    index: Some(index),
+                    ..Default::default()
+                },
+            ));
+            message
+        };
+
+        let ack = TestEngine::run(engine, async {
+            messages_tx.unbounded_send(message(1, 5)).unwrap();
+            messages_tx.unbounded_send(message(2, 5)).unwrap();
+            messages_tx.unbounded_send(message(3, 6)).unwrap();
+            assert_eq!(rx.next().await.unwrap().unwrap().0.entry_id, 1);
+            assert_eq!(rx.next().await.unwrap().unwrap().0.entry_id, 3);
+
+            // the duplicate's ack waits for the group to be sent
+            engine_tx.unbounded_send(EngineMessage::FlushAcks).unwrap();
+            outbound.next().await.unwrap().command.ack.unwrap()
+        })
+        .await;
+        assert_eq!(ack.message_id.len(), 1);
+        assert_eq!(ack.message_id[0].entry_id, 2);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn nacked_message_not_deduplicated() {
+        let options = ConsumerOptions::default().dedup_by_broker_index(true);
+        let mut test = TestEngine::new(options, None);
+        let TestEngine {
+            engine,
+            messages_tx,
+            engine_tx,
+            outbound,
+            rx,
+            ..
+        } = &mut test;
+
+        // message `entry_id` stored at the broker index `index`
+        let message = |entry_id, index| {
+            let mut message = broker_message(entry_id);
+            let payload = message.payload.take().unwrap();
+            message.payload = Some(payload.with_broker_entry_metadata(
+                proto::BrokerEntryMetadata {
+                    index: Some(index),
+                    ..Default::default()
+                },
+            ));
+            message
+        };
+
+        let ack = TestEngine::run(engine, async {
+            messages_tx.unbounded_send(message(1, 5)).unwrap();
+            messages_tx.unbounded_send(message(2, 6)).unwrap();
+            let (id, _, _) = rx.next().await.unwrap().unwrap();
+            assert_eq!(rx.next().await.unwrap().unwrap().0.entry_id, 2);
+
+            engine_tx
+                .unbounded_send(EngineMessage::Nack(MessageData {
+                    id,
+                    batch_size: None,
+                    redelivery_count: 0,
+                }))
+                .unwrap();
+            let redeliver = outbound.next().await.unwrap().command;
+            assert!(redeliver.redeliver_unacknowledged_messages.is_some());
+
+            // the nacked message comes back, then a real duplicate of it
+            messages_tx.unbounded_send(message(1, 5)).unwrap();
+            assert_eq!(rx.next().await.unwrap().unwrap().0.entry_id, 1);
+            messages_tx.unbounded_send(message(1, 5)).unwrap();
+            outbound.next().await.unwrap().command.ack.unwrap()
+        })
+        .await;
+        assert_eq!(ack.message_id[0].entry_id, 1);
+        assert!(test.engine.redelivery_requested.is_empty());
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn reconnection_resets_broker_index() {
+        let options = ConsumerOptions::default().dedup_by_broker_index(true);
+        let mut test = TestEngine::new(options, None);
+        test.engine.highest_broker_index = Some(5);
+
+        let (connection, outbound) = crate::connection::tests::test_connection();
+        test.outbound = Box::pin(outbound);
+        test.engine.resubscribe(connection).await.unwrap();
+        // the unacked messages the broker sends again are delivered
+        assert_eq!(test.engine.highest_broker_index, None);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn corrupted_payload_redelivered() {
+        let mut test = TestEngine::new(ConsumerOptions::default(), None);
+        let TestEngine {
+            engine,
+            messages_tx,
+            rx,
+            ..
+        } = &mut test;
+
+        // the codec drops the payload of a message that fails its checksum
+        let mut corrupted = broker_message(1);
+        corrupted.payload = None;
+        let (id, _, _) = TestEngine::run(engine, async {
+            messages_tx.unbounded_send(corrupted).unwrap();
+            messages_tx.unbounded_send(broker_message(2)).unwrap();
+            rx.next().await.unwrap().unwrap()
+        })
+        .await;
+        assert_eq!(id.entry_id, 2);
+
+        // the message is not acked, the broker is asked to send it again
+        let commands = test.sent_commands();
+        assert_eq!(commands.len(), 1);
+        let redelivered = commands[0]
+            .redeliver_unacknowledged_messages
+            .clone()
+            .unwrap()
+            .message_ids;
+        assert_eq!(redelivered.len(), 1);
+        assert_eq!(redelivered[0].entry_id, 1);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn end_of_topic_ends_stream() {
+        let mut test = TestEngine::new(ConsumerOptions::default(), None);
+        test.messages_tx
+            .unbounded_send(RawMessage {
+                command: BaseCommand {
+                    r#type: proto::base_command::Type::ReachedEndOfTopic as i32,
+                    reached_end_of_topic: Some(proto::CommandReachedEndOfTopic { consumer_id: 1 }),
+                    ..Default::default()
+                },
+                payload: None,
+            })
+            .unwrap();
+
+        // the engine stops, which ends the consumer's stream of messages
+        timeout(Duration::from_secs(5), test.engine.engine())
+            .await
+            .unwrap()
+            .unwrap();
+        // the initial permits were not used up, no flow was sent
+        assert!(test.sent_commands().is_empty());
+        drop(test.engine);
+        assert!(test.engine_tx.is_closed());
+        assert!(test.status.reached_end_of_topic.load(Ordering::Acquire));
+        assert!(test.rx.next().await.is_none());
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn paused_consumer_reconnects_without_permits() {
+        let mut test = TestEngine::new(ConsumerOptions::default(), None);
+        test.status.paused.store(true, Ordering::Release);
+
+        let (connection, outbound) = crate::connection::tests::test_connection();
+        test.outbound = Box::pin(outbound);
+        test.engine.resubscribe(connection).await.unwrap();
+
+        let commands = test.sent_commands();
+        assert!(commands.iter().any(|command| command.subscribe.is_some()));
+        assert!(commands.iter().all(|command| command.flow.is_none()));
+        assert_eq!(test.engine.remaining_messages, 0);
+
+        // once resumed, reconnecting grants the permits again
+        test.status.paused.store(false, Ordering::Release);
+        let (connection, outbound) = crate::connection::tests::test_connection();
+        test.outbound = Box::pin(outbound);
+        test.engine.resubscribe(connection).await.unwrap();
+        let flows: Vec<_> = test
+            .sent_commands()
+            .into_iter()
+            .filter_map(|command| command.flow)
+            .collect();
+        assert_eq!(flows.len(), 1);
+        assert_eq!(flows[0].message_permits, 1000);
+    }
+
+    #[test]
+    fn message_id_ordering() {
+        use std::cmp::Ordering;
+
+        let id = |ledger_id, entry_id, batch_index| MessageIdData {
+            ledger_id,
+            entry_id,
+            batch_index,
+            ..Default::default()
+        };
+        assert_eq!(
+            compare_message_ids(&id(1, 5, None), &id(2, 0, None)),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_message_ids(&id(2, 3, None), &id(2, 1, Some(9))),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_message_ids(&id(2, 3, Some(1)), &id(2, 3, Some(0))),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_message_ids(&id(2, 3, None), &id(2, 3, Some(0))),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_message_ids(&id(2, 3, Some(4)), &id(2, 3, Some(4))),
+            Ordering::Equal
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn ack_timeout_redelivers_once() {
+        let ack_timeout = Duration::from_secs(30);
+        let mut test = TestEngine::new(ConsumerOptions::default(), Some(ack_timeout));
+        let TestEngine {
+            engine,
+            messages_tx,
+            engine_tx,
+            outbound,
+            rx,
+            ..
+        } = &mut test;
+
+        let message_data = |entry_id| MessageData {
+            id: broker_message(entry_id).command.message.unwrap().message_id,
+            batch_size: None,
+            redelivery_count: 0,
+        };
+
+        let received = Instant::now();
+        let redelivered = TestEngine::run(engine, async {
+            for entry_id in 1..=2 {
+                messages_tx
+                    .unbounded_send(broker_message(entry_id))
+                    .unwrap();
+                rx.next().await.unwrap().unwrap();
+            }
+            // acked messages are not tracked anymore
+            engine_tx
+                .unbounded_send(EngineMessage::Ack(message_data(2), false))
+                .unwrap();
+            assert!(outbound.next().await.unwrap().command.ack.is_some());
+
+            let after = Instant::now() + ack_timeout;
+            for now in [received, after, after + ack_timeout] {
+                engine_tx
+                    .unbounded_send(EngineMessage::UnackedRedelivery(now))
+                    .unwrap();
+            }
+            let redeliver = outbound.next().await.unwrap().command;
+
+            // the engine stops tracking the messages once it asked for their
+            // redelivery, the next command is this ack
+            engine_tx
+                .unbounded_send(EngineMessage::Ack(message_data(1), false))
+                .unwrap();
+            assert!(outbound.next().await.unwrap().command.ack.is_some());
+            redeliver.redeliver_unacknowledged_messages.unwrap()
+        })
+        .await;
+        assert_eq!(redelivered.consumer_id, 1);
+        assert_eq!(redelivered.message_ids.len(), 1);
+        assert_eq!(redelivered.message_ids[0].entry_id, 1);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn reconnection_redelivers_unacked() {
+        let mut test = TestEngine::new(ConsumerOptions::default(), None);
+        let TestEngine {
+            engine,
+            messages_tx,
+            rx,
+            ..
+        } = &mut test;
+        TestEngine::run(engine, async {
+            for entry_id in 1..=2 {
+                messages_tx
+                    .unbounded_send(broker_message(entry_id))
+                    .unwrap();
+                rx.next().await.unwrap().unwrap();
+            }
+        })
+        .await;
+        test.engine
+            .ack(
+                MessageData {
+                    id: broker_message(1).command.message.unwrap().message_id,
+                    batch_size: None,
+                    redelivery_count: 0,
+                },
+                false,
+            )
+            .await;
+
+        let (connection, outbound) = crate::connection::tests::test_connection();
+        test.outbound = Box::pin(outbound);
+        test.engine.resubscribe(connection).await.unwrap();
+
+        // the message that was not acked is delivered again
+        let redelivered: Vec<_> = test
+            .sent_commands()
+            .into_iter()
+            .filter_map(|command| command.redeliver_unacknowledged_messages)
+            .collect();
+        assert_eq!(redelivered.len(), 1);
+        assert_eq!(redelivered[0].message_ids.len(), 1);
+        assert_eq!(redelivered[0].message_ids[0].entry_id, 2);
+        assert!(test.engine.unacked_messages.is_empty());
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn unacked_messages_bounded_without_delay() {
+        let options = ConsumerOptions::default().with_receiver_queue_size(2);
+        let mut test = TestEngine::new(options, None);
+        let TestEngine {
+            engine,
+            messages_tx,
+            rx,
+            ..
+        } = &mut test;
+
+        TestEngine::run(engine, async {
+            for entry_id in 1..=5 {
+                messages_tx
+                    .unbounded_send(broker_message(entry_id))
+                    .unwrap();
+                rx.next().await.unwrap().unwrap();
+            }
+        })
+        .await;
+        // the messages are never acked, only the last ones are tracked
+        assert_eq!(test.engine.unacked_messages.len(), 2);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn delayed_nacks_redelivered_on_tick() {
+        let mut test = TestEngine::new(ConsumerOptions::default(), None);
+        let TestEngine {
+            engine,
+            messages_tx,
+            engine_tx,
+            outbound,
+            rx,
+            ..
+        } = &mut test;
+
+        let message_data = |entry_id| MessageData {
+            id: broker_message(entry_id).command.message.unwrap().message_id,
+            batch_size: None,
+            redelivery_count: 0,
+        };
+
+        let nacked = Instant::now();
+        let delay = Duration::from_secs(1);
+        let redelivered = TestEngine::run(engine, async {
+            for entry_id in 1..=3 {
+                messages_tx
+                    .unbounded_send(broker_message(entry_id))
+                    .unwrap();
+                rx.next().await.unwrap().unwrap();
+            }
+            for (entry_id, deadline) in [
+                (1, nacked + delay),
+                (2, nacked + delay),
+                (3, nacked + delay * 10),
+            ] {
+                engine_tx
+                    .unbounded_send(EngineMessage::DelayedNack(message_data(entry_id), deadline))
+                    .unwrap();
+            }
+            for now in [nacked, nacked + delay * 2] {
+                engine_tx
+                    .unbounded_send(EngineMessage::UnackedRedelivery(now))
+                    .unwrap();
+            }
+            let redeliver = outbound.next().await.unwrap().command;
+
+            // the message still waiting for its delay and the unacked messages
+            // are not redelivered, the next command is this ack
+            engine_tx
+                .unbounded_send(EngineMessage::Ack(message_data(3), false))
+                .unwrap();
+            assert!(outbound.next().await.unwrap().command.ack.is_some());
+            redeliver.redeliver_unacknowledged_messages.unwrap()
+        })
+        .await;
+        let mut entry_ids: Vec<_> = redelivered
+            .message_ids
+            .iter()
+            .map(|id| id.entry_id)
+            .collect();
+        entry_ids.sort_unstable();
+        assert_eq!(entry_ids, vec![1, 2]);
+        assert_eq!(test.engine.nacked_messages.len(), 1);
+    }
+
+    /// client connected to a broker with a topic of `partitions` partitions,
+    /// returned with the commands the broker received and the channels of
+    /// the consumers that subscribed
+    #[cfg(feature = "tokio-runtime")]
+    fn test_broker(
+        partitions: Arc<std::sync::atomic::AtomicU32>,
+    ) -> (
+        Pulsar<TokioExecutor>,
+        mpsc::UnboundedReceiver<BaseCommand>,
+        mpsc::UnboundedReceiver<mpsc::UnboundedSender<RawMessage>>,
+    ) {
+        use crate::connection::{
+            tests::{manual_connection, respond},
+            Register, RequestKey,
+        };
+
+        let (connection, mut outbound, mut registrations) = manual_connection();
+        let (commands_tx, commands) = mpsc::unbounded();
+        let (consumers_tx, consumers) = mpsc::unbounded();
+        tokio::spawn(async move {
+            // the consumers' channels are kept open
+            let mut consumer_channels = Vec::new();
+            while let Some(message) = outbound.next().await {
+                let command = message.command;
+                let _ = commands_tx.unbounded_send(command.clone());
+                let (request_id, response) = if let Some(metadata) = command.partition_metadata {
+                    let response = proto::CommandPartitionedTopicMetadataResponse {
+                        partitions: Some(partitions.load(Ordering::Acquire)),
+                        request_id: metadata.request_id,
+                        response: Some(
+                            proto::command_partitioned_topic_metadata_response::LookupType::Success
+                                as i32,
+                        ),
+                        ..Default::default()
+                    };
+                    let response = BaseCommand {
+                        r#type: proto::base_command::Type::PartitionedMetadataResponse as i32,
+                        partition_metadata_response: Some(response),
+                        ..Default::default()
+                    };
+                    (metadata.request_id, response)
+                } else if let Some(lookup) = command.lookup_topic {
+                    let response = proto::CommandLookupTopicResponse {
+                        broker_service_url: Some("pulsar://127.0.0.1:6650".to_string()),
+                        response: Some(
+                            proto::command_lookup_topic_response::LookupType::Connect as i32,
+                        ),
+                        request_id: lookup.request_id,
+                        authoritative: Some(true),
+                        ..Default::default()
+                    };
+                    let response = BaseCommand {
+                        r#type: proto::base_command::Type::LookupResponse as i32,
+                        lookup_topic_response: Some(response),
+                        ..Default::default()
+                    };
+                    (lookup.request_id, response)
+                } else if let Some(request_id) = command
+                    .subscribe
+                    .map(|subscribe| subscribe.request_id)
+                    .or(command.close_consumer.map(|close| close.request_id))
+                    .or(command.seek.map(|seek| seek.request_id))
+                {
+                    let response = BaseCommand {
+                        r#type: proto::base_command::Type::Success as i32,
+                        success: Some(proto::CommandSuccess {
+                            request_id,
+                            schema: None,
+                        }),
+                        ..Default::default()
+                    };
+                    (request_id, response)
+                } else {
+                    continue;
+                };
+
+                // requests are registered before they are sent
+                loop {
+                    match registrations.next().await.unwrap() {
+                        registration @ Register::Request {
+                            key: RequestKey::RequestId(id),
+                            ..
+                        } if id == request_id => {
+                            respond(registration, response);
+                            break;
+                        }
+                        Register::Consumer { resolver, .. } => {
+                            let _ = consumers_tx.unbounded_send(resolver.clone());
+                            consumer_channels.push(resolver);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        });
+        (
+            Pulsar::with_connection(TokioExecutor, connection),
+            commands,
+            consumers,
+        )
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn partitions_added_later() {
+        let partitions = Arc::new(std::sync::atomic::AtomicU32::new(1));
+        let (pulsar, mut commands, _consumers) = test_broker(partitions.clone());
+
+        let topic = "persistent://public/default/test";
+        let partition = |n| format!("{}-partition-{}", topic, n);
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic(topic)
+            .with_subscription("test")
+            .with_topic_refresh(Duration::from_millis(10))
+            .build()
+            .await
+            .unwrap();
+
+        partitions.store(3, Ordering::Release);
+        let mut lookups = Vec::new();
+        let mut subscriptions = Vec::new();
+        while subscriptions.len() < 3 {
+            let command = match timeout(
+                Duration::from_secs(5),
+                select(consumer.next(), commands.next()),
+            )
+            .await
+            .unwrap()
+            {
+                Either::Left(_) => panic!("unexpected message"),
+                Either::Right((command, _)) => command.unwrap(),
+            };
+            if let Some(lookup) = command.lookup_topic {
+                lookups.push(lookup.topic);
+            }
+            if let Some(subscribe) = command.subscribe {
+                subscriptions.push((subscribe.topic, subscribe.consumer_id));
+            }
+        }
+        subscriptions.sort();
+        let topics: Vec<_> = subscriptions.iter().map(|(topic, _)| topic).collect();
+        assert_eq!(topics, vec![&partition(0), &partition(1), &partition(2)]);
+        // each partition has its own consumer id
+        let mut ids: Vec<_> = subscriptions.iter().map(|(_, id)| *id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 3);
+
+        // only the new partitions were looked up at the refresh
+        lookups.sort();
+        assert_eq!(lookups, vec![partition(0), partition(1), partition(2)]);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn consumer_id_rejected_for_partitions() {
+        let (pulsar, _commands, _consumers) =
+            test_broker(Arc::new(std::sync::atomic::AtomicU32::new(2)));
+        let res: Result<Consumer<Vec<u8>, _>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/test")
+            .with_subscription("test")
+            .with_consumer_id(7)
+            .build()
+            .await;
+        assert!(matches!(res, Err(Error::Custom(_))));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn dropped_consumer_flushes_acks() {
+        let (pulsar, mut commands, mut consumers) =
+            test_broker(Arc::new(std::sync::atomic::AtomicU32::new(0)));
+        let options = ConsumerOptions::default().with_ack_grouping(AckConfig {
+            ack_group_time: Duration::from_secs(3600),
+            max_group_size: 1000,
+        });
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/test")
+            .with_subscription("test")
+            .with_options(options)
+            .with_unacked_message_resend_delay(Some(Duration::from_secs(3600)))
+            .build()
+            .await
+            .unwrap();
+
+        let messages = consumers.next().await.unwrap();
+        messages.unbounded_send(broker_message(1)).unwrap();
+        let message = timeout(Duration::from_secs(5), consumer.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        consumer.ack(&message).await.unwrap();
+
+        // the engine stops once the consumer is dropped, sending the pending ack
+        drop(consumer);
+        let ack = loop {
+            let command = timeout(Duration::from_secs(5), commands.next())
+                .await
+                .unwrap()
+                .unwrap();
+            if let Some(ack) = command.ack {
+                break ack;
+            }
+        };
+        assert_eq!(ack.message_id.len(), 1);
+        assert_eq!(ack.message_id[0].entry_id, 1);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn seek_before_start_message_id() {
+        let (pulsar, _commands, mut consumers) =
+            test_broker(Arc::new(std::sync::atomic::AtomicU32::new(0)));
+        let message_id = |entry_id| MessageIdData {
+            ledger_id: 1,
+            entry_id,
+            ..Default::default()
+        };
+        let options = ConsumerOptions::default()
+            .durable(false)
+            .starting_on_message(message_id(5))
+            .with_start_message_id_inclusive(true);
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/test")
+            .with_subscription("test")
+            .with_consumer_id(1)
+            .with_options(options)
+            .build()
+            .await
+            .unwrap();
+        consumers.next().await.unwrap();
+
+        consumer
+            .seek(None, Some(message_id(2)), None, pulsar.clone())
+            .await
+            .unwrap();
+
+        // the recreated consumer receives the messages from the seek position
+        let messages = consumers.next().await.unwrap();
+        messages.unbounded_send(broker_message(2)).unwrap();
+        let message = timeout(Duration::from_secs(5), consumer.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.message_id.id.entry_id, 2);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn paused_consumer_stays_paused_after_seek() {
+        let (pulsar, mut commands, _consumers) =
+            test_broker(Arc::new(std::sync::atomic::AtomicU32::new(0)));
+        let topics = vec![
+            "persistent://public/default/test-a".to_string(),
+            "persistent://public/default/test-b".to_string(),
+        ];
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topics(&topics)
+            .with_subscription("test")
+            .build()
+            .await
+            .unwrap();
+
+        consumer.pause();
+        consumer
+            .seek(Some(topics.clone()), None, Some(0), pulsar.clone())
+            .await
+            .unwrap();
+        assert!(consumer.is_paused());
+
+        // the consumers recreated by the seek subscribe without permits
+        let mut seeked = false;
+        let mut subscriptions = 0;
+        while let Ok(Some(command)) = timeout(Duration::from_millis(500), commands.next()).await {
+            seeked |= command.seek.is_some();
+            if seeked {
+                if command.subscribe.is_some() {
+                    subscriptions += 1;
+                }
+                assert!(command.flow.is_none());
+            }
+        }
+        assert_eq!(subscriptions, 2);
+
+        // resuming grants them the permits
+        consumer.resume().await.unwrap();
+        let mut flows = Vec::new();
+        while flows.len() < 2 {
+            let command = timeout(Duration::from_secs(5), commands.next())
+                .await
+                .unwrap()
+                .unwrap();
+            flows.extend(command.flow);
+        }
+        assert!(flows.iter().all(|flow| flow.message_permits == 1000));
+    }
+
     #[tokio::test]
     #[cfg(feature = "tokio-runtime")]
     async fn multi_consumer() {
@@ -2324,6 +5553,7 @@ mod tests {
             msg.deserialize().unwrap(),
             "we probably received a message from a previous run of the test"
         );
+        assert_eq!(msg.redelivery_count(), 0);
         // Nacking message to send it to DLQ
         consumer.nack(&msg).await.unwrap();
 
@@ -5,6 +5,8 @@ use std::sync::{
 };
 use std::{fmt, io};
 
+use futures::channel::oneshot;
+
 #[derive(Debug)]
 pub enum Error {
     Connection(ConnectionError),
@@ -12,6 +14,7 @@ pub enum Error {
     Producer(ProducerError),
     ServiceDiscovery(ServiceDiscoveryError),
     Authentication(AuthenticationError),
+    Transaction(TransactionError),
     Custom(String),
     Executor,
 }
@@ -40,6 +43,12 @@ impl From<ServiceDiscoveryError> for Error {
     }
 }
 
+impl From<TransactionError> for Error {
+    fn from(err: TransactionError) -> Self {
+        Error::Transaction(err)
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -48,6 +57,7 @@ impl fmt::Display for Error {
             Error::Producer(e) => write!(f, "producer error: {}", e),
             Error::ServiceDiscovery(e) => write!(f, "service discovery error: {}", e),
             Error::Authentication(e) => write!(f, "authentication error: {}", e),
+            Error::Transaction(e) => write!(f, "transaction error: {}", e),
             Error::Custom(e) => write!(f, "error: {}", e),
             Error::Executor => write!(f, "could not spawn task"),
         }
@@ -62,6 +72,7 @@ impl std::error::Error for Error {
             Error::Producer(e) => e.source(),
             Error::ServiceDiscovery(e) => e.source(),
             Error::Authentication(e) => e.source(),
+            Error::Transaction(e) => e.source(),
             Error::Custom(_) => None,
             Error::Executor => None,
         }
@@ -79,7 +90,37 @@ pub enum ConnectionError {
     SocketAddr(String),
     UnexpectedResponse(String),
     Tls(native_tls::Error),
+    /// the TLS handshake with the broker failed, for example because its
+    /// certificate was refused or it did not accept the client certificate
+    TlsHandshake(native_tls::Error),
     Authentication(AuthenticationError),
+    /// the broker refused the connection because it is not ready yet, the
+    /// connection can be retried later
+    ServiceNotReady(String),
+    /// the broker does not support the requested feature
+    UnsupportedByBroker(String),
+    /// the URL's scheme is neither `pulsar` nor `pulsar+ssl`
+    InvalidUrl {
+        scheme: String,
+    },
+    /// the broker's host name could not be resolved
+    DnsResolution {
+        host: String,
+        source: Option<io::Error>,
+    },
+    /// the CRC32C checksum of a message's metadata and payload does not match
+    /// the one sent with it
+    Checksum {
+        expected: u32,
+        computed: u32,
+    },
+    /// a frame is larger than the maximum size allowed by the connection
+    FrameTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// the operation did not complete within its timeout
+    Timeout,
     NotFound,
     Canceled,
     Shutdown,
@@ -116,10 +157,29 @@ impl fmt::Display for ConnectionError {
             ConnectionError::Encoding(e) => write!(f, "Error encoding message: {}", e),
             ConnectionError::SocketAddr(e) => write!(f, "Error obtaining socket address: {}", e),
             ConnectionError::Tls(e) => write!(f, "Error connecting TLS stream: {}", e),
+            ConnectionError::TlsHandshake(e) => write!(f, "TLS handshake failed: {}", e),
             ConnectionError::Authentication(e) => write!(f, "Error authentication: {}", e),
+            ConnectionError::ServiceNotReady(e) => write!(f, "Service not ready: {}", e),
+            ConnectionError::UnsupportedByBroker(e) => write!(f, "Unsupported by broker: {}", e),
             ConnectionError::UnexpectedResponse(e) => {
                 write!(f, "Unexpected response from pulsar: {}", e)
             }
+            ConnectionError::InvalidUrl { scheme } => write!(f, "invalid URL scheme: {}", scheme),
+            ConnectionError::DnsResolution { host, source } => match source {
+                Some(e) => write!(f, "error resolving {}: {}", host, e),
+                None => write!(f, "error resolving {}: no address found", host),
+            },
+            ConnectionError::Checksum { expected, computed } => write!(
+                f,
+                "checksum mismatch: expected {:#010x}, computed {:#010x}",
+                expected, computed
+            ),
+            ConnectionError::FrameTooLarge { size, max } => write!(
+                f,
+                "frame of {} bytes is larger than the maximum of {} bytes",
+                size, max
+            ),
+            ConnectionError::Timeout => write!(f, "operation timed out"),
             ConnectionError::NotFound => write!(f, "error looking up URL"),
             ConnectionError::Canceled => write!(f, "canceled request"),
             ConnectionError::Shutdown => write!(f, "The connection was shut down"),
@@ -127,10 +187,85 @@ impl fmt::Display for ConnectionError {
     }
 }
 
+impl ConnectionError {
+    /// returns true if the error is transient and the connection attempt can be retried
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            ConnectionError::Io(e) => {
+                e.kind() == io::ErrorKind::ConnectionRefused || e.kind() == io::ErrorKind::TimedOut
+            }
+            ConnectionError::ServiceNotReady(_) | ConnectionError::Timeout => true,
+            ConnectionError::PulsarError(Some(code), _) => code.is_retriable(),
+            _ => false,
+        }
+    }
+
+    /// returns the code of an error sent by the server
+    pub fn server_error(&self) -> Option<crate::message::proto::ServerError> {
+        match self {
+            ConnectionError::PulsarError(code, _) => *code,
+            _ => None,
+        }
+    }
+
+    /// copies the error, errors wrapping a type that cannot be cloned are
+    /// replaced by their description
+    pub(crate) fn duplicate(&self) -> ConnectionError {
+        match self {
+            ConnectionError::Io(e) => ConnectionError::Io(io::Error::new(e.kind(), e.to_string())),
+            ConnectionError::Disconnected => ConnectionError::Disconnected,
+            ConnectionError::PulsarError(code, message) => {
+                ConnectionError::PulsarError(*code, message.clone())
+            }
+            ConnectionError::Unexpected(e) => ConnectionError::Unexpected(e.clone()),
+            ConnectionError::Decoding(e) => ConnectionError::Decoding(e.clone()),
+            ConnectionError::Encoding(e) => ConnectionError::Encoding(e.clone()),
+            ConnectionError::SocketAddr(e) => ConnectionError::SocketAddr(e.clone()),
+            ConnectionError::UnexpectedResponse(e) => {
+                ConnectionError::UnexpectedResponse(e.clone())
+            }
+            ConnectionError::Tls(_) | ConnectionError::TlsHandshake(_) => {
+                ConnectionError::Unexpected(self.to_string())
+            }
+            ConnectionError::Authentication(AuthenticationError::Custom(e)) => {
+                ConnectionError::Authentication(AuthenticationError::Custom(e.clone()))
+            }
+            ConnectionError::ServiceNotReady(e) => ConnectionError::ServiceNotReady(e.clone()),
+            ConnectionError::UnsupportedByBroker(e) => {
+                ConnectionError::UnsupportedByBroker(e.clone())
+            }
+            ConnectionError::InvalidUrl { scheme } => ConnectionError::InvalidUrl {
+                scheme: scheme.clone(),
+            },
+            ConnectionError::DnsResolution { host, source } => ConnectionError::DnsResolution {
+                host: host.clone(),
+                source: source
+                    .as_ref()
+                    .map(|e| io::Error::new(e.kind(), e.to_string())),
+            },
+            ConnectionError::Checksum { expected, computed } => ConnectionError::Checksum {
+                expected: *expected,
+                computed: *computed,
+            },
+            ConnectionError::FrameTooLarge { size, max } => ConnectionError::FrameTooLarge {
+                size: *size,
+                max: *max,
+            },
+            ConnectionError::Timeout => ConnectionError::Timeout,
+            ConnectionError::NotFound => ConnectionError::NotFound,
+            ConnectionError::Canceled => ConnectionError::Canceled,
+            ConnectionError::Shutdown => ConnectionError::Shutdown,
+        }
+    }
+}
+
 impl std::error::Error for ConnectionError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             ConnectionError::Io(e) => Some(e),
+            ConnectionError::DnsResolution {
+                source: Some(e), ..
+            } => Some(e),
             _ => None,
         }
     }
@@ -144,6 +279,21 @@ pub enum ConsumerError {
     ChannelFull,
     Closed,
     BuildError,
+    /// the subscription cannot be removed while other consumers are
+    /// connected to it
+    SubscriptionBusy(String),
+    /// the broker failed to update the subscription's metadata
+    Metadata(String),
+    /// the message was compressed with a codec that is unknown or not
+    /// enabled in this build
+    UnsupportedCompression(String),
+    /// cumulative acks are rejected by the broker on Shared and Key_Shared
+    /// subscriptions
+    CumulativeAckNotAllowed(crate::message::proto::command_subscribe::SubType),
+    /// the payload does not match the type expected by the consumer
+    Deserialization(String),
+    /// the consumer options contain a combination the broker would reject
+    InvalidOptions(String),
 }
 
 impl From<ConnectionError> for ConsumerError {
@@ -183,6 +333,18 @@ impl fmt::Display for ConsumerError {
                 "cannot send message to the consumer engine: the channel is closed"
             ),
             ConsumerError::BuildError => write!(f, "Error while building the consumer."),
+            ConsumerError::SubscriptionBusy(s) => write!(f, "Subscription busy: {}", s),
+            ConsumerError::Metadata(s) => write!(f, "Subscription metadata error: {}", s),
+            ConsumerError::UnsupportedCompression(s) => {
+                write!(f, "Unsupported compression: {}", s)
+            }
+            ConsumerError::CumulativeAckNotAllowed(sub_type) => write!(
+                f,
+                "cumulative acks are not allowed on {:?} subscriptions",
+                sub_type
+            ),
+            ConsumerError::Deserialization(s) => write!(f, "Deserialization error: {}", s),
+            ConsumerError::InvalidOptions(s) => write!(f, "Invalid consumer options: {}", s),
         }
     }
 }
@@ -205,6 +367,15 @@ pub enum ProducerError {
     Batch(Arc<Error>),
     /// Indicates this producer has lost exclusive access to the topic. Client can decided whether to recreate or not
     Fenced,
+    /// the broker acknowledged a sequence id other than the one that was sent,
+    /// or one that was already acknowledged
+    SequenceMismatch {
+        expected: u64,
+        received: u64,
+    },
+    /// `max_pending_messages` messages are already waiting for a receipt and
+    /// `block_if_queue_full` is not set
+    QueueFull,
 }
 
 impl From<ConnectionError> for ProducerError {
@@ -250,6 +421,12 @@ impl fmt::Display for ProducerError {
                 Ok(())
             }
             ProducerError::Fenced => write!(f, "Producer is fenced"),
+            ProducerError::SequenceMismatch { expected, received } => write!(
+                f,
+                "Send receipt for sequence id {}, expected {}",
+                received, expected
+            ),
+            ProducerError::QueueFull => write!(f, "Producer queue is full"),
         }
     }
 }
@@ -275,6 +452,12 @@ impl fmt::Debug for ProducerError {
                 write!(f, ")")
             }, 
             ProducerError::Fenced => write!(f, "Producer is fenced"),
+            ProducerError::SequenceMismatch { expected, received } => write!(
+                f,
+                "SequenceMismatch {{ expected: {}, received: {} }}",
+                expected, received
+            ),
+            ProducerError::QueueFull => write!(f, "QueueFull"),
         }
     }
 }
@@ -291,6 +474,8 @@ impl std::error::Error for ProducerError {
                 .map(|r| r.as_ref().map(drop).unwrap_err() as _),
             ProducerError::Custom(_) => None,
             ProducerError::Fenced => None,
+            ProducerError::SequenceMismatch { .. } => None,
+            ProducerError::QueueFull => None,
         }
     }
 }
@@ -352,10 +537,83 @@ impl fmt::Display for AuthenticationError {
 
 impl std::error::Error for AuthenticationError {}
 
+/// errors returned by the transaction coordinator, the server's message is
+/// kept when there is one
+#[derive(Debug)]
+pub enum TransactionError {
+    Connection(ConnectionError),
+    /// the broker does not serve this transaction coordinator, or transactions
+    /// are not enabled
+    CoordinatorNotFound(Option<String>),
+    /// the transaction was already committed, aborted or timed out
+    InvalidStatus(Option<String>),
+    /// an acknowledgement conflicts with another transaction
+    Conflict(Option<String>),
+    /// the coordinator does not know this transaction
+    NotFound(Option<String>),
+}
+
+impl From<ConnectionError> for TransactionError {
+    fn from(err: ConnectionError) -> Self {
+        match err {
+            ConnectionError::PulsarError(Some(ServerError::TransactionCoordinatorNotFound), m) => {
+                TransactionError::CoordinatorNotFound(m)
+            }
+            ConnectionError::PulsarError(Some(ServerError::InvalidTxnStatus), m) => {
+                TransactionError::InvalidStatus(m)
+            }
+            ConnectionError::PulsarError(Some(ServerError::TransactionConflict), m) => {
+                TransactionError::Conflict(m)
+            }
+            ConnectionError::PulsarError(Some(ServerError::TransactionNotFound), m) => {
+                TransactionError::NotFound(m)
+            }
+            err => TransactionError::Connection(err),
+        }
+    }
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransactionError::Connection(e) => write!(f, "Connection error: {}", e),
+            TransactionError::CoordinatorNotFound(m) => write!(
+                f,
+                "Transaction coordinator not found: {}",
+                m.as_deref().unwrap_or("")
+            ),
+            TransactionError::InvalidStatus(m) => {
+                write!(
+                    f,
+                    "Invalid transaction status: {}",
+                    m.as_deref().unwrap_or("")
+                )
+            }
+            TransactionError::Conflict(m) => {
+                write!(f, "Transaction conflict: {}", m.as_deref().unwrap_or(""))
+            }
+            TransactionError::NotFound(m) => {
+                write!(f, "Transaction not found: {}", m.as_deref().unwrap_or(""))
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransactionError::Connection(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct SharedError {
     error_set: Arc<AtomicBool>,
     error: Arc<Mutex<Option<ConnectionError>>>,
+    /// notified the next time an error is set
+    subscribers: Arc<Mutex<Vec<oneshot::Sender<ConnectionError>>>>,
 }
 
 impl SharedError {
@@ -363,9 +621,25 @@ impl SharedError {
         SharedError {
             error_set: Arc::new(AtomicBool::new(false)),
             error: Arc::new(Mutex::new(None)),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
+    /// returns a channel receiving a copy of the error once it is set, right
+    /// away if it is already set
+    pub fn subscribe(&self) -> oneshot::Receiver<ConnectionError> {
+        let (tx, rx) = oneshot::channel();
+        // the error's lock is held so that `set` cannot run in between
+        let lock = self.error.lock().unwrap();
+        match lock.as_ref() {
+            Some(error) => {
+                let _ = tx.send(error.duplicate());
+            }
+            None => self.subscribers.lock().unwrap().push(tx),
+        }
+        rx
+    }
+
     pub fn is_set(&self) -> bool {
         self.error_set.load(Ordering::Relaxed)
     }
@@ -379,6 +653,9 @@ impl SharedError {
 
     pub fn set(&self, error: ConnectionError) {
         let mut lock = self.error.lock().unwrap();
+        for subscriber in self.subscribers.lock().unwrap().drain(..) {
+            let _ = subscriber.send(error.duplicate());
+        }
         *lock = Some(error);
         self.error_set.store(true, Ordering::Release);
     }
@@ -418,3 +695,65 @@ pub(crate) fn server_error(i: i32) -> Option<ServerError> {
         _ => None,
     }
 }
+
+impl ServerError {
+    /// returns true if the condition is transient, so that the operation can
+    /// be retried after backing off
+    pub fn is_retriable(&self) -> bool {
+        matches!(
+            self,
+            ServerError::MetadataError
+                | ServerError::PersistenceError
+                | ServerError::ServiceNotReady
+                | ServerError::TooManyRequests
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_error_codes() {
+        for code in 0..=25 {
+            let error = server_error(code).unwrap();
+            assert_eq!(error as i32, code);
+            assert_eq!(ServerError::from_i32(code), Some(error));
+        }
+        assert_eq!(server_error(26), None);
+        assert_eq!(server_error(-1), None);
+
+        assert!(ServerError::TooManyRequests.is_retriable());
+        assert!(!ServerError::TopicNotFound.is_retriable());
+        assert!(!ServerError::ProducerBlockedQuotaExceededError.is_retriable());
+        let error = ConnectionError::PulsarError(Some(ServerError::ConsumerBusy), None);
+        assert_eq!(error.server_error(), Some(ServerError::ConsumerBusy));
+        assert!(!error.is_retriable());
+    }
+
+    #[test]
+    fn transaction_errors() {
+        let error = ConnectionError::PulsarError(
+            Some(ServerError::TransactionConflict),
+            Some("conflict".to_string()),
+        );
+        assert!(matches!(
+            TransactionError::from(error),
+            TransactionError::Conflict(Some(m)) if m == "conflict"
+        ));
+        let error = ConnectionError::PulsarError(Some(ServerError::TransactionNotFound), None);
+        assert!(matches!(
+            TransactionError::from(error),
+            TransactionError::NotFound(None)
+        ));
+        let error = ConnectionError::PulsarError(Some(ServerError::TopicNotFound), None);
+        assert!(matches!(
+            TransactionError::from(error),
+            TransactionError::Connection(ConnectionError::PulsarError(
+                Some(ServerError::TopicNotFound),
+                None
+            ))
+        ));
+    }
+}
@@ -1,8 +1,8 @@
 //! executor abstraction
 //!
-//! this crate is compatible with Tokio and async-std, by assembling  them
-//! under the [Executor] trait
-use futures::{Future, Stream};
+//! this crate is compatible with Tokio, async-std and smol, by assembling
+//! them under the [Executor] trait
+use futures::{channel::oneshot, Future, Stream};
 use std::{ops::Deref, pin::Pin, sync::Arc};
 
 /// indicates which executor is used
@@ -11,6 +11,8 @@ pub enum ExecutorKind {
     Tokio,
     /// async-std executor
     AsyncStd,
+    /// smol executor
+    Smol,
 }
 
 /// Wrapper trait abstracting the Tokio and async-std executors
@@ -19,16 +21,36 @@ pub trait Executor: Clone + Send + Sync + 'static {
     #[allow(clippy::clippy::result_unit_err)]
     fn spawn(&self, f: Pin<Box<dyn Future<Output = ()> + Send>>) -> Result<(), ()>;
     /// spawns a new blocking task
+    ///
+    /// by default, the task runs on a new thread. Executors with a pool for
+    /// blocking tasks should use it instead
     fn spawn_blocking<F, Res>(&self, f: F) -> JoinHandle<Res>
     where
         F: FnOnce() -> Res + Send + 'static,
-        Res: Send + 'static;
+        Res: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        std::thread::spawn(move || {
+            let _ = tx.send(f());
+        });
+        JoinHandle::Thread(rx)
+    }
 
     /// returns a Stream that will produce at regular intervals
+    ///
+    /// with every runtime, the first value is produced after a full period
     fn interval(&self, duration: std::time::Duration) -> Interval;
     /// waits for a configurable time
     fn delay(&self, duration: std::time::Duration) -> Delay;
 
+    /// runs the future, giving up if it has not completed after `duration`
+    fn timeout<F: Future>(&self, duration: std::time::Duration, future: F) -> Timeout<F> {
+        Timeout {
+            future: Box::pin(future),
+            delay: self.delay(duration),
+        }
+    }
+
     /// returns which executor is currently used
     // test at runtime and manually choose the implementation
     // because we cannot (yet) have async trait methods,
@@ -57,11 +79,53 @@ impl Executor for TokioExecutor {
     }
 
     fn interval(&self, duration: std::time::Duration) -> Interval {
-        Interval::Tokio(tokio::time::interval(duration))
+        // tokio's interval ticks right away, async-std's after one period
+        let start = tokio::time::Instant::now() + duration;
+        Interval::Tokio(tokio::time::interval_at(start, duration))
     }
 
     fn delay(&self, duration: std::time::Duration) -> Delay {
-        Delay::Tokio(tokio::time::sleep(duration))
+        Delay::Tokio(Box::pin(tokio::time::sleep(duration)))
+    }
+
+    fn kind(&self) -> ExecutorKind {
+        ExecutorKind::Tokio
+    }
+}
+
+/// Wrapper for a specific Tokio runtime
+///
+/// tasks are spawned on the runtime behind the handle instead of the one
+/// running the caller, so the client can be used from outside of it
+#[cfg(feature = "tokio-runtime")]
+#[derive(Clone, Debug)]
+pub struct TokioHandleExecutor(pub tokio::runtime::Handle);
+
+#[cfg(feature = "tokio-runtime")]
+impl Executor for TokioHandleExecutor {
+    fn spawn(&self, f: Pin<Box<dyn Future<Output = ()> + Send>>) -> Result<(), ()> {
+        self.0.spawn(f);
+        Ok(())
+    }
+
+    fn spawn_blocking<F, Res>(&self, f: F) -> JoinHandle<Res>
+    where
+        F: FnOnce() -> Res + Send + 'static,
+        Res: Send + 'static,
+    {
+        JoinHandle::Tokio(self.0.spawn_blocking(f))
+    }
+
+    fn interval(&self, duration: std::time::Duration) -> Interval {
+        let _guard = self.0.enter();
+        // tokio's interval ticks right away, async-std's after one period
+        let start = tokio::time::Instant::now() + duration;
+        Interval::Tokio(tokio::time::interval_at(start, duration))
+    }
+
+    fn delay(&self, duration: std::time::Duration) -> Delay {
+        let _guard = self.0.enter();
+        Delay::Tokio(Box::pin(tokio::time::sleep(duration)))
     }
 
     fn kind(&self) -> ExecutorKind {
@@ -103,6 +167,39 @@ impl Executor for AsyncStdExecutor {
     }
 }
 
+/// Wrapper for the smol executor
+#[cfg(feature = "smol-runtime")]
+#[derive(Clone, Debug)]
+pub struct SmolExecutor;
+
+#[cfg(feature = "smol-runtime")]
+impl Executor for SmolExecutor {
+    fn spawn(&self, f: Pin<Box<dyn Future<Output = ()> + Send>>) -> Result<(), ()> {
+        smol::spawn(f).detach();
+        Ok(())
+    }
+
+    fn spawn_blocking<F, Res>(&self, f: F) -> JoinHandle<Res>
+    where
+        F: FnOnce() -> Res + Send + 'static,
+        Res: Send + 'static,
+    {
+        JoinHandle::Smol(smol::unblock(f))
+    }
+
+    fn interval(&self, duration: std::time::Duration) -> Interval {
+        Interval::Smol(smol::Timer::interval(duration))
+    }
+
+    fn delay(&self, duration: std::time::Duration) -> Delay {
+        Delay::Smol(smol::Timer::after(duration))
+    }
+
+    fn kind(&self) -> ExecutorKind {
+        ExecutorKind::Smol
+    }
+}
+
 impl<Exe: Executor> Executor for Arc<Exe> {
     fn spawn(&self, f: Pin<Box<dyn Future<Output = ()> + Send>>) -> Result<(), ()> {
         self.deref().spawn(f)
@@ -137,9 +234,11 @@ pub enum JoinHandle<T> {
     /// wrapper for async-std's `JoinHandle`
     #[cfg(feature = "async-std-runtime")]
     AsyncStd(async_std::task::JoinHandle<T>),
-    // here to avoid a compilation error since T is not used
-    #[cfg(all(not(feature = "tokio-runtime"), not(feature = "async-std-runtime")))]
-    PlaceHolder(T),
+    /// wrapper for smol's `Task`
+    #[cfg(feature = "smol-runtime")]
+    Smol(smol::Task<T>),
+    /// result of a task running on its own thread
+    Thread(oneshot::Receiver<T>),
 }
 
 use std::task::Poll;
@@ -151,17 +250,26 @@ impl<T> Future for JoinHandle<T> {
             #[cfg(feature = "tokio-runtime")]
             JoinHandle::Tokio(j) => match Pin::new(j).poll(cx) {
                 Poll::Pending => Poll::Pending,
-                Poll::Ready(v) => Poll::Ready(v.ok()),
+                Poll::Ready(Ok(v)) => Poll::Ready(Some(v)),
+                Poll::Ready(Err(e)) => {
+                    error!("blocking task failed: {}", e);
+                    Poll::Ready(None)
+                }
             },
             #[cfg(feature = "async-std-runtime")]
             JoinHandle::AsyncStd(j) => match Pin::new(j).poll(cx) {
                 Poll::Pending => Poll::Pending,
                 Poll::Ready(v) => Poll::Ready(Some(v)),
             },
-            #[cfg(all(not(feature = "tokio-runtime"), not(feature = "async-std-runtime")))]
-            JoinHandle::PlaceHolder(t) => {
-                unimplemented!("please activate one of the following cargo features: tokio-runtime, async-std-runtime")
-            }
+            #[cfg(feature = "smol-runtime")]
+            JoinHandle::Smol(j) => match Pin::new(j).poll(cx) {
+                Poll::Pending => Poll::Pending,
+                Poll::Ready(v) => Poll::Ready(Some(v)),
+            },
+            JoinHandle::Thread(rx) => match Pin::new(rx).poll(cx) {
+                Poll::Pending => Poll::Pending,
+                Poll::Ready(v) => Poll::Ready(v.ok()),
+            },
         }
     }
 }
@@ -174,7 +282,14 @@ pub enum Interval {
     /// wrapper for async-std's interval
     #[cfg(feature = "async-std-runtime")]
     AsyncStd(async_std::stream::Interval),
-    #[cfg(all(not(feature = "tokio-runtime"), not(feature = "async-std-runtime")))]
+    /// wrapper for smol's periodic `Timer`
+    #[cfg(feature = "smol-runtime")]
+    Smol(smol::Timer),
+    #[cfg(all(
+        not(feature = "tokio-runtime"),
+        not(feature = "async-std-runtime"),
+        not(feature = "smol-runtime")
+    ))]
     PlaceHolder,
 }
 
@@ -185,22 +300,29 @@ impl Stream for Interval {
         self: Pin<&mut Self>,
         cx: &mut std::task::Context,
     ) -> std::task::Poll<Option<Self::Item>> {
-        unsafe {
-            match Pin::get_unchecked_mut(self) {
-                #[cfg(feature = "tokio-runtime")]
-                Interval::Tokio(j) => match Pin::new_unchecked(j).poll_tick(cx) {
-                    Poll::Pending => Poll::Pending,
-                    Poll::Ready(_) => Poll::Ready(Some(())),
-                },
-                #[cfg(feature = "async-std-runtime")]
-                Interval::AsyncStd(j) => match Pin::new_unchecked(j).poll_next(cx) {
-                    Poll::Pending => Poll::Pending,
-                    Poll::Ready(v) => Poll::Ready(v),
-                },
-                #[cfg(all(not(feature = "tokio-runtime"), not(feature = "async-std-runtime")))]
-                Interval::PlaceHolder => {
-                    unimplemented!("please activate one of the following cargo features: tokio-runtime, async-std-runtime")
-                }
+        match self.get_mut() {
+            #[cfg(feature = "tokio-runtime")]
+            Interval::Tokio(j) => match j.poll_tick(cx) {
+                Poll::Pending => Poll::Pending,
+                Poll::Ready(_) => Poll::Ready(Some(())),
+            },
+            #[cfg(feature = "async-std-runtime")]
+            Interval::AsyncStd(j) => match Pin::new(j).poll_next(cx) {
+                Poll::Pending => Poll::Pending,
+                Poll::Ready(v) => Poll::Ready(v),
+            },
+            #[cfg(feature = "smol-runtime")]
+            Interval::Smol(j) => match Pin::new(j).poll_next(cx) {
+                Poll::Pending => Poll::Pending,
+                Poll::Ready(v) => Poll::Ready(v.map(drop)),
+            },
+            #[cfg(all(
+                not(feature = "tokio-runtime"),
+                not(feature = "async-std-runtime"),
+                not(feature = "smol-runtime")
+            ))]
+            Interval::PlaceHolder => {
+                unimplemented!("please activate one of the following cargo features: tokio-runtime, async-std-runtime, smol-runtime")
             }
         }
     }
@@ -210,29 +332,173 @@ impl Stream for Interval {
 pub enum Delay {
     /// wrapper around tokio's `Sleep`
     #[cfg(feature = "tokio-runtime")]
-    Tokio(tokio::time::Sleep),
+    Tokio(Pin<Box<tokio::time::Sleep>>),
     /// wrapper around async-std's `Delay`
     #[cfg(feature = "async-std-runtime")]
     AsyncStd(Pin<Box<dyn Future<Output = ()> + Send>>),
+    /// wrapper around smol's `Timer`
+    #[cfg(feature = "smol-runtime")]
+    Smol(smol::Timer),
 }
 
 impl Future for Delay {
     type Output = ();
 
     fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context) -> std::task::Poll<Self::Output> {
-        unsafe {
-            match Pin::get_unchecked_mut(self) {
-                #[cfg(feature = "tokio-runtime")]
-                Delay::Tokio(d) => match Pin::new_unchecked(d).poll(cx) {
-                    Poll::Pending => Poll::Pending,
-                    Poll::Ready(_) => Poll::Ready(()),
-                },
-                #[cfg(feature = "async-std-runtime")]
-                Delay::AsyncStd(j) => match Pin::new_unchecked(j).poll(cx) {
-                    Poll::Pending => Poll::Pending,
-                    Poll::Ready(_) => Poll::Ready(()),
-                },
-            }
+        match self.get_mut() {
+            #[cfg(feature = "tokio-runtime")]
+            Delay::Tokio(d) => d.as_mut().poll(cx),
+            #[cfg(feature = "async-std-runtime")]
+            Delay::AsyncStd(j) => j.as_mut().poll(cx),
+            #[cfg(feature = "smol-runtime")]
+            Delay::Smol(t) => Pin::new(t).poll(cx).map(drop),
         }
     }
 }
+
+/// error returned by [Executor::timeout] when the future did not complete in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl std::fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "deadline has elapsed")
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+/// future returned by [Executor::timeout]
+pub struct Timeout<F: Future> {
+    future: Pin<Box<F>>,
+    delay: Delay,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context) -> std::task::Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Poll::Ready(v) = this.future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(v));
+        }
+        match Pin::new(&mut this.delay).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn tokio_handle_executor_spawns_on_its_runtime() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let executor = TokioHandleExecutor(runtime.handle().clone());
+
+        // there is no runtime on this thread, tokio::spawn would panic
+        let (tx, rx) = futures::channel::oneshot::channel();
+        executor
+            .spawn(Box::pin(async move {
+                let _ = tx.send(());
+            }))
+            .unwrap();
+        futures::executor::block_on(rx).unwrap();
+        futures::executor::block_on(executor.delay(std::time::Duration::from_millis(1)));
+    }
+
+    async fn first_ticks<Exe: Executor>(executor: Exe) {
+        use futures::StreamExt;
+        let period = std::time::Duration::from_millis(50);
+        let start = std::time::Instant::now();
+        let mut interval = executor.interval(period);
+
+        interval.next().await;
+        assert!(start.elapsed() >= period);
+        interval.next().await;
+        assert!(start.elapsed() >= 2 * period);
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn tokio_interval_waits_a_period() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(first_ticks(TokioExecutor));
+    }
+
+    #[cfg(feature = "async-std-runtime")]
+    #[test]
+    fn async_std_interval_waits_a_period() {
+        async_std::task::block_on(first_ticks(AsyncStdExecutor));
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn timeout_completes_first() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let res = runtime.block_on(async {
+            TokioExecutor
+                .timeout(
+                    std::time::Duration::from_secs(10),
+                    futures::future::ready(42),
+                )
+                .await
+        });
+        assert_eq!(res, Ok(42));
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn timeout_elapses_first() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let res = runtime.block_on(async {
+            TokioExecutor
+                .timeout(
+                    std::time::Duration::from_millis(10),
+                    futures::future::pending::<()>(),
+                )
+                .await
+        });
+        assert_eq!(res, Err(Elapsed));
+    }
+
+    #[cfg(feature = "smol-runtime")]
+    #[test]
+    fn smol_interval_waits_a_period() {
+        smol::block_on(first_ticks(SmolExecutor));
+    }
+
+    /// executor relying on the default `spawn_blocking`
+    #[cfg(feature = "tokio-runtime")]
+    #[derive(Clone)]
+    struct MinimalExecutor;
+
+    #[cfg(feature = "tokio-runtime")]
+    impl Executor for MinimalExecutor {
+        fn spawn(&self, f: Pin<Box<dyn Future<Output = ()> + Send>>) -> Result<(), ()> {
+            TokioExecutor.spawn(f)
+        }
+
+        fn interval(&self, duration: std::time::Duration) -> Interval {
+            TokioExecutor.interval(duration)
+        }
+
+        fn delay(&self, duration: std::time::Duration) -> Delay {
+            TokioExecutor.delay(duration)
+        }
+
+        fn kind(&self) -> ExecutorKind {
+            ExecutorKind::Tokio
+        }
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[test]
+    fn default_spawn_blocking_runs_on_a_thread() {
+        let res = futures::executor::block_on(MinimalExecutor.spawn_blocking(|| 42));
+        assert_eq!(res, Some(42));
+    }
+}
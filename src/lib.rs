@@ -8,10 +8,13 @@
 //! - URL based (`pulsar://` and `pulsar+ssl://`) connections with DNS lookup
 //! - multi topic consumers (based on a regex)
 //! - TLS connection
-//! - configurable executor (Tokio or async-std)
+//! - configurable executor (Tokio, async-std or smol, with the `smol-runtime` feature)
 //! - automatic reconnection with exponential back off
 //! - message batching
 //! - compression with LZ4, zlib, zstd or Snappy (can be deactivated with Cargo features)
+//! - typed messages with the [SerializeMessage] and [DeserializeMessage] traits,
+//!   JSON schemas (with the `json-schema` feature) and Avro values (with the `avro` feature)
+//! - `tracing` spans following each request to the broker (with the `tracing` feature)
 //!
 //! ## Examples
 //!
@@ -157,24 +160,30 @@ extern crate prost_derive;
 #[macro_use]
 extern crate serde;
 
-pub use client::{DeserializeMessage, Pulsar, PulsarBuilder, SerializeMessage};
-pub use connection::Authentication;
+pub use client::{DeserializeMessage, NamespaceTopics, Pulsar, PulsarBuilder, SerializeMessage};
+pub use connection::{
+    Authentication, ConnectionMetrics, ReceiverStats, RequestKey, Resolver, SerialId,
+};
 pub use connection_manager::{
-    BrokerAddress, ConnectionRetryOptions, OperationRetryOptions, TlsOptions,
+    BrokerAddress, ConnectionOptions, ConnectionRetryOptions, ConnectionState,
+    OperationRetryOptions, TlsOptions,
 };
-pub use consumer::{Consumer, ConsumerBuilder, ConsumerOptions};
+pub use consumer::{Consumer, ConsumerBuilder, ConsumerOptions, ConsumerOptionsBuilder};
 pub use error::Error;
 #[cfg(feature = "async-std-runtime")]
 pub use executor::AsyncStdExecutor;
 pub use executor::Executor;
+#[cfg(feature = "smol-runtime")]
+pub use executor::SmolExecutor;
 #[cfg(feature = "tokio-runtime")]
-pub use executor::TokioExecutor;
+pub use executor::{TokioExecutor, TokioHandleExecutor};
 pub use message::proto::command_subscribe::SubType;
 pub use message::{
     proto::{self, CommandSendReceipt},
     Payload,
 };
 pub use producer::{MultiTopicProducer, Producer, ProducerOptions};
+pub use transaction::TxnId;
 
 mod client;
 mod connection;
@@ -185,12 +194,14 @@ pub mod executor;
 pub mod message;
 pub mod producer;
 pub mod reader;
+pub mod schema;
 pub mod authentication;
 mod service_discovery;
+pub mod transaction;
 
 #[cfg(test)]
 mod tests {
-    use futures::{future::try_join_all, StreamExt};
+    use futures::{future::try_join_all, FutureExt, StreamExt};
     use log::{LevelFilter, Metadata, Record};
     use std::collections::BTreeSet;
     use std::time::{Duration, Instant};
@@ -203,6 +214,7 @@ mod tests {
 
     use crate::client::SerializeMessage;
     use crate::consumer::{InitialPosition, Message};
+    use crate::error::ProducerError;
     use crate::message::proto::command_subscribe::SubType;
     use crate::message::Payload;
     use crate::Error as PulsarError;
@@ -575,4 +587,440 @@ mod tests {
         }
         assert_eq!(count, 9);
     }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn batch_timeout() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_batch_timeout_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        let mut producer = pulsar
+            .producer()
+            .with_topic(&topic)
+            .with_options(ProducerOptions {
+                batch_size: Some(5),
+                batch_timeout: Some(Duration::from_millis(200)),
+                ..Default::default()
+            })
+            .build()
+            .await
+            .unwrap();
+
+        let mut consumer: Consumer<String, _> =
+            pulsar.consumer().with_topic(topic).build().await.unwrap();
+
+        // a single message is sent when the batch times out, without any
+        // other call to the producer
+        let receipt = producer.send("data".to_string()).await.unwrap();
+        timeout(Duration::from_secs(5), receipt)
+            .await
+            .unwrap()
+            .unwrap();
+        let message = timeout(Duration::from_secs(5), consumer.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.deserialize().unwrap(), "data");
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn flush_waits_for_receipts() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_flush_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        let mut producer = pulsar
+            .producer()
+            .with_topic(&topic)
+            .with_options(ProducerOptions {
+                batch_size: Some(100),
+                ..Default::default()
+            })
+            .build()
+            .await
+            .unwrap();
+
+        let mut send_receipts = Vec::new();
+        for i in 0..10 {
+            send_receipts.push(producer.send(i.to_string()).await.unwrap());
+        }
+        producer.flush().await.unwrap();
+
+        // every receipt is available once flush returns
+        for receipt in send_receipts {
+            receipt.now_or_never().unwrap().unwrap();
+        }
+        // flushing an empty batch returns immediately
+        producer.flush().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn max_pending_messages() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_pending_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        let options = ProducerOptions {
+            batch_size: Some(100),
+            max_pending_messages: Some(2),
+            ..Default::default()
+        };
+        let mut producer = pulsar
+            .producer()
+            .with_topic(&topic)
+            .with_options(options.clone())
+            .build()
+            .await
+            .unwrap();
+
+        let first = producer.send("a").await.unwrap();
+        let _second = producer.send("b").await.unwrap();
+        match producer.send("c").await {
+            Err(PulsarError::Producer(ProducerError::QueueFull)) => {}
+            other => panic!("expected a full queue, got {:?}", other.map(drop)),
+        }
+        producer.flush().await.unwrap();
+        first.await.unwrap();
+        producer.send("c").await.unwrap();
+
+        let mut producer = pulsar
+            .producer()
+            .with_topic(&topic)
+            .with_options(ProducerOptions {
+                block_if_queue_full: true,
+                ..options
+            })
+            .build()
+            .await
+            .unwrap();
+
+        let first = producer.send("a").await.unwrap();
+        let second = producer.send("b").await.unwrap();
+        // sending a third message waits for the receipts of the first two
+        let _third = producer.send("c").await.unwrap();
+        first.now_or_never().unwrap().unwrap();
+        second.now_or_never().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn topics_hash() {
+        use crate::message::proto::command_get_topics_of_namespace::Mode;
+
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_topics_hash_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        pulsar
+            .send(topic.as_str(), "data")
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+
+        let namespace = "public/default".to_string();
+        let first = pulsar
+            .get_topics_of_namespace_with_hash(namespace.clone(), Mode::Persistent, None)
+            .await
+            .unwrap();
+        assert!(first.topics.iter().any(|t| t.ends_with(&topic)));
+        assert!(first.changed_since(&None));
+
+        let unchanged = pulsar
+            .get_topics_of_namespace_with_hash(namespace, Mode::Persistent, first.hash.clone())
+            .await
+            .unwrap();
+        if first.hash.is_some() {
+            assert!(unchanged.topics.is_empty());
+            assert!(!unchanged.changed_since(&first.hash));
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn healthcheck() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_healthcheck_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        pulsar.lookup_topic(topic).await.unwrap();
+
+        let connections = pulsar.healthcheck(Duration::from_secs(5)).await;
+        assert!(!connections.is_empty());
+        assert!(connections.iter().all(|(_, healthy)| *healthy));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn start_message_id_inclusive() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_start_message_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        let mut producer = pulsar.producer().with_topic(&topic).build().await.unwrap();
+        let mut message_ids = Vec::new();
+        for i in 0..3 {
+            let receipt = producer.send(i.to_string()).await.unwrap().await.unwrap();
+            message_ids.push(receipt.message_id.unwrap());
+        }
+
+        for inclusive in [true, false] {
+            let mut consumer: Consumer<String, _> = pulsar
+                .consumer()
+                .with_topic(&topic)
+                .with_subscription_type(SubType::Exclusive)
+                .with_subscription(format!("test_start_message_{}", inclusive))
+                .with_options(
+                    ConsumerOptions::default()
+                        .durable(false)
+                        .starting_on_message(message_ids[1].clone())
+                        .with_start_message_id_inclusive(inclusive),
+                )
+                .build()
+                .await
+                .unwrap();
+
+            let msg = timeout(Duration::from_secs(10), consumer.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            let expected = if inclusive { "1" } else { "2" };
+            assert_eq!(msg.deserialize().unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(all(feature = "tokio-runtime", feature = "lz4"))]
+    async fn lz4_round_trip() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_lz4_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        let mut producer = pulsar
+            .producer()
+            .with_topic(&topic)
+            .with_options(ProducerOptions {
+                compression: Some(crate::message::proto::CompressionType::Lz4),
+                ..Default::default()
+            })
+            .build()
+            .await
+            .unwrap();
+
+        let mut consumer: Consumer<String, _> =
+            pulsar.consumer().with_topic(topic).build().await.unwrap();
+
+        let data = "lz4 ".repeat(100);
+        producer.send(data.clone()).await.unwrap().await.unwrap();
+
+        let message = timeout(Duration::from_secs(1), consumer.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.metadata().compression, Some(1));
+        assert_eq!(
+            message.metadata().uncompressed_size,
+            Some(data.len() as u32)
+        );
+        assert_eq!(message.deserialize().unwrap(), data);
+        consumer.ack(&message).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(all(feature = "tokio-runtime", feature = "zstd"))]
+    async fn zstd_round_trip() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_zstd_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        let mut producer = pulsar
+            .producer()
+            .with_topic(&topic)
+            .with_options(ProducerOptions {
+                compression: Some(crate::message::proto::CompressionType::Zstd),
+                zstd_level: Some(19),
+                ..Default::default()
+            })
+            .build()
+            .await
+            .unwrap();
+
+        let mut consumer: Consumer<String, _> =
+            pulsar.consumer().with_topic(topic).build().await.unwrap();
+
+        let data = "zstd".repeat(256 * 1024);
+        producer.send(data.clone()).await.unwrap().await.unwrap();
+
+        let message = timeout(Duration::from_secs(1), consumer.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.metadata().compression, Some(3));
+        assert_eq!(
+            message.metadata().uncompressed_size,
+            Some(data.len() as u32)
+        );
+        assert_eq!(message.deserialize().unwrap(), data);
+        consumer.ack(&message).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn failover_active_consumer() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_failover_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        let mut consumers = Vec::new();
+        for name in ["failover_1", "failover_2"] {
+            let consumer: Consumer<String, _> = pulsar
+                .consumer()
+                .with_topic(&topic)
+                .with_consumer_name(name)
+                .with_subscription_type(SubType::Failover)
+                .with_subscription("failover_subscription")
+                .build()
+                .await
+                .unwrap();
+            consumers.push(consumer);
+        }
+
+        // the broker notifies both consumers after they subscribed
+        let start = Instant::now();
+        while consumers.iter().filter(|c| c.is_active()).count() != 1 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn paused_consumer() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_pause_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        let mut consumer: Consumer<String, _> = pulsar
+            .consumer()
+            .with_topic(&topic)
+            .with_batch_size(10)
+            .build()
+            .await
+            .unwrap();
+        consumer.pause();
+
+        let mut producer = pulsar.producer().with_topic(&topic).build().await.unwrap();
+        for i in 0..30 {
+            producer.send(i.to_string()).await.unwrap().await.unwrap();
+        }
+
+        // the messages allowed by the initial flow are still delivered
+        for _ in 0..10 {
+            let message = timeout(Duration::from_secs(1), consumer.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            consumer.ack(&message).await.unwrap();
+        }
+        assert!(timeout(Duration::from_millis(500), consumer.next())
+            .await
+            .is_err());
+
+        consumer.resume().await.unwrap();
+        for _ in 10..30 {
+            let message = timeout(Duration::from_secs(1), consumer.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            consumer.ack(&message).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn close_sends_grouped_acks() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_close_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        let build_consumer = || {
+            pulsar
+                .consumer()
+                .with_topic(&topic)
+                .with_subscription("close_subscription")
+                .with_options(ConsumerOptions {
+                    initial_position: InitialPosition::Earliest,
+                    // acks are only sent by the close
+                    ack_grouping: Some(crate::consumer::AckConfig {
+                        ack_group_time: Duration::from_secs(3600),
+                        max_group_size: 1000,
+                    }),
+                    ..Default::default()
+                })
+                .build::<String>()
+        };
+        let mut consumer = build_consumer().await.unwrap();
+
+        let mut producer = pulsar.producer().with_topic(&topic).build().await.unwrap();
+        for i in 0..5 {
+            producer.send(i.to_string()).await.unwrap().await.unwrap();
+        }
+        for _ in 0..5 {
+            let message = timeout(Duration::from_secs(1), consumer.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            consumer.ack(&message).await.unwrap();
+        }
+        consumer.close().await.unwrap();
+
+        // the acked messages are not delivered again
+        let mut consumer = build_consumer().await.unwrap();
+        assert!(timeout(Duration::from_millis(500), consumer.next())
+            .await
+            .is_err());
+    }
 }
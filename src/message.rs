@@ -4,7 +4,7 @@ use crate::error::ConnectionError;
 use bytes::{Buf, BufMut, BytesMut};
 use nom::{
     bytes::streaming::take,
-    combinator::{map_res, verify},
+    combinator::{map_res, opt, verify},
     number::streaming::{be_u16, be_u32},
     IResult,
 };
@@ -32,6 +32,19 @@ pub struct Message {
 }
 
 impl Message {
+    /// size of the frame carrying this message on the wire
+    pub fn frame_size(&self) -> usize {
+        let command_size = self.command.encoded_len();
+        let metadata_size = self
+            .payload
+            .as_ref()
+            .map(|p| p.metadata.encoded_len())
+            .unwrap_or(0);
+        let payload_size = self.payload.as_ref().map(|p| p.data.len()).unwrap_or(0);
+        let header_size = if self.payload.is_some() { 18 } else { 8 };
+        command_size + metadata_size + payload_size + header_size
+    }
+
     /// returns the message's RequestKey if present
     pub fn request_key(&self) -> Option<RequestKey> {
         match &self.command {
@@ -72,10 +85,6 @@ impl Message {
                 seek: Some(CommandSeek { request_id, .. }),
                 ..
             }
-            | BaseCommand {
-                close_producer: Some(CommandCloseProducer { request_id, .. }),
-                ..
-            }
             | BaseCommand {
                 success: Some(CommandSuccess { request_id, .. }),
                 ..
@@ -117,6 +126,40 @@ impl Message {
             | BaseCommand {
                 get_schema_response: Some(CommandGetSchemaResponse { request_id, .. }),
                 ..
+            }
+            | BaseCommand {
+                new_txn: Some(CommandNewTxn { request_id, .. }),
+                ..
+            }
+            | BaseCommand {
+                new_txn_response: Some(CommandNewTxnResponse { request_id, .. }),
+                ..
+            }
+            | BaseCommand {
+                add_partition_to_txn: Some(CommandAddPartitionToTxn { request_id, .. }),
+                ..
+            }
+            | BaseCommand {
+                add_partition_to_txn_response:
+                    Some(CommandAddPartitionToTxnResponse { request_id, .. }),
+                ..
+            }
+            | BaseCommand {
+                add_subscription_to_txn: Some(CommandAddSubscriptionToTxn { request_id, .. }),
+                ..
+            }
+            | BaseCommand {
+                add_subscription_to_txn_response:
+                    Some(CommandAddSubscriptionToTxnResponse { request_id, .. }),
+                ..
+            }
+            | BaseCommand {
+                end_txn: Some(CommandEndTxn { request_id, .. }),
+                ..
+            }
+            | BaseCommand {
+                end_txn_response: Some(CommandEndTxnResponse { request_id, .. }),
+                ..
             } => Some(RequestKey::RequestId(*request_id)),
             BaseCommand {
                 send:
@@ -186,6 +229,17 @@ impl Message {
                 consumer_id: *consumer_id,
                 request_id: *request_id,
             }),
+            BaseCommand {
+                close_producer:
+                    Some(CommandCloseProducer {
+                        producer_id,
+                        request_id,
+                    }),
+                ..
+            } => Some(RequestKey::CloseProducer {
+                producer_id: *producer_id,
+                request_id: *request_id,
+            }),
             BaseCommand {
                 connect: Some(_), ..
             }
@@ -215,8 +269,42 @@ impl Message {
     }
 }
 
+/// default `maxMessageSize` of the brokers, used when they do not send theirs
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 5 * 1024 * 1024;
+/// room left in a frame for the command and metadata
+pub(crate) const FRAME_PADDING: usize = 10 * 1024;
+/// default maximum size of a frame: the broker's default `maxMessageSize`
+/// with room for the command and metadata
+pub const DEFAULT_MAX_FRAME_SIZE: usize = DEFAULT_MAX_MESSAGE_SIZE + FRAME_PADDING;
+
 /// tokio and async-std codec for Pulsar messages
-pub struct Codec;
+pub struct Codec {
+    max_frame_size: usize,
+}
+
+impl Codec {
+    /// codec refusing to encode or decode frames larger than `max_frame_size`
+    pub fn new(max_frame_size: usize) -> Codec {
+        Codec { max_frame_size }
+    }
+
+    fn check_frame_size(&self, size: usize) -> Result<(), ConnectionError> {
+        if size > self.max_frame_size {
+            Err(ConnectionError::FrameTooLarge {
+                size,
+                max: self.max_frame_size,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
 
 #[cfg(feature = "tokio-runtime")]
 impl tokio_util::codec::Encoder<Message> for Codec {
@@ -233,6 +321,7 @@ impl tokio_util::codec::Encoder<Message> for Codec {
         let header_size = if item.payload.is_some() { 18 } else { 8 };
         // Total size does not include the size of the 'totalSize' field, so we subtract 4
         let total_size = command_size + metadata_size + payload_size + header_size - 4;
+        self.check_frame_size(total_size + 4)?;
         let mut buf = Vec::with_capacity(total_size + 4);
 
         // Simple command frame
@@ -277,6 +366,8 @@ impl tokio_util::codec::Decoder for Codec {
             let mut buf = Cursor::new(src);
             // `messageSize` refers only to _remaining_ message size, so we add 4 to get total frame size
             let message_size = buf.get_u32() as usize + 4;
+            // a malformed size would otherwise make the buffer grow without limit
+            self.check_frame_size(message_size)?;
             let src = buf.into_inner();
             if src.len() >= message_size {
                 let msg = {
@@ -290,20 +381,7 @@ impl tokio_util::codec::Decoder for Codec {
                     let command = BaseCommand::decode(command_frame.command)?;
 
                     let payload = if !buf.is_empty() {
-                        let (buf, payload_frame) = payload_frame(buf).map_err(|err| {
-                            ConnectionError::Decoding(format!(
-                                "Error decoding payload frame: {:?}",
-                                err
-                            ))
-                        })?;
-
-                        // TODO: Check crc32 of payload data
-
-                        let metadata = Metadata::decode(payload_frame.metadata)?;
-                        Some(Payload {
-                            metadata,
-                            data: buf.to_vec(),
-                        })
+                        decode_payload(buf)?
                     } else {
                         None
                     };
@@ -321,7 +399,7 @@ impl tokio_util::codec::Decoder for Codec {
     }
 }
 
-#[cfg(feature = "async-std-runtime")]
+#[cfg(any(feature = "async-std-runtime", feature = "smol-runtime"))]
 impl asynchronous_codec::Encoder for Codec {
     type Item = Message;
     type Error = ConnectionError;
@@ -337,6 +415,7 @@ impl asynchronous_codec::Encoder for Codec {
         let header_size = if item.payload.is_some() { 18 } else { 8 };
         // Total size does not include the size of the 'totalSize' field, so we subtract 4
         let total_size = command_size + metadata_size + payload_size + header_size - 4;
+        self.check_frame_size(total_size + 4)?;
         let mut buf = Vec::with_capacity(total_size + 4);
 
         // Simple command frame
@@ -370,7 +449,7 @@ impl asynchronous_codec::Encoder for Codec {
     }
 }
 
-#[cfg(feature = "async-std-runtime")]
+#[cfg(any(feature = "async-std-runtime", feature = "smol-runtime"))]
 impl asynchronous_codec::Decoder for Codec {
     type Item = Message;
     type Error = ConnectionError;
@@ -381,6 +460,8 @@ impl asynchronous_codec::Decoder for Codec {
             let mut buf = Cursor::new(src);
             // `messageSize` refers only to _remaining_ message size, so we add 4 to get total frame size
             let message_size = buf.get_u32() as usize + 4;
+            // a malformed size would otherwise make the buffer grow without limit
+            self.check_frame_size(message_size)?;
             let src = buf.into_inner();
             if src.len() >= message_size {
                 let msg = {
@@ -394,20 +475,7 @@ impl asynchronous_codec::Decoder for Codec {
                     let command = BaseCommand::decode(command_frame.command)?;
 
                     let payload = if !buf.is_empty() {
-                        let (buf, payload_frame) = payload_frame(buf).map_err(|err| {
-                            ConnectionError::Decoding(format!(
-                                "Error decoding payload frame: {:?}",
-                                err
-                            ))
-                        })?;
-
-                        // TODO: Check crc32 of payload data
-
-                        let metadata = Metadata::decode(payload_frame.metadata)?;
-                        Some(Payload {
-                            metadata,
-                            data: buf.to_vec(),
-                        })
+                        decode_payload(buf)?
                     } else {
                         None
                     };
@@ -425,15 +493,67 @@ impl asynchronous_codec::Decoder for Codec {
     }
 }
 
+/// decodes the payload frame following a command
+///
+/// a payload that does not match its checksum is dropped, the consumer then
+/// asks the broker to send it again
+fn decode_payload(buf: &[u8]) -> Result<Option<Payload>, ConnectionError> {
+    let (buf, payload_frame) = payload_frame(buf).map_err(|err| {
+        ConnectionError::Decoding(format!("Error decoding payload frame: {:?}", err))
+    })?;
+
+    if let Err(e) = payload_frame.verify_checksum() {
+        warn!("dropping corrupted payload, it will be redelivered: {}", e);
+        return Ok(None);
+    }
+
+    let metadata = Metadata::decode(payload_frame.metadata)?;
+    let broker_entry_metadata = payload_frame
+        .broker_entry_metadata
+        .map(BrokerEntryMetadata::decode)
+        .transpose()?;
+    Ok(Some(Payload {
+        metadata,
+        broker_entry_metadata,
+        data: buf.to_vec(),
+    }))
+}
+
 /// message payload
-#[derive(Debug, Clone)]
+///
+/// fields added in later versions have a default value, so a payload is built
+/// with [`Payload::new`] or with `..Default::default()`
+#[derive(Debug, Clone, Default)]
 pub struct Payload {
     /// message metadata added by Pulsar
     pub metadata: Metadata,
+    /// metadata added by the broker when storing the entry, only present if
+    /// the broker is configured to expose it to clients
+    pub broker_entry_metadata: Option<BrokerEntryMetadata>,
     /// raw message data
     pub data: Vec<u8>,
 }
 
+impl Payload {
+    /// payload without broker entry metadata
+    pub fn new(metadata: Metadata, data: Vec<u8>) -> Self {
+        Payload {
+            metadata,
+            broker_entry_metadata: None,
+            data,
+        }
+    }
+
+    /// sets the metadata added by the broker when storing the entry
+    pub fn with_broker_entry_metadata(
+        mut self,
+        broker_entry_metadata: BrokerEntryMetadata,
+    ) -> Self {
+        self.broker_entry_metadata = Some(broker_entry_metadata);
+        self
+    }
+}
+
 struct CommandFrame<'a> {
     #[allow(dead_code)]
     total_size: u32,
@@ -458,32 +578,62 @@ fn command_frame(i: &[u8]) -> IResult<&[u8], CommandFrame> {
 }
 
 struct PayloadFrame<'a> {
-    #[allow(dead_code)]
+    broker_entry_metadata: Option<&'a [u8]>,
     magic_number: u16,
-    #[allow(dead_code)]
     checksum: u32,
     #[allow(dead_code)]
     metadata_size: u32,
     metadata: &'a [u8],
+    /// metadata size, metadata and payload, covered by the checksum
+    checksummed: &'a [u8],
+}
+
+impl<'a> PayloadFrame<'a> {
+    fn verify_checksum(&self) -> Result<(), ConnectionError> {
+        // other magic numbers do not come with a CRC32C checksum
+        if self.magic_number != 0x0e01 {
+            return Ok(());
+        }
+        let computed = CRC_CASTAGNOLI.checksum(self.checksummed);
+        if computed == self.checksum {
+            Ok(())
+        } else {
+            Err(ConnectionError::Checksum {
+                expected: self.checksum,
+                computed,
+            })
+        }
+    }
 }
 
 fn payload_frame(i: &[u8]) -> IResult<&[u8], PayloadFrame> {
+    let (i, broker_entry_metadata) = opt(broker_entry_metadata_frame)(i)?;
     let (i, magic_number) = be_u16(i)?;
     let (i, checksum) = be_u32(i)?;
+    let checksummed = i;
     let (i, metadata_size) = be_u32(i)?;
     let (i, metadata) = take(metadata_size)(i)?;
 
     Ok((
         i,
         PayloadFrame {
+            broker_entry_metadata,
             magic_number,
             checksum,
             metadata_size,
             metadata,
+            checksummed,
         },
     ))
 }
 
+// the broker entry metadata, when present, is placed before the payload frame
+fn broker_entry_metadata_frame(i: &[u8]) -> IResult<&[u8], &[u8]> {
+    let (i, _magic_number) = verify(be_u16, |magic_number| *magic_number == 0x0e02)(i)?;
+    let (i, metadata_size) = be_u32(i)?;
+    take(metadata_size)(i)
+}
+
 pub(crate) struct BatchedMessage {
     pub metadata: proto::SingleMessageMetadata,
     pub payload: Vec<u8>,
@@ -604,6 +754,7 @@ impl From<prost::DecodeError> for ConnectionError {
 
 #[cfg(test)]
 mod tests {
+    use crate::error::ConnectionError;
     use crate::message::Codec;
     use bytes::BytesMut;
     use std::convert::TryFrom;
@@ -617,7 +768,7 @@ mod tests {
             0x6E, 0x67, 0x20, 0x0C, 0x2A, 0x04, 0x6E, 0x6F, 0x6E, 0x65,
         ];
 
-        let message = Codec.decode(&mut input.into()).unwrap().unwrap();
+        let message = Codec::default().decode(&mut input.into()).unwrap().unwrap();
 
         {
             let connect = message.command.connect.as_ref().unwrap();
@@ -626,8 +777,9 @@ mod tests {
             assert_eq!(connect.protocol_version.as_ref().unwrap(), &12);
         }
 
+        assert_eq!(message.frame_size(), input.len());
         let mut output = BytesMut::with_capacity(38);
-        Codec.encode(message, &mut output).unwrap();
+        Codec::default().encode(message, &mut output).unwrap();
         assert_eq!(&output, input);
     }
 
@@ -641,7 +793,7 @@ mod tests {
             0x2D, 0x70, 0x75, 0x6C, 0x73, 0x61, 0x72, 0x2D, 0x38,
         ];
 
-        let message = Codec.decode(&mut input.into()).unwrap().unwrap();
+        let message = Codec::default().decode(&mut input.into()).unwrap().unwrap();
         {
             let send = message.command.send.as_ref().unwrap();
             assert_eq!(send.producer_id, 0);
@@ -655,11 +807,86 @@ mod tests {
             assert_eq!(payload.metadata.publish_time, 1533850624062);
         }
 
+        assert_eq!(message.frame_size(), input.len());
         let mut output = BytesMut::with_capacity(65);
-        Codec.encode(message, &mut output).unwrap();
+        Codec::default().encode(message, &mut output).unwrap();
         assert_eq!(&output, input);
     }
 
+    #[test]
+    fn parse_payload_with_broker_entry_metadata() {
+        let input: &[u8] = &[
+            0x00, 0x00, 0x00, 0x47, 0x00, 0x00, 0x00, 0x08, 0x08, 0x06, 0x32, 0x04, 0x08, 0x00,
+            0x10, 0x08, 0x0E, 0x02, 0x00, 0x00, 0x00, 0x04, 0x08, 0x01, 0x10, 0x05, 0x0E, 0x01,
+            0x42, 0x83, 0x54, 0xB5, 0x00, 0x00, 0x00, 0x19, 0x0A, 0x0E, 0x73, 0x74, 0x61, 0x6E,
+            0x64, 0x61, 0x6C, 0x6F, 0x6E, 0x65, 0x2D, 0x30, 0x2D, 0x33, 0x10, 0x08, 0x18, 0xBE,
+            0xC0, 0xFC, 0x84, 0xD2, 0x2C, 0x68, 0x65, 0x6C, 0x6C, 0x6F, 0x2D, 0x70, 0x75, 0x6C,
+            0x73, 0x61, 0x72, 0x2D, 0x38,
+        ];
+
+        let message = Codec::default().decode(&mut input.into()).unwrap().unwrap();
+        let payload = message.payload.as_ref().unwrap();
+        let broker_entry_metadata = payload.broker_entry_metadata.as_ref().unwrap();
+        assert_eq!(broker_entry_metadata.broker_timestamp, Some(1));
+        assert_eq!(broker_entry_metadata.index, Some(5));
+        assert_eq!(payload.metadata.producer_name, "standalone-0-3");
+        assert_eq!(payload.data, b"hello-pulsar-8");
+    }
+
+    #[test]
+    fn frame_too_large() {
+        // a size prefix of 1GB
+        let input: &[u8] = &[0x40, 0x00, 0x00, 0x00, 0x00, 0x00];
+        match Codec::default().decode(&mut input.into()) {
+            Err(ConnectionError::FrameTooLarge { size, max }) => {
+                assert_eq!(size, 0x4000_0004);
+                assert_eq!(max, super::DEFAULT_MAX_FRAME_SIZE);
+            }
+            other => panic!("oversized frame accepted: {:?}", other),
+        }
+
+        let input: &[u8] = &[
+            0x00, 0x00, 0x00, 0x22, 0x00, 0x00, 0x00, 0x1E, 0x08, 0x02, 0x12, 0x1A, 0x0A, 0x10,
+            0x32, 0x2E, 0x30, 0x2E, 0x31, 0x2D, 0x69, 0x6E, 0x63, 0x75, 0x62, 0x61, 0x74, 0x69,
+            0x6E, 0x67, 0x20, 0x0C, 0x2A, 0x04, 0x6E, 0x6F, 0x6E, 0x65,
+        ];
+        let message = Codec::default().decode(&mut input.into()).unwrap().unwrap();
+        let mut output = BytesMut::new();
+        assert!(matches!(
+            Codec::new(32).encode(message, &mut output),
+            Err(ConnectionError::FrameTooLarge { size: 38, max: 32 })
+        ));
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn corrupted_payload() {
+        let mut input = vec![
+            0x00, 0x00, 0x00, 0x3D, 0x00, 0x00, 0x00, 0x08, 0x08, 0x06, 0x32, 0x04, 0x08, 0x00,
+            0x10, 0x08, 0x0E, 0x01, 0x42, 0x83, 0x54, 0xB5, 0x00, 0x00, 0x00, 0x19, 0x0A, 0x0E,
+            0x73, 0x74, 0x61, 0x6E, 0x64, 0x61, 0x6C, 0x6F, 0x6E, 0x65, 0x2D, 0x30, 0x2D, 0x33,
+            0x10, 0x08, 0x18, 0xBE, 0xC0, 0xFC, 0x84, 0xD2, 0x2C, 0x68, 0x65, 0x6C, 0x6C, 0x6F,
+            0x2D, 0x70, 0x75, 0x6C, 0x73, 0x61, 0x72, 0x2D, 0x38,
+        ];
+        // "hello-pulsar-8" becomes "hello-pulsar-9"
+        *input.last_mut().unwrap() = 0x39;
+
+        let (buf, _) = super::command_frame(&input).unwrap();
+        let (_, payload_frame) = super::payload_frame(buf).unwrap();
+        match payload_frame.verify_checksum() {
+            Err(ConnectionError::Checksum { expected, .. }) => assert_eq!(expected, 0x428354B5),
+            other => panic!("corrupted payload accepted: {:?}", other),
+        }
+
+        // the command is still decoded, so that the message can be redelivered
+        let message = Codec::default()
+            .decode(&mut input[..].into())
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.command.send.as_ref().unwrap().sequence_id, 8);
+        assert!(message.payload.is_none());
+    }
+
     #[test]
     fn base_command_type_parsing() {
         use super::proto::base_command::Type;
@@ -1,20 +1,25 @@
 //! Message publication
-use futures::{channel::oneshot, future::try_join_all, lock::Mutex};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::try_join_all,
+    lock::Mutex,
+};
 use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::io::Write;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::client::SerializeMessage;
 use crate::connection::{self, Connection, SerialId};
 use crate::error::{ConnectionError, ProducerError};
 use crate::executor::Executor;
 use crate::message::proto::{self, CommandSendReceipt, CompressionType, EncryptionKeys, Schema};
-use crate::message::BatchedMessage;
-use crate::{Error, Pulsar};
+use crate::message::{BatchedMessage, Message as RawMessage};
+use crate::{Error, Pulsar, TxnId};
 use futures::task::{Context, Poll};
-use futures::Future;
+use futures::{Future, FutureExt, StreamExt};
 
 type ProducerId = u64;
 type ProducerName = String;
@@ -64,6 +69,12 @@ pub struct Message {
     pub event_time: ::std::option::Option<u64>,
     /// current version of the schema
     pub schema_version: ::std::option::Option<Vec<u8>>,
+    /// marks the message as a replication or transaction marker, using the
+    /// values of Pulsar's `MarkerType`. Regular applications should leave it empty
+    pub marker_type: ::std::option::Option<i32>,
+    /// transaction the message is part of, see [crate::transaction].
+    /// Transactional messages are not batched
+    pub txn_id: ::std::option::Option<TxnId>,
 }
 
 /// internal message type carrying options that must be defined
@@ -96,6 +107,14 @@ pub(crate) struct ProducerMessage {
     /// UTC Unix timestamp in milliseconds, time at which the message should be
     /// delivered to consumers
     pub deliver_at_time: ::std::option::Option<i64>,
+    pub marker_type: ::std::option::Option<i32>,
+    /// identifier shared by the chunks of a message
+    pub uuid: ::std::option::Option<String>,
+    pub chunk_id: ::std::option::Option<i32>,
+    pub num_chunks_from_msg: ::std::option::Option<i32>,
+    /// size of the whole payload split in chunks
+    pub total_chunk_msg_size: ::std::option::Option<i32>,
+    pub txn_id: ::std::option::Option<TxnId>,
 }
 
 impl From<Message> for ProducerMessage {
@@ -108,6 +127,8 @@ impl From<Message> for ProducerMessage {
             replicate_to: m.replicate_to,
             event_time: m.event_time,
             schema_version: m.schema_version,
+            marker_type: m.marker_type,
+            txn_id: m.txn_id,
             ..Default::default()
         }
     }
@@ -124,10 +145,53 @@ pub struct ProducerOptions {
     pub schema: Option<Schema>,
     /// batch message size
     pub batch_size: Option<u32>,
+    /// maximum size in bytes of a batch's payload, only used if `batch_size` is set
+    pub batch_byte_size: Option<usize>,
+    /// maximum time a message waits in a batch, only used if `batch_size` is set.
+    /// The batch is sent when it expires, even if no other message is sent
+    pub batch_timeout: Option<Duration>,
     /// algorithm used to compress the messages
     pub compression: Option<proto::CompressionType>,
+    /// zstd compression level, from 1 to 22 (default: 3)
+    pub zstd_level: Option<i32>,
     /// producer access mode: shared = 0, exclusive = 1, waitforexclusive =2, exclusivewithoutfencing =3
     pub access_mode: Option<i32>,
+    /// how messages are assigned to the partitions of a partitioned topic
+    /// (default: round robin)
+    pub partition_routing: Option<PartitionRouting>,
+    /// if set, payloads larger than this size (after compression) are split in
+    /// chunks sent as separate messages and reassembled by the consumer. It is
+    /// lowered to the broker's maximum message size if that one is smaller.
+    /// Chunking cannot be used with batching
+    pub max_message_size: Option<usize>,
+    /// sequence id of the first message sent by this producer (default: 0)
+    ///
+    /// when the broker knows a higher sequence id for this producer name, the
+    /// producer resumes after it instead. Messages sent again with the same
+    /// sequence id, after a reconnection for example, are only dropped by the
+    /// broker if deduplication is enabled on the namespace or topic
+    /// (`brokerDeduplicationEnabled`), and the producer name must then stay
+    /// the same across restarts
+    pub initial_sequence_id: Option<u64>,
+    /// maximum number of messages waiting for their receipt, counting the
+    /// messages still in the batch and the batches being sent. Once it is
+    /// reached, `send` returns [ProducerError::QueueFull]
+    pub max_pending_messages: Option<usize>,
+    /// when `max_pending_messages` is reached, send the current batch and
+    /// wait for the pending receipts instead of returning [ProducerError::QueueFull]
+    pub block_if_queue_full: bool,
+}
+
+/// how a producer on a partitioned topic chooses the partition of a message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionRouting {
+    /// messages are sent to each partition in turn
+    RoundRobin,
+    /// messages with a partition key are sent to the partition given by the
+    /// key's hash, so that they stay ordered. It uses the same hash as the
+    /// Java client's default, `JavaStringHash`. Messages without a key are
+    /// sent to each partition in turn
+    KeyHash,
 }
 
 /// Wrapper structure that manges multiple producers at once, creating them as needed
@@ -309,23 +373,60 @@ impl<Exe: Executor> Producer<Exe> {
     ) -> Result<SendFuture, Error> {
         match &mut self.inner {
             ProducerInner::Single(p) => p.send(message).await,
-            ProducerInner::Partitioned(p) => p.next().send(message).await,
+            ProducerInner::Partitioned(p) => {
+                let message = T::serialize_message(message)?;
+                p.route(message.partition_key.as_deref())
+                    .send_raw(message.into())
+                    .await
+            }
         }
     }
 
+    /// sends a message that will be delivered to consumers after `delay`
+    ///
+    /// delayed delivery is only supported on Shared subscriptions
+    pub async fn send_after<T: SerializeMessage + Sized>(
+        &mut self,
+        message: T,
+        delay: Duration,
+    ) -> Result<SendFuture, Error> {
+        self.create_message()
+            .with_content(message)
+            .delay(delay)
+            .map_err(|e| Error::Custom(e.to_string()))?
+            .send()
+            .await
+    }
+
     /// sends a list of messages
     pub async fn send_all<T, I>(&mut self, messages: I) -> Result<Vec<SendFuture>, Error>
     where
         T: SerializeMessage,
         I: IntoIterator<Item = T>,
     {
-        let producer = match &mut self.inner {
-            ProducerInner::Single(p) => p,
-            ProducerInner::Partitioned(p) => p.next(),
-        };
         let mut sends = Vec::new();
-        for message in messages {
-            sends.push(producer.send(message).await);
+        match &mut self.inner {
+            ProducerInner::Single(p) => {
+                for message in messages {
+                    sends.push(p.send(message).await);
+                }
+            }
+            ProducerInner::Partitioned(p) => {
+                // messages that are not routed by key all go to the same partition
+                let partition = p.next_partition();
+                for message in messages {
+                    let send = match T::serialize_message(message) {
+                        Ok(message) => {
+                            let index = p
+                                .key_partition(message.partition_key.as_deref())
+                                .unwrap_or(partition);
+                            p.producers[index].send_raw(message.into()).await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    sends.push(send);
+                }
+            }
         }
         if sends.iter().all(|s| s.is_ok()) {
             Ok(sends.into_iter().map(|s| s.unwrap()).collect())
@@ -346,10 +447,31 @@ impl<Exe: Executor> Producer<Exe> {
         }
     }
 
+    /// sends the pending batch right away and waits for the receipts of all
+    /// the messages sent until now
+    ///
+    /// returns an error if one of those messages could not be sent. Without
+    /// batching, [Producer::send] already waits for the broker's receipt, so
+    /// there is nothing left to wait for
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        match &mut self.inner {
+            ProducerInner::Single(p) => p.flush().await,
+            ProducerInner::Partitioned(p) => {
+                try_join_all(p.producers.iter_mut().map(|p| p.flush()))
+                    .await
+                    .map(drop)
+            }
+        }
+    }
+
     pub(crate) async fn send_raw(&mut self, message: ProducerMessage) -> Result<SendFuture, Error> {
         match &mut self.inner {
             ProducerInner::Single(p) => p.send_raw(message).await,
-            ProducerInner::Partitioned(p) => p.next().send_raw(message).await,
+            ProducerInner::Partitioned(p) => {
+                p.route(message.partition_key.as_deref())
+                    .send_raw(message)
+                    .await
+            }
         }
     }
 }
@@ -360,32 +482,244 @@ enum ProducerInner<Exe: Executor> {
 }
 
 struct PartitionedProducer<Exe: Executor> {
-    // Guaranteed to be non-empty
-    producers: VecDeque<TopicProducer<Exe>>,
+    // Guaranteed to be non-empty, ordered by partition
+    producers: Vec<TopicProducer<Exe>>,
+    /// partition used by the next message routed in round robin
+    next_partition: usize,
     topic: String,
     options: ProducerOptions,
 }
 
 impl<Exe: Executor> PartitionedProducer<Exe> {
-    pub fn next(&mut self) -> &mut TopicProducer<Exe> {
-        self.producers.rotate_left(1);
-        self.producers.front_mut().unwrap()
+    /// returns the producer of the partition a message should be sent to
+    pub fn route(&mut self, partition_key: Option<&str>) -> &mut TopicProducer<Exe> {
+        let index = match self.key_partition(partition_key) {
+            Some(index) => index,
+            None => self.next_partition(),
+        };
+        &mut self.producers[index]
+    }
+
+    fn next_partition(&mut self) -> usize {
+        let index = self.next_partition;
+        self.next_partition = (index + 1) % self.producers.len();
+        index
+    }
+
+    /// partition of a message routed by key, if the routing mode uses the key
+    fn key_partition(&self, partition_key: Option<&str>) -> Option<usize> {
+        match (self.options.partition_routing, partition_key) {
+            (Some(PartitionRouting::KeyHash), Some(key)) => {
+                Some(java_string_hash(key) as usize % self.producers.len())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// hash of a string computed like Java's `String.hashCode`, made positive
+fn java_string_hash(s: &str) -> u32 {
+    let hash = s
+        .encode_utf16()
+        .fold(0i32, |hash, c| hash.wrapping_mul(31).wrapping_add(c as i32));
+    (hash & i32::MAX) as u32
+}
+
+/// checks that the receipt is for the message sent with `sequence_id`, the
+/// connection gives a send the receipt of another message when the broker
+/// skipped it or acknowledged a message twice
+fn check_receipt(
+    sequence_id: u64,
+    receipt: proto::CommandSendReceipt,
+) -> Result<proto::CommandSendReceipt, ProducerError> {
+    if receipt.sequence_id != sequence_id {
+        error!(
+            "producer {} got a receipt for sequence id {}, expected {}",
+            receipt.producer_id, receipt.sequence_id, sequence_id
+        );
+        return Err(ProducerError::SequenceMismatch {
+            expected: sequence_id,
+            received: receipt.sequence_id,
+        });
+    }
+    Ok(receipt)
+}
+
+/// returns true if the producer must wait for the pending messages' receipts
+/// before sending another one
+fn queue_full(pending: usize, options: &ProducerOptions) -> Result<bool, ProducerError> {
+    match options.max_pending_messages {
+        Some(max) if pending >= max => {
+            if options.block_if_queue_full {
+                Ok(true)
+            } else {
+                Err(ProducerError::QueueFull)
+            }
+        }
+        _ => Ok(false),
+    }
+}
+
+/// compresses the message's payload with the producer's algorithm
+// the options only hold the zstd compression level
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+fn compress(
+    mut message: ProducerMessage,
+    compression: Option<CompressionType>,
+    options: &ProducerOptions,
+) -> Result<ProducerMessage, Error> {
+    let compressed_message = match compression {
+        None | Some(CompressionType::None) => message,
+        Some(CompressionType::Lz4) => {
+            #[cfg(not(feature = "lz4"))]
+            return unimplemented!();
+
+            #[cfg(feature = "lz4")]
+            {
+                let compressed_payload: Vec<u8> =
+                    lz4::block::compress(&message.payload[..], None, false)
+                        .map_err(ProducerError::Io)?;
+
+                message.uncompressed_size = Some(message.payload.len() as u32);
+                message.payload = compressed_payload;
+                message.compression = Some(1);
+                message
+            }
+        }
+        Some(CompressionType::Zlib) => {
+            #[cfg(not(feature = "flate2"))]
+            return unimplemented!();
+
+            #[cfg(feature = "flate2")]
+            {
+                let mut e =
+                    flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                e.write_all(&message.payload[..])
+                    .map_err(ProducerError::Io)?;
+                let compressed_payload = e.finish().map_err(ProducerError::Io)?;
+
+                message.uncompressed_size = Some(message.payload.len() as u32);
+                message.payload = compressed_payload;
+                message.compression = Some(2);
+                message
+            }
+        }
+        Some(CompressionType::Zstd) => {
+            #[cfg(not(feature = "zstd"))]
+            return unimplemented!();
+
+            #[cfg(feature = "zstd")]
+            {
+                let level = options.zstd_level.unwrap_or(3);
+                let compressed_payload =
+                    zstd::encode_all(&message.payload[..], level).map_err(ProducerError::Io)?;
+                message.uncompressed_size = Some(message.payload.len() as u32);
+                message.payload = compressed_payload;
+                message.compression = Some(3);
+                message
+            }
+        }
+        Some(CompressionType::Snappy) => {
+            #[cfg(not(feature = "snap"))]
+            return unimplemented!();
+
+            #[cfg(feature = "snap")]
+            {
+                let compressed_payload: Vec<u8> = Vec::new();
+                let mut encoder = snap::write::FrameEncoder::new(compressed_payload);
+                encoder
+                    .write(&message.payload[..])
+                    .map_err(ProducerError::Io)?;
+                let compressed_payload = encoder
+                    .into_inner()
+                    //FIXME
+                    .map_err(|e| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::Other,
+                            format!("Snappy compression error: {:?}", e),
+                        )
+                    })
+                    .map_err(ProducerError::Io)?;
+
+                message.uncompressed_size = Some(message.payload.len() as u32);
+                message.payload = compressed_payload;
+                message.compression = Some(4);
+                message
+            }
+        }
+    };
+    Ok(compressed_message)
+}
+
+/// connection on which a producer was created, shared with its batch timer
+struct ProducerConnection<Exe: Executor> {
+    connection: Arc<Connection<Exe>>,
+    /// notifications sent by the broker for this producer
+    broker_events: mpsc::UnboundedReceiver<RawMessage>,
+    /// set once the broker closed the producer on this connection
+    closed_by_broker: bool,
+    /// version of the producer's schema registered by the broker
+    schema_version: Option<Vec<u8>>,
+    /// dropped with the producer, which closes it on this connection
+    drop_signal: oneshot::Sender<()>,
+}
+
+impl<Exe: Executor> ProducerConnection<Exe> {
+    fn new(
+        connection: Arc<Connection<Exe>>,
+        broker_events: mpsc::UnboundedReceiver<RawMessage>,
+        schema_version: Option<Vec<u8>>,
+        drop_signal: oneshot::Sender<()>,
+    ) -> Self {
+        ProducerConnection {
+            connection,
+            broker_events,
+            closed_by_broker: false,
+            schema_version,
+            drop_signal,
+        }
+    }
+
+    /// returns the connection, unless it failed or the broker closed the
+    /// producer on it, which happens when the topic is unloaded or moved to
+    /// another broker. The producer must then be created again
+    fn usable(&mut self) -> Option<Arc<Connection<Exe>>> {
+        if !self.closed_by_broker {
+            if let Some(Some(msg)) = self.broker_events.next().now_or_never() {
+                debug!(
+                    "producer closed by the broker: {:?}",
+                    msg.command.close_producer
+                );
+                self.closed_by_broker = true;
+            }
+        }
+        if self.closed_by_broker || !self.connection.is_valid() {
+            None
+        } else {
+            Some(self.connection.clone())
+        }
     }
 }
 
 /// a producer is used to publish messages on a topic
+///
+/// its clones share its connection, sequence ids and batch, the batch timer
+/// sends the batch with one of them
+#[derive(Clone)]
 struct TopicProducer<Exe: Executor> {
     client: Pulsar<Exe>,
-    connection: Arc<Connection<Exe>>,
+    /// replaced when the producer reconnects
+    connection: Arc<std::sync::Mutex<ProducerConnection<Exe>>>,
     id: ProducerId,
     name: ProducerName,
     topic: String,
     message_id: SerialId,
     //putting it in a mutex because we must send multiple messages at once
     // while we might be pushing more messages from elsewhere
-    batch: Option<Mutex<Batch>>,
+    batch: Option<Arc<Mutex<Batch>>>,
+    /// messages whose `SendFuture` did not get its receipt yet
+    pending_messages: Arc<AtomicUsize>,
     compression: Option<proto::CompressionType>,
-    _drop_signal: oneshot::Sender<()>,
     options: ProducerOptions,
 }
 
@@ -398,13 +732,25 @@ impl<Exe: Executor> TopicProducer<Exe> {
         options: ProducerOptions,
     ) -> Result<Self, Error> {
         let topic = topic.into();
-        let producer_id = rand::random();
-        let sequence_ids = SerialId::new();
+        let producer_id = connection.sender().next_producer_id();
+        let sequence_ids = SerialId::starting_at(options.initial_sequence_id.unwrap_or(0));
 
         let topic = topic.clone();
-        let batch_size = options.batch_size;
+        let batch = options.batch_size.map(|length| {
+            Arc::new(Mutex::new(Batch::new(
+                length,
+                options.batch_byte_size,
+                options.batch_timeout,
+            )))
+        });
         let compression = options.compression;
 
+        if options.batch_size.is_some() && options.max_message_size.is_some() {
+            return Err(Error::Custom(
+                "cannot create a producer with both batching and chunking enabled".to_string(),
+            ));
+        }
+
         match compression {
             None | Some(CompressionType::None) => {}
             Some(CompressionType::Lz4) => {
@@ -418,6 +764,16 @@ impl<Exe: Executor> TopicProducer<Exe> {
             Some(CompressionType::Zstd) => {
                 #[cfg(not(feature = "zstd"))]
                 return Err(Error::Custom("cannot create a producer with zstd compression because the 'zstd' cargo feature is not active".to_string()));
+
+                #[cfg(feature = "zstd")]
+                if let Some(level) = options.zstd_level {
+                    if !(1..=22).contains(&level) {
+                        return Err(Error::Custom(format!(
+                            "invalid zstd compression level {}, it must be between 1 and 22",
+                            level
+                        )));
+                    }
+                }
             }
             Some(CompressionType::Snappy) => {
                 #[cfg(not(feature = "snap"))]
@@ -426,6 +782,7 @@ impl<Exe: Executor> TopicProducer<Exe> {
         };
 
         let producer_name: ProducerName;
+        let schema_version;
         let mut current_retries = 0u32;
         let start = std::time::Instant::now();
         let operation_retry_options = client.operation_retry_options.clone();
@@ -453,7 +810,16 @@ impl<Exe: Executor> TopicProducer<Exe> {
                             trace!("result is received: {:?}", result);
                         }
                     }
+                    // the broker chooses a name if the client did not set one
                     producer_name = partial_success.producer_name;
+                    schema_version = partial_success.schema_version;
+                    // with deduplication, the broker drops messages with a sequence
+                    // id lower than the last one it persisted for this producer
+                    if let Some(last_sequence_id) = partial_success.last_sequence_id {
+                        if last_sequence_id >= 0 {
+                            sequence_ids.advance_past(last_sequence_id as u64);
+                        }
+                    }
 
                     if current_retries > 0 {
                         let dur = (std::time::Instant::now() - start).as_secs();
@@ -502,29 +868,49 @@ impl<Exe: Executor> TopicProducer<Exe> {
             }
         }
 
-        // drop_signal will be dropped when the TopicProducer is dropped, then
-        // drop_receiver will return, and we can close the producer
-        let (_drop_signal, drop_receiver) = oneshot::channel::<()>();
+        let (resolver, broker_events) = mpsc::unbounded();
+        connection
+            .sender()
+            .register_producer(producer_id, resolver)?;
+
+        // drop_signal will be dropped when the TopicProducer and its clones
+        // held by batch timers are dropped, then drop_receiver will return,
+        // and we can close the producer
+        let (drop_signal, drop_receiver) = oneshot::channel::<()>();
         let conn = connection.clone();
         let _ = client.executor.spawn(Box::pin(async move {
             let _res = drop_receiver.await;
-            let _ = conn.sender().close_producer(producer_id).await;
+            // if we receive a message, the producer moved to another
+            // connection and must not be closed
+            if _res.is_err() {
+                let _ = conn.sender().close_producer(producer_id).await;
+            }
         }));
 
         Ok(TopicProducer {
             client,
-            connection,
+            connection: Arc::new(std::sync::Mutex::new(ProducerConnection::new(
+                connection,
+                broker_events,
+                schema_version,
+                drop_signal,
+            ))),
             id: producer_id,
             name: producer_name,
             topic,
             message_id: sequence_ids,
-            batch: batch_size.map(Batch::new).map(Mutex::new),
+            batch,
+            pending_messages: Arc::new(AtomicUsize::new(0)),
             compression,
-            _drop_signal,
             options,
         })
     }
 
+    /// connection the producer was last created on
+    fn connection(&self) -> Arc<Connection<Exe>> {
+        self.connection.lock().unwrap().connection.clone()
+    }
+
     fn topic(&self) -> &str {
         &self.topic
     }
@@ -534,7 +920,7 @@ impl<Exe: Executor> TopicProducer<Exe> {
     }
 
     async fn check_connection(&self) -> Result<(), Error> {
-        self.connection.sender().send_ping().await?;
+        self.connection().sender().send_ping().await?;
         Ok(())
     }
 
@@ -546,91 +932,122 @@ impl<Exe: Executor> TopicProducer<Exe> {
     }
 
     async fn send_batch(&mut self) -> Result<(), Error> {
-        match self.batch.as_ref() {
-            None => Err(ProducerError::Custom("not a batching producer".to_string()).into()),
-            Some(batch) => {
-                let mut payload: Vec<u8> = Vec::new();
-                let mut receipts = Vec::new();
-                let message_count;
+        if self.batch.is_none() {
+            return Err(ProducerError::Custom("not a batching producer".to_string()).into());
+        }
+        // the error is also sent to the SendFuture of each message
+        let _ = self.flush().await;
+        Ok(())
+    }
 
-                {
-                    let batch = batch.lock().await;
-                    let messages = batch.get_messages().await;
-                    message_count = messages.len();
-                    for (tx, message) in messages {
-                        receipts.push(tx);
-                        message.serialize(&mut payload);
-                    }
-                }
+    async fn flush(&mut self) -> Result<(), Error> {
+        let mut batch = match self.batch.clone() {
+            None => return Ok(()),
+            Some(batch) => batch.lock_owned().await,
+        };
+        // the lock is kept while sending, so that a batch timer cannot send
+        // a message with a higher sequence id before this one
+        let messages = batch.get_messages().await;
+        self.send_batched(messages).await
+    }
 
-                if message_count == 0 {
-                    return Ok(());
-                }
+    /// sends the messages taken from the batch as a single message, the
+    /// receipt or the error is sent to each of them
+    #[allow(clippy::type_complexity)]
+    async fn send_batched(
+        &mut self,
+        messages: Vec<(
+            oneshot::Sender<Result<proto::CommandSendReceipt, Error>>,
+            BatchedMessage,
+        )>,
+    ) -> Result<(), Error> {
+        if messages.is_empty() {
+            return Ok(());
+        }
 
-                let message = ProducerMessage {
-                    payload,
-                    num_messages_in_batch: Some(message_count as i32),
-                    ..Default::default()
-                };
-
-                trace!("sending a batched message of size {}", message_count);
-                let send_receipt = self.send_compress(message).await.map_err(Arc::new);
-                for resolver in receipts {
-                    let _ = resolver.send(
-                        send_receipt
-                            .clone()
-                            .map_err(|e| ProducerError::Batch(e).into()),
-                    );
-                }
+        let mut payload: Vec<u8> = Vec::new();
+        let mut receipts = Vec::with_capacity(messages.len());
+        for (tx, message) in messages {
+            receipts.push(tx);
+            message.serialize(&mut payload);
+        }
 
-                Ok(())
-            }
+        let message = ProducerMessage {
+            payload,
+            num_messages_in_batch: Some(receipts.len() as i32),
+            ..Default::default()
+        };
+
+        trace!("sending a batched message of size {}", receipts.len());
+        let send_receipt = self.send_compress(message).await.map_err(Arc::new);
+        self.pending_messages
+            .fetch_sub(receipts.len(), Ordering::AcqRel);
+        for resolver in receipts {
+            let _ = resolver.send(
+                send_receipt
+                    .clone()
+                    .map_err(|e| ProducerError::Batch(e).into()),
+            );
         }
+
+        send_receipt
+            .map(drop)
+            .map_err(|e| ProducerError::Batch(e).into())
     }
 
     pub(crate) async fn send_raw(&mut self, message: ProducerMessage) -> Result<SendFuture, Error> {
+        let pending = self.pending_messages.load(Ordering::Acquire);
+        if queue_full(pending, &self.options)? {
+            // flushing waits for the batch being sent by a timer, then sends
+            // the current one. Its messages get the error if it cannot be sent
+            let _ = self.flush().await;
+        }
+
+        self.pending_messages.fetch_add(1, Ordering::AcqRel);
         let (tx, rx) = oneshot::channel();
+        // marker, transactional and delayed messages are never batched, since
+        // the batch's metadata would apply to all of its messages. The messages
+        // already in the batch are sent first to keep the order of the sends
+        if message.marker_type.is_some()
+            || message.txn_id.is_some()
+            || message.deliver_at_time.is_some()
+        {
+            // a batch timer must not take a sequence id at the same time
+            let mut batch = match self.batch.clone() {
+                Some(batch) => Some(batch.lock_owned().await),
+                None => None,
+            };
+            if let Some(batch) = batch.as_mut() {
+                let messages = batch.get_messages().await;
+                // the messages of the batch get the error if it cannot be sent
+                let _ = self.send_batched(messages).await;
+            }
+            let receipt = self.send_compress(message).await;
+            self.pending_messages.fetch_sub(1, Ordering::AcqRel);
+            drop(batch);
+            let _ = tx.send(Ok(receipt?));
+            return Ok(SendFuture(rx));
+        }
+
         match self.batch.as_ref() {
             None => {
-                let receipt = self.send_compress(message).await?;
-                let _ = tx.send(Ok(receipt));
+                let receipt = self.send_compress(message).await;
+                self.pending_messages.fetch_sub(1, Ordering::AcqRel);
+                let _ = tx.send(Ok(receipt?));
                 Ok(SendFuture(rx))
             }
             Some(batch) => {
-                let mut payload: Vec<u8> = Vec::new();
-                let mut receipts = Vec::new();
-                let mut counter = 0i32;
-
-                {
-                    let batch = batch.lock().await;
-                    batch.push_back((tx, message)).await;
-
-                    if batch.is_full().await {
-                        for (tx, message) in batch.get_messages().await {
-                            receipts.push(tx);
-                            message.serialize(&mut payload);
-                            counter += 1;
-                        }
-                    }
-                }
+                let mut batch = batch.clone().lock_owned().await;
+                let first = batch.started.is_none();
+                batch.push_back((tx, message)).await;
 
-                if counter > 0 {
-                    let message = ProducerMessage {
-                        payload,
-                        num_messages_in_batch: Some(counter),
-                        ..Default::default()
-                    };
-
-                    let send_receipt = self.send_compress(message).await.map_err(Arc::new);
-
-                    trace!("sending a batched message of size {}", counter);
-                    for tx in receipts.drain(..) {
-                        let _ = tx.send(
-                            send_receipt
-                                .clone()
-                                .map_err(|e| ProducerError::Batch(e).into()),
-                        );
-                    }
+                if batch.is_full().await {
+                    // the lock is kept while sending, see flush
+                    let messages = batch.get_messages().await;
+                    // the messages get the error if the batch cannot be sent
+                    let _ = self.send_batched(messages).await;
+                } else if let (true, Some(timeout)) = (first, batch.timeout) {
+                    self.arm_batch_timer(batch.generation, timeout);
                 }
 
                 Ok(SendFuture(rx))
@@ -638,106 +1055,130 @@ impl<Exe: Executor> TopicProducer<Exe> {
         }
     }
 
+    /// sends the batch after `timeout` if it still holds the messages of
+    /// `generation`, so that they do not wait for the next call to `send`
+    ///
+    /// the batch is sent like by [TopicProducer::flush], with a clone of the
+    /// producer that creates it again if needed
+    fn arm_batch_timer(&self, generation: u64, timeout: Duration) {
+        let batch = match self.batch.clone() {
+            Some(batch) => batch,
+            None => return,
+        };
+        let executor = self.client.executor.clone();
+        let mut producer = self.clone();
+
+        let res = self.client.executor.spawn(Box::pin(async move {
+            executor.delay(timeout).await;
+            // the lock is kept while sending, see flush
+            let mut batch = batch.lock_owned().await;
+            if batch.generation != generation {
+                return;
+            }
+            let messages = batch.get_messages().await;
+            trace!(
+                "sending a batch of {} messages after {:?}",
+                messages.len(),
+                timeout
+            );
+            // the messages get the error if the batch cannot be sent
+            let _ = producer.send_batched(messages).await;
+        }));
+        if res.is_err() {
+            error!("could not spawn the batch timer of producer {}", self.id);
+        }
+    }
+
     async fn send_compress(
         &mut self,
-        mut message: ProducerMessage,
+        message: ProducerMessage,
     ) -> Result<proto::CommandSendReceipt, Error> {
-        let compressed_message = match self.compression {
-            None | Some(CompressionType::None) => message,
-            Some(CompressionType::Lz4) => {
-                #[cfg(not(feature = "lz4"))]
-                return unimplemented!();
-
-                #[cfg(feature = "lz4")]
-                {
-                    let compressed_payload: Vec<u8> =
-                        lz4::block::compress(&message.payload[..], None, false)
-                            .map_err(ProducerError::Io)?;
-
-                    message.uncompressed_size = Some(message.payload.len() as u32);
-                    message.payload = compressed_payload;
-                    message.compression = Some(1);
-                    message
-                }
+        let compressed_message = compress(message, self.compression, &self.options)?;
+
+        // chunks cannot be larger than what the broker accepts
+        let max_message_size = self
+            .options
+            .max_message_size
+            .map(|size| size.min(self.connection().max_message_size()));
+        match max_message_size {
+            Some(max_size) if compressed_message.payload.len() > max_size => {
+                self.send_chunks(compressed_message, max_size).await
             }
-            Some(CompressionType::Zlib) => {
-                #[cfg(not(feature = "flate2"))]
-                return unimplemented!();
-
-                #[cfg(feature = "flate2")]
-                {
-                    let mut e =
-                        flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
-                    e.write_all(&message.payload[..])
-                        .map_err(ProducerError::Io)?;
-                    let compressed_payload = e.finish().map_err(ProducerError::Io)?;
-
-                    message.uncompressed_size = Some(message.payload.len() as u32);
-                    message.payload = compressed_payload;
-                    message.compression = Some(2);
-                    message
-                }
-            }
-            Some(CompressionType::Zstd) => {
-                #[cfg(not(feature = "zstd"))]
-                return unimplemented!();
-
-                #[cfg(feature = "zstd")]
-                {
-                    let compressed_payload =
-                        zstd::encode_all(&message.payload[..], 0).map_err(ProducerError::Io)?;
-                    message.uncompressed_size = Some(message.payload.len() as u32);
-                    message.payload = compressed_payload;
-                    message.compression = Some(3);
-                    message
-                }
+            _ => {
+                let sequence_id = self.message_id.get();
+                self.send_inner(compressed_message, sequence_id).await
             }
-            Some(CompressionType::Snappy) => {
-                #[cfg(not(feature = "snap"))]
-                return unimplemented!();
-
-                #[cfg(feature = "snap")]
-                {
-                    let compressed_payload: Vec<u8> = Vec::new();
-                    let mut encoder = snap::write::FrameEncoder::new(compressed_payload);
-                    encoder
-                        .write(&message.payload[..])
-                        .map_err(ProducerError::Io)?;
-                    let compressed_payload = encoder
-                        .into_inner()
-                        //FIXME
-                        .map_err(|e| {
-                            std::io::Error::new(
-                                std::io::ErrorKind::Other,
-                                format!("Snappy compression error: {:?}", e),
-                            )
-                        })
-                        .map_err(ProducerError::Io)?;
+        }
+    }
 
-                    message.uncompressed_size = Some(message.payload.len() as u32);
-                    message.payload = compressed_payload;
-                    message.compression = Some(4);
-                    message
-                }
-            }
-        };
+    /// splits the message's payload in chunks of at most `max_size` bytes and
+    /// sends them in order, returning the receipt of the last chunk
+    async fn send_chunks(
+        &mut self,
+        mut message: ProducerMessage,
+        max_size: usize,
+    ) -> Result<proto::CommandSendReceipt, Error> {
+        let max_size = max_size.max(1);
+        let uuid = format!("{}-{}", self.name, rand::random::<u64>());
+        let payload = std::mem::take(&mut message.payload);
+        let total_size = payload.len();
+        let num_chunks = total_size.div_ceil(max_size);
+        trace!(
+            "sending message {} of {} bytes in {} chunks",
+            uuid,
+            total_size,
+            num_chunks
+        );
 
-        self.send_inner(compressed_message).await
+        // the chunks share the message's sequence id, the broker tells them
+        // apart with their chunk id
+        let sequence_id = self.message_id.get();
+        let mut receipt = None;
+        for (chunk_id, data) in payload.chunks(max_size).enumerate() {
+            let chunk = ProducerMessage {
+                payload: data.to_vec(),
+                uuid: Some(uuid.clone()),
+                chunk_id: Some(chunk_id as i32),
+                num_chunks_from_msg: Some(num_chunks as i32),
+                total_chunk_msg_size: Some(total_size as i32),
+                ..message.clone()
+            };
+            receipt = Some(self.send_inner(chunk, sequence_id).await?);
+        }
+        receipt
+            .ok_or_else(|| ProducerError::Custom("cannot send an empty message".to_string()).into())
     }
 
+    /// sends the message with `sequence_id`, which is used again if the message
+    /// is sent after a reconnection, so that the broker can deduplicate it
     async fn send_inner(
         &mut self,
-        message: ProducerMessage,
+        mut message: ProducerMessage,
+        sequence_id: u64,
     ) -> Result<proto::CommandSendReceipt, Error> {
+        // the connection manager replaces connections that failed, the producer
+        // must then be created again on the new one
+        let usable = self.connection.lock().unwrap().usable();
+        let connection = match usable {
+            Some(connection) => connection,
+            None => {
+                self.reconnect().await?;
+                self.connection()
+            }
+        };
+
+        if message.schema_version.is_none() {
+            message.schema_version = self.connection.lock().unwrap().schema_version.clone();
+        }
+
         let msg = message.clone();
-        match self
-            .connection
+        match connection
             .sender()
-            .send(self.id, self.name.clone(), self.message_id.get(), message)
+            .send(self.id, self.name.clone(), sequence_id, message)
             .await
         {
-            Ok(receipt) => return Ok(receipt),
-            Err(ConnectionError::Disconnected) => {}
+            Ok(receipt) => return Ok(check_receipt(sequence_id, receipt)?),
+            Err(ConnectionError::Disconnected) | Err(ConnectionError::Timeout) => {}
             Err(ConnectionError::Io(e)) => {
                 if e.kind() != std::io::ErrorKind::TimedOut {
                     error!("send_inner got io error: {:?}", e);
@@ -750,19 +1191,16 @@ impl<Exe: Executor> TopicProducer<Exe> {
             }
         };
 
-        error!(
-            "send_inner: connection {} disconnected",
-            self.connection.id()
-        );
+        error!("send_inner: connection {} disconnected", connection.id());
         self.reconnect().await?;
 
         match self
-            .connection
+            .connection()
             .sender()
-            .send(self.id, self.name.clone(), self.message_id.get(), msg)
+            .send(self.id, self.name.clone(), sequence_id, msg)
             .await
         {
-            Ok(receipt) => Ok(receipt),
+            Ok(receipt) => Ok(check_receipt(sequence_id, receipt)?),
             Err(e) => {
                 error!("send_inner got error: {:?}", e);
                 Err(ProducerError::Connection(e).into())
@@ -773,20 +1211,17 @@ impl<Exe: Executor> TopicProducer<Exe> {
     async fn reconnect(&mut self) -> Result<(), Error> {
         debug!("reconnecting producer for topic: {}", self.topic);
         let broker_address = self.client.lookup_topic(&self.topic).await?;
-        let conn = self.client.manager.get_connection(&broker_address).await?;
-
-        self.connection = conn;
+        let mut connection = self.client.manager.get_connection(&broker_address).await?;
 
         let topic = self.topic.clone();
-        let batch_size = self.options.batch_size;
+        let mut schema_version = self.connection.lock().unwrap().schema_version.clone();
 
         let mut current_retries = 0u32;
         let start = std::time::Instant::now();
         let operation_retry_options = self.client.operation_retry_options.clone();
 
         loop {
-            match self
-                .connection
+            match connection
                 .sender()
                 .create_producer(
                     topic.clone(),
@@ -799,7 +1234,16 @@ impl<Exe: Executor> TopicProducer<Exe> {
                     error!("TopicProducer::from_connection error[{}]: {:?}", line!(), e);
                     e
                 }) {
-                Ok(_success) => {
+                Ok(success) => {
+                    if success.schema_version.is_some() {
+                        schema_version = success.schema_version;
+                    }
+                    // resume after the last sequence id persisted by the broker
+                    if let Some(last_sequence_id) = success.last_sequence_id {
+                        if last_sequence_id >= 0 {
+                            self.message_id.advance_past(last_sequence_id as u64);
+                        }
+                    }
                     if current_retries > 0 {
                         let dur = (std::time::Instant::now() - start).as_secs();
                         log::info!(
@@ -829,7 +1273,7 @@ impl<Exe: Executor> TopicProducer<Exe> {
                             .await;
 
                         let addr = self.client.lookup_topic(&topic).await?;
-                        self.connection = self.client.manager.get_connection(&addr).await?;
+                        connection = self.client.manager.get_connection(&addr).await?;
 
                         continue;
                     } else {
@@ -846,19 +1290,36 @@ impl<Exe: Executor> TopicProducer<Exe> {
             }
         }
 
+        let (resolver, broker_events) = mpsc::unbounded();
+        connection.sender().register_producer(self.id, resolver)?;
+
         // drop_signal will be dropped when the TopicProducer is dropped, then
         // drop_receiver will return, and we can close the producer
-        let (_drop_signal, drop_receiver) = oneshot::channel::<()>();
-        let batch = batch_size.map(Batch::new).map(Mutex::new);
-        let conn = self.connection.clone();
+        let (drop_signal, drop_receiver) = oneshot::channel::<()>();
+        let conn = connection.clone();
         let producer_id = self.id;
         let _ = self.client.executor.spawn(Box::pin(async move {
             let _res = drop_receiver.await;
-            let _ = conn.sender().close_producer(producer_id).await;
+            // if we receive a message, the producer moved to another
+            // connection and must not be closed
+            if _res.is_err() {
+                let _ = conn.sender().close_producer(producer_id).await;
+            }
         }));
 
-        self.batch = batch;
-        self._drop_signal = _drop_signal;
+        // the pending batch is kept, its messages will be sent on the new connection
+        let old = std::mem::replace(
+            &mut *self.connection.lock().unwrap(),
+            ProducerConnection::new(connection, broker_events, schema_version, drop_signal),
+        );
+        // the reconnection can reuse the same connection, the old task must
+        // then not close the producer that was just created on it
+        if let Err(e) = old.drop_signal.send(()) {
+            error!(
+                "could not send the drop signal to the old producer(id={}): {:?}",
+                self.id, e
+            );
+        }
 
         Ok(())
     }
@@ -943,16 +1404,12 @@ impl<Exe: Executor> ProducerBuilder<Exe> {
                 )))
             }
             1 => ProducerInner::Single(producers.into_iter().next().unwrap()),
-            _ => {
-                let mut producers = VecDeque::from(producers);
-                // write to topic-1 first
-                producers.rotate_right(1);
-                ProducerInner::Partitioned(PartitionedProducer {
-                    producers,
-                    topic,
-                    options,
-                })
-            }
+            _ => ProducerInner::Partitioned(PartitionedProducer {
+                producers,
+                next_partition: 0,
+                topic,
+                options,
+            }),
         };
 
         Ok(Producer { inner: producer })
@@ -971,6 +1428,15 @@ impl<Exe: Executor> ProducerBuilder<Exe> {
 
 struct Batch {
     pub length: u32,
+    pub max_size: Option<usize>,
+    pub timeout: Option<Duration>,
+    /// size of the payloads currently in the batch
+    size: usize,
+    /// time at which the oldest message of the batch was added
+    started: Option<Instant>,
+    /// incremented each time the batch is emptied, so that a timer knows if
+    /// the messages it was armed for are still waiting
+    generation: u64,
     // put it in a mutex because the design of Producer requires an immutable TopicProducer,
     // so we cannot have a mutable Batch in a send_raw(&mut self, ...)
     #[allow(clippy::type_complexity)]
@@ -983,25 +1449,39 @@ struct Batch {
 }
 
 impl Batch {
-    pub fn new(length: u32) -> Batch {
+    pub fn new(length: u32, max_size: Option<usize>, timeout: Option<Duration>) -> Batch {
         Batch {
             length,
+            max_size,
+            timeout,
+            size: 0,
+            started: None,
+            generation: 0,
             storage: Mutex::new(VecDeque::with_capacity(length as usize)),
         }
     }
 
     pub async fn is_full(&self) -> bool {
         self.storage.lock().await.len() >= self.length as usize
+            || self.max_size.map(|max| self.size >= max).unwrap_or(false)
+            || match (self.timeout, self.started) {
+                (Some(timeout), Some(started)) => started.elapsed() >= timeout,
+                _ => false,
+            }
     }
 
     pub async fn push_back(
-        &self,
+        &mut self,
         msg: (
             oneshot::Sender<Result<proto::CommandSendReceipt, Error>>,
             ProducerMessage,
         ),
     ) {
         let (tx, message) = msg;
+        self.size += message.payload.len();
+        if self.started.is_none() {
+            self.started = Some(Instant::now());
+        }
 
         let properties = message
             .properties
@@ -1024,11 +1504,14 @@ impl Batch {
     }
 
     pub async fn get_messages(
-        &self,
+        &mut self,
     ) -> Vec<(
         oneshot::Sender<Result<proto::CommandSendReceipt, Error>>,
         BatchedMessage,
     )> {
+        self.size = 0;
+        self.started = None;
+        self.generation += 1;
         self.storage.lock().await.drain(..).collect()
     }
 }
@@ -1081,8 +1564,8 @@ impl<'a, T, Exe: Executor> MessageBuilder<'a, T, Exe> {
         self
     }
     /// sets the message's ordering key for key_shared subscription
-    pub fn with_ordering_key<S: Into<String>>(mut self, partition_key: S) -> Self {
-        self.partition_key = Some(partition_key.into());
+    pub fn with_ordering_key<S: Into<String>>(mut self, ordering_key: S) -> Self {
+        self.ordering_key = Some(ordering_key.into().into_bytes());
         self
     }
 
@@ -1109,17 +1592,12 @@ impl<'a, T, Exe: Executor> MessageBuilder<'a, T, Exe> {
 
     /// delays message deliver with this duration
     pub fn delay(mut self, delay: Duration) -> Result<Self, std::time::SystemTimeError> {
-        let date = SystemTime::now() + delay;
-        println!(
-            "current date: {}, deliver_at: {}",
-            SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis(),
-            date.duration_since(UNIX_EPOCH)?.as_millis()
-        );
-        self.deliver_at_time = Some(date.duration_since(UNIX_EPOCH)?.as_millis() as i64);
+        self.deliver_at_time = Some(delivery_time(delay)?);
         Ok(self)
     }
 
-    /// delivers the message at this date
+    /// sets the time the message's event happened, in milliseconds since the
+    /// Unix epoch
     pub fn event_time(mut self, event_time: u64) -> Self {
         self.event_time = Some(event_time);
         self
@@ -1150,3 +1628,394 @@ impl<'a, T: SerializeMessage + Sized, Exe: Executor> MessageBuilder<'a, T, Exe>
         producer.send_raw(producer_message).await
     }
 }
+
+/// time at which a message delayed by `delay` is delivered, in milliseconds
+/// since the Unix epoch
+fn delivery_time(delay: Duration) -> Result<i64, std::time::SystemTimeError> {
+    let date = SystemTime::now() + delay;
+    Ok(date.duration_since(UNIX_EPOCH)?.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn java_string_hash_matches_java() {
+        // values of String.hashCode in Java
+        assert_eq!(java_string_hash(""), 0);
+        assert_eq!(java_string_hash("abc"), 96354);
+        assert_eq!(java_string_hash("key-1"), 101943363);
+        // the hash of this string is i32::MIN, the sign bit is cleared
+        assert_eq!(java_string_hash("polygenelubricants"), 0);
+    }
+
+    #[test]
+    fn delayed_delivery_time() {
+        let millis = |date: SystemTime| date.duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+        let delay = Duration::from_secs(30);
+
+        let before = millis(SystemTime::now());
+        let deliver_at = delivery_time(delay).unwrap();
+        let after = millis(SystemTime::now());
+        assert!(deliver_at >= before + 30_000);
+        assert!(deliver_at <= after + 30_000);
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    fn test_producer(
+        connection: Arc<Connection<crate::TokioExecutor>>,
+        broker_events: mpsc::UnboundedReceiver<RawMessage>,
+        batch: Batch,
+    ) -> TopicProducer<crate::TokioExecutor> {
+        TopicProducer {
+            client: Pulsar::disconnected(crate::TokioExecutor),
+            connection: Arc::new(std::sync::Mutex::new(test_producer_connection(
+                connection,
+                broker_events,
+                None,
+            ))),
+            id: 1,
+            name: "test".to_string(),
+            topic: "test".to_string(),
+            message_id: SerialId::new(),
+            batch: Some(Arc::new(Mutex::new(batch))),
+            pending_messages: Arc::new(AtomicUsize::new(0)),
+            compression: None,
+            options: ProducerOptions::default(),
+        }
+    }
+
+    /// producer 1 created on `connection` with `schema_version`, which is not
+    /// closed when it is dropped
+    #[cfg(feature = "tokio-runtime")]
+    fn test_producer_connection(
+        connection: Arc<Connection<crate::TokioExecutor>>,
+        broker_events: mpsc::UnboundedReceiver<RawMessage>,
+        schema_version: Option<Vec<u8>>,
+    ) -> ProducerConnection<crate::TokioExecutor> {
+        let (drop_signal, _drop_receiver) = oneshot::channel();
+        ProducerConnection::new(connection, broker_events, schema_version, drop_signal)
+    }
+
+    /// producer batching up to 10 messages on a connection answering every
+    /// send with a receipt
+    #[cfg(feature = "tokio-runtime")]
+    fn batching_producer() -> (
+        TopicProducer<crate::TokioExecutor>,
+        impl futures::Stream<Item = RawMessage> + Unpin,
+    ) {
+        let (connection, outbound) = crate::connection::tests::test_connection();
+        let (_events_tx, broker_events) = mpsc::unbounded();
+        let producer = test_producer(connection, broker_events, Batch::new(10, None, None));
+        (producer, outbound)
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[tokio::test]
+    async fn batch_timer() {
+        use crate::connection::tests::{answer, manual_connection};
+        use crate::connection::{Register, RequestKey};
+
+        let message = |payload: &[u8]| ProducerMessage {
+            payload: payload.to_vec(),
+            ..Default::default()
+        };
+        let (old_connection, mut old_outbound, _old_requests) = manual_connection();
+        let (_events_tx, broker_events) = mpsc::unbounded();
+        let batch = Batch::new(10, None, Some(Duration::from_millis(50)));
+        let mut producer = test_producer(old_connection, broker_events, batch);
+
+        let first = producer.send_raw(message(b"first")).await.unwrap();
+        // the producer reconnects before the timer fires, the broker gives
+        // it a new schema version
+        let (connection, mut outbound, mut requests) = manual_connection();
+        let (_events_tx, broker_events) = mpsc::unbounded();
+        *producer.connection.lock().unwrap() =
+            test_producer_connection(connection, broker_events, Some(vec![2]));
+
+        let sent = outbound.next().await.unwrap();
+        let metadata = sent.payload.unwrap().metadata;
+        assert_eq!(metadata.num_messages_in_batch, Some(1));
+        assert_eq!(metadata.schema_version, Some(vec![2]));
+        assert!(!matches!(old_outbound.next().now_or_never(), Some(Some(_))));
+
+        let receipt = requests.next().await.unwrap();
+        assert!(matches!(
+            receipt,
+            Register::Request {
+                key: RequestKey::ProducerSend { .. },
+                ..
+            }
+        ));
+        answer(receipt);
+        first.await.unwrap();
+
+        // the producer was closed by the broker, the timer creates it again.
+        // The lookup fails, the message gets the error instead of waiting
+        // for the next send
+        let (lookups, _lookup_outbound) = crate::connection::tests::test_connection();
+        producer.client = Pulsar::with_connection(crate::TokioExecutor, lookups);
+        let (events_tx, broker_events) = mpsc::unbounded();
+        let (connection, mut outbound, _requests) = manual_connection();
+        *producer.connection.lock().unwrap() =
+            test_producer_connection(connection, broker_events, None);
+        events_tx
+            .unbounded_send(RawMessage {
+                command: proto::BaseCommand {
+                    r#type: proto::base_command::Type::CloseProducer as i32,
+                    close_producer: Some(proto::CommandCloseProducer {
+                        producer_id: 1,
+                        request_id: 0,
+                    }),
+                    ..Default::default()
+                },
+                payload: None,
+            })
+            .unwrap();
+        let second = producer.send_raw(message(b"second")).await.unwrap();
+        let res = tokio::time::timeout(Duration::from_secs(5), second)
+            .await
+            .expect("the batch timer did not send the batch");
+        assert!(res.is_err());
+        assert!(outbound.next().now_or_never().is_none());
+        assert_eq!(producer.pending_messages.load(Ordering::Acquire), 0);
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[tokio::test]
+    async fn message_builder_ordering_key() {
+        let (mut producer, mut outbound) = batching_producer();
+        producer.batch = None;
+        let mut producer = Producer {
+            inner: ProducerInner::Single(producer),
+        };
+
+        producer
+            .create_message()
+            .with_content("data")
+            .with_ordering_key("key")
+            .send()
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+
+        let metadata = outbound.next().await.unwrap().payload.unwrap().metadata;
+        assert_eq!(metadata.ordering_key, Some(b"key".to_vec()));
+        assert_eq!(metadata.partition_key, None);
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[tokio::test]
+    async fn markers_bypass_batching() {
+        let (mut producer, mut outbound) = batching_producer();
+
+        let batched = producer
+            .send_raw(ProducerMessage {
+                payload: b"data".to_vec(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let marker = producer
+            .send_raw(ProducerMessage {
+                // TXN_COMMIT
+                marker_type: Some(20),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        marker.await.unwrap();
+        batched.await.unwrap();
+
+        // the batch is sent before the marker, which is sent on its own
+        let sent = outbound.next().await.unwrap();
+        let first_sequence_id = sent.command.send.unwrap().sequence_id;
+        let metadata = sent.payload.unwrap().metadata;
+        assert_eq!(metadata.marker_type, None);
+        assert_eq!(metadata.num_messages_in_batch, Some(1));
+
+        let sent = outbound.next().await.unwrap();
+        assert!(sent.command.send.unwrap().sequence_id > first_sequence_id);
+        let metadata = sent.payload.unwrap().metadata;
+        assert_eq!(metadata.marker_type, Some(20));
+        assert_eq!(metadata.num_messages_in_batch, None);
+        assert_eq!(producer.pending_messages.load(Ordering::Acquire), 0);
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[tokio::test]
+    async fn delayed_messages_bypass_batching() {
+        let (mut producer, mut outbound) = batching_producer();
+        let deliver_at_time = delivery_time(Duration::from_secs(60)).unwrap();
+
+        let batched = producer
+            .send_raw(ProducerMessage {
+                payload: b"now".to_vec(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let delayed = producer
+            .send_raw(ProducerMessage {
+                payload: b"later".to_vec(),
+                deliver_at_time: Some(deliver_at_time),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        batched.await.unwrap();
+        delayed.await.unwrap();
+
+        let sent = outbound.next().await.unwrap();
+        let metadata = sent.payload.unwrap().metadata;
+        assert_eq!(metadata.num_messages_in_batch, Some(1));
+        assert_eq!(metadata.deliver_at_time, None);
+
+        let sent = outbound.next().await.unwrap();
+        let payload = sent.payload.unwrap();
+        assert_eq!(payload.metadata.num_messages_in_batch, None);
+        assert_eq!(payload.metadata.deliver_at_time, Some(deliver_at_time));
+        assert_eq!(payload.data, b"later");
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[tokio::test]
+    async fn transactional_messages_keep_order() {
+        let (mut producer, mut outbound) = batching_producer();
+
+        let batched = producer
+            .send_raw(ProducerMessage {
+                payload: b"first".to_vec(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let transactional = producer
+            .send_raw(ProducerMessage {
+                payload: b"second".to_vec(),
+                txn_id: Some(crate::transaction::TxnId {
+                    most_bits: 1,
+                    least_bits: 2,
+                }),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        batched.await.unwrap();
+        transactional.await.unwrap();
+
+        let sent = outbound.next().await.unwrap();
+        let send = sent.command.send.unwrap();
+        assert_eq!(send.txnid_most_bits, None);
+        assert_eq!(
+            sent.payload.unwrap().metadata.num_messages_in_batch,
+            Some(1)
+        );
+
+        let sent = outbound.next().await.unwrap();
+        let transactional_send = sent.command.send.unwrap();
+        assert!(transactional_send.sequence_id > send.sequence_id);
+        assert_eq!(transactional_send.txnid_most_bits, Some(1));
+        assert_eq!(transactional_send.txnid_least_bits, Some(2));
+        assert_eq!(sent.payload.unwrap().data, b"second");
+    }
+
+    #[test]
+    fn duplicate_send_receipts() {
+        let receipt = |sequence_id| proto::CommandSendReceipt {
+            producer_id: 1,
+            sequence_id,
+            ..Default::default()
+        };
+
+        assert_eq!(check_receipt(5, receipt(5)).unwrap().sequence_id, 5);
+        match check_receipt(6, receipt(5)) {
+            Err(ProducerError::SequenceMismatch { expected, received }) => {
+                assert_eq!(expected, 6);
+                assert_eq!(received, 5);
+            }
+            other => panic!("duplicate receipt accepted: {:?}", other),
+        }
+        assert!(matches!(
+            check_receipt(6, receipt(8)),
+            Err(ProducerError::SequenceMismatch {
+                expected: 6,
+                received: 8
+            })
+        ));
+    }
+
+    #[test]
+    fn max_pending_messages() {
+        let mut options = ProducerOptions::default();
+        assert!(!queue_full(1000, &options).unwrap());
+
+        options.max_pending_messages = Some(2);
+        assert!(!queue_full(1, &options).unwrap());
+        assert!(matches!(
+            queue_full(2, &options),
+            Err(ProducerError::QueueFull)
+        ));
+
+        // blocking producers send the batch instead
+        options.block_if_queue_full = true;
+        assert!(!queue_full(1, &options).unwrap());
+        assert!(queue_full(2, &options).unwrap());
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[tokio::test]
+    async fn pending_messages_include_sent_batches() {
+        use crate::connection::tests::{answer, manual_connection};
+
+        let message = || ProducerMessage {
+            payload: b"data".to_vec(),
+            ..Default::default()
+        };
+        let (connection, mut outbound, mut requests) = manual_connection();
+        let (_events_tx, broker_events) = mpsc::unbounded();
+        let batch = Batch::new(10, None, Some(Duration::from_millis(10)));
+        let mut producer = test_producer(connection, broker_events, batch);
+        producer.options.max_pending_messages = Some(1);
+
+        let first = producer.send_raw(message()).await.unwrap();
+        // the timer sent the batch, its receipt is still awaited
+        outbound.next().await.unwrap();
+        assert!(matches!(
+            producer.send_raw(message()).await,
+            Err(Error::Producer(ProducerError::QueueFull))
+        ));
+
+        answer(requests.next().await.unwrap());
+        first.await.unwrap();
+        producer.send_raw(message()).await.unwrap();
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[tokio::test]
+    async fn blocking_producer_waits_for_receipts() {
+        let (mut producer, _outbound) = batching_producer();
+        producer.options.max_pending_messages = Some(1);
+        producer.options.block_if_queue_full = true;
+
+        let first = producer
+            .send_raw(ProducerMessage {
+                payload: b"first".to_vec(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let _second = producer
+            .send_raw(ProducerMessage {
+                payload: b"second".to_vec(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        // the first message was sent before the second one was queued
+        first.now_or_never().unwrap().unwrap();
+        assert_eq!(producer.pending_messages.load(Ordering::Acquire), 1);
+    }
+}
@@ -1,5 +1,8 @@
 use crate::client::DeserializeMessage;
-use crate::consumer::{ConsumerOptions, DeadLetterPolicy, EngineMessage, Message, TopicConsumer};
+use crate::consumer::{
+    before_start, compare_message_ids, ConsumerOptions, DeadLetterPolicy, EngineMessage, Message,
+    TopicConsumer,
+};
 use crate::error::Error;
 use crate::executor::Executor;
 use crate::message::proto::{command_subscribe::SubType, MessageIdData};
@@ -153,6 +156,32 @@ impl<T: DeserializeMessage, Exe: Executor> Reader<T, Exe> {
         self.consumer.get_last_message_id().await
     }
 
+    /// returns true if the reader received the last message published on the topic
+    ///
+    /// the position is compared to the topic's last message id, so messages
+    /// published after this call are not taken into account
+    pub async fn has_reached_end_of_topic(&mut self) -> Result<bool, Error> {
+        // the broker notifies readers of terminated topics
+        if self.consumer.reached_end_of_topic() {
+            return Ok(true);
+        }
+        let last_message_id = self.consumer.get_last_message_id().await?;
+        // the entry id is -1 if nothing was published on the topic
+        if last_message_id.entry_id as i64 == -1 {
+            return Ok(true);
+        }
+        Ok(match self.consumer.highest_message_id() {
+            Some(message_id) => {
+                compare_message_ids(message_id, &last_message_id) != std::cmp::Ordering::Less
+            }
+            // nothing was received since the reader started or was moved
+            None => match self.consumer.start_message_id() {
+                Some((start, inclusive)) => before_start(&last_message_id, start, inclusive),
+                None => false,
+            },
+        })
+    }
+
     /// returns the current number of messages received
     pub fn messages_received(&self) -> u64 {
         self.consumer.messages_received()
@@ -0,0 +1,321 @@
+//! schemas registered with the broker
+//!
+//! [proto::Schema::avro] describes messages encoded with Avro. With the `avro`
+//! feature, [Avro] values are encoded with their schema, and payloads are
+//! decoded with [decode_avro], using the schema they were written with as
+//! returned by [Consumer::writer_schema](crate::Consumer::writer_schema):
+//!
+//! ```rust,ignore
+//! let schema = Arc::new(apache_avro::Schema::parse_str(definition)?);
+//! let mut producer = pulsar
+//!     .producer()
+//!     .with_topic("test")
+//!     .with_options(ProducerOptions {
+//!         schema: Some(Schema::avro(definition)),
+//!         ..Default::default()
+//!     })
+//!     .build()
+//!     .await?;
+//! producer.send(Avro { schema: schema.clone(), value }).await?;
+//!
+//! let mut consumer: Consumer<Vec<u8>, _> = pulsar.consumer().with_topic("test").build().await?;
+//! let msg = consumer.next().await.unwrap()?;
+//! let writer_schema = consumer.writer_schema(&msg).await?.unwrap().avro_schema()?;
+//! let value = decode_avro(&msg.payload, &writer_schema, Some(&schema))?;
+//! ```
+//!
+//! With the `json-schema` feature, wrapping a type in `Json` registers its
+//! schema with the broker when producing, and checks that consumed payloads
+//! match it:
+//!
+//! ```rust,ignore
+//! let mut producer = pulsar
+//!     .producer()
+//!     .with_topic("test")
+//!     .with_options(ProducerOptions {
+//!         schema: Some(Schema::json::<Data>()),
+//!         ..Default::default()
+//!     })
+//!     .build()
+//!     .await?;
+//! producer.send(Json(data)).await?;
+//!
+//! let mut consumer: Consumer<Json<Data>, _> = pulsar.consumer().with_topic("test").build().await?;
+//! let data: Data = consumer.next().await.unwrap()?.deserialize()?;
+//! ```
+#[cfg(feature = "json-schema")]
+use serde::{de::DeserializeOwned, Serialize};
+#[cfg(feature = "avro")]
+use std::sync::Arc;
+
+#[cfg(feature = "json-schema")]
+use crate::client::DeserializeMessage;
+#[cfg(any(feature = "json-schema", feature = "avro"))]
+use crate::client::SerializeMessage;
+#[cfg(any(feature = "json-schema", feature = "avro"))]
+use crate::error::{ConsumerError, Error, ProducerError};
+use crate::message::proto::{self, schema::Type as SchemaType};
+#[cfg(any(feature = "json-schema", feature = "avro"))]
+use crate::message::Payload;
+#[cfg(any(feature = "json-schema", feature = "avro"))]
+use crate::producer;
+
+/// a type that can be sent and received with a JSON schema
+#[cfg(feature = "json-schema")]
+pub trait JsonSchema: Serialize + DeserializeOwned {
+    /// definition of the type registered with the broker. Like the Java
+    /// client, Pulsar expects an Avro record definition, as an example:
+    /// `{"type": "record", "name": "Data", "fields": [{"name": "value", "type": "int"}]}`
+    fn schema_definition() -> serde_json::Value;
+}
+
+impl proto::Schema {
+    /// Avro schema from its JSON definition, to use in
+    /// `ProducerOptions::schema` or `ConsumerOptions::schema`
+    pub fn avro<S: Into<String>>(definition: S) -> proto::Schema {
+        proto::Schema {
+            r#type: SchemaType::Avro as i32,
+            schema_data: definition.into().into_bytes(),
+            ..Default::default()
+        }
+    }
+
+    /// parses the definition of an Avro schema, like the ones returned by
+    /// [Consumer::writer_schema](crate::Consumer::writer_schema)
+    #[cfg(feature = "avro")]
+    pub fn avro_schema(&self) -> Result<apache_avro::Schema, ConsumerError> {
+        if self.r#type != SchemaType::Avro as i32 {
+            return Err(ConsumerError::Deserialization(format!(
+                "schema {} is not an Avro schema",
+                self.name
+            )));
+        }
+        let definition = std::str::from_utf8(&self.schema_data)
+            .map_err(|e| ConsumerError::Deserialization(e.to_string()))?;
+        apache_avro::Schema::parse_str(definition)
+            .map_err(|e| ConsumerError::Deserialization(e.to_string()))
+    }
+
+    /// JSON schema of `T`, to use in `ProducerOptions::schema` or
+    /// `ConsumerOptions::schema`
+    #[cfg(feature = "json-schema")]
+    pub fn json<T: JsonSchema>() -> proto::Schema {
+        proto::Schema {
+            r#type: SchemaType::Json as i32,
+            schema_data: T::schema_definition().to_string().into_bytes(),
+            ..Default::default()
+        }
+    }
+}
+
+/// an Avro value, sent encoded with `schema`
+///
+/// the schema registered with `ProducerOptions::schema` must have the same
+/// definition
+#[cfg(feature = "avro")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Avro {
+    pub schema: Arc<apache_avro::Schema>,
+    pub value: apache_avro::types::Value,
+}
+
+#[cfg(feature = "avro")]
+impl SerializeMessage for Avro {
+    fn serialize_message(input: Self) -> Result<producer::Message, Error> {
+        let payload = apache_avro::to_avro_datum(&input.schema, input.value)
+            .map_err(|e| ProducerError::Custom(e.to_string()))?;
+        Ok(producer::Message {
+            payload,
+            ..Default::default()
+        })
+    }
+}
+
+/// decodes an Avro payload encoded with `writer_schema`
+///
+/// if `reader_schema` is set, the value is converted to it, following Avro's
+/// schema resolution rules: as an example, fields added to the reader schema
+/// get their default value
+#[cfg(feature = "avro")]
+pub fn decode_avro(
+    payload: &Payload,
+    writer_schema: &apache_avro::Schema,
+    reader_schema: Option<&apache_avro::Schema>,
+) -> Result<apache_avro::types::Value, ConsumerError> {
+    apache_avro::from_avro_datum(writer_schema, &mut &payload.data[..], reader_schema)
+        .map_err(|e| ConsumerError::Deserialization(e.to_string()))
+}
+
+/// wraps a value sent or received with a JSON schema
+#[cfg(feature = "json-schema")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+#[cfg(feature = "json-schema")]
+impl<T: JsonSchema> SerializeMessage for Json<T> {
+    fn serialize_message(input: Self) -> Result<producer::Message, Error> {
+        let payload =
+            serde_json::to_vec(&input.0).map_err(|e| ProducerError::Custom(e.to_string()))?;
+        Ok(producer::Message {
+            payload,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(feature = "json-schema")]
+impl<T: JsonSchema> DeserializeMessage for Json<T> {
+    type Output = Result<T, ConsumerError>;
+
+    fn deserialize_message(payload: &Payload) -> Self::Output {
+        serde_json::from_slice(&payload.data)
+            .map_err(|e| ConsumerError::Deserialization(e.to_string()))
+    }
+}
+
+/// untyped JSON messages, sent without registering a schema
+#[cfg(feature = "json-schema")]
+impl SerializeMessage for serde_json::Value {
+    fn serialize_message(input: Self) -> Result<producer::Message, Error> {
+        let payload =
+            serde_json::to_vec(&input).map_err(|e| ProducerError::Custom(e.to_string()))?;
+        Ok(producer::Message {
+            payload,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(feature = "json-schema")]
+impl DeserializeMessage for serde_json::Value {
+    type Output = Result<serde_json::Value, ConsumerError>;
+
+    fn deserialize_message(payload: &Payload) -> Self::Output {
+        serde_json::from_slice(&payload.data)
+            .map_err(|e| ConsumerError::Deserialization(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn avro_record() {
+        let definition =
+            r#"{"type": "record", "name": "Data", "fields": [{"name": "value", "type": "int"}]}"#;
+        let schema = proto::Schema::avro(definition);
+        assert_eq!(schema.r#type, SchemaType::Avro as i32);
+        assert_eq!(
+            std::str::from_utf8(&schema.schema_data).unwrap(),
+            definition
+        );
+    }
+}
+
+#[cfg(all(test, feature = "json-schema"))]
+mod json_tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Data {
+        value: i32,
+    }
+
+    impl JsonSchema for Data {
+        fn schema_definition() -> serde_json::Value {
+            serde_json::json!({
+                "type": "record",
+                "name": "Data",
+                "fields": [{ "name": "value", "type": "int" }],
+            })
+        }
+    }
+
+    #[test]
+    fn json_value() {
+        let value = serde_json::json!({ "value": [1, 2, 3] });
+        let message = serde_json::Value::serialize_message(value.clone()).unwrap();
+        let payload = Payload::new(Default::default(), message.payload);
+        assert_eq!(
+            serde_json::Value::deserialize_message(&payload).unwrap(),
+            value
+        );
+    }
+
+    #[test]
+    fn json_roundtrip() {
+        let schema = proto::Schema::json::<Data>();
+        assert_eq!(schema.r#type, SchemaType::Json as i32);
+        let definition: serde_json::Value = serde_json::from_slice(&schema.schema_data).unwrap();
+        assert_eq!(definition["name"], "Data");
+
+        let message = Json::serialize_message(Json(Data { value: 42 })).unwrap();
+        let payload = Payload::new(Default::default(), message.payload);
+        assert_eq!(
+            Json::<Data>::deserialize_message(&payload).unwrap(),
+            Data { value: 42 }
+        );
+
+        let payload = Payload::new(Default::default(), b"{\"other\": true}".to_vec());
+        assert!(matches!(
+            Json::<Data>::deserialize_message(&payload),
+            Err(ConsumerError::Deserialization(_))
+        ));
+    }
+}
+
+#[cfg(all(test, feature = "avro"))]
+mod avro_tests {
+    use super::*;
+    use apache_avro::types::Value;
+
+    #[test]
+    fn avro_roundtrip() {
+        let writer_definition =
+            r#"{"type": "record", "name": "Data", "fields": [{"name": "value", "type": "int"}]}"#;
+        let writer_schema = proto::Schema::avro(writer_definition)
+            .avro_schema()
+            .unwrap();
+        let value = Value::Record(vec![("value".to_string(), Value::Int(42))]);
+
+        let message = Avro::serialize_message(Avro {
+            schema: Arc::new(writer_schema.clone()),
+            value: value.clone(),
+        })
+        .unwrap();
+        let payload = Payload::new(Default::default(), message.payload);
+        assert_eq!(decode_avro(&payload, &writer_schema, None).unwrap(), value);
+
+        // a field added to the schema since the message was written
+        let reader_schema = apache_avro::Schema::parse_str(
+            r#"{"type": "record", "name": "Data", "fields": [
+                {"name": "value", "type": "int"},
+                {"name": "label", "type": "string", "default": "none"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            decode_avro(&payload, &writer_schema, Some(&reader_schema)).unwrap(),
+            Value::Record(vec![
+                ("value".to_string(), Value::Int(42)),
+                ("label".to_string(), Value::String("none".to_string())),
+            ])
+        );
+
+        let payload = Payload::new(Default::default(), Vec::new());
+        assert!(matches!(
+            decode_avro(&payload, &writer_schema, None),
+            Err(ConsumerError::Deserialization(_))
+        ));
+        assert!(matches!(
+            proto::Schema {
+                r#type: SchemaType::String as i32,
+                ..Default::default()
+            }
+            .avro_schema(),
+            Err(ConsumerError::Deserialization(_))
+        ));
+    }
+}
@@ -93,59 +93,42 @@ impl<Exe: Executor> ServiceDiscovery<Exe> {
                     dur
                 );
             }
-            let LookupResponse {
-                broker_url,
-                broker_url_tls,
-                proxy,
-                redirect,
-                authoritative,
-            } = convert_lookup_response(&response)?;
-            is_authoritative = authoritative;
-
-            // use the TLS connection if available
-            let connection_url = if let Some(u) = &broker_url_tls {
-                u.clone()
-            } else if let Some(u) = &broker_url {
-                u.clone()
-            } else {
-                return Err(ServiceDiscoveryError::NotFound);
-            };
-
-            // if going through a proxy, we use the base URL
-            let url = if proxied_query || proxy {
-                base_url.clone()
-            } else {
-                connection_url.clone()
-            };
-
-            let broker_url = if let Some(u) = broker_url_tls {
-                format!("{}:{}", u.host_str().unwrap(), u.port().unwrap_or(6651))
-            } else if let Some(u) = broker_url {
-                format!("{}:{}", u.host_str().unwrap(), u.port().unwrap_or(6650))
-            } else {
-                return Err(ServiceDiscoveryError::NotFound);
-            };
-
-            broker_address = BrokerAddress {
-                url,
-                broker_url,
-                proxy: proxied_query || proxy,
-            };
-
-            // if the response indicated a redirect, do another query
-            // to the target broker
-            if redirect {
-                conn = self.manager.get_connection(&broker_address).await?;
-                proxied_query = broker_address.proxy;
-                continue;
-            } else {
-                let res = self
-                    .manager
-                    .get_connection(&broker_address)
-                    .await
-                    .map(|_| broker_address)
-                    .map_err(ServiceDiscoveryError::Connection);
-                break res;
+            match lookup_outcome(&response, &base_url, proxied_query)? {
+                // if the response indicated a redirect, do another query
+                // to the target broker
+                LookupOutcome::Redirect {
+                    address,
+                    authoritative,
+                } => {
+                    is_authoritative = authoritative;
+                    broker_address = address;
+                    conn = self.manager.get_connection(&broker_address).await?;
+                    proxied_query = broker_address.proxy;
+                    continue;
+                }
+                LookupOutcome::Connect(new_address)
+                    if !new_address.proxy
+                        && !broker_address.proxy
+                        && same_endpoint(&broker_address.url, &new_address.url) =>
+                {
+                    // the broker we queried owns the topic, we can reuse the
+                    // same connection instead of opening a new one
+                    debug!(
+                        "lookup({}): reusing connection to {}",
+                        topic, broker_address.url
+                    );
+                    break Ok(broker_address);
+                }
+                LookupOutcome::Connect(new_address) => {
+                    broker_address = new_address;
+                    let res = self
+                        .manager
+                        .get_connection(&broker_address)
+                        .await
+                        .map(|_| broker_address)
+                        .map_err(ServiceDiscoveryError::Connection);
+                    break res;
+                }
             }
         }
     }
@@ -253,6 +236,87 @@ impl<Exe: Executor> ServiceDiscovery<Exe> {
     }
 }
 
+/// checks if two URLs point to the same host and port, ignoring the path
+/// and whether the default port is explicit
+fn same_endpoint(a: &Url, b: &Url) -> bool {
+    let port = |u: &Url| {
+        u.port().unwrap_or(match u.scheme() {
+            "pulsar+ssl" => 6651,
+            _ => 6650,
+        })
+    };
+    a.scheme() == b.scheme() && a.host_str() == b.host_str() && port(a) == port(b)
+}
+
+/// what to do after a successful lookup query
+#[derive(Debug, PartialEq)]
+enum LookupOutcome {
+    /// the broker owns the topic
+    Connect(BrokerAddress),
+    /// the query must be sent again to this broker
+    Redirect {
+        address: BrokerAddress,
+        authoritative: bool,
+    },
+}
+
+/// finds the broker designated by a lookup response
+///
+/// when the lookup goes through a proxy, the connection is made to the base
+/// URL and the broker is named in the `CONNECT` command, so that a single
+/// connection to the proxy can serve several brokers
+fn lookup_outcome(
+    response: &CommandLookupTopicResponse,
+    base_url: &Url,
+    proxied_query: bool,
+) -> Result<LookupOutcome, ServiceDiscoveryError> {
+    let LookupResponse {
+        broker_url,
+        broker_url_tls,
+        proxy,
+        redirect,
+        authoritative,
+    } = convert_lookup_response(response)?;
+
+    // use the TLS connection if available
+    let connection_url = if let Some(u) = &broker_url_tls {
+        u.clone()
+    } else if let Some(u) = &broker_url {
+        u.clone()
+    } else {
+        return Err(ServiceDiscoveryError::NotFound);
+    };
+
+    // if going through a proxy, we use the base URL
+    let url = if proxied_query || proxy {
+        base_url.clone()
+    } else {
+        connection_url
+    };
+
+    let broker_url = if let Some(u) = broker_url_tls {
+        format!("{}:{}", u.host_str().unwrap(), u.port().unwrap_or(6651))
+    } else if let Some(u) = broker_url {
+        format!("{}:{}", u.host_str().unwrap(), u.port().unwrap_or(6650))
+    } else {
+        return Err(ServiceDiscoveryError::NotFound);
+    };
+
+    let address = BrokerAddress {
+        url,
+        broker_url,
+        proxy: proxied_query || proxy,
+    };
+    if redirect {
+        Ok(LookupOutcome::Redirect {
+            address,
+            authoritative,
+        })
+    } else {
+        Ok(LookupOutcome::Connect(address))
+    }
+}
+
 struct LookupResponse {
     pub broker_url: Option<Url>,
     pub broker_url_tls: Option<Url>,
@@ -294,3 +358,75 @@ fn convert_lookup_response(
         authoritative,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxied_redirect() {
+        let base_url = Url::parse("pulsar://proxy:6650").unwrap();
+        let response = CommandLookupTopicResponse {
+            broker_service_url: Some("pulsar://broker-2:6650".to_string()),
+            response: Some(command_lookup_topic_response::LookupType::Redirect as i32),
+            proxy_through_service_url: Some(true),
+            authoritative: Some(true),
+            ..Default::default()
+        };
+
+        let outcome = lookup_outcome(&response, &base_url, false).unwrap();
+        assert_eq!(
+            outcome,
+            LookupOutcome::Redirect {
+                address: BrokerAddress {
+                    url: base_url.clone(),
+                    broker_url: "broker-2:6650".to_string(),
+                    proxy: true,
+                },
+                authoritative: true,
+            }
+        );
+
+        let response = CommandLookupTopicResponse {
+            response: Some(command_lookup_topic_response::LookupType::Connect as i32),
+            proxy_through_service_url: Some(false),
+            ..response
+        };
+        // the connection stays on the proxy once a query went through it
+        let outcome = lookup_outcome(&response, &base_url, true).unwrap();
+        assert_eq!(
+            outcome,
+            LookupOutcome::Connect(BrokerAddress {
+                url: base_url,
+                broker_url: "broker-2:6650".to_string(),
+                proxy: true,
+            })
+        );
+    }
+
+    #[test]
+    fn same_endpoint_ignores_default_port() {
+        let url = |s| Url::parse(s).unwrap();
+
+        assert!(same_endpoint(
+            &url("pulsar://broker-1:6650"),
+            &url("pulsar://broker-1")
+        ));
+        assert!(same_endpoint(
+            &url("pulsar+ssl://broker-1"),
+            &url("pulsar+ssl://broker-1:6651/")
+        ));
+        assert!(!same_endpoint(
+            &url("pulsar://broker-1"),
+            &url("pulsar://broker-2")
+        ));
+        assert!(!same_endpoint(
+            &url("pulsar://broker-1:6650"),
+            &url("pulsar://broker-1:6660")
+        ));
+        assert!(!same_endpoint(
+            &url("pulsar://broker-1:6651"),
+            &url("pulsar+ssl://broker-1")
+        ));
+    }
+}
@@ -0,0 +1,38 @@
+//! transactions
+//!
+//! a transaction is opened with [Pulsar::new_txn](crate::Pulsar::new_txn).
+//! The partitions it produces on must be enrolled with
+//! [Pulsar::add_partition_to_txn](crate::Pulsar::add_partition_to_txn), then
+//! messages are attached to it by setting
+//! [Message::txn_id](crate::producer::Message::txn_id). It is committed or
+//! aborted with [Pulsar::end_txn](crate::Pulsar::end_txn).
+//!
+//! ```rust,ignore
+//! let txn_id = pulsar.new_txn(Duration::from_secs(60)).await?;
+//! pulsar.add_partition_to_txn(txn_id, vec![topic.clone()]).await?;
+//! producer
+//!     .send(producer::Message {
+//!         payload: b"data".to_vec(),
+//!         txn_id: Some(txn_id),
+//!         ..Default::default()
+//!     })
+//!     .await?;
+//! pulsar.end_txn(txn_id, true).await?;
+//! ```
+//!
+//! subscriptions can be enrolled with
+//! [Pulsar::add_subscription_to_txn](crate::Pulsar::add_subscription_to_txn),
+//! but consumers do not send transactional acknowledgements yet.
+
+/// identifier of a transaction, allocated by the transaction coordinator
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TxnId {
+    pub most_bits: u64,
+    pub least_bits: u64,
+}
+
+impl std::fmt::Display for TxnId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "({},{})", self.most_bits, self.least_bits)
+    }
+}